@@ -0,0 +1,74 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::tags::TagError;
+
+/// Errors describing why artwork couldn't
+/// be stored, fetched or resized
+#[derive(Debug)]
+pub enum ArtworkError {
+    Io(std::io::Error),
+    /// The data isn't an image in a supported format
+    UnsupportedFormat,
+    /// The image is in a supported format, but can't be decoded
+    Decode(image::ImageError),
+    Tags(TagError),
+    Download(reqwest::Error),
+    /// The downloaded image is bigger than `MAX_DOWNLOAD_SIZE`
+    TooLarge(u64),
+}
+
+impl Display for ArtworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArtworkError::Io(err) => {
+                writeln!(f, "An I/O error occured while accessing artwork")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            ArtworkError::UnsupportedFormat => {
+                writeln!(f, "The artwork isn't an image in a supported format")
+            }
+            ArtworkError::Decode(err) => {
+                writeln!(f, "The artwork image couldn't be decoded")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            ArtworkError::Tags(err) => {
+                writeln!(f, "The artwork couldn't be read from the song tags")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            ArtworkError::Download(err) => {
+                writeln!(f, "The artwork couldn't be downloaded")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            ArtworkError::TooLarge(size) => {
+                writeln!(f, "The artwork is too large to be stored ({} bytes)", size)
+            }
+        }
+    }
+}
+
+impl Error for ArtworkError {}
+
+impl From<std::io::Error> for ArtworkError {
+    fn from(err: std::io::Error) -> Self {
+        ArtworkError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for ArtworkError {
+    fn from(err: image::ImageError) -> Self {
+        ArtworkError::Decode(err)
+    }
+}
+
+impl From<TagError> for ArtworkError {
+    fn from(err: TagError) -> Self {
+        ArtworkError::Tags(err)
+    }
+}
+
+impl From<reqwest::Error> for ArtworkError {
+    fn from(err: reqwest::Error) -> Self {
+        ArtworkError::Download(err)
+    }
+}
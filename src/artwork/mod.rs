@@ -0,0 +1,260 @@
+use std::{
+    ffi::OsString,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::integrity::sha256_hex;
+
+pub use self::error::ArtworkError;
+
+mod error;
+
+/// Largest image accepted from a download, in bytes
+pub const MAX_DOWNLOAD_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Name of the directory, inside the store, holding thumbnails
+const THUMBNAILS_DIR: &str = "thumbnails";
+
+/// An image stored in an `ArtworkStore`. It's identified by the
+/// SHA-256 of its content, so the same cover shared by every song of
+/// an album is stored once.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Hash)]
+pub struct Artwork {
+    /// Hex encoded SHA-256 of the image file
+    hash: String,
+    format: ImageFormat,
+}
+
+impl Artwork {
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// Returns the name of the image file inside the store
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.hash, self.format.extension())
+    }
+}
+
+/// The image formats artwork can be stored in
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Hash)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Bmp,
+    WebP,
+}
+
+impl ImageFormat {
+    /// Recognises the format of an image from its first bytes
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if data.starts_with(b"\xff\xd8\xff") {
+            Some(ImageFormat::Jpeg)
+        } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if data.starts_with(b"BM") {
+            Some(ImageFormat::Bmp)
+        } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Bmp => "bmp",
+            ImageFormat::WebP => "webp",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Bmp => "image/bmp",
+            ImageFormat::WebP => "image/webp",
+        }
+    }
+
+    fn decoder_format(&self) -> image::ImageFormat {
+        match self {
+            ImageFormat::Png => image::ImageFormat::Png,
+            ImageFormat::Jpeg => image::ImageFormat::Jpeg,
+            ImageFormat::Gif => image::ImageFormat::Gif,
+            ImageFormat::Bmp => image::ImageFormat::Bmp,
+            ImageFormat::WebP => image::ImageFormat::WebP,
+        }
+    }
+}
+
+/// Stores artwork in a directory, each image in a file named after
+/// its hash. Thumbnails are made on request and cached in the
+/// `thumbnails` directory inside it.
+pub struct ArtworkStore {
+    dir: PathBuf,
+}
+
+impl ArtworkStore {
+    /// Opens the store in `dir`, creating the
+    /// directory if it doesn't exist
+    pub fn new(dir: OsString) -> std::io::Result<Self> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(dir.join(THUMBNAILS_DIR))?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores the image `data`, unless an identical one already is.
+    /// The image must be in a supported format and have a readable
+    /// header; it isn't decoded as a whole.
+    pub fn add(&self, data: &[u8]) -> Result<Artwork, ArtworkError> {
+        let format = ImageFormat::detect(data).ok_or(ArtworkError::UnsupportedFormat)?;
+        image::ImageReader::with_format(Cursor::new(data), format.decoder_format())
+            .into_dimensions()?;
+
+        let artwork = Artwork {
+            hash: sha256_hex(data),
+            format,
+        };
+        let path = self.path(&artwork);
+        if !path.exists() {
+            write_new(&path, |tmp| {
+                std::fs::write(tmp, data).map_err(ArtworkError::from)
+            })?;
+        }
+        Ok(artwork)
+    }
+
+    /// Stores the cover embedded in the tags of the song file at `path`,
+    /// or the first picture if none is marked as the front cover.
+    /// Returns `None` if the file has no picture.
+    pub fn extract(&self, path: &OsString) -> Result<Option<Artwork>, ArtworkError> {
+        let tags = crate::tags::read(path)?;
+        match tags.cover() {
+            Some(picture) => self.add(picture.data()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Downloads the image at `url`, e.g. a thumbnail returned by a
+    /// plugin, and stores it. Images bigger than `MAX_DOWNLOAD_SIZE`
+    /// are refused.
+    pub async fn download(
+        &self,
+        client: &reqwest::Client,
+        url: &reqwest::Url,
+    ) -> Result<Artwork, ArtworkError> {
+        let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+        if let Some(size) = response.content_length() {
+            if size > MAX_DOWNLOAD_SIZE {
+                return Err(ArtworkError::TooLarge(size));
+            }
+        }
+
+        // The declared length can't be trusted
+        let mut data = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            data.extend_from_slice(&chunk);
+            if data.len() as u64 > MAX_DOWNLOAD_SIZE {
+                return Err(ArtworkError::TooLarge(data.len() as u64));
+            }
+        }
+
+        self.add(&data)
+    }
+
+    /// Returns the position of the image file of `artwork`
+    pub fn path(&self, artwork: &Artwork) -> PathBuf {
+        self.dir.join(artwork.file_name())
+    }
+
+    /// Returns `true` if the image file of `artwork` is in the store
+    pub fn contains(&self, artwork: &Artwork) -> bool {
+        self.path(artwork).is_file()
+    }
+
+    /// Reads the image file of `artwork`
+    pub fn read(&self, artwork: &Artwork) -> Result<Vec<u8>, ArtworkError> {
+        Ok(std::fs::read(self.path(artwork))?)
+    }
+
+    /// Returns the position of a PNG thumbnail of `artwork` fitting in a
+    /// `size` by `size` square, making it if it isn't cached yet. The
+    /// aspect ratio is kept and smaller images aren't enlarged.
+    pub fn thumbnail(&self, artwork: &Artwork, size: u32) -> Result<PathBuf, ArtworkError> {
+        let size = size.max(1);
+        let path = self
+            .dir
+            .join(THUMBNAILS_DIR)
+            .join(format!("{}-{}.png", artwork.hash, size));
+        if path.exists() {
+            return Ok(path);
+        }
+
+        let data = self.read(artwork)?;
+        let image = image::load_from_memory_with_format(&data, artwork.format.decoder_format())?;
+        let image = if image.width() > size || image.height() > size {
+            image.thumbnail(size, size)
+        } else {
+            image
+        };
+        write_new(&path, |tmp| {
+            image
+                .save_with_format(tmp, image::ImageFormat::Png)
+                .map_err(ArtworkError::from)
+        })?;
+        Ok(path)
+    }
+
+    /// Removes `artwork` from the store, together with its thumbnails
+    pub fn remove(&self, artwork: &Artwork) -> std::io::Result<()> {
+        let prefix = format!("{}-", artwork.hash);
+        for thumbnail in std::fs::read_dir(self.dir.join(THUMBNAILS_DIR))?.flatten() {
+            if thumbnail.file_name().to_string_lossy().starts_with(&prefix) {
+                std::fs::remove_file(thumbnail.path())?;
+            }
+        }
+
+        match std::fs::remove_file(self.path(artwork)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes a file of the store through a temporary one, so that an
+/// interrupted write never leaves a file whose name promises a content
+/// it doesn't have
+fn write_new<F>(path: &Path, write: F) -> Result<(), ArtworkError>
+where
+    F: FnOnce(&Path) -> Result<(), ArtworkError>,
+{
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    if let Err(err) = write(&tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
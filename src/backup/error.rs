@@ -0,0 +1,72 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::Display;
+
+use crate::song::LoadError;
+
+/// Errors describing why a backup couldn't be
+/// made or restored
+#[derive(Debug)]
+pub enum BackupError {
+    Io(std::io::Error),
+    /// The archive has no manifest
+    MissingManifest,
+    Manifest(serde_json::Error),
+    /// The archive was made by a newer version of the format
+    UnsupportedVersion(u64),
+    /// The entry of the archive is missing, isn't the one listed
+    /// in the manifest, or leads out of the library root
+    Corrupted(OsString),
+    /// The library in the archive couldn't be loaded
+    Load(LoadError),
+}
+
+impl Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::Io(err) => {
+                writeln!(f, "The archive couldn't be read or written")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            BackupError::MissingManifest => writeln!(f, "The archive has no manifest"),
+            BackupError::Manifest(err) => {
+                writeln!(f, "The manifest of the archive isn't valid")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            BackupError::UnsupportedVersion(version) => writeln!(
+                f,
+                "The archive has version {}, which is newer than the supported one",
+                version
+            ),
+            BackupError::Corrupted(path) => writeln!(
+                f,
+                "`{}` is missing from the archive, damaged or outside the library",
+                path.to_string_lossy()
+            ),
+            BackupError::Load(err) => {
+                writeln!(f, "The library in the archive couldn't be loaded")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for BackupError {}
+
+impl From<std::io::Error> for BackupError {
+    fn from(err: std::io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for BackupError {
+    fn from(err: serde_json::Error) -> Self {
+        BackupError::Manifest(err)
+    }
+}
+
+impl From<LoadError> for BackupError {
+    fn from(err: LoadError) -> Self {
+        BackupError::Load(err)
+    }
+}
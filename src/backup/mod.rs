@@ -0,0 +1,572 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::Read,
+    path::{Component, Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    integrity::to_hex,
+    storage::{Collection, MemoryStorage, Storage},
+};
+
+pub use self::error::BackupError;
+
+mod error;
+
+/// Name of the entry of the archive describing it
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Version of the format of archives
+const MANIFEST_VERSION: u64 = 1;
+
+/// Directory of the archive holding the song files
+const AUDIO_DIR: &str = "audio";
+
+/// Directory of the archive holding the artwork of the songs
+const ARTWORK_DIR: &str = "artwork";
+
+/// Describes a backup archive: where the library was,
+/// and every other entry of the archive
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Manifest {
+    version: u64,
+    created: DateTime<Utc>,
+    /// Where the library root was when the archive was made
+    #[serde(with = "crate::os_string")]
+    library_root: OsString,
+    entries: Vec<ManifestEntry>,
+}
+
+/// An entry of a backup archive
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    #[serde(with = "crate::os_string")]
+    path: OsString,
+    /// Hex encoded SHA-256 of the content of the entry
+    hash: String,
+    size: u64,
+    /// The song whose file this entry is, if any
+    #[serde(default)]
+    song: Option<Uuid>,
+}
+
+impl Manifest {
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn created(&self) -> &DateTime<Utc> {
+        &self.created
+    }
+
+    pub fn library_root(&self) -> &OsString {
+        &self.library_root
+    }
+
+    pub fn entries(&self) -> &Vec<ManifestEntry> {
+        &self.entries
+    }
+
+    /// Returns `true` if the archive holds song files
+    pub fn has_audio(&self) -> bool {
+        self.entries.iter().any(|entry| entry.song.is_some())
+    }
+}
+
+impl ManifestEntry {
+    /// Returns the path of the entry inside the archive
+    pub fn path(&self) -> &OsString {
+        &self.path
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn song(&self) -> Option<Uuid> {
+        self.song
+    }
+}
+
+/// Writes a backup archive, an uncompressed tar file,
+/// one entry at a time. The manifest comes last.
+pub(crate) struct ArchiveWriter {
+    builder: tar::Builder<File>,
+    entries: Vec<ManifestEntry>,
+    library_root: OsString,
+}
+
+impl ArchiveWriter {
+    pub(crate) fn create(path: &OsString, library_root: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            builder: tar::Builder::new(File::create(path)?),
+            entries: vec![],
+            library_root: library_root.as_os_str().to_os_string(),
+        })
+    }
+
+    /// Adds the document `name` of `collection`, held in `data`
+    pub(crate) fn add_document(
+        &mut self,
+        collection: Collection,
+        name: &OsStr,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        let path = Path::new(collection.name()).join(name);
+        self.add(&path, data.len() as u64, data, None)
+    }
+
+    /// Adds the image `data`, named `name` in the artwork store
+    pub(crate) fn add_artwork(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = Path::new(ARTWORK_DIR).join(name);
+        self.add(&path, data.len() as u64, data, None)
+    }
+
+    /// Adds the file at `file`, which is the one of the song with id `id`
+    pub(crate) fn add_song_file(&mut self, id: Uuid, file: &OsString) -> std::io::Result<()> {
+        let mut name = OsString::from(id.to_string());
+        if let Some(extension) = Path::new(file).extension() {
+            name.push(".");
+            name.push(extension);
+        }
+        let path = Path::new(AUDIO_DIR).join(name);
+        let file = File::open(file)?;
+        let size = file.metadata()?.len();
+        self.add(&path, size, file, Some(id))
+    }
+
+    fn add<R: Read>(
+        &mut self,
+        path: &Path,
+        size: u64,
+        data: R,
+        song: Option<Uuid>,
+    ) -> std::io::Result<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp().max(0) as u64);
+        let mut reader = HashingReader::new(data);
+        self.builder.append_data(&mut header, path, &mut reader)?;
+        self.entries.push(ManifestEntry {
+            path: path.as_os_str().to_os_string(),
+            hash: reader.hash(),
+            size,
+            song,
+        });
+        Ok(())
+    }
+
+    /// Writes the manifest and closes the archive
+    pub(crate) fn finish(mut self) -> Result<Manifest, BackupError> {
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            created: Utc::now(),
+            library_root: self.library_root,
+            entries: self.entries,
+        };
+        let data = serde_json::to_vec_pretty(&manifest)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created.timestamp().max(0) as u64);
+        self.builder
+            .append_data(&mut header, MANIFEST_FILE, &data[..])?;
+        self.builder.into_inner()?.sync_all()?;
+        Ok(manifest)
+    }
+}
+
+/// What a backup archive holds, but the song files
+pub(crate) struct ArchiveContents {
+    pub(crate) manifest: Manifest,
+    /// The documents of the library, as they were in its storage
+    pub(crate) documents: MemoryStorage,
+    /// Images, by name in the artwork store
+    pub(crate) artwork: Vec<(String, Vec<u8>)>,
+}
+
+/// Reads the archive at `path`, checking every entry against its
+/// manifest. The song files are only checked, `extract_songs` copies
+/// them out.
+pub(crate) fn read(path: &OsString) -> Result<ArchiveContents, BackupError> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut found: HashMap<OsString, (String, u64)> = HashMap::new();
+    let mut contents: Vec<(PathBuf, Vec<u8>)> = vec![];
+    let mut manifest = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if path == Path::new(MANIFEST_FILE) {
+            let mut data = vec![];
+            entry.read_to_end(&mut data)?;
+            manifest = Some(data);
+            continue;
+        }
+        let mut reader = HashingReader::new(&mut entry);
+        if path.starts_with(AUDIO_DIR) {
+            std::io::copy(&mut reader, &mut std::io::sink())?;
+        } else {
+            let mut data = vec![];
+            reader.read_to_end(&mut data)?;
+            contents.push((path.clone(), data));
+        }
+        found.insert(path.into_os_string(), (reader.hash(), reader.read));
+    }
+
+    let manifest = manifest.ok_or(BackupError::MissingManifest)?;
+    let version = serde_json::from_slice::<serde_json::Value>(&manifest)?
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(MANIFEST_VERSION);
+    if version > MANIFEST_VERSION {
+        return Err(BackupError::UnsupportedVersion(version));
+    }
+    let manifest: Manifest = serde_json::from_slice(&manifest)?;
+
+    // Every entry must be the one listed, and only those can be there
+    let listed: HashSet<&OsString> = manifest.entries.iter().map(|entry| &entry.path).collect();
+    for entry in &manifest.entries {
+        if found.get(&entry.path) != Some(&(entry.hash.clone(), entry.size)) {
+            return Err(BackupError::Corrupted(entry.path.clone()));
+        }
+    }
+    if let Some(path) = found.keys().find(|path| !listed.contains(path)) {
+        return Err(BackupError::Corrupted(path.clone()));
+    }
+
+    let documents = MemoryStorage::new();
+    let mut artwork = vec![];
+    for (path, data) in contents {
+        let (dir, name) = match (path.parent(), path.file_name()) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => return Err(BackupError::Corrupted(path.into_os_string())),
+        };
+        if dir == Path::new(ARTWORK_DIR) {
+            artwork.push((name.to_string_lossy().into_owned(), data));
+            continue;
+        }
+        let collection = [Collection::Songs, Collection::Playlists]
+            .into_iter()
+            .find(|collection| dir == Path::new(collection.name()))
+            .ok_or_else(|| BackupError::Corrupted(path.clone().into_os_string()))?;
+        documents.write(collection, name, &data)?;
+    }
+
+    Ok(ArchiveContents {
+        manifest,
+        documents,
+        artwork,
+    })
+}
+
+/// Copies the files of the songs out of the archive at `path`, each one
+/// to the path `targets` gives for the song. Files already there are
+/// left as they are. Nothing is copied if a target isn't inside
+/// `library_root`, since archives can't be trusted.
+pub(crate) fn extract_songs(
+    path: &OsString,
+    manifest: &Manifest,
+    targets: &HashMap<Uuid, PathBuf>,
+    library_root: &Path,
+) -> Result<(), BackupError> {
+    for target in targets.values() {
+        let inside = target
+            .strip_prefix(library_root)
+            .map(|relative| !relative.as_os_str().is_empty() && goes_down(relative))
+            .unwrap_or(false);
+        if !inside {
+            return Err(BackupError::Corrupted(target.clone().into_os_string()));
+        }
+    }
+    let songs: HashMap<&OsString, Uuid> = manifest
+        .entries
+        .iter()
+        .filter_map(|entry| entry.song.map(|song| (&entry.path, song)))
+        .collect();
+
+    let mut archive = tar::Archive::new(File::open(path)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned().into_os_string();
+        let target = match songs.get(&path).and_then(|id| targets.get(id)) {
+            Some(target) if !target.exists() => target,
+            _ => continue,
+        };
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(target)?;
+        if let Err(err) = std::io::copy(&mut entry, &mut file).and_then(|_| file.sync_all()) {
+            let _ = std::fs::remove_file(target);
+            return Err(err.into());
+        }
+    }
+    Ok(())
+}
+
+/// Returns `true` if `path` is relative and only goes down
+/// into directories, so that it stays inside the one it's joined to
+pub(crate) fn goes_down(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Hashes what's read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            read: 0,
+        }
+    }
+
+    /// Returns the hex encoded SHA-256 of what was read so far
+    fn hash(&self) -> String {
+        to_hex(&self.hasher.clone().finalize())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temporary directory, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(extension: &str) -> Self {
+            let name = format!("phosphorus-{}.{}", Uuid::new_v4(), extension);
+            Self(std::env::temp_dir().join(name))
+        }
+
+        fn path(&self) -> OsString {
+            self.0.clone().into_os_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Writes a tar file at `path` holding `entries` as they are
+    fn write_tar(path: &OsString, entries: &[(&str, &[u8])]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for (name, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    /// Returns a manifest listing `entries` with their actual content
+    fn manifest(version: u64, entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let entries: Vec<serde_json::Value> = entries
+            .iter()
+            .map(|(name, data)| {
+                let mut hasher = Sha256::new();
+                hasher.update(data);
+                serde_json::json!({
+                    "path": name,
+                    "hash": to_hex(&hasher.finalize()),
+                    "size": data.len(),
+                })
+            })
+            .collect();
+        serde_json::to_vec(&serde_json::json!({
+            "version": version,
+            "created": "2020-01-01T00:00:00Z",
+            "library_root": "/music",
+            "entries": entries,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reads_what_was_written() {
+        let archive = TempFile::new("tar");
+        let song = TempFile::new("ogg");
+        std::fs::write(&song.0, b"audio").unwrap();
+        let id = Uuid::new_v4();
+
+        let mut writer = ArchiveWriter::create(&archive.path(), Path::new("/music")).unwrap();
+        writer
+            .add_document(Collection::Songs, OsStr::new("library.json"), b"{}")
+            .unwrap();
+        writer
+            .add_document(Collection::Playlists, OsStr::new("a.json"), b"[]")
+            .unwrap();
+        writer.add_artwork("cover.png", b"png").unwrap();
+        writer.add_song_file(id, &song.path()).unwrap();
+        writer.finish().unwrap();
+
+        let contents = read(&archive.path()).unwrap();
+        assert_eq!(contents.manifest.version(), MANIFEST_VERSION);
+        assert_eq!(contents.manifest.library_root(), "/music");
+        assert!(contents.manifest.has_audio());
+        let documents = &contents.documents;
+        assert_eq!(
+            documents
+                .read(Collection::Songs, OsStr::new("library.json"))
+                .unwrap(),
+            b"{}"
+        );
+        assert_eq!(
+            documents
+                .read(Collection::Playlists, OsStr::new("a.json"))
+                .unwrap(),
+            b"[]"
+        );
+        assert_eq!(
+            contents.artwork,
+            vec![("cover.png".into(), b"png".to_vec())]
+        );
+    }
+
+    #[test]
+    fn refuses_damaged_entries() {
+        let archive = TempFile::new("tar");
+        let manifest = manifest(1, &[("songs/library.json", b"{}")]);
+        write_tar(
+            &archive.path(),
+            &[("songs/library.json", b"{ }"), (MANIFEST_FILE, &manifest)],
+        );
+        match read(&archive.path()) {
+            Err(BackupError::Corrupted(path)) => assert_eq!(path, "songs/library.json"),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn refuses_missing_entries() {
+        let archive = TempFile::new("tar");
+        let manifest = manifest(1, &[("songs/library.json", b"{}")]);
+        write_tar(&archive.path(), &[(MANIFEST_FILE, &manifest)]);
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::Corrupted(path)) if path == "songs/library.json"
+        ));
+    }
+
+    #[test]
+    fn refuses_entries_missing_from_the_manifest() {
+        let archive = TempFile::new("tar");
+        let manifest = manifest(1, &[("songs/library.json", b"{}")]);
+        write_tar(
+            &archive.path(),
+            &[
+                ("songs/library.json", b"{}"),
+                ("songs/extra.json", b"{}"),
+                (MANIFEST_FILE, &manifest),
+            ],
+        );
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::Corrupted(path)) if path == "songs/extra.json"
+        ));
+    }
+
+    #[test]
+    fn refuses_entries_outside_the_collections() {
+        let archive = TempFile::new("tar");
+        let entries: &[(&str, &[u8])] = &[("other/library.json", b"{}")];
+        let manifest = manifest(1, entries);
+        write_tar(&archive.path(), &[entries[0], (MANIFEST_FILE, &manifest)]);
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::Corrupted(path)) if path == "other/library.json"
+        ));
+    }
+
+    #[test]
+    fn checks_the_manifest() {
+        let archive = TempFile::new("tar");
+        write_tar(&archive.path(), &[("songs/library.json", b"{}")]);
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::MissingManifest)
+        ));
+
+        write_tar(&archive.path(), &[(MANIFEST_FILE, b"not json")]);
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::Manifest(_))
+        ));
+
+        let newer = manifest(MANIFEST_VERSION + 1, &[]);
+        write_tar(&archive.path(), &[(MANIFEST_FILE, &newer)]);
+        assert!(matches!(
+            read(&archive.path()),
+            Err(BackupError::UnsupportedVersion(version)) if version == MANIFEST_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn only_extracts_inside_the_library_root() {
+        let archive = TempFile::new("tar");
+        let root = std::env::temp_dir().join(format!("phosphorus-{}", Uuid::new_v4()));
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            created: Utc::now(),
+            library_root: "/music".into(),
+            entries: vec![],
+        };
+        write_tar(&archive.path(), &[]);
+
+        for target in [
+            root.join("..").join("escaped.ogg"),
+            PathBuf::from("/elsewhere/song.ogg"),
+            root.clone(),
+        ] {
+            let targets = HashMap::from([(Uuid::new_v4(), target.clone())]);
+            assert!(matches!(
+                extract_songs(&archive.path(), &manifest, &targets, &root),
+                Err(BackupError::Corrupted(path)) if path == target.into_os_string()
+            ));
+        }
+        let targets = HashMap::from([(Uuid::new_v4(), root.join("a").join("song.ogg"))]);
+        extract_songs(&archive.path(), &manifest, &targets, &root).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn tells_paths_that_only_go_down() {
+        assert!(goes_down(Path::new("a/b.ogg")));
+        assert!(goes_down(Path::new("./a.ogg")));
+        assert!(!goes_down(Path::new("../a.ogg")));
+        assert!(!goes_down(Path::new("a/../../b.ogg")));
+        assert!(!goes_down(Path::new("/a.ogg")));
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt::Display;
+
+/// Returned by `Bytes` when the data ends before
+/// the requested value.
+#[derive(Debug)]
+pub(crate) struct Truncated;
+
+impl Display for Truncated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unexpected end of data")
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+/// A cursor over a byte slice that reads
+/// the integer types used by audio containers.
+pub(crate) struct Bytes<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bytes<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Returns how many bytes are left to be read
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    /// Returns the unread part of the data without consuming it
+    pub fn rest(&self) -> &'a [u8] {
+        &self.data[self.pos..]
+    }
+
+    pub fn skip(&mut self, count: usize) -> Result<(), Truncated> {
+        self.take(count).map(|_| ())
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], Truncated> {
+        if self.remaining() < count {
+            return Err(Truncated);
+        }
+        let slice = &self.data[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Truncated> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16_be(&mut self) -> Result<u16, Truncated> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u24_be(&mut self) -> Result<u32, Truncated> {
+        let bytes = self.take(3)?;
+        Ok(u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]))
+    }
+
+    pub fn u32_be(&mut self) -> Result<u32, Truncated> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32_le(&mut self) -> Result<u32, Truncated> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64_be(&mut self) -> Result<u64, Truncated> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Truncated> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+/// Decodes a 28 bits syncsafe integer, as used by ID3v2
+pub(crate) fn syncsafe(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .take(4)
+        .fold(0, |value, byte| (value << 7) | (*byte & 0x7F) as u32)
+}
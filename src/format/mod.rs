@@ -0,0 +1,193 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use serde::{Deserialize, Serialize};
+
+pub(crate) mod bytes;
+pub(crate) mod mp4;
+pub(crate) mod mpeg;
+pub(crate) mod ogg;
+
+/// The container formats the core knows how to read
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Hash)]
+pub enum AudioFormat {
+    /// MPEG audio, possibly preceded by an ID3v2 tag
+    Mp3,
+    /// Native FLAC stream
+    Flac,
+    /// Ogg container holding Vorbis, Opus or FLAC
+    Ogg,
+    /// ISO base media file (m4a, mp4, ...)
+    Mp4,
+}
+
+impl AudioFormat {
+    /// Recognises the format of the file at `path` by looking at its content.
+    /// Returns `None` if the content doesn't match any supported format.
+    pub fn from_path(path: &OsString) -> std::io::Result<Option<Self>> {
+        let mut file = File::open(path)?;
+        Self::detect(&mut file)
+    }
+
+    /// Recognises the format of `reader` by looking at its first bytes. Its
+    /// cursor is moved back to the start before returning.
+    pub fn detect<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Self>> {
+        let offset = skip_id3v2(reader)?;
+        // Enough for two MPEG audio frames, the largest being 2881 bytes
+        let mut head = [0u8; 4096];
+        let read = read_up_to(reader, &mut head)?;
+        reader.seek(SeekFrom::Start(0))?;
+        let head = &head[..read];
+
+        let format = if head.starts_with(b"fLaC") {
+            Some(AudioFormat::Flac)
+        } else if head.starts_with(b"OggS") {
+            Some(AudioFormat::Ogg)
+        } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+            Some(AudioFormat::Mp4)
+        } else if mpeg::starts_stream(head) || offset > 0 {
+            // Some encoders leave junk between the ID3v2 tag and the first
+            // frame, so the tag alone is taken as a strong enough hint.
+            Some(AudioFormat::Mp3)
+        } else {
+            None
+        };
+
+        Ok(format)
+    }
+
+    /// Returns the extension usually given to files of this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp4 => "m4a",
+        }
+    }
+}
+
+/// Moves `reader` past an ID3v2 tag, if there is one at the current
+/// position, and returns the size of the skipped tag.
+pub(crate) fn skip_id3v2<R: Read + Seek>(reader: &mut R) -> std::io::Result<u64> {
+    let start = reader.stream_position()?;
+    let mut header = [0u8; 10];
+    if read_up_to(reader, &mut header)? < header.len() || &header[..3] != b"ID3" {
+        reader.seek(SeekFrom::Start(start))?;
+        return Ok(0);
+    }
+
+    let mut size = 10 + bytes::syncsafe(&header[6..10]) as u64;
+    // Footer present
+    if header[5] & 0x10 != 0 {
+        size += 10;
+    }
+    reader.seek(SeekFrom::Start(start + size))?;
+    Ok(size)
+}
+
+/// Like `Read::read_exact`, but stops without error at the end of the
+/// stream. Returns how many bytes were read.
+pub(crate) fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Returns a silent MPEG-1 layer III frame, at 128 kbit/s and 44.1 kHz
+    fn mpeg_frame() -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        frame
+    }
+
+    fn detect(data: &[u8]) -> Option<AudioFormat> {
+        let mut cursor = Cursor::new(data);
+        let format = AudioFormat::detect(&mut cursor).unwrap();
+        assert_eq!(cursor.position(), 0);
+        format
+    }
+
+    #[test]
+    fn detects_containers() {
+        assert_eq!(detect(b"fLaC\0\0\0\x22"), Some(AudioFormat::Flac));
+        assert_eq!(detect(b"OggS\0\x02"), Some(AudioFormat::Ogg));
+        assert_eq!(
+            detect(b"\0\0\0\x20ftypM4A \0\0\0\0"),
+            Some(AudioFormat::Mp4)
+        );
+        assert_eq!(detect(b""), None);
+        assert_eq!(detect(b"RIFF\0\0\0\0WAVE"), None);
+    }
+
+    #[test]
+    fn needs_two_mpeg_frames() {
+        let frames = [mpeg_frame(), mpeg_frame()].concat();
+        assert_eq!(detect(&frames), Some(AudioFormat::Mp3));
+        // A stream can end right after its first frame
+        assert_eq!(detect(&mpeg_frame()), Some(AudioFormat::Mp3));
+        assert_eq!(detect(&mpeg_frame()[..100]), None);
+
+        let mut junk = mpeg_frame();
+        junk.extend_from_slice(b"not a frame");
+        assert_eq!(detect(&junk), None);
+    }
+
+    #[test]
+    fn refuses_headers_with_invalid_fields() {
+        let with = |third: u8| {
+            let mut frame = mpeg_frame();
+            frame[2] = third;
+            [frame.clone(), frame].concat()
+        };
+        // Bitrate index 15, then sample rate index 3
+        assert_eq!(detect(&with(0xF0)), None);
+        assert_eq!(detect(&with(0x9C)), None);
+        // Reserved layer
+        assert_eq!(detect(&[0xFF, 0xF9, 0x90, 0x64]), None);
+    }
+
+    #[test]
+    fn refuses_utf16_text() {
+        let text: Vec<u8> = [0xFF, 0xFE]
+            .into_iter()
+            .chain(
+                "REM GENRE Rock\r\nFILE \"a.wav\" WAVE\r\n"
+                    .encode_utf16()
+                    .flat_map(u16::to_le_bytes),
+            )
+            .collect();
+        assert_eq!(detect(&text), None);
+        assert_eq!(detect(&[0xFF, 0xFE, b'E', 0]), None);
+    }
+
+    #[test]
+    fn takes_an_id3v2_tag_as_a_hint() {
+        let mut data = b"ID3\x03\0\0\0\0\0\x02\0\0".to_vec();
+        data.extend_from_slice(b"junk");
+        assert_eq!(detect(&data), Some(AudioFormat::Mp3));
+
+        let mut cursor = Cursor::new(&data);
+        assert_eq!(skip_id3v2(&mut cursor).unwrap(), 12);
+        assert_eq!(cursor.position(), 12);
+        // A header cut short isn't a tag
+        let mut cursor = Cursor::new(&data[..6]);
+        assert_eq!(skip_id3v2(&mut cursor).unwrap(), 0);
+    }
+}
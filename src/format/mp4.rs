@@ -0,0 +1,423 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use super::read_up_to;
+
+/// Iterates over the atoms (boxes) stored one after the other in a
+/// byte slice, yielding their type and their body. Iteration stops at
+/// the first atom whose size doesn't fit in the slice.
+pub(crate) struct Atoms<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Atoms<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 8 {
+            return None;
+        }
+
+        let size = u32::from_be_bytes(self.data[..4].try_into().unwrap()) as u64;
+        let kind: [u8; 4] = self.data[4..8].try_into().unwrap();
+        let (header, size) = match size {
+            0 => (8, self.data.len() as u64),
+            1 if self.data.len() >= 16 => {
+                (16, u64::from_be_bytes(self.data[8..16].try_into().unwrap()))
+            }
+            _ => (8, size),
+        };
+
+        if size < header || size > self.data.len() as u64 {
+            self.data = &[];
+            return None;
+        }
+
+        let body = &self.data[header as usize..size as usize];
+        self.data = &self.data[size as usize..];
+        Some((kind, body))
+    }
+}
+
+/// Returns the body of the first child of `data` with type `kind`
+pub(crate) fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    Atoms::new(data)
+        .find(|(atom, _)| atom == kind)
+        .map(|(_, body)| body)
+}
+
+/// Follows `path` from `data` and returns the body of the last atom.
+/// `meta` atoms are handled transparently, whether they carry the
+/// full box header or not.
+pub(crate) fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let mut data = data;
+    for kind in path {
+        data = child(data, kind)?;
+        if *kind == b"meta" {
+            data = meta_children(data);
+        }
+    }
+    Some(data)
+}
+
+/// Returns the part of a `meta` body holding its children. ISO files
+/// prefix them with a version and flags, QuickTime ones don't.
+pub(crate) fn meta_children(body: &[u8]) -> &[u8] {
+    if body.len() >= 8 && &body[4..8] == b"hdlr" {
+        body
+    } else if body.len() >= 4 {
+        &body[4..]
+    } else {
+        &[]
+    }
+}
+
+/// Position of an atom inside a file
+pub(crate) struct Location {
+    pub offset: u64,
+    /// Size of the whole atom, header included
+    pub size: u64,
+    pub header_size: u64,
+}
+
+/// Walks the top level atoms of `reader` and returns the location of
+/// the first one with type `kind`.
+pub(crate) fn locate_top_level<R: Read + Seek>(
+    reader: &mut R,
+    kind: &[u8; 4],
+) -> std::io::Result<Option<Location>> {
+    let end = reader.seek(SeekFrom::End(0))?;
+    let mut offset = 0;
+
+    while offset + 8 <= end {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut header = [0u8; 16];
+        let read = read_up_to(reader, &mut header)?;
+        if read < 8 {
+            break;
+        }
+
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        let (header_size, size) = match size {
+            0 => (8, end - offset),
+            1 if read == 16 => (16, u64::from_be_bytes(header[8..16].try_into().unwrap())),
+            _ => (8, size),
+        };
+        if size < header_size || offset + size > end {
+            break;
+        }
+
+        if &header[4..8] == kind {
+            return Ok(Some(Location {
+                offset,
+                size,
+                header_size,
+            }));
+        }
+        offset += size;
+    }
+
+    Ok(None)
+}
+
+/// Walks the top level atoms of `reader` and returns the body of the
+/// first one with type `kind`.
+pub(crate) fn read_top_level<R: Read + Seek>(
+    reader: &mut R,
+    kind: &[u8; 4],
+) -> std::io::Result<Option<Vec<u8>>> {
+    let location = match locate_top_level(reader, kind)? {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+
+    let mut body = vec![0u8; (location.size - location.header_size) as usize];
+    reader.seek(SeekFrom::Start(location.offset + location.header_size))?;
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+/// Serializes an atom
+pub(crate) fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut atom = Vec::with_capacity(body.len() + 8);
+    atom.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+    atom.extend_from_slice(kind);
+    atom.extend_from_slice(body);
+    atom
+}
+
+/// Rebuilds the atoms in `data` giving the atom at `path` the new
+/// `body`. Atoms missing along the path are created, `meta` ones with
+/// the handler iTunes metadata requires.
+pub(crate) fn replace(data: &[u8], path: &[&[u8; 4]], body: &[u8]) -> Vec<u8> {
+    let kind = path[0];
+    let mut result = vec![];
+    let mut found = false;
+
+    for (atom_kind, atom_body) in Atoms::new(data) {
+        if &atom_kind != kind || found {
+            result.extend_from_slice(&atom(&atom_kind, atom_body));
+            continue;
+        }
+        found = true;
+
+        if path.len() == 1 {
+            result.extend_from_slice(&atom(kind, body));
+        } else if kind == b"meta" {
+            let children = meta_children(atom_body);
+            let mut new_body = atom_body[..atom_body.len() - children.len()].to_vec();
+            new_body.extend_from_slice(&replace(children, &path[1..], body));
+            result.extend_from_slice(&atom(kind, &new_body));
+        } else {
+            result.extend_from_slice(&atom(kind, &replace(atom_body, &path[1..], body)));
+        }
+    }
+
+    if !found {
+        let new_body = if path.len() == 1 {
+            body.to_vec()
+        } else if kind == b"meta" {
+            let mut hdlr = vec![0u8; 8];
+            hdlr.extend_from_slice(b"mdirappl");
+            hdlr.extend_from_slice(&[0u8; 9]);
+            let mut new_body = vec![0u8; 4];
+            new_body.extend_from_slice(&atom(b"hdlr", &hdlr));
+            new_body.extend_from_slice(&replace(&[], &path[1..], body));
+            new_body
+        } else {
+            replace(&[], &path[1..], body)
+        };
+        result.extend_from_slice(&atom(kind, &new_body));
+    }
+
+    result
+}
+
+/// Adds `delta` to every chunk offset in the `stco` and `co64` tables
+/// of the tracks in `moov` (a moov body) that is greater than `after`.
+pub(crate) fn adjust_chunk_offsets(moov: &mut [u8], after: u64, delta: i64) {
+    for body in bodies_mut(moov, b"trak") {
+        for mdia in bodies_mut(body, b"mdia") {
+            for minf in bodies_mut(mdia, b"minf") {
+                for stbl in bodies_mut(minf, b"stbl") {
+                    for stco in bodies_mut(stbl, b"stco") {
+                        adjust_table(stco, 4, after, delta);
+                    }
+                    for co64 in bodies_mut(stbl, b"co64") {
+                        adjust_table(co64, 8, after, delta);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns mutable bodies of the atoms of type `kind` in `data`
+fn bodies_mut<'a>(data: &'a mut [u8], kind: &[u8; 4]) -> Vec<&'a mut [u8]> {
+    let mut bodies = vec![];
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let size = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        if size < 8 || size > rest.len() {
+            break;
+        }
+        let (current, next) = rest.split_at_mut(size);
+        if &current[4..8] == kind {
+            bodies.push(&mut current[8..]);
+        }
+        rest = next;
+    }
+    bodies
+}
+
+/// Adjusts the entries of a `stco` (`width` 4) or `co64` (`width` 8) body
+fn adjust_table(body: &mut [u8], width: usize, after: u64, delta: i64) {
+    if body.len() < 8 {
+        return;
+    }
+    let count = u32::from_be_bytes(body[4..8].try_into().unwrap()) as usize;
+    for entry in body[8..].chunks_exact_mut(width).take(count) {
+        let offset = if width == 4 {
+            u32::from_be_bytes(entry.try_into().unwrap()) as u64
+        } else {
+            u64::from_be_bytes(entry.try_into().unwrap())
+        };
+        if offset <= after {
+            continue;
+        }
+        let offset = (offset as i64 + delta) as u64;
+        if width == 4 {
+            entry.copy_from_slice(&(offset as u32).to_be_bytes());
+        } else {
+            entry.copy_from_slice(&offset.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Returns a `trak` atom holding a chunk offset `table`
+    fn track(table: &[u8; 4], entries: &[u64]) -> Vec<u8> {
+        let mut body = vec![0, 0, 0, 0];
+        body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for entry in entries {
+            if table == b"stco" {
+                body.extend_from_slice(&(*entry as u32).to_be_bytes());
+            } else {
+                body.extend_from_slice(&entry.to_be_bytes());
+            }
+        }
+        let mut nested = atom(table, &body);
+        for kind in [b"stbl", b"minf", b"mdia", b"trak"] {
+            nested = atom(kind, &nested);
+        }
+        nested
+    }
+
+    /// Returns the entries of the first `table` found in the tracks of `moov`
+    fn entries(moov: &[u8], table: &[u8; 4]) -> Vec<u64> {
+        let body = Atoms::new(moov)
+            .find_map(|(_, trak)| find(trak, &[b"mdia", b"minf", b"stbl", table]))
+            .unwrap();
+        body[8..]
+            .chunks_exact(if table == b"stco" { 4 } else { 8 })
+            .map(|entry| {
+                let mut bytes = [0u8; 8];
+                bytes[8 - entry.len()..].copy_from_slice(entry);
+                u64::from_be_bytes(bytes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn iterates_over_atoms() {
+        let mut data = atom(b"free", b"ab");
+        // 64 bits size
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(b"wide");
+        data.extend_from_slice(&19u64.to_be_bytes());
+        data.extend_from_slice(b"cde");
+        // Size zero, up to the end
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"rest");
+        data.extend_from_slice(b"fgh");
+
+        let atoms: Vec<_> = Atoms::new(&data).collect();
+        assert_eq!(
+            atoms,
+            [
+                (*b"free", &b"ab"[..]),
+                (*b"wide", &b"cde"[..]),
+                (*b"rest", &b"fgh"[..])
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_at_invalid_sizes() {
+        let mut data = atom(b"free", b"ab");
+        data.extend_from_slice(&[0, 0, 0, 0x40]);
+        data.extend_from_slice(b"long");
+        assert_eq!(Atoms::new(&data).count(), 1);
+
+        // Smaller than its own header
+        let data = [0, 0, 0, 4, b'b', b'a', b'd', b'!'];
+        assert_eq!(Atoms::new(&data).count(), 0);
+        let data = [0, 0, 0, 1, b'w', b'i', b'd', b'e', 0, 0];
+        assert_eq!(Atoms::new(&data).count(), 0);
+    }
+
+    #[test]
+    fn finds_atoms_through_meta() {
+        let ilst = atom(b"ilst", b"items");
+        let iso = atom(b"meta", &[vec![0; 4], ilst.clone()].concat());
+        let quicktime = atom(b"meta", &[atom(b"hdlr", &[0; 20]), ilst].concat());
+        for meta in [iso, quicktime] {
+            let udta = atom(b"udta", &meta);
+            assert_eq!(
+                find(&udta, &[b"udta", b"meta", b"ilst"]),
+                Some(&b"items"[..])
+            );
+            assert_eq!(find(&udta, &[b"udta", b"trak"]), None);
+        }
+        assert_eq!(meta_children(b"\0\0"), b"");
+    }
+
+    #[test]
+    fn replaces_and_creates_atoms() {
+        let moov = [atom(b"mvhd", &[0; 8]), atom(b"udta", &atom(b"name", b"x"))].concat();
+        let body = replace(&moov, &[b"udta", b"meta", b"ilst"], b"items");
+        assert_eq!(find(&body, &[b"mvhd"]), Some(&[0u8; 8][..]));
+        assert_eq!(find(&body, &[b"udta", b"name"]), Some(&b"x"[..]));
+        assert_eq!(
+            find(&body, &[b"udta", b"meta", b"ilst"]),
+            Some(&b"items"[..])
+        );
+        let meta = find(&body, &[b"udta", b"meta"]).unwrap();
+        assert_eq!(
+            &child(meta_children(meta), b"hdlr").unwrap()[8..16],
+            b"mdirappl"
+        );
+
+        // The existing `meta` keeps its handler
+        let body = replace(&body, &[b"udta", b"meta", b"ilst"], b"other");
+        assert_eq!(
+            find(&body, &[b"udta", b"meta", b"ilst"]),
+            Some(&b"other"[..])
+        );
+        assert_eq!(Atoms::new(find(&body, &[b"udta"]).unwrap()).count(), 2);
+    }
+
+    #[test]
+    fn adjusts_chunk_offsets() {
+        let mut moov = [track(b"stco", &[50, 200]), track(b"co64", &[100, 1 << 33])].concat();
+        adjust_chunk_offsets(&mut moov, 100, 16);
+        assert_eq!(entries(&moov, b"stco"), [50, 216]);
+        assert_eq!(entries(&moov, b"co64"), [100, (1 << 33) + 16]);
+
+        adjust_chunk_offsets(&mut moov, 100, -16);
+        assert_eq!(entries(&moov, b"stco"), [50, 200]);
+
+        // A count bigger than the table
+        let mut moov = track(b"stco", &[200]);
+        let count = moov.len() - 8;
+        moov[count..count + 4].copy_from_slice(&[0, 0, 0, 9]);
+        adjust_chunk_offsets(&mut moov, 0, 1);
+    }
+
+    #[test]
+    fn locates_top_level_atoms() {
+        let data = [
+            atom(b"ftyp", b"M4A "),
+            atom(b"moov", b"body"),
+            atom(b"mdat", b""),
+        ]
+        .concat();
+        let mut cursor = Cursor::new(&data);
+        let location = locate_top_level(&mut cursor, b"moov").unwrap().unwrap();
+        assert_eq!(
+            (location.offset, location.size, location.header_size),
+            (12, 12, 8)
+        );
+        assert_eq!(
+            read_top_level(&mut cursor, b"moov").unwrap().unwrap(),
+            b"body"
+        );
+        assert!(locate_top_level(&mut cursor, b"udta").unwrap().is_none());
+
+        for len in 0..data.len() {
+            let mut cursor = Cursor::new(&data[..len]);
+            let found = read_top_level(&mut cursor, b"moov").unwrap();
+            assert_eq!(found.is_some(), len >= 24);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+/// Bitrates in kbit/s, by version (MPEG-1 first) and layer (I first)
+#[rustfmt::skip]
+const BITRATES: [[[u16; 15]; 3]; 2] = [
+    [
+        [0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+        [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    ],
+    [
+        [0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ],
+];
+
+/// Sample rates of MPEG-1, the other versions divide them
+const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+/// The fields of an MPEG audio frame header
+pub(crate) struct FrameHeader {
+    pub version: Version,
+    pub layer: u8,
+    /// In kbit/s, zero for free format streams
+    pub bitrate: u32,
+    pub sample_rate: u32,
+    pub padding: bool,
+    pub mono: bool,
+}
+
+impl FrameHeader {
+    /// Parses the header at the start of `head`. Returns `None` unless
+    /// it starts with the sync word, and every field has a valid value.
+    pub fn parse(head: &[u8]) -> Option<Self> {
+        // 11 set bits, then a version that isn't `reserved` and a layer that
+        // isn't `reserved` either (the latter excludes ADTS AAC streams)
+        if head.len() < 4
+            || head[0] != 0xFF
+            || head[1] & 0xE0 != 0xE0
+            || head[1] & 0x18 == 0x08
+            || head[1] & 0x06 == 0x00
+        {
+            return None;
+        }
+
+        let version = match (head[1] >> 3) & 0x03 {
+            0 => Version::Mpeg25,
+            2 => Version::Mpeg2,
+            _ => Version::Mpeg1,
+        };
+        let layer = 4 - ((head[1] >> 1) & 0x03);
+        let bitrate_index = (head[2] >> 4) as usize;
+        let rate_index = ((head[2] >> 2) & 0x03) as usize;
+        if bitrate_index == 15 || rate_index == 3 {
+            return None;
+        }
+
+        let table = if version == Version::Mpeg1 { 0 } else { 1 };
+        let sample_rate = match version {
+            Version::Mpeg1 => SAMPLE_RATES[rate_index],
+            Version::Mpeg2 => SAMPLE_RATES[rate_index] / 2,
+            Version::Mpeg25 => SAMPLE_RATES[rate_index] / 4,
+        };
+
+        Some(Self {
+            version,
+            layer,
+            bitrate: BITRATES[table][layer as usize - 1][bitrate_index] as u32,
+            sample_rate,
+            padding: head[2] & 0x02 != 0,
+            mono: head[3] >> 6 == 0x03,
+        })
+    }
+
+    pub fn samples_per_frame(&self) -> u32 {
+        match (self.layer, self.version) {
+            (1, _) => 384,
+            (3, Version::Mpeg2 | Version::Mpeg25) => 576,
+            _ => 1152,
+        }
+    }
+
+    /// Returns the size of the frame in bytes, or `None` for free format
+    /// streams, whose frame size isn't written in the header
+    pub fn frame_size(&self) -> Option<u32> {
+        if self.bitrate == 0 {
+            return None;
+        }
+        let bits = self.bitrate * 1000;
+        let padding = self.padding as u32;
+        Some(match self.layer {
+            1 => (12 * bits / self.sample_rate + padding) * 4,
+            _ => self.samples_per_frame() / 8 * bits / self.sample_rate + padding,
+        })
+    }
+
+    /// Returns where the Xing header would start, counted from the
+    /// start of the frame, which is right after the side information
+    pub fn xing_offset(&self) -> usize {
+        match (self.version, self.mono) {
+            (Version::Mpeg1, false) => 4 + 32,
+            (Version::Mpeg1, true) | (_, false) => 4 + 17,
+            (_, true) => 4 + 9,
+        }
+    }
+}
+
+/// Returns `true` if `data` starts with an MPEG audio frame followed by
+/// another one, or by nothing. A single header isn't enough: text can
+/// start with the same bytes, e.g. the BOM of UTF-16 text.
+pub(crate) fn starts_stream(data: &[u8]) -> bool {
+    let size = match FrameHeader::parse(data).and_then(|header| header.frame_size()) {
+        Some(size) => size as usize,
+        None => return false,
+    };
+    match data.get(size..) {
+        Some([]) => true,
+        Some(rest) => FrameHeader::parse(rest).is_some(),
+        None => false,
+    }
+}
@@ -0,0 +1,297 @@
+use std::io::{Read, Write};
+
+use super::read_up_to;
+
+/// Header type flag of pages continuing a packet
+const CONTINUED: u8 = 0x01;
+
+/// A single page of an Ogg bitstream
+pub(crate) struct Page {
+    pub header_type: u8,
+    pub granule_position: u64,
+    pub serial: u32,
+    pub sequence: u32,
+    /// The lacing values of the page segments
+    pub segments: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    /// Serializes the page, computing its checksum
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let mut bytes = Vec::with_capacity(27 + self.segments.len() + self.data.len());
+        bytes.extend_from_slice(b"OggS");
+        bytes.push(0);
+        bytes.push(self.header_type);
+        bytes.extend_from_slice(&self.granule_position.to_le_bytes());
+        bytes.extend_from_slice(&self.serial.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.push(self.segments.len() as u8);
+        bytes.extend_from_slice(&self.segments);
+        bytes.extend_from_slice(&self.data);
+
+        let crc = crc32(&bytes);
+        bytes[22..26].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&bytes)
+    }
+
+    /// Returns how many packets end in this page
+    pub fn completed_packets(&self) -> usize {
+        self.segments.iter().filter(|lacing| **lacing < 255).count()
+    }
+}
+
+/// Reads the page at the current position of `reader`. Returns `None` at
+/// the end of the stream.
+pub(crate) fn read_page<R: Read>(reader: &mut R) -> std::io::Result<Option<Page>> {
+    let mut header = [0u8; 27];
+    let read = read_up_to(reader, &mut header)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read < header.len() || &header[..4] != b"OggS" {
+        return Err(invalid("missing Ogg page capture pattern"));
+    }
+
+    let mut segments = vec![0u8; header[26] as usize];
+    reader.read_exact(&mut segments)?;
+    let size = segments.iter().map(|lacing| *lacing as usize).sum();
+    let mut data = vec![0u8; size];
+    reader.read_exact(&mut data)?;
+
+    Ok(Some(Page {
+        header_type: header[5],
+        granule_position: u64::from_le_bytes(header[6..14].try_into().unwrap()),
+        serial: u32::from_le_bytes(header[14..18].try_into().unwrap()),
+        sequence: u32::from_le_bytes(header[18..22].try_into().unwrap()),
+        segments,
+        data,
+    }))
+}
+
+/// Reads the first `count` packets of the first logical bitstream
+/// found in `reader`. Less packets are returned if the stream ends
+/// before.
+pub(crate) fn read_packets<R: Read>(reader: &mut R, count: usize) -> std::io::Result<Vec<Vec<u8>>> {
+    let mut packets = vec![];
+    let mut packet = vec![];
+    let mut serial = None;
+
+    while packets.len() < count {
+        let page = match read_page(reader)? {
+            Some(page) => page,
+            None => break,
+        };
+        if *serial.get_or_insert(page.serial) != page.serial {
+            continue;
+        }
+
+        let mut offset = 0;
+        for lacing in &page.segments {
+            let lacing = *lacing as usize;
+            packet.extend_from_slice(&page.data[offset..offset + lacing]);
+            offset += lacing;
+            if lacing < 255 {
+                packets.push(std::mem::take(&mut packet));
+                if packets.len() == count {
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(packets)
+}
+
+/// Lays `packets` out in as few pages as possible, numbering them from
+/// `sequence`. The last packet ends its page, as required for codec
+/// headers. Header pages have a granule position of zero.
+pub(crate) fn paginate(packets: &[Vec<u8>], serial: u32, sequence: u32) -> Vec<Page> {
+    let mut pages = vec![];
+    let mut page = new_page(serial, sequence, 0);
+
+    for packet in packets {
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut offset = 0;
+        for value in lacing {
+            if page.segments.len() == 255 {
+                let header_type = match page.segments.last() {
+                    Some(255) => CONTINUED,
+                    _ => 0,
+                };
+                let next = new_page(serial, page.sequence + 1, header_type);
+                pages.push(std::mem::replace(&mut page, next));
+            }
+            page.segments.push(value);
+            page.data
+                .extend_from_slice(&packet[offset..offset + value as usize]);
+            offset += value as usize;
+        }
+    }
+    pages.push(page);
+
+    // Pages where no packet ends carry no valid granule position
+    for page in pages.iter_mut() {
+        if page.completed_packets() == 0 {
+            page.granule_position = u64::MAX;
+        }
+    }
+
+    pages
+}
+
+fn new_page(serial: u32, sequence: u32, header_type: u8) -> Page {
+    Page {
+        header_type,
+        granule_position: 0,
+        serial,
+        sequence,
+        segments: vec![],
+        data: vec![],
+    }
+}
+
+/// The CRC used by Ogg: polynomial 0x04C11DB7,
+/// no reflection, zero initial value
+fn crc32(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ *byte) as usize]
+    })
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = (index as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mut data: &[u8]) -> Vec<Page> {
+        let mut pages = vec![];
+        while let Some(page) = read_page(&mut data).unwrap() {
+            pages.push(page);
+        }
+        pages
+    }
+
+    #[test]
+    fn computes_the_ogg_checksum() {
+        // The check value of CRC-32/POSIX, without its final inversion
+        assert_eq!(crc32(b"123456789"), !0x765E_7680);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn writes_and_reads_pages() {
+        let page = Page {
+            header_type: 0x02,
+            granule_position: 1234,
+            serial: 7,
+            sequence: 3,
+            segments: vec![3, 2],
+            data: b"abcde".to_vec(),
+        };
+        let mut data = vec![];
+        page.write_to(&mut data).unwrap();
+        assert_eq!(data.len(), 27 + 2 + 5);
+        let mut unsigned = data.clone();
+        unsigned[22..26].copy_from_slice(&[0; 4]);
+        assert_eq!(data[22..26], crc32(&unsigned).to_le_bytes());
+
+        let read = read_all(&data);
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].header_type, 0x02);
+        assert_eq!(read[0].granule_position, 1234);
+        assert_eq!(read[0].serial, 7);
+        assert_eq!(read[0].sequence, 3);
+        assert_eq!(read[0].segments, [3, 2]);
+        assert_eq!(read[0].data, b"abcde");
+        assert_eq!(read[0].completed_packets(), 2);
+    }
+
+    #[test]
+    fn paginates_packets() {
+        // Packets of exactly 255 bytes end with an empty segment
+        let pages = paginate(&[vec![1; 255], vec![2; 3]], 1, 5);
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].segments, [255, 0, 3]);
+        assert_eq!(pages[0].sequence, 5);
+
+        let big = vec![3; 70_000];
+        let pages = paginate(&[vec![1; 10], big.clone()], 1, 1);
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].header_type, 0);
+        assert_eq!(pages[1].header_type, CONTINUED);
+        assert_eq!(pages[0].granule_position, 0);
+        assert_eq!(pages[1].sequence, 2);
+
+        let mut data = vec![];
+        for page in &pages {
+            page.write_to(&mut data).unwrap();
+        }
+        let packets = read_packets(&mut data.as_slice(), 5).unwrap();
+        assert_eq!(packets, [vec![1; 10], big]);
+
+        // No packet ends in the first page
+        let pages = paginate(&[vec![4; 70_000]], 1, 0);
+        assert_eq!(pages[0].granule_position, u64::MAX);
+        assert_eq!(pages[1].granule_position, 0);
+    }
+
+    #[test]
+    fn reads_packets_of_the_first_stream() {
+        let mut data = vec![];
+        let pages = [
+            paginate(&[b"first".to_vec()], 1, 0),
+            paginate(&[b"other".to_vec()], 2, 0),
+            paginate(&[b"second".to_vec(), b"third".to_vec()], 1, 1),
+        ];
+        for page in pages.iter().flatten() {
+            page.write_to(&mut data).unwrap();
+        }
+        let packets = read_packets(&mut data.as_slice(), 2).unwrap();
+        assert_eq!(packets, [b"first".to_vec(), b"second".to_vec()]);
+        let packets = read_packets(&mut data.as_slice(), 10).unwrap();
+        assert_eq!(packets.len(), 3);
+    }
+
+    #[test]
+    fn survives_truncated_pages() {
+        let mut data = vec![];
+        for page in paginate(&[vec![1; 300], vec![2; 10]], 1, 0) {
+            page.write_to(&mut data).unwrap();
+        }
+        assert!(read_page(&mut &data[..0]).unwrap().is_none());
+        for len in 1..data.len() {
+            assert!(read_page(&mut &data[..len]).is_err());
+            assert!(read_packets(&mut &data[..len], 2).is_err());
+        }
+        assert!(
+            read_page(&mut &b"RIFF\0\0\0\0WAVEfmt \0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"[..]).is_err()
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{ErrorKind, Read},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// What identifies the content of a song file: its SHA-256
+/// hash, along with its size and modification time
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub struct Fingerprint {
+    /// Hex encoded SHA-256 of the whole file
+    hash: String,
+    size: u64,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl Fingerprint {
+    /// Reads the whole file at `path` to fingerprint it
+    pub fn compute(path: &OsString) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let read = match file.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(Self {
+            hash: to_hex(&hasher.finalize()),
+            size: metadata.len(),
+            modified: metadata.modified().ok().map(DateTime::from),
+        })
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn modified(&self) -> Option<&DateTime<Utc>> {
+        self.modified.as_ref()
+    }
+
+    /// Compares the file at `path` with the one this fingerprint was
+    /// taken from. Only the content of the file is looked at: a file
+    /// written since is `Modified` even if its audio stream is damaged.
+    pub fn verify(&self, path: &OsString) -> FileStatus {
+        let current = match Fingerprint::compute(path) {
+            Ok(current) => current,
+            Err(err) if err.kind() == ErrorKind::NotFound => return FileStatus::Missing,
+            Err(err) => return FileStatus::Unreadable(err.kind()),
+        };
+
+        if current.hash == self.hash {
+            FileStatus::Intact
+        } else if current.size == self.size && current.modified == self.modified {
+            // The content changed without the file being written
+            FileStatus::Corrupt
+        } else {
+            FileStatus::Modified
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 of `data`
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The state of a song file compared to
+/// its recorded fingerprint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileStatus {
+    /// The file is exactly the one that was fingerprinted
+    Intact,
+    /// No fingerprint was recorded for the song
+    Unverified,
+    Missing,
+    /// The file exists but can't be read
+    Unreadable(ErrorKind),
+    /// The file was written since it was fingerprinted,
+    /// and still holds a readable audio stream
+    Modified,
+    /// The file is truncated or damaged: either its audio stream can't
+    /// be read anymore or its content changed without it being written
+    Corrupt,
+}
@@ -1,11 +1,30 @@
+use std::ffi::OsString;
+
 use playlist_manager::Playlist;
-use song::Song;
 use sanitise_file_name::sanitise;
+use song::Song;
+use uuid::Uuid;
 
+pub mod artwork;
+pub mod backup;
+pub mod format;
+pub mod integrity;
+pub mod library;
+pub mod loudness;
+pub mod lyrics;
+mod os_string;
 pub mod playlist_manager;
 pub mod plugin_manager;
+pub mod properties;
 pub mod queue;
+pub mod schema;
+pub mod search;
 pub mod song;
+pub mod stats;
+pub mod storage;
+pub mod tags;
+#[cfg(feature = "watcher")]
+pub mod watcher;
 
 /// Returnes the name that can represent the provided song. NO EXTENSION!
 ///
@@ -29,16 +48,21 @@ pub fn file_name_from_basics(song_name: &str, song_artist: &str) -> String {
         .replace(" ", "_")
 }
 
-/// Returnes the name that can represent the provided playlist
+/// Returnes the name of the file holding the provided playlist. It's
+/// derived from the playlist id, so renaming the playlist doesn't move
+/// it and playlists with similar names can't overwrite each other.
+pub fn file_name_from_playlist(playlist: &Playlist) -> String {
+    file_name_from_id(&playlist.id())
+}
+
+/// Returnes the name of the meta file of the song or
+/// playlist identified by `id`
 ///
 /// # Example
-/// A playlist called `playlist` will be associated to a file called
-/// `playlist.json`. Everything is lowercase and spaces are replaced by
-/// `_`.
-pub fn file_name_from_playlist(playlist: &Playlist) -> String {
-    format!("{}.json", sanitise(playlist.name()))
-        .to_lowercase()
-        .replace(" ", "_")
+/// The id `67e55044-10b1-426f-9247-bb680e5fe0c8` is associated to a file
+/// called `67e55044-10b1-426f-9247-bb680e5fe0c8.json`.
+pub fn file_name_from_id(id: &Uuid) -> String {
+    format!("{}.json", id)
 }
 
 /// This enum should be used to pass around the app tranking information for
@@ -56,3 +80,18 @@ pub enum TrackInfo {
     /// The download for the song is failed.
     Failed(Song, String),
 }
+
+/// This enum should be used to pass around the app information on
+/// the progress of an import.
+pub enum ImportInfo {
+    /// The import is started, and this many audio files were found
+    Started(usize),
+    /// The song was added to the library
+    Imported(Box<Song>),
+    /// The file is already in the library
+    Skipped(OsString),
+    /// The file couldn't be imported
+    Failed(OsString, String),
+    /// Every file was handled
+    Finished,
+}
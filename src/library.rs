@@ -0,0 +1,502 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::mpsc::{channel, Receiver, Sender},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    schema::{self, DocumentKind},
+    search::{Query, SearchIndex, SearchResult},
+    song::{LoadError, LoadPolicy, Song, SongDetails},
+    storage::{Collection, Storage},
+};
+
+/// Name of the document, among the songs in storage,
+/// listing every song of the library
+pub const LIBRARY_FILE: &str = "library.json";
+
+/// A change made to the library
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibraryEvent {
+    Added(Uuid),
+    /// The details or the position of the song changed
+    Updated(Uuid),
+    Removed(Uuid),
+}
+
+/// Every song known to the player, each one held once. Songs are indexed
+/// by artist, album, year and genre; playlists and queues refer to them
+/// by id.
+#[derive(Default)]
+pub struct Library {
+    songs: HashMap<Uuid, Song>,
+    by_artist: BTreeMap<String, BTreeSet<Uuid>>,
+    by_album: BTreeMap<String, BTreeSet<Uuid>>,
+    by_year: BTreeMap<u16, BTreeSet<Uuid>>,
+    by_genre: BTreeMap<String, BTreeSet<Uuid>>,
+    search: SearchIndex,
+    /// Told about every change
+    subscribers: Vec<Sender<LibraryEvent>>,
+    /// Songs whose details changed since they were last saved
+    dirty: HashSet<Uuid>,
+    /// Set when songs were added, removed or moved since
+    /// the library file was last saved
+    catalogue_changed: bool,
+    /// Hash of the song_meta file last written for each song, to tell
+    /// the writes of the library from the ones of other programs
+    written: HashMap<Uuid, u64>,
+    /// Documents of the songs left out while loading, which
+    /// belong to no song of the library but are kept as they are
+    left_out: HashSet<OsString>,
+}
+
+#[derive(Serialize)]
+struct Catalogue<'a> {
+    songs: Vec<&'a Song>,
+}
+
+#[derive(Deserialize)]
+struct StoredCatalogue {
+    songs: Vec<Song>,
+}
+
+impl Library {
+    /// Creates an empty library
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the library listed in the library file inside `storage`, or
+    /// an empty one if there's no such file. Song paths are resolved
+    /// against `library_root`, song details are read from `storage`.
+    ///
+    /// Songs whose details can't be loaded are handled as told by
+    /// `policy`, and their errors are returned along with the library.
+    /// A library file that can't be loaded is always an error, since
+    /// saving over it would lose every song.
+    pub fn load(
+        library_root: &Path,
+        storage: &dyn Storage,
+        policy: LoadPolicy,
+    ) -> Result<(Self, Vec<LoadError>), LoadError> {
+        let name = OsStr::new(LIBRARY_FILE);
+        let path = storage.location(Collection::Songs, name);
+        let (value, upgraded) =
+            match schema::read(storage, Collection::Songs, name, DocumentKind::Library) {
+                Ok(read) => read,
+                Err(LoadError::NotFound(_)) => return Ok((Self::new(), vec![])),
+                Err(err) => return Err(err),
+            };
+        let catalogue: StoredCatalogue =
+            serde_json::from_value(value).map_err(|err| LoadError::Malformed(path.clone(), err))?;
+
+        let mut library = Self::new();
+        let mut errors = vec![];
+        for song in catalogue.songs {
+            if let Some(err) = library.adopt(song, library_root, storage, policy)? {
+                errors.push(err);
+            }
+        }
+        // An upgraded library file is written again at the next save
+        library.catalogue_changed = upgraded;
+        Ok((library, errors))
+    }
+
+    /// Loads the details of `song`, which has just been read from a file,
+    /// and adds it to the library. Failures are handled as told by
+    /// `policy`: the error is returned if the song was still added or
+    /// left out, and as an error only with `LoadPolicy::Fail`.
+    pub(crate) fn adopt(
+        &mut self,
+        mut song: Song,
+        library_root: &Path,
+        storage: &dyn Storage,
+        policy: LoadPolicy,
+    ) -> Result<Option<LoadError>, LoadError> {
+        let mut error = None;
+        let mut upgraded = false;
+        match song.load(library_root, storage) {
+            Ok(was_upgraded) => upgraded = was_upgraded,
+            Err(err) if policy == LoadPolicy::Fail => return Err(err),
+            Err(err) => {
+                let keep = song.recover(&err, policy, storage);
+                error = Some(err);
+                if !keep {
+                    self.left_out
+                        .extend([song.details_path().clone(), song.lyrics_path()]);
+                    return Ok(error);
+                }
+            }
+        }
+        song.set_library_root(library_root);
+        let id = song.id();
+        self.add(song);
+        // Details just loaded are already saved, repaired or upgraded ones aren't
+        if error.is_none() && !upgraded {
+            self.dirty.remove(&id);
+        }
+        Ok(error)
+    }
+
+    /// Saves to `storage` the details of the songs that changed since
+    /// they were last saved, each one to its song_meta file, and the list
+    /// of songs to the library file if it changed. Files that couldn't be
+    /// loaded are left as they are. Nothing is forgotten if saving fails:
+    /// the next save tries again.
+    pub fn save(&mut self, storage: &dyn Storage) -> std::io::Result<()> {
+        let songs = &self.songs;
+        let dirty = &self.dirty;
+        let catalogue_changed = self.catalogue_changed;
+        let mut written = vec![];
+        storage.batch(&mut || {
+            for song in dirty.iter().filter_map(|id| songs.get(id)) {
+                if song.is_read_only() {
+                    continue;
+                }
+                let data = song.details().to_bytes()?;
+                storage.write(Collection::Songs, song.details_path(), &data)?;
+                written.push((song.id(), hash(&data)));
+            }
+
+            if catalogue_changed {
+                let mut songs: Vec<&Song> = songs.values().collect();
+                songs.sort_by_key(|song| song.id());
+                storage.write(
+                    Collection::Songs,
+                    OsStr::new(LIBRARY_FILE),
+                    &schema::to_bytes(&Catalogue { songs }, DocumentKind::Library)?,
+                )?;
+            }
+            Ok(())
+        })?;
+
+        self.written.extend(written);
+        self.dirty.clear();
+        self.catalogue_changed = false;
+        Ok(())
+    }
+
+    /// Returns the names of the documents of the songs left out while
+    /// loading, as `LoadPolicy::Skip` tells, which mustn't be touched
+    pub(crate) fn left_out_documents(&self) -> &HashSet<OsString> {
+        &self.left_out
+    }
+
+    /// Returns `true` if the details of the song with
+    /// id `id` changed since they were last saved
+    pub(crate) fn is_dirty(&self, id: Uuid) -> bool {
+        self.dirty.contains(&id)
+    }
+
+    /// Makes the next save write the song_meta file
+    /// of the song with id `id`
+    pub(crate) fn mark_dirty(&mut self, id: Uuid) {
+        if self.songs.contains_key(&id) {
+            self.dirty.insert(id);
+        }
+    }
+
+    /// Returns `true` if `data` is the song_meta file last
+    /// written by the library for the song with id `id`
+    #[cfg(feature = "watcher")]
+    pub(crate) fn wrote(&self, id: Uuid, data: &[u8]) -> bool {
+        self.written.get(&id) == Some(&hash(data))
+    }
+
+    /// Replaces the details of the song with id `id` with `details`, just
+    /// read from its song_meta file, which so doesn't need to be saved.
+    /// Returns `false` if there's no such song.
+    #[cfg(feature = "watcher")]
+    pub(crate) fn reload(&mut self, id: Uuid, details: SongDetails) -> bool {
+        let found = self
+            .update(id, |song| {
+                *song.details_mut() = details;
+                song.set_read_only(false);
+            })
+            .is_some();
+        self.dirty.remove(&id);
+        found
+    }
+
+    /// Returns `true` if something changed since the library was last saved
+    pub fn has_unsaved_changes(&self) -> bool {
+        self.catalogue_changed
+            || self
+                .dirty
+                .iter()
+                .filter_map(|id| self.songs.get(id))
+                .any(|song| !song.is_read_only())
+    }
+
+    /// Returns a receiver told about every change made
+    /// to the library from now on
+    pub fn subscribe(&mut self) -> Receiver<LibraryEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Adds `song` to the library. Returns `false`, leaving the
+    /// library as it is, if a song with the same id is already there.
+    pub fn add(&mut self, song: Song) -> bool {
+        let id = song.id();
+        if self.songs.contains_key(&id) {
+            return false;
+        }
+        self.index(&song);
+        self.songs.insert(id, song);
+        self.dirty.insert(id);
+        self.catalogue_changed = true;
+        self.notify(LibraryEvent::Added(id));
+        true
+    }
+
+    /// Removes the song with id `id` from the library and returns it.
+    /// Its song_meta file is left where it is.
+    pub fn remove(&mut self, id: Uuid) -> Option<Song> {
+        let song = self.songs.remove(&id)?;
+        self.unindex(&song);
+        self.dirty.remove(&id);
+        self.written.remove(&id);
+        self.catalogue_changed = true;
+        self.notify(LibraryEvent::Removed(id));
+        Some(song)
+    }
+
+    /// Applies `update` to the song with id `id` and returns its result,
+    /// or `None` if there's no such song. Indexes are kept up to date,
+    /// and subscribers told if the song changed.
+    pub fn update<F, R>(&mut self, id: Uuid, update: F) -> Option<R>
+    where
+        F: FnOnce(&mut Song) -> R,
+    {
+        let mut song = self.songs.remove(&id)?;
+        self.unindex(&song);
+
+        let details = song.details().clone();
+        let path = song.path().clone();
+        let stored_path = song.stored_path().clone();
+        let result = update(&mut song);
+        let details_changed = *song.details() != details;
+        let moved = *song.path() != path || *song.stored_path() != stored_path;
+
+        self.index(&song);
+        self.songs.insert(id, song);
+        if details_changed {
+            self.dirty.insert(id);
+        }
+        if moved {
+            self.catalogue_changed = true;
+        }
+        if details_changed || moved {
+            self.notify(LibraryEvent::Updated(id));
+        }
+        Some(result)
+    }
+
+    /// Applies `update` to the details of the song with id `id`, as
+    /// `update` does. Returns `false` if there's no such song.
+    pub fn update_details<F>(&mut self, id: Uuid, update: F) -> bool
+    where
+        F: FnOnce(&mut SongDetails),
+    {
+        self.update(id, |song| update(song.details_mut())).is_some()
+    }
+
+    /// Applies `update` to every song, as `update` does
+    pub fn update_all<F>(&mut self, mut update: F)
+    where
+        F: FnMut(&mut Song),
+    {
+        for id in self.ids() {
+            self.update(id, &mut update);
+        }
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<&Song> {
+        self.songs.get(&id)
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.songs.contains_key(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.songs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.songs.is_empty()
+    }
+
+    /// Returns every song, in no particular order
+    pub fn songs(&self) -> Vec<&Song> {
+        self.songs.values().collect()
+    }
+
+    /// Returns the id of every song, in no particular order
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.songs.keys().copied().collect()
+    }
+
+    /// Returns the songs with id in `ids`, in the same order.
+    /// Unknown ids are skipped.
+    pub fn resolve(&self, ids: &[Uuid]) -> Vec<&Song> {
+        ids.iter().filter_map(|id| self.songs.get(id)).collect()
+    }
+
+    /// Returns every artist, sorted
+    pub fn artists(&self) -> Vec<&str> {
+        self.by_artist
+            .keys()
+            .map(|artist| artist.as_str())
+            .collect()
+    }
+
+    /// Returns every album name, sorted
+    pub fn albums(&self) -> Vec<&str> {
+        self.by_album.keys().map(|album| album.as_str()).collect()
+    }
+
+    /// Returns every year, sorted
+    pub fn years(&self) -> Vec<u16> {
+        self.by_year.keys().copied().collect()
+    }
+
+    /// Returns every genre, sorted
+    pub fn genres(&self) -> Vec<&str> {
+        self.by_genre.keys().map(|genre| genre.as_str()).collect()
+    }
+
+    /// Returns the songs with every word of `query` in their name, artist,
+    /// album, genres, composer or comment, the best matches first. Case
+    /// and diacritics are ignored, words can be partial, as when typing
+    /// them, and words of four letters or more can have a typo, two
+    /// from eight letters.
+    pub fn search(&self, query: &str) -> Vec<SearchResult> {
+        self.search.search(query)
+    }
+
+    /// Returns the songs matching `query`, in no particular order
+    pub fn query(&self, query: &Query) -> Vec<&Song> {
+        self.songs
+            .values()
+            .filter(|song| query.matches(song.details()))
+            .collect()
+    }
+
+    /// Returns the songs whose artist is `artist`
+    pub fn by_artist(&self, artist: &str) -> Vec<&Song> {
+        self.lookup(self.by_artist.get(artist))
+    }
+
+    /// Returns the songs of the album `album`, by `album_artist` if given,
+    /// sorted by disc and track. Songs without an album artist are taken
+    /// to be by their artist.
+    pub fn by_album(&self, album: &str, album_artist: Option<&str>) -> Vec<&Song> {
+        let mut songs: Vec<&Song> = self
+            .lookup(self.by_album.get(album))
+            .into_iter()
+            .filter(|song| {
+                let details = song.details();
+                album_artist.is_none()
+                    || details.album_artist().or(details.artist()) == album_artist
+            })
+            .collect();
+        songs.sort_by_key(|song| {
+            let details = song.details();
+            (details.disc_number(), details.track_number())
+        });
+        songs
+    }
+
+    /// Returns the songs released in `year`
+    pub fn by_year(&self, year: u16) -> Vec<&Song> {
+        self.lookup(self.by_year.get(&year))
+    }
+
+    /// Returns the songs having `genre` among their genres
+    pub fn by_genre(&self, genre: &str) -> Vec<&Song> {
+        self.lookup(self.by_genre.get(genre))
+    }
+
+    fn lookup(&self, ids: Option<&BTreeSet<Uuid>>) -> Vec<&Song> {
+        ids.into_iter()
+            .flatten()
+            .filter_map(|id| self.songs.get(id))
+            .collect()
+    }
+
+    fn index(&mut self, song: &Song) {
+        let id = song.id();
+        let details = song.details();
+        if let Some(artist) = details.artist() {
+            insert(&mut self.by_artist, artist.to_string(), id);
+        }
+        if let Some(album) = details.album() {
+            insert(&mut self.by_album, album.to_string(), id);
+        }
+        if let Some(year) = details.year() {
+            insert(&mut self.by_year, year, id);
+        }
+        for genre in details.genres() {
+            insert(&mut self.by_genre, genre.clone(), id);
+        }
+        self.search.insert(id, details);
+    }
+
+    fn unindex(&mut self, song: &Song) {
+        let id = song.id();
+        let details = song.details();
+        if let Some(artist) = details.artist() {
+            remove(&mut self.by_artist, artist, id);
+        }
+        if let Some(album) = details.album() {
+            remove(&mut self.by_album, album, id);
+        }
+        if let Some(year) = details.year() {
+            remove(&mut self.by_year, &year, id);
+        }
+        for genre in details.genres() {
+            remove(&mut self.by_genre, genre.as_str(), id);
+        }
+        self.search.remove(id);
+    }
+
+    /// Tells `event` to every subscriber, forgetting
+    /// the ones that stopped listening
+    fn notify(&mut self, event: LibraryEvent) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn insert<K: Ord>(index: &mut BTreeMap<K, BTreeSet<Uuid>>, key: K, id: Uuid) {
+    index.entry(key).or_default().insert(id);
+}
+
+/// Removes `id` from the entry `key` of `index`,
+/// and the entry itself once it's empty
+fn remove<K, Q>(index: &mut BTreeMap<K, BTreeSet<Uuid>>, key: &Q, id: Uuid)
+where
+    K: Ord + std::borrow::Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    if let Some(ids) = index.get_mut(key) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(key);
+        }
+    }
+}
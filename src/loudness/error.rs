@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use symphonia::core::errors::Error as DecodeError;
+
+/// Errors describing why the loudness
+/// of a song couldn't be measured
+#[derive(Debug)]
+pub enum LoudnessError {
+    Io(std::io::Error),
+    /// The file format or its codec can't be decoded
+    UnsupportedFormat,
+    Decode(DecodeError),
+    Analysis(ebur128::Error),
+}
+
+impl Display for LoudnessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoudnessError::Io(err) => {
+                writeln!(f, "An I/O error occured while reading the song")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            LoudnessError::UnsupportedFormat => {
+                writeln!(f, "The song can't be decoded to measure its loudness")
+            }
+            LoudnessError::Decode(err) => {
+                writeln!(f, "The audio of the song couldn't be decoded")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            LoudnessError::Analysis(err) => {
+                writeln!(f, "The loudness of the song couldn't be measured")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for LoudnessError {}
+
+impl From<std::io::Error> for LoudnessError {
+    fn from(err: std::io::Error) -> Self {
+        LoudnessError::Io(err)
+    }
+}
+
+impl From<DecodeError> for LoudnessError {
+    fn from(err: DecodeError) -> Self {
+        match err {
+            DecodeError::IoError(err) => LoudnessError::Io(err),
+            DecodeError::Unsupported(_) => LoudnessError::UnsupportedFormat,
+            err => LoudnessError::Decode(err),
+        }
+    }
+}
+
+impl From<ebur128::Error> for LoudnessError {
+    fn from(err: ebur128::Error) -> Self {
+        LoudnessError::Analysis(err)
+    }
+}
@@ -0,0 +1,347 @@
+use std::{ffi::OsString, fs::File, io::ErrorKind};
+
+use ebur128::{Channel, EbuR128, Mode};
+use serde::{Deserialize, Serialize};
+use symphonia::core::{
+    audio::{Channels, SampleBuffer},
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+pub use self::error::LoudnessError;
+
+mod error;
+
+/// Loudness that ReplayGain 2.0 brings songs to, in LUFS
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+
+/// Loudness that R128 gains in Opus files bring songs to, in LUFS
+const R128_REFERENCE_LOUDNESS: f64 = -23.0;
+
+/// Loudness of a song, or of a whole album,
+/// measured as EBU R128 recommends
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    integrated: f64,
+    /// Highest true peak of any channel, where 1.0 is full scale
+    true_peak: f64,
+}
+
+impl Loudness {
+    pub fn integrated(&self) -> f64 {
+        self.integrated
+    }
+
+    pub fn true_peak(&self) -> f64 {
+        self.true_peak
+    }
+
+    /// Returns the true peak in dBTP
+    pub fn true_peak_db(&self) -> f64 {
+        20.0 * self.true_peak.log10()
+    }
+
+    /// Returns the gain bringing the song to the
+    /// ReplayGain reference loudness, in dB
+    pub fn gain(&self) -> f64 {
+        REFERENCE_LOUDNESS - self.integrated
+    }
+}
+
+/// The ReplayGain values of a song. They come either from its
+/// tags or from measuring its loudness and that of its album.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, PartialOrd, Default)]
+#[serde(default)]
+pub struct ReplayGain {
+    /// In dB
+    track_gain: Option<f64>,
+    /// Where 1.0 is full scale
+    track_peak: Option<f64>,
+    album_gain: Option<f64>,
+    album_peak: Option<f64>,
+}
+
+impl ReplayGain {
+    pub fn track_gain(&self) -> Option<f64> {
+        self.track_gain
+    }
+
+    pub fn track_peak(&self) -> Option<f64> {
+        self.track_peak
+    }
+
+    pub fn album_gain(&self) -> Option<f64> {
+        self.album_gain
+    }
+
+    pub fn album_peak(&self) -> Option<f64> {
+        self.album_peak
+    }
+
+    /// Returns `true` if no value is known
+    pub fn is_empty(&self) -> bool {
+        self.track_gain.is_none() && self.album_gain.is_none()
+    }
+
+    /// Sets track gain and peak from the loudness of the song
+    pub fn set_track(&mut self, loudness: &Loudness) {
+        self.track_gain = Some(loudness.gain());
+        self.track_peak = Some(loudness.true_peak);
+    }
+
+    /// Sets album gain and peak from the loudness of its album
+    pub fn set_album(&mut self, loudness: &Loudness) {
+        self.album_gain = Some(loudness.gain());
+        self.album_peak = Some(loudness.true_peak);
+    }
+
+    /// Returns the factor the samples of the song should be multiplied
+    /// by to normalise its volume, after adding `preamp` dB to the gain.
+    /// Album values are used with `GainMode::Album`, if known. The
+    /// factor is lowered when the peak would clip otherwise.
+    pub fn scale(&self, mode: GainMode, preamp: f64) -> f64 {
+        let (gain, peak) = match (mode, self.album_gain) {
+            (GainMode::Album, Some(gain)) => (gain, self.album_peak),
+            _ => match self.track_gain {
+                Some(gain) => (gain, self.track_peak),
+                None => return 1.0,
+            },
+        };
+
+        let scale = 10f64.powf((gain + preamp) / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 && scale * peak > 1.0 => 1.0 / peak,
+            _ => scale,
+        }
+    }
+
+    /// Stores the value of the tag `key`, if it's a ReplayGain one. Opus
+    /// R128 gains are converted to the ReplayGain reference. Returns
+    /// `false` if the key isn't known or the value can't be parsed.
+    pub(crate) fn set_tag(&mut self, key: &str, value: &str) -> bool {
+        let key = key.to_ascii_uppercase();
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+
+        let (field, value) = match key.as_str() {
+            "R128_TRACK_GAIN" | "R128_ALBUM_GAIN" => {
+                // Q7.8 fixed point, relative to -23 LUFS
+                let gain = match value.parse::<i16>() {
+                    Ok(gain) => gain as f64 / 256.0,
+                    Err(_) => return false,
+                };
+                let gain = gain + REFERENCE_LOUDNESS - R128_REFERENCE_LOUDNESS;
+                let field = if key == "R128_TRACK_GAIN" {
+                    &mut self.track_gain
+                } else {
+                    &mut self.album_gain
+                };
+                (field, gain)
+            }
+            _ => {
+                let field = match key.as_str() {
+                    "REPLAYGAIN_TRACK_GAIN" => &mut self.track_gain,
+                    "REPLAYGAIN_TRACK_PEAK" => &mut self.track_peak,
+                    "REPLAYGAIN_ALBUM_GAIN" => &mut self.album_gain,
+                    "REPLAYGAIN_ALBUM_PEAK" => &mut self.album_peak,
+                    _ => return false,
+                };
+                let number = value
+                    .strip_suffix("dB")
+                    .or_else(|| value.strip_suffix("db"))
+                    .unwrap_or(value)
+                    .trim();
+                match number.parse::<f64>() {
+                    Ok(number) if number.is_finite() => (field, number),
+                    _ => return false,
+                }
+            }
+        };
+
+        *field = Some(value);
+        true
+    }
+
+    /// Replaces the values set in `other`, keeping the others
+    pub(crate) fn merge(&mut self, other: &ReplayGain) {
+        for (target, value) in [
+            (&mut self.track_gain, other.track_gain),
+            (&mut self.track_peak, other.track_peak),
+            (&mut self.album_gain, other.album_gain),
+            (&mut self.album_peak, other.album_peak),
+        ] {
+            if value.is_some() {
+                *target = value;
+            }
+        }
+    }
+}
+
+/// Which ReplayGain values normalise the volume
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GainMode {
+    /// Every song is brought to the same loudness
+    #[default]
+    Track,
+    /// Albums are brought to the same loudness, keeping the
+    /// differences between their songs
+    Album,
+}
+
+/// Decodes the song file at `path` and measures its loudness.
+/// Returns `None` if the song is silent.
+pub fn analyze(path: &OsString) -> Result<Option<Loudness>, LoudnessError> {
+    let state = measure(path)?;
+    loudness(std::iter::once(&state))
+}
+
+/// Measures the loudness of the songs of an album, one by one,
+/// and the loudness of the whole album
+#[derive(Default)]
+pub struct AlbumAnalysis {
+    tracks: Vec<EbuR128>,
+}
+
+impl AlbumAnalysis {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decodes the song file at `path` and measures its loudness, which
+    /// will count for the album one. Returns `None` if the song is silent.
+    pub fn add(&mut self, path: &OsString) -> Result<Option<Loudness>, LoudnessError> {
+        let state = measure(path)?;
+        let loudness = loudness(std::iter::once(&state))?;
+        self.tracks.push(state);
+        Ok(loudness)
+    }
+
+    /// Returns the loudness of the album, made of every song added
+    /// so far. Returns `None` if there's none or they're all silent.
+    pub fn album(&self) -> Result<Option<Loudness>, LoudnessError> {
+        loudness(self.tracks.iter())
+    }
+}
+
+/// Combines the measures in `states`
+fn loudness<'a, I>(states: I) -> Result<Option<Loudness>, LoudnessError>
+where
+    I: Iterator<Item = &'a EbuR128> + Clone,
+{
+    let integrated = EbuR128::loudness_global_multiple(states.clone())?;
+    if !integrated.is_finite() {
+        return Ok(None);
+    }
+
+    let mut true_peak: f64 = 0.0;
+    for state in states {
+        for channel in 0..state.channels() {
+            true_peak = true_peak.max(state.true_peak(channel)?);
+        }
+    }
+    Ok(Some(Loudness {
+        integrated,
+        true_peak,
+    }))
+}
+
+/// Decodes the first audio track of the file at `path`
+/// and feeds it to a loudness meter
+fn measure(path: &OsString) -> Result<EbuR128, LoudnessError> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let options = FormatOptions {
+        // Encoder delay and padding aren't part of the song
+        enable_gapless: true,
+        ..Default::default()
+    };
+    let probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        stream,
+        &options,
+        &MetadataOptions::default(),
+    )?;
+    let mut reader = probed.format;
+
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(LoudnessError::UnsupportedFormat)?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut state: Option<EbuR128> = None;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(DecodeError::ResetRequired) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet doesn't spoil the measure
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if decoded.frames() == 0 {
+            continue;
+        }
+
+        let spec = *decoded.spec();
+        if state.is_none() {
+            let channels = spec.channels.count() as u32;
+            let mut meter = EbuR128::new(channels, spec.rate, Mode::I | Mode::TRUE_PEAK)?;
+            if channels > 2 {
+                meter.set_channel_map(&channel_map(spec.channels))?;
+            }
+            state = Some(meter);
+        }
+        // Capacities are in samples for the buffer, in frames for `decoded`
+        let needed = decoded.capacity() * spec.channels.count();
+        let buffer = match &mut samples {
+            Some(buffer) if buffer.capacity() >= needed => buffer,
+            _ => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        if let Some(state) = &mut state {
+            state.add_frames_f32(buffer.samples())?;
+        }
+    }
+
+    state.ok_or(LoudnessError::UnsupportedFormat)
+}
+
+/// Tells the meter which channel is which. The LFE
+/// channel doesn't count for the loudness.
+fn channel_map(channels: Channels) -> Vec<Channel> {
+    channels
+        .iter()
+        .map(|channel| {
+            if channel == Channels::FRONT_LEFT {
+                Channel::Left
+            } else if channel == Channels::FRONT_RIGHT {
+                Channel::Right
+            } else if channel == Channels::FRONT_CENTRE {
+                Channel::Center
+            } else if channel == Channels::REAR_LEFT || channel == Channels::SIDE_LEFT {
+                Channel::LeftSurround
+            } else if channel == Channels::REAR_RIGHT || channel == Channels::SIDE_RIGHT {
+                Channel::RightSurround
+            } else {
+                Channel::Unused
+            }
+        })
+        .collect()
+}
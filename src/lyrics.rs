@@ -0,0 +1,379 @@
+use std::time::Duration;
+
+/// The lyrics of a song. They can hold plain text, lines synchronized
+/// with the song, or both when they come from separate sources (e.g. an
+/// USLT and a SYLT frame).
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct Lyrics {
+    plain: Option<String>,
+    /// Sorted by time
+    synced: Vec<LyricLine>,
+}
+
+impl Lyrics {
+    /// Creates lyrics from plain text
+    pub fn new_plain(text: &str) -> Self {
+        Self {
+            plain: Some(text.trim().to_string()),
+            synced: vec![],
+        }
+    }
+
+    /// Creates lyrics from synchronized lines, in any order
+    pub fn new_synced(mut lines: Vec<LyricLine>) -> Self {
+        lines.sort_by_key(|line| line.time);
+        Self {
+            plain: None,
+            synced: lines,
+        }
+    }
+
+    /// Parses lyrics in the LRC format. Lines may carry many timestamps,
+    /// and the `offset` tag shifts all of them. Word timestamps of the
+    /// enhanced format are dropped, as are the other ID tags `ID_TAGS`
+    /// lists; other bracketed lines, such as `[Verse 1: X]`, are text.
+    ///
+    /// Text without any timestamp is taken as plain lyrics, so this can
+    /// be used on lyrics whose format isn't known. So are lines whose
+    /// time is too large to be held.
+    pub fn parse(text: &str) -> Self {
+        let mut offset = 0i64;
+        let mut timed: Vec<(i64, String)> = vec![];
+        let mut plain = vec![];
+        // Untimed lines following the last timed one
+        let mut trailing = vec![];
+
+        for line in text.lines() {
+            let line = line.trim();
+            let mut rest = line;
+            let mut times = vec![];
+            let mut is_tag = false;
+
+            while let Some(stripped) = rest.strip_prefix('[') {
+                let end = match stripped.find(']') {
+                    Some(end) => end,
+                    None => break,
+                };
+                let content = &stripped[..end];
+                if let Some(time) = parse_timestamp(content) {
+                    times.push(time);
+                } else if times.is_empty() {
+                    if let Some((key, value)) = content.split_once(':') {
+                        let key = key.trim();
+                        if key.eq_ignore_ascii_case("offset") {
+                            offset = value.trim().parse().unwrap_or(0);
+                        }
+                        is_tag = ID_TAGS.iter().any(|tag| key.eq_ignore_ascii_case(tag));
+                    }
+                    break;
+                } else {
+                    break;
+                }
+                rest = &stripped[end + 1..];
+            }
+
+            if is_tag {
+                continue;
+            }
+            if times.is_empty() {
+                plain.push(line);
+                trailing.push(line);
+                continue;
+            }
+            trailing.clear();
+            let text = strip_word_timestamps(rest);
+            for time in times {
+                timed.push((time, text.clone()));
+            }
+        }
+
+        if timed.is_empty() {
+            return Self::new_plain(&plain.join("\n"));
+        }
+
+        // A positive offset makes lines show up sooner
+        let mut lines = vec![];
+        let mut untimed = vec![];
+        for (time, text) in timed {
+            match time.checked_sub(offset) {
+                Some(millis) => lines.push(LyricLine::new(
+                    Duration::from_millis(millis.max(0) as u64),
+                    &text,
+                )),
+                None => untimed.push(text),
+            }
+        }
+        let mut lyrics = Self::new_synced(lines);
+
+        // Untimed text after timed lines is what `to_lrc` writes for
+        // lyrics having both. Elsewhere, it's section names and such.
+        untimed.extend(trailing.into_iter().map(String::from));
+        let trailing = untimed.join("\n");
+        if !trailing.trim().is_empty() {
+            lyrics.set_plain(&trailing);
+        }
+        lyrics
+    }
+
+    /// Serializes the lyrics in the LRC format. Plain lyrics are
+    /// written after the synchronized lines, without timestamps.
+    pub fn to_lrc(&self) -> String {
+        let mut lrc = String::new();
+        for line in &self.synced {
+            let millis = line.time.as_millis();
+            lrc.push_str(&format!(
+                "[{:02}:{:02}.{:02}]{}\n",
+                millis / 60_000,
+                millis / 1000 % 60,
+                millis % 1000 / 10,
+                line.text
+            ));
+        }
+        if let Some(plain) = &self.plain {
+            lrc.push_str(plain);
+            lrc.push('\n');
+        }
+        lrc
+    }
+
+    pub fn plain(&self) -> Option<&str> {
+        self.plain.as_deref()
+    }
+
+    pub fn synced(&self) -> &[LyricLine] {
+        &self.synced
+    }
+
+    pub fn is_synced(&self) -> bool {
+        !self.synced.is_empty()
+    }
+
+    pub(crate) fn set_plain(&mut self, text: &str) {
+        self.plain = Some(text.trim().to_string());
+    }
+
+    pub(crate) fn set_synced(&mut self, lines: Vec<LyricLine>) {
+        self.synced = Self::new_synced(lines).synced;
+    }
+
+    /// Returns the whole text of the lyrics, preferring
+    /// the plain one if there's both
+    pub fn text(&self) -> String {
+        match &self.plain {
+            Some(plain) => plain.clone(),
+            None => self
+                .synced
+                .iter()
+                .map(|line| line.text.as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Returns the synchronized line being sung at `position`, which
+    /// is the last one starting before it. Returns `None` before the
+    /// first line or if the lyrics aren't synchronized.
+    pub fn line_at(&self, position: Duration) -> Option<&LyricLine> {
+        let index = self.synced.partition_point(|line| line.time <= position);
+        index.checked_sub(1).map(|index| &self.synced[index])
+    }
+}
+
+/// A line of synchronized lyrics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LyricLine {
+    /// When the line starts, from the start of the song
+    time: Duration,
+    text: String,
+}
+
+impl LyricLine {
+    pub fn new(time: Duration, text: &str) -> Self {
+        Self {
+            time,
+            text: text.trim().to_string(),
+        }
+    }
+
+    pub fn time(&self) -> &Duration {
+        &self.time
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// Keys of the LRC ID tags, which describe the lyrics rather than being
+/// part of them
+const ID_TAGS: [&str; 11] = [
+    "ar", "al", "ti", "au", "by", "length", "offset", "re", "tool", "ve", "#",
+];
+
+/// Parses LRC timestamps like `01:23`, `01:23.45`, `01:23.456` or
+/// `01:23:45` into milliseconds. Timestamps too large to be held
+/// aren't timestamps.
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let (minutes, rest) = value.split_once(':')?;
+    let (seconds, fraction) = match rest.find(['.', ':']) {
+        Some(index) => (&rest[..index], &rest[index + 1..]),
+        None => (rest, ""),
+    };
+
+    let all_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if minutes.is_empty() || seconds.is_empty() || !all_digits(minutes) || !all_digits(seconds) {
+        return None;
+    }
+    if !all_digits(fraction) || fraction.len() > 3 {
+        return None;
+    }
+
+    let minutes: i64 = minutes.parse().ok()?;
+    let seconds: i64 = seconds.parse().ok()?;
+    // Hundredths and thousandths are both in use
+    let fraction = match fraction.len() {
+        0 => 0,
+        len => fraction.parse::<i64>().ok()? * 10i64.pow(3 - len as u32),
+    };
+    minutes
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1000)?
+        .checked_add(fraction)
+}
+
+/// Removes the `<mm:ss.xx>` word timestamps of enhanced LRC
+fn strip_word_timestamps(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) if parse_timestamp(&rest[start + 1..start + end]).is_some() => {
+                result.push_str(&rest[..start]);
+                rest = &rest[start + end + 1..];
+            }
+            _ => {
+                result.push_str(&rest[..=start]);
+                rest = &rest[start + 1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(lyrics: &Lyrics) -> Vec<(u128, &str)> {
+        lyrics
+            .synced()
+            .iter()
+            .map(|line| (line.time().as_millis(), line.text()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("01:23"), Some(83_000));
+        assert_eq!(parse_timestamp("01:23.45"), Some(83_450));
+        assert_eq!(parse_timestamp("01:23.456"), Some(83_456));
+        assert_eq!(parse_timestamp("01:23:45"), Some(83_450));
+        assert_eq!(parse_timestamp("1:2.5"), Some(62_500));
+        assert_eq!(parse_timestamp("01:23.4567"), None);
+        assert_eq!(parse_timestamp(":23"), None);
+        assert_eq!(parse_timestamp("ar:Someone"), None);
+        assert_eq!(parse_timestamp("-1:00"), None);
+        assert_eq!(parse_timestamp("999999999999999999:00"), None);
+        assert_eq!(parse_timestamp("99999999999999999999:00"), None);
+        assert_eq!(parse_timestamp("0:9223372036854775807"), None);
+    }
+
+    #[test]
+    fn parses_synced_lines() {
+        let lyrics = Lyrics::parse(
+            "[ti:Song]\n[ar: Someone]\n[00:12.00][01:00.50]Chorus\n[00:01.000] First  line \n\n",
+        );
+        assert_eq!(
+            times(&lyrics),
+            [
+                (1_000, "First line"),
+                (12_000, "Chorus"),
+                (60_500, "Chorus")
+            ]
+        );
+        assert_eq!(lyrics.plain(), None);
+        assert!(lyrics.is_synced());
+    }
+
+    #[test]
+    fn drops_word_timestamps() {
+        let lyrics = Lyrics::parse("[00:01.00]<00:01.00>Hello <00:01.50>world <not a time>");
+        assert_eq!(times(&lyrics), [(1_000, "Hello world <not a time>")]);
+    }
+
+    #[test]
+    fn shifts_lines_by_the_offset() {
+        let lyrics = Lyrics::parse("[offset:+500]\n[00:01.00]a\n[00:00.20]b");
+        assert_eq!(times(&lyrics), [(0, "b"), (500, "a")]);
+        let lyrics = Lyrics::parse("[offset:-500]\n[00:01.00]a");
+        assert_eq!(times(&lyrics), [(1_500, "a")]);
+        let lyrics = Lyrics::parse("[offset:soon]\n[00:01.00]a");
+        assert_eq!(times(&lyrics), [(1_000, "a")]);
+    }
+
+    #[test]
+    fn keeps_lines_out_of_range_as_text() {
+        let lyrics = Lyrics::parse("[999999999999999999:00]x");
+        assert!(!lyrics.is_synced());
+        assert_eq!(lyrics.plain(), Some("[999999999999999999:00]x"));
+
+        let lyrics = Lyrics::parse("[offset:-9223372036854775808]\n[00:01.00]a\n[00:02.00]b");
+        assert!(!lyrics.is_synced());
+        assert_eq!(lyrics.plain(), Some("a\nb"));
+    }
+
+    #[test]
+    fn keeps_bracketed_text_that_isnt_a_tag() {
+        let lyrics = Lyrics::parse("[Verse 1: X]\nSome words\n[Chorus]\nMore words");
+        assert_eq!(
+            lyrics.plain(),
+            Some("[Verse 1: X]\nSome words\n[Chorus]\nMore words")
+        );
+        let lyrics = Lyrics::parse("[al:Album]\n[length: 03:20]\n[by:Me]\nWords");
+        assert_eq!(lyrics.plain(), Some("Words"));
+    }
+
+    #[test]
+    fn keeps_trailing_text_as_plain() {
+        let lyrics = Lyrics::parse("[Intro]\n[00:01.00]a\n[00:02.00]b\nThe whole\ntext");
+        assert_eq!(times(&lyrics), [(1_000, "a"), (2_000, "b")]);
+        assert_eq!(lyrics.plain(), Some("The whole\ntext"));
+        assert_eq!(lyrics.text(), "The whole\ntext");
+    }
+
+    #[test]
+    fn writes_lrc_back() {
+        let lyrics = Lyrics::parse("[00:01.50]a\n[01:02.03]b\nplain");
+        let lrc = lyrics.to_lrc();
+        assert_eq!(lrc, "[00:01.50]a\n[01:02.03]b\nplain\n");
+        assert_eq!(Lyrics::parse(&lrc), lyrics);
+    }
+
+    #[test]
+    fn finds_the_line_at_a_position() {
+        let lyrics = Lyrics::parse("[00:01.00]a\n[00:02.00]b");
+        assert_eq!(lyrics.line_at(Duration::from_millis(500)), None);
+        let at = |millis| {
+            lyrics
+                .line_at(Duration::from_millis(millis))
+                .unwrap()
+                .text()
+        };
+        assert_eq!(at(1_000), "a");
+        assert_eq!(at(1_999), "a");
+        assert_eq!(at(60_000), "b");
+        assert_eq!(Lyrics::new_plain("x").line_at(Duration::ZERO), None);
+    }
+}
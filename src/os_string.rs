@@ -0,0 +1,84 @@
+use std::ffi::OsString;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// How an `OsString` is stored in JSON files. Valid UTF-8 is kept as a
+/// plain string, which is what files written before non-UTF-8 paths were
+/// supported contain. Anything else is stored as raw bytes on Unix, and
+/// as UTF-16 code units on Windows, so no path is ever mangled.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum Stored {
+    Utf8(String),
+    Bytes { bytes: Vec<u8> },
+    Wide { wide: Vec<u16> },
+}
+
+/// Serializes an `OsString` without losing any of its content.
+/// Meant to be used through `#[serde(with = "crate::os_string")]`.
+pub(crate) fn serialize<S: Serializer>(value: &OsString, serializer: S) -> Result<S::Ok, S::Error> {
+    let stored = match value.to_str() {
+        Some(value) => Stored::Utf8(value.to_string()),
+        None => non_utf8(value),
+    };
+    stored.serialize(serializer)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<OsString, D::Error> {
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Utf8(value) => OsString::from(value),
+        Stored::Bytes { bytes } => from_bytes(bytes),
+        Stored::Wide { wide } => from_wide(wide),
+    })
+}
+
+#[cfg(unix)]
+fn non_utf8(value: &OsString) -> Stored {
+    use std::os::unix::ffi::OsStrExt;
+    Stored::Bytes {
+        bytes: value.as_bytes().to_vec(),
+    }
+}
+
+#[cfg(windows)]
+fn non_utf8(value: &OsString) -> Stored {
+    use std::os::windows::ffi::OsStrExt;
+    Stored::Wide {
+        wide: value.encode_wide().collect(),
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn non_utf8(value: &OsString) -> Stored {
+    Stored::Utf8(value.to_string_lossy().into_owned())
+}
+
+/// Paths stored as bytes come from Unix, other systems
+/// can only read them as best as they can
+pub(crate) fn from_bytes(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        OsString::from_vec(bytes)
+    }
+    #[cfg(not(unix))]
+    {
+        OsString::from(String::from_utf8_lossy(&bytes).into_owned())
+    }
+}
+
+/// Paths stored as UTF-16 come from Windows, other
+/// systems can only read them as best as they can
+pub(crate) fn from_wide(wide: Vec<u16>) -> OsString {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStringExt;
+        OsString::from_wide(&wide)
+    }
+    #[cfg(not(windows))]
+    {
+        OsString::from(String::from_utf16_lossy(&wide))
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Component, Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use super::{Playlist, PlaylistManager};
+use crate::{
+    backup::{self, ArchiveWriter, BackupError, Manifest},
+    library::Library,
+    schema,
+    song::{LoadPolicy, Song},
+    storage::{Collection, Storage},
+};
+
+/// What `PlaylistManager::restore` does with the songs
+/// and playlists already in the library
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RestoreMode {
+    /// Songs and playlists of the archive are added, unless
+    /// ones with the same id are already there
+    #[default]
+    Merge,
+    /// Songs and playlists are replaced by the ones of the archive
+    Replace,
+}
+
+impl PlaylistManager {
+    /// Saves the library, then bundles into a single archive at `path`
+    /// the details, stats and lyrics of every song, the playlists, the
+    /// artwork of the songs if there's an artwork store and, if
+    /// `include_audio` is `true`, the song files. A manifest lists what
+    /// the archive holds. Missing song files are left out.
+    pub fn export(
+        &mut self,
+        path: &OsString,
+        include_audio: bool,
+    ) -> Result<Manifest, BackupError> {
+        self.save()?;
+        let exported = self.write_archive(path, include_audio);
+        if exported.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        exported
+    }
+
+    fn write_archive(&self, path: &OsString, include_audio: bool) -> Result<Manifest, BackupError> {
+        let mut archive = ArchiveWriter::create(path, &self.library_root)?;
+        for collection in [Collection::Songs, Collection::Playlists] {
+            for name in self.storage.list(collection)? {
+                if schema::is_backup(&name) {
+                    continue;
+                }
+                archive.add_document(collection, &name, &self.storage.read(collection, &name)?)?;
+            }
+        }
+
+        if let Some(store) = &self.artwork {
+            let mut added = HashSet::new();
+            for song in self.library.songs() {
+                let details = song.details();
+                for artwork in [details.artwork(), details.artist_artwork()]
+                    .into_iter()
+                    .flatten()
+                {
+                    if store.contains(artwork) && added.insert(artwork.file_name()) {
+                        if let Ok(data) = store.read(artwork) {
+                            archive.add_artwork(&artwork.file_name(), &data)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        if include_audio {
+            let mut songs = self.library.songs();
+            songs.sort_by_key(|song| song.id());
+            for song in songs {
+                if Path::new(song.path()).is_file() {
+                    archive.add_song_file(song.id(), song.path())?;
+                }
+            }
+        }
+        archive.finish()
+    }
+
+    /// Restores the archive at `path`, made by `export`, once every entry
+    /// is checked against its manifest. Songs inside the library root of
+    /// the archive are moved to the same position inside this one. If the
+    /// archive holds song files, they're copied there, and songs that were
+    /// outside the library root are put directly inside it; files already
+    /// there are kept. Archives with song paths leading out of the library
+    /// root are refused. The library is saved afterwards. Returns the ids
+    /// of the songs added.
+    pub fn restore(
+        &mut self,
+        path: &OsString,
+        mode: RestoreMode,
+    ) -> Result<Vec<Uuid>, BackupError> {
+        let contents = backup::read(path)?;
+        let documents = &contents.documents;
+        let (mut restored, _) = Library::load(&self.library_root, documents, LoadPolicy::Keep)?;
+        let mut playlists = vec![];
+        for name in documents.list(Collection::Playlists)? {
+            if !schema::is_backup(&name) {
+                playlists.push(Playlist::load(documents, &name)?.0);
+            }
+        }
+
+        // Songs are put where they'd be if the library had been moved
+        let old_root = PathBuf::from(contents.manifest.library_root());
+        let files: HashSet<Uuid> = contents
+            .manifest
+            .entries()
+            .iter()
+            .filter_map(|entry| entry.song())
+            .collect();
+        // Paths leading out of where they're saved are refused
+        for song in restored.songs() {
+            let stored = Path::new(song.stored_path());
+            let valid = if stored.is_relative() {
+                backup::goes_down(stored)
+            } else {
+                !stored
+                    .components()
+                    .any(|component| component == Component::ParentDir)
+            };
+            if !valid {
+                return Err(BackupError::Corrupted(song.stored_path().clone()));
+            }
+        }
+        let mut targets = HashMap::new();
+        let library_root = &self.library_root;
+        restored.update_all(|song| {
+            // Relative paths are already resolved against this library root
+            let inside = Path::new(song.stored_path()).is_relative()
+                || song.relocate(&old_root, library_root);
+            if !inside && files.contains(&song.id()) {
+                let target = outside_target(song, library_root);
+                song.relocate(Path::new(&song.path().clone()), &target);
+            }
+            song.set_library_root(library_root);
+            if files.contains(&song.id()) {
+                targets.insert(song.id(), PathBuf::from(song.path()));
+            }
+        });
+
+        // Nothing is changed before the files are out
+        if mode == RestoreMode::Merge {
+            targets.retain(|id, _| !self.library.contains(*id));
+        }
+        backup::extract_songs(path, &contents.manifest, &targets, &self.library_root)?;
+
+        let replaced = match mode {
+            RestoreMode::Merge => vec![],
+            RestoreMode::Replace => {
+                self.playlists.clear();
+                let ids = self.library.ids();
+                ids.into_iter()
+                    .filter_map(|id| self.library.remove(id))
+                    .collect()
+            }
+        };
+
+        let mut added = vec![];
+        for id in restored.ids() {
+            if self.library.contains(id) {
+                continue;
+            }
+            if let Some(song) = restored.remove(id) {
+                let lyrics = song.lyrics_path();
+                if let Ok(data) = documents.read(Collection::Songs, &lyrics) {
+                    self.storage.write(Collection::Songs, &lyrics, &data)?;
+                }
+                self.library.add(song);
+                added.push(id);
+            }
+        }
+        for playlist in playlists {
+            if self.playlist(playlist.id()).is_none() {
+                self.playlists.push(playlist);
+            }
+        }
+        if let Some(store) = &self.artwork {
+            for (_, data) in &contents.artwork {
+                // Images are named after their content
+                let _ = store.add(data);
+            }
+        }
+        self.refresh_smart_playlists();
+        self.save()?;
+
+        // Documents of the songs that weren't restored
+        for song in replaced {
+            if !self.library.contains(song.id()) {
+                self.storage
+                    .remove(Collection::Songs, song.details_path())?;
+                self.storage
+                    .remove(Collection::Songs, &song.lyrics_path())?;
+            }
+        }
+        Ok(added)
+    }
+}
+
+/// Returns where the file of `song`, which was outside the library
+/// root, is restored: directly inside `library_root`, under the same
+/// name unless it's taken
+fn outside_target(song: &Song, library_root: &Path) -> PathBuf {
+    let path = Path::new(song.path());
+    let name = path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .unwrap_or_else(|| OsString::from(song.id().to_string()));
+    let target = library_root.join(&name);
+    if !target.exists() {
+        return target;
+    }
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(" ({})", song.id()));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    library_root.join(name)
+}
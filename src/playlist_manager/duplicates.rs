@@ -0,0 +1,284 @@
+use std::{collections::HashMap, io::ErrorKind, time::Duration};
+
+use uuid::Uuid;
+
+use super::PlaylistManager;
+use crate::{song::Song, storage::Collection};
+
+/// Largest difference between the durations of two
+/// songs still taken for the same track
+pub const DURATION_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Why songs were taken for duplicates
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateKind {
+    /// Every song has a file with the same content
+    SameContent,
+    /// Songs have the same name and artist, once normalised, and about
+    /// the same duration, but their files differ, e.g. because they were
+    /// downloaded by different plugins
+    SameTrack,
+}
+
+/// Songs that are likely the same one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DuplicateGroup {
+    kind: DuplicateKind,
+    songs: Vec<Uuid>,
+}
+
+impl DuplicateGroup {
+    pub fn kind(&self) -> DuplicateKind {
+        self.kind
+    }
+
+    /// Returns the ids of the songs, the most played first
+    pub fn songs(&self) -> &Vec<Uuid> {
+        &self.songs
+    }
+}
+
+impl PlaylistManager {
+    /// Groups the songs of the library that are likely the same one:
+    /// songs whose files have the same content, and songs with the same
+    /// name and artist, ignoring case, punctuation and what's between
+    /// brackets, whose durations are within `DURATION_TOLERANCE`. Songs
+    /// without a duration are only taken for the same track as the others
+    /// if those all have about the same duration, so they never join two
+    /// versions of a track together.
+    pub fn find_duplicates(&self) -> Vec<DuplicateGroup> {
+        let mut songs = self.library.songs();
+        songs.sort_by_key(|song| song.id());
+        let mut groups = Groups::new(songs.len());
+
+        let mut by_hash: HashMap<&str, usize> = HashMap::new();
+        let mut by_track: HashMap<(String, String), Vec<usize>> = HashMap::new();
+        for (index, song) in songs.iter().enumerate() {
+            let details = song.details();
+            if let Some(fingerprint) = details.fingerprint() {
+                match by_hash.get(fingerprint.hash()) {
+                    Some(&first) => groups.join(first, index),
+                    None => {
+                        by_hash.insert(fingerprint.hash(), index);
+                    }
+                }
+            }
+            let name = normalise(details.name());
+            if !name.is_empty() {
+                let artist = normalise(details.artist().unwrap_or_default());
+                by_track.entry((name, artist)).or_default().push(index);
+            }
+        }
+        for indexes in by_track.values() {
+            let duration = |index: usize| songs[index].details().duration().copied();
+            let (timed, untimed): (Vec<usize>, Vec<usize>) = indexes
+                .iter()
+                .partition(|&&index| duration(index).is_some());
+            for (position, &first) in timed.iter().enumerate() {
+                for &second in &timed[position + 1..] {
+                    if close_durations(songs[first], songs[second]) {
+                        groups.join(first, second);
+                    }
+                }
+            }
+
+            let durations = timed.iter().filter_map(|&index| duration(index));
+            let agree = match (durations.clone().min(), durations.max()) {
+                (Some(shortest), Some(longest)) => longest - shortest <= DURATION_TOLERANCE,
+                _ => true,
+            };
+            // Timed songs that agree were all joined already
+            if agree {
+                let mut same = untimed.iter().chain(timed.first());
+                if let Some(&first) = same.next() {
+                    for &other in same {
+                        groups.join(first, other);
+                    }
+                }
+            }
+        }
+
+        let mut found: Vec<DuplicateGroup> = groups
+            .sets()
+            .into_iter()
+            .filter(|set| set.len() > 1)
+            .map(|mut set| {
+                let hash = |index: usize| {
+                    songs[index]
+                        .details()
+                        .fingerprint()
+                        .map(|fingerprint| fingerprint.hash())
+                };
+                let kind = if hash(set[0]).is_some() && set.iter().all(|&i| hash(i) == hash(set[0]))
+                {
+                    DuplicateKind::SameContent
+                } else {
+                    DuplicateKind::SameTrack
+                };
+                set.sort_by_key(|&index| {
+                    std::cmp::Reverse(songs[index].details().stats().play_count())
+                });
+                DuplicateGroup {
+                    kind,
+                    songs: set.into_iter().map(|index| songs[index].id()).collect(),
+                }
+            })
+            .collect();
+        found.sort_by_key(|group| group.songs[0]);
+        found
+    }
+
+    /// Merges the songs with ids in `duplicates` into the one with id
+    /// `keep`: missing details are taken from them, their listening stats
+    /// are added up, and their lyrics kept if `keep` has none. Playlists
+    /// referring to them refer to `keep` instead, unless they already
+    /// hold it. They're then removed from the library, which is saved,
+    /// along with their documents, and with their files if `remove_files`
+    /// is `true`. Returns how many songs were merged.
+    pub fn merge_songs(
+        &mut self,
+        keep: Uuid,
+        duplicates: &[Uuid],
+        remove_files: bool,
+    ) -> std::io::Result<usize> {
+        let kept = match self.library.get(keep) {
+            Some(song) => song.clone(),
+            None => return Ok(0),
+        };
+        let merged: Vec<Song> = duplicates
+            .iter()
+            .filter(|&&id| id != keep)
+            .filter_map(|&id| self.library.get(id).cloned())
+            .collect();
+        if merged.is_empty() {
+            return Ok(0);
+        }
+
+        self.library.update_details(keep, |details| {
+            for song in &merged {
+                details.merge(song.details());
+            }
+        });
+        let mut lyrics = self.storage.exists(Collection::Songs, &kept.lyrics_path());
+        for song in &merged {
+            if !lyrics && self.storage.exists(Collection::Songs, &song.lyrics_path()) {
+                self.storage
+                    .rename(Collection::Songs, &song.lyrics_path(), &kept.lyrics_path())?;
+                lyrics = true;
+            }
+        }
+
+        for playlist in self.playlists.iter_mut() {
+            let mut holds_kept = playlist.contains(keep);
+            let songs = playlist.songs_mut();
+            let mut index = 0;
+            while index < songs.len() {
+                if merged.iter().any(|song| song.id() == songs[index]) {
+                    if holds_kept {
+                        songs.remove(index);
+                        continue;
+                    }
+                    songs[index] = keep;
+                    holds_kept = true;
+                }
+                index += 1;
+            }
+        }
+        for song in &merged {
+            self.library.remove(song.id());
+        }
+        self.save()?;
+
+        // Documents are removed once the library doesn't refer to them
+        let storage = &self.storage;
+        storage.batch(&mut || {
+            for song in &merged {
+                storage.remove(Collection::Songs, song.details_path())?;
+                storage.remove(Collection::Songs, &song.lyrics_path())?;
+            }
+            Ok(())
+        })?;
+        if remove_files {
+            for song in &merged {
+                if song.path() == kept.path() {
+                    continue;
+                }
+                match std::fs::remove_file(song.path()) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+        Ok(merged.len())
+    }
+}
+
+/// Returns `true` if `first` and `second` are about as long
+fn close_durations(first: &Song, second: &Song) -> bool {
+    match (first.details().duration(), second.details().duration()) {
+        (Some(first), Some(second)) => first.abs_diff(*second) <= DURATION_TOLERANCE,
+        _ => false,
+    }
+}
+
+/// Returns `text` in lower case, without what's between brackets, e.g.
+/// "(Official Video)", and with words separated by single spaces
+fn normalise(text: &str) -> String {
+    let mut normalised = String::new();
+    let mut depth = 0usize;
+    let mut space = false;
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth = depth.saturating_sub(1),
+            _ if depth > 0 => {}
+            c if c.is_alphanumeric() => {
+                if space && !normalised.is_empty() {
+                    normalised.push(' ');
+                }
+                space = false;
+                normalised.push(c);
+            }
+            _ => space = true,
+        }
+    }
+    normalised
+}
+
+/// Disjoint sets of indexes, joined one pair at a time
+struct Groups {
+    parents: Vec<usize>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Self {
+        Self {
+            parents: (0..len).collect(),
+        }
+    }
+
+    fn root(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    fn join(&mut self, first: usize, second: usize) {
+        let (first, second) = (self.root(first), self.root(second));
+        if first != second {
+            self.parents[second.max(first)] = first.min(second);
+        }
+    }
+
+    /// Returns every set, each one sorted
+    fn sets(&mut self) -> Vec<Vec<usize>> {
+        let mut sets: HashMap<usize, Vec<usize>> = HashMap::new();
+        for index in 0..self.parents.len() {
+            let root = self.root(index);
+            sets.entry(root).or_default().push(index);
+        }
+        sets.into_values().collect()
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+use super::{Playlist, PlaylistManager};
+use crate::{
+    format::AudioFormat,
+    song::{LoadError, SongDetails, TagSync},
+    storage::Collection,
+    watcher::{Change, WatchError, Watcher},
+};
+
+/// A change made by another program to the files of the
+/// library, and found by watching them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExternalChange {
+    /// An audio file, which is no song of the library,
+    /// appeared inside the library root
+    SongFileAdded(OsString),
+    /// The file of the song was edited
+    SongFileModified(Uuid),
+    /// The file of the song was moved, and the song followed it
+    SongFileMoved(Uuid),
+    /// The file of the song was removed. The song is left in the library.
+    SongFileRemoved(Uuid),
+    /// The details of the song were replaced by the
+    /// ones in its song_meta file
+    DetailsChanged(Uuid),
+    /// The song_meta file of the song was removed. It's
+    /// written again at the next save.
+    DetailsRemoved(Uuid),
+    PlaylistAdded(Uuid),
+    PlaylistChanged(Uuid),
+    PlaylistRemoved(Uuid),
+    /// The song or playlist with this id changed both in its file and in
+    /// memory since it was last saved. The conflict was solved as told
+    /// by the `ConflictPolicy`.
+    Conflict(Uuid),
+}
+
+/// How conflicts between changes made by other programs
+/// and changes made in memory are solved
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Changes made by other programs replace the ones made in memory
+    #[default]
+    PreferExternal,
+    /// Changes made in memory are kept, and replace
+    /// the ones in the file at the next save
+    PreferMemory,
+}
+
+impl PlaylistManager {
+    /// Starts watching the library root, and the directories holding song
+    /// details and playlists if the storage keeps them in files, for
+    /// changes made by other programs. Changes are applied, and told, by
+    /// `process_external_changes`.
+    pub fn watch(&mut self) -> Result<(), WatchError> {
+        let mut watcher = Watcher::new()?;
+        watcher.watch(&self.library_root)?;
+        for collection in [Collection::Songs, Collection::Playlists] {
+            if let Some(dir) = self.storage.directory(collection) {
+                watcher.watch(dir)?;
+            }
+        }
+        self.watcher = Some(watcher);
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self) {
+        self.watcher = None;
+    }
+
+    pub fn is_watching(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict_policy
+    }
+
+    /// Applies the changes made by other programs since the last call, as
+    /// found by watching the files of the library, and returns them. Meant
+    /// to be called regularly, e.g. from the event loop of the application;
+    /// `save` calls it too, so that it never overwrites changes it doesn't
+    /// know about. Library subscribers are told about changed songs as
+    /// usual.
+    pub fn process_external_changes(&mut self) -> Vec<ExternalChange> {
+        let mut changes = std::mem::take(&mut self.unreported);
+        let watcher = match &self.watcher {
+            Some(watcher) => watcher,
+            None => return changes,
+        };
+
+        let songs_dir = self.storage.directory(Collection::Songs);
+        let playlists_dir = self.storage.directory(Collection::Playlists);
+        let mut details = BTreeSet::new();
+        let mut playlists = BTreeSet::new();
+        let mut files = BTreeSet::new();
+        let mut moves = vec![];
+        let mut rescan = false;
+        {
+            let mut classify = |path: PathBuf| {
+                let parent = path.parent();
+                match path.file_name() {
+                    Some(name) if parent.is_some() && parent == songs_dir => {
+                        details.insert(name.to_os_string());
+                    }
+                    Some(name) if parent.is_some() && parent == playlists_dir => {
+                        playlists.insert(name.to_os_string());
+                    }
+                    _ => {
+                        files.insert(path);
+                    }
+                }
+            };
+            for change in watcher.changes() {
+                match change {
+                    Change::Changed(path) => classify(path),
+                    Change::Moved(from, to) => {
+                        moves.push((from.clone(), to.clone()));
+                        classify(from);
+                        classify(to);
+                    }
+                    Change::Rescan => rescan = true,
+                }
+            }
+        }
+
+        // Files of the library can't be listed cheaply, unlike documents
+        if rescan {
+            details.extend(
+                self.library
+                    .songs()
+                    .iter()
+                    .map(|song| song.details_path().clone()),
+            );
+            if let Ok(names) = self.storage.list(Collection::Playlists) {
+                playlists.extend(names);
+            }
+            playlists.extend(
+                self.playlists
+                    .iter()
+                    .map(|playlist| OsString::from(crate::file_name_from_playlist(playlist))),
+            );
+        }
+
+        self.process_moves(&moves, &mut files, &mut changes);
+        self.process_files(&files, &mut changes);
+        for name in details {
+            self.process_details(&name, &mut changes);
+        }
+        for name in playlists {
+            self.process_playlist(&name, &mut changes);
+        }
+        // Smart playlists loaded again hold no songs yet
+        if !changes.is_empty() {
+            self.refresh_smart_playlists();
+        }
+        changes
+    }
+
+    /// Moves the songs inside the moved files and directories
+    fn process_moves(
+        &mut self,
+        moves: &[(PathBuf, PathBuf)],
+        files: &mut BTreeSet<PathBuf>,
+        changes: &mut Vec<ExternalChange>,
+    ) {
+        let library_root = &self.library_root;
+        for (from, to) in moves {
+            let mut moved = vec![];
+            self.library.update_all(|song| {
+                if song.relocate(from, to) {
+                    song.set_library_root(library_root);
+                    moved.push(song.id());
+                }
+            });
+            if !moved.is_empty() {
+                files.remove(from);
+                files.remove(to);
+            }
+            changes.extend(moved.into_iter().map(ExternalChange::SongFileMoved));
+        }
+    }
+
+    /// Finds out what happened to each of `files`, inside the library root
+    fn process_files(&mut self, files: &BTreeSet<PathBuf>, changes: &mut Vec<ExternalChange>) {
+        if files.is_empty() {
+            return;
+        }
+        let by_path: HashMap<OsString, Uuid> = self
+            .library
+            .songs()
+            .iter()
+            .map(|song| (song.path().clone(), song.id()))
+            .collect();
+
+        for path in files.iter() {
+            if !path.starts_with(&self.library_root) {
+                continue;
+            }
+            // Songs inside a removed file, or directory, are gone with it
+            if !path.exists() {
+                for song in self.library.songs() {
+                    if Path::new(song.path()).starts_with(path) {
+                        changes.push(ExternalChange::SongFileRemoved(song.id()));
+                    }
+                }
+                continue;
+            }
+            if path.is_dir() {
+                for file in audio_files(path) {
+                    if !by_path.contains_key(file.as_os_str()) {
+                        changes.push(ExternalChange::SongFileAdded(file.into_os_string()));
+                    }
+                }
+                continue;
+            }
+
+            match by_path.get(path.as_os_str()) {
+                Some(&id) => {
+                    if self.tag_sync == Some(TagSync::FromFile) {
+                        self.library.update(id, |song| {
+                            let _ = song.sync_tags(TagSync::FromFile);
+                        });
+                    }
+                    changes.push(ExternalChange::SongFileModified(id));
+                }
+                None if is_audio(path) => {
+                    changes.push(ExternalChange::SongFileAdded(path.clone().into_os_string()));
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Compares the song_meta file `name` with the details in memory
+    fn process_details(&mut self, name: &OsStr, changes: &mut Vec<ExternalChange>) {
+        let id = match self
+            .library
+            .songs()
+            .iter()
+            .find(|song| song.details_path() == name)
+        {
+            Some(song) => song.id(),
+            None => return,
+        };
+
+        let data = match self.storage.read(Collection::Songs, name) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                self.library.mark_dirty(id);
+                changes.push(ExternalChange::DetailsRemoved(id));
+                return;
+            }
+            Err(_) => return,
+        };
+        // The echo of a save
+        if self.library.wrote(id, &data) {
+            return;
+        }
+        // Files still being written, or that can't be handled, are
+        // left alone: they're seen again once they change
+        let details = match SongDetails::load(&*self.storage, name) {
+            Ok(details) => details,
+            Err(_) => return,
+        };
+        let song = match self.library.get(id) {
+            Some(song) => song,
+            None => return,
+        };
+        if *song.details() == details && !song.is_read_only() {
+            return;
+        }
+
+        if !self.library.is_dirty(id) {
+            self.library.reload(id, details);
+            changes.push(ExternalChange::DetailsChanged(id));
+        } else {
+            if self.conflict_policy == ConflictPolicy::PreferExternal {
+                self.library.reload(id, details);
+            }
+            changes.push(ExternalChange::Conflict(id));
+        }
+    }
+
+    /// Compares the playlist file `name` with the playlist in memory
+    fn process_playlist(&mut self, name: &OsStr, changes: &mut Vec<ExternalChange>) {
+        let id = match Path::new(name)
+            .file_stem()
+            .and_then(|stem| Uuid::parse_str(&stem.to_string_lossy()).ok())
+        {
+            Some(id) => id,
+            None => return,
+        };
+        let position = self
+            .playlists
+            .iter()
+            .position(|playlist| playlist.id() == id);
+        let saved = self.saved_playlists.get(&id);
+        let dirty = match position {
+            Some(position) => self.playlists[position].to_bytes().ok().as_ref() != saved,
+            // Removed from memory, and not saved yet
+            None => saved.is_some(),
+        };
+        let prefer_external = self.conflict_policy == ConflictPolicy::PreferExternal;
+
+        let playlist = match Playlist::load(&*self.storage, name) {
+            Ok((playlist, legacy)) if playlist.id() == id && legacy.is_empty() => playlist,
+            Err(LoadError::NotFound(_)) => {
+                match position {
+                    Some(position) if !dirty || prefer_external => {
+                        self.playlists.remove(position);
+                        self.saved_playlists.remove(&id);
+                        changes.push(if dirty {
+                            ExternalChange::Conflict(id)
+                        } else {
+                            ExternalChange::PlaylistRemoved(id)
+                        });
+                    }
+                    Some(_) => {
+                        // Written again at the next save
+                        self.saved_playlists.remove(&id);
+                        changes.push(ExternalChange::Conflict(id));
+                    }
+                    None => {
+                        self.saved_playlists.remove(&id);
+                    }
+                }
+                return;
+            }
+            // Files still being written, or that can't be handled, are
+            // left alone: they're seen again once they change
+            _ => return,
+        };
+        let data = match playlist.to_bytes() {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+
+        let in_memory = position.map(|position| &self.playlists[position]);
+        if in_memory.and_then(|playlist| playlist.to_bytes().ok()) == Some(data.clone()) {
+            // The echo of a save, or the same change made twice
+            self.saved_playlists.insert(id, data);
+            return;
+        }
+        if Some(&data) == saved {
+            return;
+        }
+
+        if dirty && !prefer_external {
+            // Saving compares the playlist with the file, and replaces it
+            self.saved_playlists.insert(id, data);
+            changes.push(ExternalChange::Conflict(id));
+            return;
+        }
+        let change = match (dirty, position) {
+            (true, _) => ExternalChange::Conflict(id),
+            (false, Some(_)) => ExternalChange::PlaylistChanged(id),
+            (false, None) => ExternalChange::PlaylistAdded(id),
+        };
+        match position {
+            Some(position) => self.playlists[position] = playlist,
+            None => self.playlists.push(playlist),
+        }
+        self.saved_playlists.insert(id, data);
+        changes.push(change);
+    }
+}
+
+/// Returns `true` if the file at `path` is in a known audio format
+fn is_audio(path: &Path) -> bool {
+    matches!(
+        AudioFormat::from_path(&path.as_os_str().to_os_string()),
+        Ok(Some(_))
+    )
+}
+
+/// Returns every audio file inside the directory `dir`
+fn audio_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(kind) if kind.is_dir() => files.extend(audio_files(&path)),
+            Ok(kind) if kind.is_file() && is_audio(&path) => files.push(path),
+            _ => {}
+        }
+    }
+    files
+}
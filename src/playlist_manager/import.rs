@@ -0,0 +1,307 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::mpsc::Sender,
+};
+
+use sanitise_file_name::sanitise;
+use uuid::Uuid;
+
+use super::PlaylistManager;
+use crate::{
+    format::AudioFormat,
+    integrity::Fingerprint,
+    song::{Song, SongDetails},
+    ImportInfo,
+};
+
+/// Where imported songs are put by default, inside the library root
+pub const DEFAULT_LAYOUT: &str = "{album_artist}/{album}/{track} {title}";
+
+/// What is done with the files of imported songs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ImportMode {
+    /// Files are left where they are
+    #[default]
+    InPlace,
+    /// Files are copied into the library root, following the layout
+    Copy,
+    /// Files are moved into the library root, following the layout
+    Move,
+}
+
+/// How a music folder is imported into the library
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    mode: ImportMode,
+    layout: String,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self::new(ImportMode::default())
+    }
+}
+
+impl ImportOptions {
+    pub fn new(mode: ImportMode) -> Self {
+        Self {
+            mode,
+            layout: String::from(DEFAULT_LAYOUT),
+        }
+    }
+
+    pub fn mode(&self) -> ImportMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: ImportMode) {
+        self.mode = mode;
+    }
+
+    pub fn layout(&self) -> &str {
+        &self.layout
+    }
+
+    /// Sets where songs are put inside the library root when they're copied
+    /// or moved. `/` separates directories, and `{artist}`, `{album_artist}`,
+    /// `{album}`, `{title}`, `{track}`, `{disc}`, `{year}` and `{genre}` are
+    /// replaced by the details of the song. The extension of the file is
+    /// added to it.
+    pub fn set_layout(&mut self, layout: &str) {
+        self.layout = String::from(layout);
+    }
+
+    /// Returns the path of a song with `details`, relative
+    /// to the library root and without extension
+    pub fn file_path(&self, details: &SongDetails) -> PathBuf {
+        let artist = details.artist().unwrap_or("Unknown Artist");
+        let placeholders = [
+            ("{artist}", String::from(artist)),
+            (
+                "{album_artist}",
+                String::from(details.album_artist().unwrap_or(artist)),
+            ),
+            (
+                "{album}",
+                String::from(details.album().unwrap_or("Unknown Album")),
+            ),
+            ("{title}", String::from(details.name())),
+            (
+                "{track}",
+                details
+                    .track_number()
+                    .map(|track| format!("{:02}", track))
+                    .unwrap_or_default(),
+            ),
+            (
+                "{disc}",
+                details
+                    .disc_number()
+                    .map(|disc| disc.to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "{year}",
+                details
+                    .year()
+                    .map(|year| year.to_string())
+                    .unwrap_or_default(),
+            ),
+            ("{genre}", String::from(details.genre().unwrap_or_default())),
+        ];
+
+        let mut path = PathBuf::new();
+        for component in self.layout.split('/') {
+            let mut component = String::from(component);
+            for (placeholder, value) in &placeholders {
+                // Values can't add directories
+                component = component.replace(placeholder, &sanitise(value));
+            }
+            let component = component.trim();
+            if !component.is_empty() {
+                path.push(sanitise(component));
+            }
+        }
+        path
+    }
+}
+
+impl PlaylistManager {
+    /// Adds every audio file inside `dir`, and its subdirectories, to the
+    /// library. Files are recognised by their content, and songs get their
+    /// details from the tags and properties of their file. Files already
+    /// in the library, by path or by content, are skipped. Progress is
+    /// sent to `progress`, if given. The library is saved once every file
+    /// is handled, and smart playlists updated. Returns the ids of the
+    /// imported songs.
+    pub fn import(
+        &mut self,
+        dir: &OsString,
+        options: &ImportOptions,
+        progress: Option<Sender<ImportInfo>>,
+    ) -> std::io::Result<Vec<Uuid>> {
+        let send = |info: ImportInfo| {
+            if let Some(progress) = &progress {
+                let _ = progress.send(info);
+            }
+        };
+
+        let mut files = vec![];
+        find_audio_files(Path::new(dir), &mut files)?;
+        send(ImportInfo::Started(files.len()));
+
+        let mut paths: HashSet<OsString> = self
+            .library
+            .songs()
+            .iter()
+            .map(|song| song.path().clone())
+            .collect();
+        let mut hashes: HashSet<String> = self
+            .library
+            .songs()
+            .iter()
+            .filter_map(|song| song.details().fingerprint())
+            .map(|fingerprint| String::from(fingerprint.hash()))
+            .collect();
+
+        let mut imported = vec![];
+        for file in files {
+            let file = file.into_os_string();
+            if paths.contains(&file) {
+                send(ImportInfo::Skipped(file));
+                continue;
+            }
+            let fingerprint = match Fingerprint::compute(&file) {
+                Ok(fingerprint) => fingerprint,
+                Err(err) => {
+                    send(ImportInfo::Failed(file, err.to_string()));
+                    continue;
+                }
+            };
+            if hashes.contains(fingerprint.hash()) {
+                send(ImportInfo::Skipped(file));
+                continue;
+            }
+
+            let mut song = Song::from_file(&file);
+            song.details_mut().set_fingerprint(fingerprint.clone());
+            if options.mode != ImportMode::InPlace {
+                let target = match self.place(&file, song.details(), options) {
+                    Ok(target) => target,
+                    Err(err) => {
+                        send(ImportInfo::Failed(file, err.to_string()));
+                        continue;
+                    }
+                };
+                song.relocate(Path::new(&file), &target);
+                // Copies have a modification time of their own
+                if options.mode == ImportMode::Copy {
+                    if let Ok(fingerprint) = Fingerprint::compute(song.path()) {
+                        song.details_mut().set_fingerprint(fingerprint);
+                    }
+                }
+            }
+
+            paths.insert(song.path().clone());
+            hashes.insert(String::from(fingerprint.hash()));
+            let id = self.insert_song(song);
+            if let Some(song) = self.library.get(id) {
+                send(ImportInfo::Imported(Box::new(song.clone())));
+            }
+            imported.push(id);
+        }
+
+        send(ImportInfo::Finished);
+        self.save()?;
+        Ok(imported)
+    }
+
+    /// Copies or moves `file` inside the library root, where the layout
+    /// of `options` puts a song with `details`. Returns its new path.
+    fn place(
+        &self,
+        file: &OsString,
+        details: &SongDetails,
+        options: &ImportOptions,
+    ) -> std::io::Result<PathBuf> {
+        let extension = match Path::new(file).extension() {
+            Some(extension) => extension.to_os_string(),
+            None => AudioFormat::from_path(file)?
+                .map(|format| OsString::from(format.extension()))
+                .unwrap_or_default(),
+        };
+        let relative = options.file_path(details);
+        let stem = relative
+            .file_name()
+            .map(|name| name.to_os_string())
+            .unwrap_or_else(|| OsString::from("Unknown"));
+        let dir = self
+            .library_root
+            .join(relative.parent().unwrap_or(Path::new("")));
+        std::fs::create_dir_all(&dir)?;
+
+        // Songs with the same details get a number
+        let mut target;
+        let mut copies = 1;
+        loop {
+            let mut name = stem.clone();
+            if copies > 1 {
+                name.push(format!(" ({})", copies));
+            }
+            if !extension.is_empty() {
+                name.push(".");
+                name.push(&extension);
+            }
+            target = dir.join(name);
+            if !target.exists() {
+                break;
+            }
+            copies += 1;
+        }
+
+        match options.mode {
+            ImportMode::Move => {
+                // Files on another file system can't be renamed
+                if std::fs::rename(file, &target).is_err() {
+                    std::fs::copy(file, &target)?;
+                    if let Err(err) = std::fs::remove_file(file) {
+                        let _ = std::fs::remove_file(&target);
+                        return Err(err);
+                    }
+                }
+            }
+            _ => {
+                std::fs::copy(file, &target)?;
+            }
+        }
+        Ok(target)
+    }
+}
+
+/// Adds every audio file inside the directory `dir` to `files`, sorted.
+/// Symbolic links to directories aren't followed.
+pub(super) fn find_audio_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let kind = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => continue,
+        };
+        if kind.is_dir() {
+            find_audio_files(&path, files)?;
+        } else if path.is_file()
+            && matches!(
+                AudioFormat::from_path(&path.as_os_str().to_os_string()),
+                Ok(Some(_))
+            )
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
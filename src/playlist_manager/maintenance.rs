@@ -0,0 +1,236 @@
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+use uuid::Uuid;
+
+use super::{import::find_audio_files, PlaylistManager};
+use crate::{library::LIBRARY_FILE, schema, song::Song, storage::Collection};
+
+/// Extension added to the name of the documents set aside by `repair`
+pub const ARCHIVE_EXTENSION: &str = "orphaned";
+
+/// What `PlaylistManager::check` found wrong in the library
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    missing_files: Vec<Uuid>,
+    dangling_entries: Vec<(Uuid, Uuid)>,
+    orphaned_details: Vec<OsString>,
+    missing_details: Vec<Uuid>,
+    untracked_files: Vec<OsString>,
+}
+
+impl MaintenanceReport {
+    /// Returns the songs whose file is gone
+    pub fn missing_files(&self) -> &Vec<Uuid> {
+        &self.missing_files
+    }
+
+    /// Returns the entries of playlists referring to songs that aren't in
+    /// the library, as the id of the playlist and the one of the song
+    pub fn dangling_entries(&self) -> &Vec<(Uuid, Uuid)> {
+        &self.dangling_entries
+    }
+
+    /// Returns the names of the documents, among the songs in storage,
+    /// that belong to no song of the library. The ones of songs left
+    /// out while loading, as `LoadPolicy::Skip` tells, aren't counted.
+    pub fn orphaned_details(&self) -> &Vec<OsString> {
+        &self.orphaned_details
+    }
+
+    /// Returns the songs whose song_meta file is missing
+    pub fn missing_details(&self) -> &Vec<Uuid> {
+        &self.missing_details
+    }
+
+    /// Returns the audio files inside the library
+    /// root that are no song of the library
+    pub fn untracked_files(&self) -> &Vec<OsString> {
+        &self.untracked_files
+    }
+
+    /// Returns `true` if nothing is wrong
+    pub fn is_empty(&self) -> bool {
+        self.missing_files.is_empty()
+            && self.dangling_entries.is_empty()
+            && self.orphaned_details.is_empty()
+            && self.missing_details.is_empty()
+            && self.untracked_files.is_empty()
+    }
+}
+
+impl PlaylistManager {
+    /// Looks for what deletions left behind: songs whose file is gone,
+    /// playlists referring to songs that aren't in the library, documents
+    /// of songs that aren't in it anymore, songs without a song_meta file
+    /// and audio files inside the library root without details. Nothing
+    /// is changed; `repair` fixes what's found.
+    pub fn check(&self) -> MaintenanceReport {
+        let mut report = MaintenanceReport::default();
+        let songs = self.library.songs();
+
+        for song in songs.iter() {
+            if !Path::new(song.path()).exists() {
+                report.missing_files.push(song.id());
+            } else if !self.storage.exists(Collection::Songs, song.details_path()) {
+                report.missing_details.push(song.id());
+            }
+        }
+        report.missing_files.sort();
+        report.missing_details.sort();
+
+        for playlist in &self.playlists {
+            for &id in playlist.songs() {
+                if !self.library.contains(id) {
+                    report.dangling_entries.push((playlist.id(), id));
+                }
+            }
+        }
+
+        let mut owned: HashSet<OsString> = songs
+            .iter()
+            .flat_map(|song| [song.details_path().clone(), song.lyrics_path()])
+            .collect();
+        owned.insert(OsString::from(LIBRARY_FILE));
+        // Skipped songs keep their documents untouched
+        owned.extend(self.library.left_out_documents().iter().cloned());
+        if let Ok(names) = self.storage.list(Collection::Songs) {
+            // Documents set aside earlier aren't orphans anymore
+            report.orphaned_details = names
+                .into_iter()
+                .filter(|name| !owned.contains(name) && !is_set_aside(name))
+                .collect();
+        }
+
+        let paths: HashSet<&OsString> = songs.iter().map(|song| song.path()).collect();
+        let mut files = vec![];
+        // A library root that doesn't exist has no files
+        let _ = find_audio_files(&self.library_root, &mut files);
+        report.untracked_files = files
+            .into_iter()
+            .map(|file| file.into_os_string())
+            .filter(|file| !paths.contains(file))
+            .collect();
+
+        report
+    }
+
+    /// Fixes what `check` finds, and returns it. Songs whose file is gone
+    /// are removed from the library and from every playlist, as are the
+    /// other dangling entries of playlists. Documents of songs that aren't
+    /// in the library are set aside, by adding `ARCHIVE_EXTENSION` to their
+    /// name, and so are the ones of the removed songs. Missing song_meta
+    /// files are written again, from the song file if the details were
+    /// lost with them, and untracked audio files are added to the
+    /// library. The library is saved afterwards.
+    pub fn repair(&mut self) -> std::io::Result<MaintenanceReport> {
+        let report = self.check();
+
+        let mut orphaned = report.orphaned_details.clone();
+        for &id in &report.missing_files {
+            if let Some(song) = self.remove_song(id) {
+                for name in [song.details_path().clone(), song.lyrics_path()] {
+                    if self.storage.exists(Collection::Songs, &name) {
+                        orphaned.push(name);
+                    }
+                }
+            }
+        }
+        for playlist in self.playlists.iter_mut() {
+            let library = &self.library;
+            playlist.songs_mut().retain(|&id| library.contains(id));
+        }
+
+        let storage = &self.storage;
+        storage.batch(&mut || {
+            for name in &orphaned {
+                let mut archived = name.clone();
+                archived.push(".");
+                archived.push(ARCHIVE_EXTENSION);
+                storage.rename(Collection::Songs, name, &archived)?;
+            }
+            Ok(())
+        })?;
+
+        for &id in &report.missing_details {
+            // Read-only songs have no details of their own to write
+            self.library.update(id, |song| {
+                if song.is_read_only() {
+                    song.rebuild_details();
+                    song.set_read_only(false);
+                }
+            });
+            self.library.mark_dirty(id);
+        }
+        for file in &report.untracked_files {
+            self.insert_song(Song::from_file(file));
+        }
+
+        self.save()?;
+        Ok(report)
+    }
+}
+
+/// Returns `true` if the document `name` was set aside, either because it
+/// couldn't be loaded, because it belonged to no song or before a migration
+fn is_set_aside(name: &OsStr) -> bool {
+    schema::is_backup(name)
+        || matches!(
+            Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str()),
+            Some("broken") | Some(ARCHIVE_EXTENSION)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        song::{LoadPolicy, SongDetails},
+        storage::{MemoryStorage, Storage},
+    };
+
+    #[test]
+    fn leaves_documents_of_skipped_songs_alone() {
+        let root = std::env::temp_dir().join(format!("phosphorus-{}", Uuid::new_v4()));
+        let song = Song::new(root.join("song.mp3"), SongDetails::default());
+        let storage = MemoryStorage::new();
+        let library = json!({ "songs": [song] });
+        storage
+            .write(
+                Collection::Songs,
+                OsStr::new(LIBRARY_FILE),
+                &serde_json::to_vec(&library).unwrap(),
+            )
+            .unwrap();
+        storage
+            .write(Collection::Songs, song.details_path(), b"not json")
+            .unwrap();
+        storage
+            .write(Collection::Songs, OsStr::new("stray.json"), b"{}")
+            .unwrap();
+
+        let mut manager = PlaylistManager::load_from_storage(
+            root.into_os_string(),
+            Box::new(storage),
+            LoadPolicy::Skip,
+        )
+        .unwrap();
+        assert_eq!(manager.load_errors().len(), 1);
+
+        let report = manager.repair().unwrap();
+        assert_eq!(
+            report.orphaned_details(),
+            &vec![OsString::from("stray.json")]
+        );
+        let storage = &manager.storage;
+        assert!(storage.exists(Collection::Songs, song.details_path()));
+        assert!(storage.exists(Collection::Songs, OsStr::new("stray.json.orphaned")));
+    }
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, ffi::OsString};
+
+use uuid::Uuid;
+
+use super::Playlist;
+use crate::{
+    song::Song,
+    storage::{Collection, Storage},
+};
+
+/// A playlist as just loaded: the name it was loaded from, and the songs
+/// it held, with their position, if it was saved before the library existed
+pub(super) struct LoadedPlaylist {
+    pub(super) name: OsString,
+    pub(super) playlist: Playlist,
+    pub(super) legacy: Vec<(usize, Song)>,
+    /// Set when the playlist has to be saved again in the current
+    /// format, or under the name it should have
+    pub(super) outdated: bool,
+    /// Set when the playlist was upgraded from an older version of
+    /// the format, and has to be saved again at the next save
+    pub(super) upgraded: bool,
+}
+
+/// Gives an id to the playlists and songs saved before ids existed, and
+/// returns the songs held by playlists saved before the library existed,
+/// each one once, for them to be moved to the library.
+///
+/// Every copy of a song, found through its path, gets the same id and its
+/// song_meta file is renamed after it. Playlists refer to their songs by
+/// that id from now on. Playlists that changed, or whose file isn't named
+/// after their id yet, are marked as outdated.
+pub(super) fn assign_ids(
+    storage: &dyn Storage,
+    playlists: &mut [LoadedPlaylist],
+) -> std::io::Result<Vec<Song>> {
+    // Songs that already have an id keep it, and lend it to
+    // their copies that don't
+    let mut ids: HashMap<OsString, Uuid> = HashMap::new();
+    for loaded in playlists.iter() {
+        for (_, song) in loaded.legacy.iter() {
+            if !song.id().is_nil() {
+                ids.entry(song.stored_path().clone()).or_insert(song.id());
+            }
+        }
+    }
+
+    let mut songs: HashMap<Uuid, Song> = HashMap::new();
+    for loaded in playlists.iter_mut() {
+        if loaded.playlist.id().is_nil() {
+            loaded.playlist.set_id(Uuid::new_v4());
+        }
+        let target = OsString::from(crate::file_name_from_playlist(&loaded.playlist));
+        loaded.outdated = !loaded.legacy.is_empty() || loaded.name != target;
+
+        for (position, mut song) in std::mem::take(&mut loaded.legacy) {
+            if song.id().is_nil() {
+                let id = *ids
+                    .entry(song.stored_path().clone())
+                    .or_insert_with(Uuid::new_v4);
+                let old_meta = song.details_path().clone();
+                song.set_id(id);
+                let new_meta = song.details_path();
+                // Copies after the first one find the file already renamed
+                if storage.exists(Collection::Songs, &old_meta)
+                    && !storage.exists(Collection::Songs, new_meta)
+                {
+                    storage.rename(Collection::Songs, &old_meta, new_meta)?;
+                }
+            }
+            loaded.playlist.songs_mut()[position] = song.id();
+            songs.entry(song.id()).or_insert(song);
+        }
+    }
+
+    Ok(songs.into_values().collect())
+}
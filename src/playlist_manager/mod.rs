@@ -1,12 +1,47 @@
-use std::{collections::HashMap, ffi::OsString, fmt::Display, path::PathBuf};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
 
 use chrono::Utc;
+use uuid::Uuid;
 
-use crate::song::Song;
+use crate::{
+    artwork::{Artwork, ArtworkError, ArtworkStore},
+    integrity::FileStatus,
+    library::{Library, LibraryEvent},
+    loudness::{AlbumAnalysis, Loudness, LoudnessError},
+    lyrics::Lyrics,
+    schema,
+    song::{LoadError, LoadPolicy, Song, TagSync},
+    stats::PlaybackEvent,
+    storage::{Collection, JsonDirStorage, Storage},
+    tags::TagError,
+};
 
+pub use self::backup::RestoreMode;
+pub use self::duplicates::{DuplicateGroup, DuplicateKind, DURATION_TOLERANCE};
+#[cfg(feature = "watcher")]
+pub use self::external::{ConflictPolicy, ExternalChange};
+pub use self::import::{ImportMode, ImportOptions, DEFAULT_LAYOUT};
+pub use self::maintenance::{MaintenanceReport, ARCHIVE_EXTENSION};
+use self::migration::LoadedPlaylist;
 pub use self::playlist::Playlist;
+pub use self::smart::{SmartRules, SortKey};
 
+mod backup;
+mod duplicates;
+#[cfg(feature = "watcher")]
+mod external;
+mod import;
+mod maintenance;
+mod migration;
 mod playlist;
+mod smart;
 
 #[derive(Debug)]
 pub enum PlaylistManagerError {
@@ -29,58 +64,327 @@ impl Display for PlaylistManagerError {
 
 impl std::error::Error for PlaylistManagerError {}
 
-pub const ALL_SONGS: &'static str = "All songs";
+pub const ALL_SONGS: &str = "All songs";
 
 pub struct PlaylistManager {
-    songs_meta: OsString,
-    playlists_meta: OsString,
+    /// Directory holding the song files. Songs inside it are
+    /// saved with a path relative to it.
+    library_root: PathBuf,
+    /// Where the library, song details and playlists are saved
+    storage: Box<dyn Storage>,
+    /// Every known song, the ones in playlists included
+    library: Library,
     playlists: Vec<Playlist>,
+    /// Changes to the library smart playlists weren't updated for yet
+    library_events: Receiver<LibraryEvent>,
+    /// How song details are kept in sync with the tags of
+    /// song files, if they are
+    tag_sync: Option<TagSync>,
+    /// Files that couldn't be loaded, but didn't stop the manager
+    /// from loading
+    load_errors: Vec<LoadError>,
+    /// Where artwork is stored, if it's handled
+    artwork: Option<ArtworkStore>,
+    /// Each playlist as it was last saved
+    saved_playlists: HashMap<Uuid, Vec<u8>>,
+    /// How often changes are saved by `autosave`, if they are
+    autosave: Option<Duration>,
+    last_save: Instant,
+    /// Watches the files of the library for changes made by other
+    /// programs, once `watch` was called
+    #[cfg(feature = "watcher")]
+    watcher: Option<crate::watcher::Watcher>,
+    #[cfg(feature = "watcher")]
+    conflict_policy: ConflictPolicy,
+    /// External changes applied by `save`, to be returned by the
+    /// next call to `process_external_changes`
+    #[cfg(feature = "watcher")]
+    unreported: Vec<ExternalChange>,
 }
 
 impl PlaylistManager {
+    /// Loads the library and every playlist saved in `playlists_meta`.
+    /// Songs saved with a relative path are looked for in `library_root`,
+    /// and songs inside it will be saved with a relative path from now on.
+    ///
+    /// Files that can't be loaded are handled as with `LoadPolicy::Keep`.
     pub fn load(
+        library_root: OsString,
         songs_meta: OsString,
         playlists_meta: OsString,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut playlists = vec![];
+        Self::load_with_policy(library_root, songs_meta, playlists_meta, LoadPolicy::Keep)
+    }
+
+    /// As `load`, but songs whose details can't be loaded are handled as
+    /// told by `policy`. Unless the policy is `LoadPolicy::Fail`, playlist
+    /// files that can't be loaded are left out, and untouched. Errors that
+    /// didn't stop the loading are available from `load_errors`.
+    ///
+    /// Playlists saved before the library existed held their own copy of
+    /// their songs: those are moved to the library, and the playlists
+    /// saved again referring to them.
+    pub fn load_with_policy(
+        library_root: OsString,
+        songs_meta: OsString,
+        playlists_meta: OsString,
+        policy: LoadPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let storage = JsonDirStorage::new(songs_meta, playlists_meta);
+        Self::load_from_storage(library_root, Box::new(storage), policy)
+    }
+
+    /// As `load_with_policy`, but the library, song details and
+    /// playlists are loaded from, and saved to, `storage`
+    pub fn load_from_storage(
+        library_root: OsString,
+        storage: Box<dyn Storage>,
+        policy: LoadPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let library_root = PathBuf::from(library_root);
+        let (mut library, mut load_errors) = Library::load(&library_root, &*storage, policy)?;
 
-        let files = std::fs::read_dir(&playlists_meta)?;
+        let mut loaded = vec![];
+        for name in storage.list(Collection::Playlists)? {
+            if schema::is_backup(&name) {
+                continue;
+            }
+            match Playlist::read(&*storage, &name) {
+                Ok((playlist, legacy, upgraded)) => loaded.push(LoadedPlaylist {
+                    name,
+                    playlist,
+                    legacy,
+                    outdated: false,
+                    upgraded,
+                }),
+                Err(err) if policy == LoadPolicy::Fail => return Err(Box::new(err)),
+                Err(err) => load_errors.push(err),
+            }
+        }
+
+        // Libraries saved before songs and playlists had an
+        // id, or before the library existed
+        let legacy = migration::assign_ids(&*storage, &mut loaded)?;
+        for song in legacy {
+            if library.contains(song.id()) {
+                continue;
+            }
+            if let Some(err) = library.adopt(song, &library_root, &*storage, policy)? {
+                load_errors.push(err);
+            }
+        }
+
+        // Outdated playlist files are replaced only once the
+        // songs they held are safe in the library
+        if loaded.iter().any(|loaded| loaded.outdated) {
+            library.save(&*storage)?;
+            for loaded in loaded.iter().filter(|loaded| loaded.outdated) {
+                loaded.playlist.save(&*storage)?;
+                if loaded.name != crate::file_name_from_playlist(&loaded.playlist).as_str() {
+                    storage.remove(Collection::Playlists, &loaded.name)?;
+                }
+            }
+        }
 
-        for file in files {
-            let file = file?;
-            let playlist = Playlist::load(&file.path().into_os_string())?;
-            playlists.push(playlist);
+        // Upgraded playlists aren't saved yet in the current format
+        let mut saved_playlists = HashMap::new();
+        for loaded in loaded
+            .iter()
+            .filter(|loaded| !loaded.upgraded || loaded.outdated)
+        {
+            saved_playlists.insert(loaded.playlist.id(), loaded.playlist.to_bytes()?);
         }
+        let playlists: Vec<Playlist> = loaded.into_iter().map(|loaded| loaded.playlist).collect();
+        let library_events = library.subscribe();
 
-        Ok(Self {
-            songs_meta,
-            playlists_meta,
+        let mut manager = Self {
+            library_root,
+            storage,
+            library,
             playlists,
+            library_events,
+            tag_sync: None,
+            load_errors,
+            artwork: None,
+            saved_playlists,
+            autosave: None,
+            last_save: Instant::now(),
+            #[cfg(feature = "watcher")]
+            watcher: None,
+            #[cfg(feature = "watcher")]
+            conflict_policy: ConflictPolicy::default(),
+            #[cfg(feature = "watcher")]
+            unreported: vec![],
+        };
+        manager.refresh_smart_playlists();
+        Ok(manager)
+    }
+
+    /// Returns where the library, song
+    /// details and playlists are saved
+    pub fn storage(&self) -> &dyn Storage {
+        &*self.storage
+    }
+
+    /// Saves what changed since the last save: the details of the changed
+    /// songs, the list of songs if it changed, and the changed playlists.
+    /// Playlists removed from the manager are removed from the storage.
+    /// Every document is written as a whole or not at all, and nothing is
+    /// forgotten if saving fails: the next save tries again.
+    ///
+    /// With `TagSync::ToFile`, the tags of the changed songs are written
+    /// first. Returns the songs whose file couldn't be, together with the
+    /// reason; their details are saved anyway.
+    pub fn save(&mut self) -> std::io::Result<Vec<(OsString, TagError)>> {
+        #[cfg(feature = "watcher")]
+        {
+            self.unreported = self.process_external_changes();
+        }
+        self.update_smart_playlists();
+
+        let mut failures = vec![];
+        if self.tag_sync == Some(TagSync::ToFile) {
+            for id in self.library.ids() {
+                if !self.library.is_dirty(id) {
+                    continue;
+                }
+                self.library.update(id, |song| {
+                    if let Err(err) = song.sync_tags(TagSync::ToFile) {
+                        failures.push((song.path().clone(), err));
+                    }
+                });
+            }
+        }
+
+        self.library.save(&*self.storage)?;
+
+        let storage = &*self.storage;
+        let playlists = &self.playlists;
+        let previous = &self.saved_playlists;
+        let mut saved = HashMap::new();
+        storage.batch(&mut || {
+            for playlist in playlists.iter() {
+                let data = playlist.to_bytes()?;
+                if previous.get(&playlist.id()) != Some(&data) {
+                    let name = crate::file_name_from_playlist(playlist);
+                    storage.write(Collection::Playlists, OsStr::new(&name), &data)?;
+                }
+                saved.insert(playlist.id(), data);
+            }
+            for id in previous.keys().filter(|id| !saved.contains_key(*id)) {
+                let name = crate::file_name_from_id(id);
+                storage.remove(Collection::Playlists, OsStr::new(&name))?;
+            }
+            Ok(())
+        })?;
+
+        self.saved_playlists = saved;
+        self.last_save = Instant::now();
+        Ok(failures)
+    }
+
+    /// Saves what changed, as `save` does, and makes sure it
+    /// survives a crash of the system, not only of the process
+    pub fn flush(&mut self) -> std::io::Result<Vec<(OsString, TagError)>> {
+        let failures = self.save()?;
+        self.storage.flush()?;
+        Ok(failures)
+    }
+
+    /// Returns `true` if something changed since the last save
+    pub fn has_unsaved_changes(&self) -> bool {
+        if self.library.has_unsaved_changes() || self.playlists.len() != self.saved_playlists.len()
+        {
+            return true;
+        }
+        self.playlists.iter().any(|playlist| {
+            playlist.to_bytes().ok().as_ref() != self.saved_playlists.get(&playlist.id())
         })
     }
 
+    /// Sets how often `autosave` saves changes. `None`, the
+    /// default, turns autosaving off.
+    pub fn set_autosave(&mut self, interval: Option<Duration>) {
+        self.autosave = interval;
+    }
+
+    pub fn autosave_interval(&self) -> Option<Duration> {
+        self.autosave
+    }
+
+    /// Saves changes if autosaving is on and its interval passed since the
+    /// last save. Meant to be called regularly, e.g. from the event loop
+    /// of the application. Returns `None` if nothing was saved, or the
+    /// songs whose tags couldn't be written, as `save` does.
+    pub fn autosave(&mut self) -> std::io::Result<Option<Vec<(OsString, TagError)>>> {
+        match self.autosave {
+            Some(interval) if self.last_save.elapsed() >= interval => {}
+            _ => return Ok(None),
+        }
+        if !self.has_unsaved_changes() {
+            self.last_save = Instant::now();
+            return Ok(None);
+        }
+        self.save().map(Some)
+    }
+
+    /// Returns the files that couldn't be loaded when the
+    /// manager was, without stopping it from loading
+    pub fn load_errors(&self) -> &Vec<LoadError> {
+        &self.load_errors
+    }
+
     /// Ensures that basic playlists are loaded and creates them if they don't
-    /// already exists. Basic playlists are: playlists of all downloaded songs
-    /// (named `All songs`)
+    /// already exists. Basic playlists are: playlists of all the songs in
+    /// the library (named `All songs`), which also gets any song it misses.
     pub fn ensure_basics(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if !self.names().contains(&ALL_SONGS) {
-            let mut playlist = Playlist::new(ALL_SONGS, Utc::now());
-            // Loads all downloaded songs and their metadata
-            let songs_meta = std::fs::read_dir(&self.songs_meta)?;
-            for song_meta in songs_meta {
-                if let Ok(song_meta) = song_meta {
-                    let song = Song::load(serde_json::from_str(&std::fs::read_to_string(
-                        song_meta.path(),
-                    )?)?);
-                    playlist.add(song);
-                }
+            self.playlists.push(Playlist::new(ALL_SONGS, Utc::now()));
+        }
+
+        let mut songs = self.library.songs();
+        songs.sort_by(|a, b| a.details().name().cmp(b.details().name()));
+        if let Some(playlist) = self
+            .playlists
+            .iter_mut()
+            .find(|playlist| playlist.name() == ALL_SONGS)
+        {
+            for song in songs {
+                playlist.add_unique(song.id());
             }
-            self.playlists.push(playlist);
         }
 
         Ok(())
     }
 
+    pub fn library_root(&self) -> &Path {
+        &self.library_root
+    }
+
+    pub fn library(&self) -> &Library {
+        &self.library
+    }
+
+    pub fn library_mut(&mut self) -> &mut Library {
+        &mut self.library
+    }
+
+    /// Moves the songs found in `from`, which is usually where the library
+    /// used to be, to the same position inside the library root. Songs
+    /// saved with an absolute path inside the library root are saved with
+    /// a relative one from now on. Returns how many songs were moved.
+    pub fn relocate(&mut self, from: &Path) -> usize {
+        let library_root = &self.library_root;
+        let mut relocated = 0;
+        self.library.update_all(|song| {
+            if song.relocate(from, library_root) {
+                relocated += 1;
+            }
+            song.set_library_root(library_root);
+        });
+        relocated
+    }
+
     pub fn playlists(&self) -> &Vec<Playlist> {
         &self.playlists
     }
@@ -89,61 +393,356 @@ impl PlaylistManager {
         &mut self.playlists
     }
 
+    /// Returns the playlist with id `id`, if there is one
+    pub fn playlist(&self, id: Uuid) -> Option<&Playlist> {
+        self.playlists.iter().find(|playlist| playlist.id() == id)
+    }
+
+    pub fn playlist_mut(&mut self, id: Uuid) -> Option<&mut Playlist> {
+        self.playlists
+            .iter_mut()
+            .find(|playlist| playlist.id() == id)
+    }
+
+    /// Returns the songs of the playlist with id `id`, in
+    /// order, or `None` if there's no such playlist
+    pub fn playlist_songs(&self, id: Uuid) -> Option<Vec<&Song>> {
+        let playlist = self.playlist(id)?;
+        Some(self.library.resolve(playlist.songs()))
+    }
+
     /// Returns all playlist names
     pub fn names(&self) -> Vec<&str> {
         self.playlists.iter().map(|p| p.name()).collect()
     }
 
-    /// Addds `song` to the playlist named `playlist`, but only if this exits.
-    /// Nothing is done otherwise.
-    pub fn add_to(&mut self, song: Song, playlist: &str) {
-        for pl in &mut self.playlists {
-            if pl.name() == playlist {
-                pl.add(song);
-                break;
+    /// Sets how song details are kept in sync with the tags embedded in
+    /// song files. With `TagSync::FromFile` details are updated right away
+    /// and whenever a song is added; with `TagSync::ToFile` the tags of
+    /// changed songs are written by `save`. `None` disables syncing.
+    pub fn set_tag_sync(&mut self, sync: Option<TagSync>) -> Vec<(OsString, TagError)> {
+        self.tag_sync = sync;
+        match sync {
+            Some(TagSync::FromFile) => self.sync_tags(TagSync::FromFile),
+            _ => vec![],
+        }
+    }
+
+    /// Copies metadata between every song of the library and its file, in
+    /// the direction given by `sync`. Returns the songs whose file couldn't
+    /// be handled, together with the reason.
+    pub fn sync_tags(&mut self, sync: TagSync) -> Vec<(OsString, TagError)> {
+        let mut failures = vec![];
+        self.library.update_all(|song| {
+            if let Err(err) = song.sync_tags(sync) {
+                failures.push((song.path().clone(), err));
             }
+        });
+        self.update_smart_playlists();
+        failures
+    }
+
+    /// Sets where artwork is stored. Once set, the cover embedded in
+    /// added songs is extracted to it. `None` stops handling artwork.
+    pub fn set_artwork_store(&mut self, store: Option<ArtworkStore>) {
+        self.artwork = store;
+    }
+
+    pub fn artwork_store(&self) -> Option<&ArtworkStore> {
+        self.artwork.as_ref()
+    }
+
+    /// Extracts the cover embedded in every song file of the library that
+    /// has no artwork yet. Returns the songs whose file couldn't be handled,
+    /// together with the reason. Nothing is done without a store.
+    pub fn extract_artwork(&mut self) -> Vec<(OsString, ArtworkError)> {
+        let store = match &self.artwork {
+            Some(store) => store,
+            None => return vec![],
+        };
+
+        let mut failures = vec![];
+        self.library.update_all(|song| {
+            if song.details().artwork().is_some() {
+                return;
+            }
+            match store.extract(song.path()) {
+                Ok(artwork) => song.details_mut().set_artwork(artwork),
+                Err(err) => failures.push((song.path().clone(), err)),
+            }
+        });
+        failures
+    }
+
+    /// Sets the cover of the song with id `id`, or removes it if
+    /// `None`. Returns `false` if there's no such song.
+    pub fn set_artwork(&mut self, id: Uuid, artwork: Option<Artwork>) -> bool {
+        self.library
+            .update_details(id, |details| details.set_artwork(artwork))
+    }
+
+    /// Sets the picture of the artist of the song with id `id`, or
+    /// removes it if `None`. Returns `false` if there's no such song.
+    pub fn set_artist_artwork(&mut self, id: Uuid, artwork: Option<Artwork>) -> bool {
+        self.library
+            .update_details(id, |details| details.set_artist_artwork(artwork))
+    }
+
+    /// Returns the cover of the album `album`, which is the one of its
+    /// first song having one. With `album_artist`, only albums by that
+    /// artist are looked at.
+    pub fn album_artwork(&self, album: &str, album_artist: Option<&str>) -> Option<&Artwork> {
+        self.library
+            .by_album(album, album_artist)
+            .into_iter()
+            .find_map(|song| song.details().artwork())
+    }
+
+    /// Sets the cover of every song of the album `album`, or removes it
+    /// if `None`. With `album_artist`, only albums by that artist are
+    /// changed. Returns how many songs were changed.
+    pub fn set_album_artwork(
+        &mut self,
+        album: &str,
+        album_artist: Option<&str>,
+        artwork: Option<Artwork>,
+    ) -> usize {
+        let ids: Vec<Uuid> = self
+            .library
+            .by_album(album, album_artist)
+            .iter()
+            .map(|song| song.id())
+            .collect();
+        for id in ids.iter() {
+            self.library
+                .update_details(*id, |details| details.set_artwork(artwork.clone()));
+        }
+        ids.len()
+    }
+
+    /// Returns a thumbnail of the cover of the song with id `id`, as
+    /// `ArtworkStore::thumbnail` does. Songs without a cover get the one
+    /// of their album. Returns `None` if there's no cover or no store.
+    pub fn thumbnail(&self, id: Uuid, size: u32) -> Result<Option<PathBuf>, ArtworkError> {
+        let (store, details) = match (&self.artwork, self.song(id)) {
+            (Some(store), Some(song)) => (store, song.details()),
+            _ => return Ok(None),
+        };
+
+        let artwork = details.artwork().or_else(|| {
+            let album = details.album()?;
+            self.album_artwork(album, details.album_artist())
+        });
+        match artwork {
+            Some(artwork) => store.thumbnail(artwork, size).map(Some),
+            None => Ok(None),
         }
     }
 
-    /// Returns a vector of all the song saved in any playlist.
-    fn songs(&self) -> Vec<&Song> {
-        let mut songs: HashMap<&OsString, &Song> = HashMap::new();
+    /// Returns the song with id `id`
+    /// from the library
+    pub fn song(&self, id: Uuid) -> Option<&Song> {
+        self.library.get(id)
+    }
+
+    /// Loads the lyrics of the song with id `id`, as `Song::load_lyrics`
+    /// does. Returns `None` if there's no such song.
+    pub fn lyrics(&self, id: Uuid) -> Result<Option<Lyrics>, LoadError> {
+        match self.song(id) {
+            Some(song) => song.load_lyrics(&*self.storage),
+            None => Ok(None),
+        }
+    }
 
-        for playlist in self.playlists.iter() {
-            for song in playlist.songs() {
-                songs.insert(song.path(), song);
+    /// Stores the lyrics of the song with id `id`, or removes them if
+    /// `None`. Returns `false` if there's no such song.
+    pub fn set_lyrics(&self, id: Uuid, lyrics: Option<&Lyrics>) -> std::io::Result<bool> {
+        match self.song(id) {
+            Some(song) => {
+                song.save_lyrics(&*self.storage, lyrics)?;
+                Ok(true)
             }
+            None => Ok(false),
         }
+    }
 
-        songs.values().map(|value| *value).collect()
+    /// Records `event` in the stats of the song with id `id`, at the
+    /// current time. Returns `false` if there's no such song.
+    ///
+    /// Songs of the queue are copies, so playback of the current one is
+    /// recorded by passing the id of `QueueManager::current` here.
+    pub fn record_playback(&mut self, id: Uuid, event: PlaybackEvent) -> bool {
+        let at = Utc::now();
+        let recorded = self
+            .library
+            .update_details(id, |details| details.stats_mut().record(event, at));
+        self.update_smart_playlists();
+        recorded
+    }
+
+    /// Rates the song with id `id` with `stars`, or removes its rating
+    /// if `None`. Returns `false` if there's no such song.
+    pub fn set_stars(&mut self, id: Uuid, stars: Option<u8>) -> bool {
+        let rated = self
+            .library
+            .update_details(id, |details| details.stats_mut().set_stars(stars));
+        self.update_smart_playlists();
+        rated
+    }
+
+    /// Marks the song with id `id` as favourite, or not.
+    /// Returns `false` if there's no such song.
+    pub fn set_favourite(&mut self, id: Uuid, favourite: bool) -> bool {
+        let marked = self
+            .library
+            .update_details(id, |details| details.stats_mut().set_favourite(favourite));
+        self.update_smart_playlists();
+        marked
+    }
+
+    /// Measures the loudness of every known song without a track
+    /// ReplayGain value, whether read from its tags or computed before.
+    /// Songs of albums where any song lacks an album value are measured
+    /// all together, to compute it. Returns the songs whose file couldn't
+    /// be decoded, together with the reason; they don't count for the
+    /// loudness of their album.
+    pub fn analyze_loudness(&mut self) -> Vec<(OsString, LoudnessError)> {
+        let mut albums: HashMap<(&str, Option<&str>), Vec<&Song>> = HashMap::new();
+        let mut singles = vec![];
+        for song in self.library.songs() {
+            let details = song.details();
+            match details.album() {
+                Some(album) => {
+                    let album_artist = details.album_artist().or(details.artist());
+                    albums.entry((album, album_artist)).or_default().push(song);
+                }
+                None => singles.push(song),
+            }
+        }
+
+        let mut failures = vec![];
+        let mut measured: Vec<(Uuid, Option<Loudness>, Option<Loudness>)> = vec![];
+        for songs in albums.into_values() {
+            let replay_gains = songs.iter().map(|song| song.details().replay_gain());
+            if replay_gains.clone().all(|gain| gain.album_gain().is_some()) {
+                singles.extend(songs);
+                continue;
+            }
+
+            let mut analysis = AlbumAnalysis::new();
+            let mut tracks = vec![];
+            for song in songs {
+                match analysis.add(song.path()) {
+                    Ok(loudness) => tracks.push((song.id(), loudness)),
+                    Err(err) => failures.push((song.path().clone(), err)),
+                }
+            }
+            // Every song is measured the same way, so they can't mismatch
+            let album = analysis.album().ok().flatten();
+            for (id, track) in tracks {
+                measured.push((id, track, album));
+            }
+        }
+
+        for song in singles {
+            if song.details().replay_gain().track_gain().is_some() {
+                continue;
+            }
+            match crate::loudness::analyze(song.path()) {
+                Ok(track) => measured.push((song.id(), track, None)),
+                Err(err) => failures.push((song.path().clone(), err)),
+            }
+        }
+
+        for (id, track, album) in measured {
+            self.library.update_details(id, |details| {
+                if let Some(track) = track {
+                    details.set_loudness(track);
+                }
+                if let Some(album) = &album {
+                    details.set_album_loudness(album);
+                }
+            });
+        }
+        failures
+    }
+
+    /// Checks every song file of the library against its recorded
+    /// fingerprint. Returns the songs whose file isn't intact, together
+    /// with its status.
+    pub fn verify(&self) -> Vec<(&Song, FileStatus)> {
+        self.library
+            .songs()
+            .into_iter()
+            .map(|song| (song, song.verify()))
+            .filter(|(_, status)| *status != FileStatus::Intact)
+            .collect()
+    }
+
+    /// Adds `song` to the library, unless it's already there, and returns
+    /// its id. Songs whose file hasn't been probed or fingerprinted yet get
+    /// their properties read and their fingerprint taken first, and their
+    /// embedded cover extracted if there's an artwork store.
+    pub fn add_song(&mut self, song: Song) -> Uuid {
+        let id = self.insert_song(song);
+        self.update_smart_playlists();
+        id
+    }
+
+    /// Adds `song` to the library as `add_song` does, leaving smart
+    /// playlists as they are, for songs added in bulk
+    pub(super) fn insert_song(&mut self, mut song: Song) -> Uuid {
+        let id = song.id();
+        if self.library.contains(id) {
+            return id;
+        }
+
+        if self.tag_sync == Some(TagSync::FromFile) {
+            let _ = song.sync_tags(TagSync::FromFile);
+        }
+        if song.details().properties().is_none() {
+            let _ = song.probe();
+        }
+        if song.details().fingerprint().is_none() {
+            let _ = song.fingerprint();
+        }
+        if let (Some(store), None) = (&self.artwork, song.details().artwork()) {
+            if let Ok(Some(artwork)) = store.extract(song.path()) {
+                song.details_mut().set_artwork(Some(artwork));
+            }
+        }
+        song.set_library_root(&self.library_root);
+        self.library.add(song);
+        id
+    }
+
+    /// Addds `song` to the playlist named `playlist`, but only if this exits.
+    /// Nothing is done otherwise. The song is added to the library first,
+    /// as `add_song` does; smart playlists only get it if their rules
+    /// select it.
+    pub fn add_to(&mut self, song: Song, playlist: &str) {
+        if !self.names().contains(&playlist) {
+            return;
+        }
+        let id = self.add_song(song);
+        for pl in &mut self.playlists {
+            if pl.name() == playlist {
+                if !pl.is_smart() {
+                    pl.add(id);
+                }
+                break;
+            }
+        }
     }
-}
 
-impl Drop for PlaylistManager {
-    fn drop(&mut self) {
-        // For any song still in a playslist make sure that its meta file
-        // exits
-        let songs = self.songs();
-        let mut songs_meta = PathBuf::from(&self.songs_meta);
-        for song in songs {
-            songs_meta.push(song.details_path());
-            let _ = std::fs::write(
-                songs_meta.as_path(),
-                serde_json::to_string(song.details()).unwrap(),
-            );
-            songs_meta.pop();
-        }
-
-        // For any playlist make sure its meta file exists
-        let mut playlist_meta = PathBuf::from(&self.playlists_meta);
-        for playlist in self.playlists.iter() {
-            let file_name = crate::file_name_from_playlist(playlist);
-            playlist_meta.push(file_name);
-            let _ = std::fs::write(
-                playlist_meta.as_path(),
-                serde_json::to_string(playlist).unwrap(),
-            );
-            playlist_meta.pop();
+    /// Removes the song with id `id` from the library and from every
+    /// playlist, and returns it. Its files are left where they are.
+    pub fn remove_song(&mut self, id: Uuid) -> Option<Song> {
+        for playlist in self.playlists.iter_mut() {
+            playlist.songs_mut().retain(|song| *song != id);
         }
+        let song = self.library.remove(id);
+        self.update_smart_playlists();
+        song
     }
 }
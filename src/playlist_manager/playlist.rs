@@ -1,17 +1,62 @@
-use std::ffi::OsString;
+use std::{ffi::OsStr, time::Duration};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
 
-use crate::song::Song;
+use super::SmartRules;
+use crate::{
+    library::Library,
+    schema::{self, DocumentKind},
+    song::{LoadError, Song},
+    storage::{Collection, Storage},
+};
 
 /// A playlist is just a collection of songs
-/// identified by a name.
+/// identified by an id and named by the user.
+/// Songs are held by the library, the playlist
+/// only refers to them. Smart playlists hold the
+/// songs their rules select instead.
 #[derive(Deserialize, Serialize)]
 pub struct Playlist {
+    /// Identifies the playlist across renames. Playlists saved before ids
+    /// existed get the nil id until `PlaylistManager::load` gives them one.
+    #[serde(default)]
+    id: Uuid,
     name: String,
     creation_date: DateTime<Utc>,
-    songs: Vec<Song>,
+    /// Rules selecting the songs, if it's a smart playlist. Only the
+    /// rules are saved: the songs are selected when it's loaded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rules: Option<SmartRules>,
+    /// Ids of the songs, in the library
+    songs: Vec<Uuid>,
+}
+
+/// Songs held by a playlist saved before the library existed,
+/// with their position in it
+type LegacySongs = Vec<(usize, Song)>;
+
+/// A playlist as saved before the library existed, when it held
+/// a copy of each of its songs
+#[derive(Deserialize)]
+struct StoredPlaylist {
+    #[serde(default)]
+    id: Uuid,
+    name: String,
+    creation_date: DateTime<Utc>,
+    #[serde(default)]
+    rules: Option<SmartRules>,
+    #[serde(default)]
+    songs: Vec<StoredSong>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredSong {
+    Id(Uuid),
+    Song(Box<Song>),
 }
 
 impl Playlist {
@@ -19,22 +64,78 @@ impl Playlist {
     /// name `name`
     pub fn new(name: &str, creation_date: DateTime<Utc>) -> Self {
         Self {
+            id: Uuid::new_v4(),
             name: String::from(name),
             creation_date,
+            rules: None,
             songs: vec![],
         }
     }
 
-    pub fn load(path: &OsString) -> Result<Self, Box<dyn std::error::Error>> {
-        let file = std::fs::read_to_string(path)?;
-        let mut playlist: Playlist = serde_json::from_str(&file)?;
+    /// Loads the playlist saved as `name` in `storage`.
+    ///
+    /// Playlists saved before the library existed hold their songs rather
+    /// than their ids: those are returned along with the playlist, with
+    /// their position in it, for them to be moved to the library. Their
+    /// details aren't loaded yet.
+    pub fn load(storage: &dyn Storage, name: &OsStr) -> Result<(Self, LegacySongs), LoadError> {
+        Self::read(storage, name).map(|(playlist, legacy, _)| (playlist, legacy))
+    }
+
+    /// Loads a playlist like `load`, along with `true` if it was upgraded
+    pub(crate) fn read(
+        storage: &dyn Storage,
+        name: &OsStr,
+    ) -> Result<(Self, LegacySongs, bool), LoadError> {
+        let (value, upgraded) =
+            schema::read(storage, Collection::Playlists, name, DocumentKind::Playlist)?;
+        let stored: StoredPlaylist = serde_json::from_value(value).map_err(|err| {
+            LoadError::Malformed(storage.location(Collection::Playlists, name), err)
+        })?;
+
         let mut songs = vec![];
-        for song in playlist.songs.into_iter() {
-            let detailed_song = song.load();
-            songs.push(detailed_song);
+        let mut legacy = vec![];
+        for (position, song) in stored.songs.into_iter().enumerate() {
+            match song {
+                StoredSong::Id(id) => songs.push(id),
+                StoredSong::Song(song) => {
+                    songs.push(song.id());
+                    legacy.push((position, *song));
+                }
+            }
+        }
+
+        let playlist = Self {
+            id: stored.id,
+            name: stored.name,
+            creation_date: stored.creation_date,
+            rules: stored.rules,
+            songs,
+        };
+        Ok((playlist, legacy, upgraded))
+    }
+
+    /// Saves the playlist in `storage`, named after its id
+    pub fn save(&self, storage: &dyn Storage) -> std::io::Result<()> {
+        let name = crate::file_name_from_playlist(self);
+        storage.write(Collection::Playlists, OsStr::new(&name), &self.to_bytes()?)
+    }
+
+    /// Returns the content of the playlist file
+    pub(crate) fn to_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        let mut value = serde_json::to_value(self)?;
+        if let (Some(_), Value::Object(map)) = (&self.rules, &mut value) {
+            map.remove("songs");
         }
-        playlist.songs = songs;
-        Ok(playlist)
+        schema::to_bytes(&value, DocumentKind::Playlist)
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub(crate) fn set_id(&mut self, id: Uuid) {
+        self.id = id;
     }
 
     /// Returns playlist name
@@ -42,51 +143,88 @@ impl Playlist {
         &self.name
     }
 
+    /// Renames the playlist. Its id, and so
+    /// its file, stay the same.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = String::from(name);
+    }
+
     pub fn creation_date(&self) -> &DateTime<Utc> {
         &self.creation_date
     }
 
-    /// Returns playlist songs
-    pub fn songs(&self) -> &Vec<Song> {
+    /// Returns the rules selecting the songs, if it's a smart playlist
+    pub fn rules(&self) -> Option<&SmartRules> {
+        self.rules.as_ref()
+    }
+
+    pub(crate) fn set_rules(&mut self, rules: Option<SmartRules>) {
+        self.rules = rules;
+    }
+
+    /// Returns `true` if the songs are selected by rules
+    pub fn is_smart(&self) -> bool {
+        self.rules.is_some()
+    }
+
+    /// Returns the ids of the playlist songs
+    pub fn songs(&self) -> &Vec<Uuid> {
         &self.songs
     }
 
-    pub fn songs_mut(&mut self) -> &mut Vec<Song> {
+    /// Returns the ids of the playlist songs, to change them. The
+    /// songs of smart playlists are replaced when selected again.
+    pub fn songs_mut(&mut self) -> &mut Vec<Uuid> {
         &mut self.songs
     }
 
-    /// Adds `song` to the playlist
-    pub fn add(&mut self, song: Song) {
-        self.songs.push(song);
+    /// Returns the total duration of the songs whose
+    /// duration is known
+    pub fn duration(&self, library: &Library) -> Duration {
+        library
+            .resolve(&self.songs)
+            .into_iter()
+            .filter_map(|song| song.details().duration())
+            .sum()
     }
 
-    /// Adds `song` to the playlist, but only
-    /// if wasn't previously inserted
-    pub fn add_unique(&mut self, song: Song) {
-        for item in &self.songs {
-            if *item == song {
-                return;
-            }
+    /// Returns `true` if the song with
+    /// id `id` is in the playlist
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.songs.contains(&id)
+    }
+
+    /// Adds the song with id `id` to the playlist
+    pub fn add(&mut self, id: Uuid) {
+        self.songs.push(id);
+    }
+
+    /// Adds the song with id `id` to the playlist,
+    /// but only if wasn't previously inserted
+    pub fn add_unique(&mut self, id: Uuid) {
+        if !self.contains(id) {
+            self.songs.push(id);
         }
-        self.songs.push(song);
     }
 
-    /// Removes `song` from the playlist
-    pub fn remove(&mut self, song: &Song) {
-        if let Some(index) = self.find(song) {
+    /// Removes the song with id `id` from the playlist
+    pub fn remove(&mut self, id: Uuid) {
+        if let Some(index) = self.find(id) {
             self.songs.remove(index);
         }
     }
 
-    /// Returns the position of `song` in the
-    /// playlist.
-    fn find(&mut self, song: &Song) -> Option<usize> {
-        for (index, item) in self.songs.iter().enumerate() {
-            if *item == *song {
-                return Some(index);
-            }
-        }
+    /// Returns the position of the song
+    /// with id `id` in the playlist.
+    fn find(&self, id: Uuid) -> Option<usize> {
+        self.songs.iter().position(|item| *item == id)
+    }
+}
 
-        return None;
+impl PartialEq for Playlist {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
     }
 }
+
+impl Eq for Playlist {}
@@ -0,0 +1,349 @@
+use std::cmp::Ordering;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Playlist, PlaylistManager};
+use crate::{
+    library::Library,
+    search::{fold, Query},
+    song::SongDetails,
+};
+
+/// What the songs of a smart playlist are sorted by
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SortKey {
+    Name,
+    #[default]
+    Artist,
+    Album,
+    Year,
+    Duration,
+    PlayCount,
+    SkipCount,
+    Stars,
+    FirstPlayed,
+    LastPlayed,
+}
+
+/// A value of a song to sort by. A key always gives the same kind.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum SortValue {
+    Number(u64),
+    Text(String),
+    Date(DateTime<Utc>),
+}
+
+impl SortKey {
+    fn value(&self, details: &SongDetails) -> Option<SortValue> {
+        let stats = details.stats();
+        match self {
+            SortKey::Name => Some(SortValue::Text(fold(details.name()))),
+            SortKey::Artist => details.artist().map(fold).map(SortValue::Text),
+            SortKey::Album => details.album().map(fold).map(SortValue::Text),
+            SortKey::Year => details.year().map(u64::from).map(SortValue::Number),
+            SortKey::Duration => details
+                .duration()
+                .map(|duration| SortValue::Number(duration.as_secs())),
+            SortKey::PlayCount => Some(SortValue::Number(u64::from(stats.play_count()))),
+            SortKey::SkipCount => Some(SortValue::Number(u64::from(stats.skip_count()))),
+            SortKey::Stars => stats.stars().map(u64::from).map(SortValue::Number),
+            SortKey::FirstPlayed => stats.first_played().copied().map(SortValue::Date),
+            SortKey::LastPlayed => stats.last_played().copied().map(SortValue::Date),
+        }
+    }
+}
+
+/// The rules of a smart playlist: it holds the songs of the library
+/// matching `query`, sorted by `sort`, and at most `limit` of them.
+/// Songs missing the value sorted by come last, and songs with the same
+/// value are sorted by artist, album, disc, track and name.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct SmartRules {
+    query: Query,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    descending: bool,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+impl SmartRules {
+    /// Creates rules holding every song matching `query`, sorted by artist
+    pub fn new(query: Query) -> Self {
+        Self {
+            query,
+            sort: SortKey::default(),
+            descending: false,
+            limit: None,
+        }
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn set_query(&mut self, query: Query) {
+        self.query = query;
+    }
+
+    pub fn sort(&self) -> SortKey {
+        self.sort
+    }
+
+    /// Returns `true` if the songs are sorted from the highest value
+    pub fn is_descending(&self) -> bool {
+        self.descending
+    }
+
+    pub fn set_sort(&mut self, sort: SortKey, descending: bool) {
+        self.sort = sort;
+        self.descending = descending;
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Sets how many songs the playlist holds at most, or
+    /// lifts the limit if `None`
+    pub fn set_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    /// Returns the ids of the songs of `library` the rules select, in order
+    pub fn evaluate(&self, library: &Library) -> Vec<Uuid> {
+        let mut songs: Vec<_> = library
+            .query(&self.query)
+            .into_iter()
+            .map(|song| {
+                let details = song.details();
+                let tie = (
+                    details.artist().map(fold),
+                    details.album().map(fold),
+                    details.disc_number(),
+                    details.track_number(),
+                    fold(details.name()),
+                    song.id(),
+                );
+                (self.sort.value(details), tie)
+            })
+            .collect();
+        songs.sort_by(|(first, first_tie), (second, second_tie)| {
+            let order = match (first, second) {
+                (Some(first), Some(second)) if self.descending => second.cmp(first),
+                (Some(first), Some(second)) => first.cmp(second),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            };
+            order.then_with(|| first_tie.cmp(second_tie))
+        });
+        songs
+            .into_iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(_, (.., id))| id)
+            .collect()
+    }
+}
+
+impl PlaylistManager {
+    /// Creates a smart playlist named `name`, holding the songs `rules`
+    /// select, and returns its id. Its songs are kept up to date as
+    /// songs are added, changed, rated or played.
+    pub fn add_smart_playlist(&mut self, name: &str, rules: SmartRules) -> Uuid {
+        let mut playlist = Playlist::new(name, Utc::now());
+        *playlist.songs_mut() = rules.evaluate(&self.library);
+        playlist.set_rules(Some(rules));
+        let id = playlist.id();
+        self.playlists.push(playlist);
+        id
+    }
+
+    /// Changes the rules of the playlist with id `id`, making it a smart
+    /// playlist, or a regular one holding its current songs if `None`.
+    /// Returns `false` if there's no such playlist.
+    pub fn set_rules(&mut self, id: Uuid, rules: Option<SmartRules>) -> bool {
+        let library = &self.library;
+        match self
+            .playlists
+            .iter_mut()
+            .find(|playlist| playlist.id() == id)
+        {
+            Some(playlist) => {
+                if let Some(rules) = &rules {
+                    *playlist.songs_mut() = rules.evaluate(library);
+                }
+                playlist.set_rules(rules);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Selects the songs of every smart playlist again. The manager does
+    /// it after changing songs itself, and when saving; it's only needed
+    /// after changing songs through `library_mut`, for the playlists to
+    /// be up to date before the next save.
+    pub fn refresh_smart_playlists(&mut self) {
+        // Pending changes are all taken into account now
+        self.library_events.try_iter().for_each(drop);
+        for playlist in self.playlists.iter_mut() {
+            let songs = match playlist.rules() {
+                Some(rules) => rules.evaluate(&self.library),
+                None => continue,
+            };
+            *playlist.songs_mut() = songs;
+        }
+    }
+
+    /// Selects the songs of smart playlists again if the library changed
+    pub(super) fn update_smart_playlists(&mut self) {
+        if self.library_events.try_iter().next().is_some() {
+            self.refresh_smart_playlists();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        song::{LoadPolicy, Song},
+        storage::MemoryStorage,
+    };
+
+    fn details(name: &str, artist: Option<&str>, year: Option<u16>) -> SongDetails {
+        SongDetails::new(name, artist, year, None)
+    }
+
+    /// Returns a library holding songs with the given details, and their ids
+    fn library(songs: Vec<SongDetails>) -> (Library, Vec<Uuid>) {
+        let mut library = Library::new();
+        let ids = songs
+            .into_iter()
+            .map(|details| {
+                let song = Song::new(format!("/music/{}.ogg", details.name()), details);
+                let id = song.id();
+                library.add(song);
+                id
+            })
+            .collect();
+        (library, ids)
+    }
+
+    fn rules(query: &str, sort: SortKey, descending: bool) -> SmartRules {
+        let mut rules = SmartRules::new(Query::parse(query).unwrap());
+        rules.set_sort(sort, descending);
+        rules
+    }
+
+    #[test]
+    fn sorts_by_the_key() {
+        let (library, ids) = library(vec![
+            details("b", None, Some(2001)),
+            details("c", None, None),
+            details("A", None, Some(1999)),
+        ]);
+        let by_name = rules("", SortKey::Name, false);
+        assert_eq!(by_name.evaluate(&library), [ids[2], ids[0], ids[1]]);
+        let by_name = rules("", SortKey::Name, true);
+        assert_eq!(by_name.evaluate(&library), [ids[1], ids[0], ids[2]]);
+
+        // Songs without a year come last either way
+        let by_year = rules("", SortKey::Year, false);
+        assert_eq!(by_year.evaluate(&library), [ids[2], ids[0], ids[1]]);
+        let by_year = rules("", SortKey::Year, true);
+        assert_eq!(by_year.evaluate(&library), [ids[0], ids[2], ids[1]]);
+    }
+
+    #[test]
+    fn breaks_ties_by_artist_album_disc_track_and_name() {
+        let track = |name: &str, artist: &str, album: &str, disc: u16, track: u16| {
+            let mut details = details(name, Some(artist), Some(2000));
+            details.set_album(album);
+            details.set_disc_number(disc);
+            details.set_track_number(track);
+            details
+        };
+        let (library, ids) = library(vec![
+            track("z", "Bowie", "Low", 2, 1),
+            track("y", "Bowie", "Low", 1, 2),
+            track("x", "Bowie", "Low", 1, 1),
+            track("w", "Bowie", "Heroes", 1, 1),
+            track("v", "ABBA", "Waterloo", 1, 1),
+            track("a", "Bowie", "Low", 1, 1),
+        ]);
+        let by_year = rules("", SortKey::Year, true);
+        assert_eq!(
+            by_year.evaluate(&library),
+            [ids[4], ids[3], ids[5], ids[2], ids[1], ids[0]]
+        );
+    }
+
+    #[test]
+    fn keeps_the_matching_songs_up_to_the_limit() {
+        let (library, ids) = library(vec![
+            details("One", Some("Daft Punk"), Some(2000)),
+            details("Two", Some("Daft Punk"), Some(2001)),
+            details("Three", Some("Bowie"), Some(1977)),
+            details("Four", Some("Daft Punk"), Some(1997)),
+        ]);
+        let mut rules = rules("artist:punk", SortKey::Year, true);
+        assert_eq!(rules.evaluate(&library), [ids[1], ids[0], ids[3]]);
+        rules.set_limit(Some(2));
+        assert_eq!(rules.evaluate(&library), [ids[1], ids[0]]);
+        rules.set_limit(Some(0));
+        assert!(rules.evaluate(&library).is_empty());
+    }
+
+    #[test]
+    fn saves_the_rules() {
+        let mut saved = rules("year:>=2000 -live", SortKey::PlayCount, true);
+        saved.set_limit(Some(25));
+        let data = serde_json::to_string(&saved).unwrap();
+        let loaded: SmartRules = serde_json::from_str(&data).unwrap();
+        assert_eq!(loaded.query().text(), "year:>=2000 -live");
+        assert_eq!(loaded.sort(), SortKey::PlayCount);
+        assert!(loaded.is_descending());
+        assert_eq!(loaded.limit(), Some(25));
+
+        let loaded: SmartRules = serde_json::from_str(r#"{"query":"bowie"}"#).unwrap();
+        assert_eq!(loaded.sort(), SortKey::Artist);
+        assert!(!loaded.is_descending());
+        assert_eq!(loaded.limit(), None);
+    }
+
+    #[test]
+    fn follows_the_library() {
+        let mut manager = PlaylistManager::load_from_storage(
+            "/music".into(),
+            Box::new(MemoryStorage::new()),
+            LoadPolicy::Fail,
+        )
+        .unwrap();
+        let first = manager.add_song(Song::new("/music/a.ogg", details("a", None, None)));
+        let mut top = rules("stars:>=4", SortKey::Stars, true);
+        top.set_limit(Some(2));
+        let id = manager.add_smart_playlist("Top", top);
+        assert!(manager.playlist(id).unwrap().songs().is_empty());
+
+        manager.set_stars(first, Some(4));
+        let second = manager.add_song(Song::new("/music/b.ogg", details("b", None, None)));
+        manager.set_stars(second, Some(5));
+        let third = manager.add_song(Song::new("/music/c.ogg", details("c", None, None)));
+        manager.set_stars(third, Some(4));
+        assert_eq!(manager.playlist(id).unwrap().songs(), &[second, first]);
+
+        manager.set_stars(second, None);
+        assert_eq!(manager.playlist(id).unwrap().songs(), &[first, third]);
+
+        // Regular playlists keep the songs they held
+        assert!(manager.set_rules(id, None));
+        manager.set_stars(first, None);
+        assert_eq!(manager.playlist(id).unwrap().songs(), &[first, third]);
+        assert!(manager.playlist(id).unwrap().rules().is_none());
+    }
+}
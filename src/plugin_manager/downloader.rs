@@ -24,6 +24,7 @@ pub struct Downloader {
 /// the creation of a `Downloader`
 pub struct DownloadBuilder;
 impl DownloadBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(download_fn: DownloadFn) -> Downloader {
         Downloader { download_fn }
     }
@@ -48,17 +49,14 @@ impl Downloader {
             let _bytes_read = out.read_until(b'%', &mut vec);
 
             let result = regex.captures(std::str::from_utf8(&vec).unwrap());
-            match result {
-                Some(result) => {
-                    if let Some(percentage) = result.get(1) {
-                        let value: f32 = percentage.as_str().parse().unwrap();
-                        tx.send(value)?;
-                        if value == 100.0 {
-                            break;
-                        }
+            if let Some(result) = result {
+                if let Some(percentage) = result.get(1) {
+                    let value: f32 = percentage.as_str().parse().unwrap();
+                    tx.send(value)?;
+                    if value == 100.0 {
+                        break;
                     }
                 }
-                None => (),
             }
         }
 
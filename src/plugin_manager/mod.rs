@@ -5,7 +5,11 @@ pub mod error;
 pub mod plugin;
 pub mod query;
 pub mod streamer;
-use crate::{song::Song, TrackInfo};
+use crate::{
+    artwork::{Artwork, ArtworkError, ArtworkStore},
+    song::Song,
+    TrackInfo,
+};
 
 use {downloader::*, error::*, plugin::*, query::*};
 
@@ -23,6 +27,7 @@ impl PluginManager {
     /// Creates a new `PluginManger` with no plugin
     /// registered. To make a call to `query` a plugin
     /// has to be set as default.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         PluginManager {
             client: reqwest::Client::new(),
@@ -67,6 +72,7 @@ impl PluginManager {
     }
 
     /// Returns a reference to the default plugin
+    #[allow(clippy::borrowed_box)]
     pub fn default(&self) -> Result<&Box<dyn Plugin>, PluginError> {
         if self.default.is_none() {
             return Err(PluginError::NoDefaultPlugin);
@@ -114,15 +120,25 @@ impl PluginManager {
 
         let pf = std::thread::spawn(move || progress_follower(rx, progress_forwarder, song));
         let file_name = downloader.download(url, file_name, tx)?;
-        if let Err(_) = pf.join() {
-            return Err(Box::new(PluginError::DownloadError(format!(
-                "An error occured while joining progress follower thread",
-            ))));
+        if pf.join().is_err() {
+            return Err(Box::new(PluginError::DownloadError(
+                "An error occured while joining progress follower thread".to_string(),
+            )));
         }
 
         Ok(file_name)
     }
 
+    /// Downloads the image at `url`, usually one of the thumbnails of a
+    /// `QueryResultData`, and stores it in `store`
+    pub async fn download_artwork(
+        &self,
+        url: &reqwest::Url,
+        store: &ArtworkStore,
+    ) -> Result<Artwork, ArtworkError> {
+        store.download(&self.client, url).await
+    }
+
     pub fn stream(&self, url: &str, file_name: &str) -> Result<(), Box<dyn std::error::Error>> {
         let default = self.default.as_ref().unwrap();
         let plugin = self.plugins.get(default).unwrap();
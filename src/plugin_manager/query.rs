@@ -1,4 +1,4 @@
-use std::{error::Error, time::Duration, vec};
+use std::{error::Error, time::Duration};
 
 /// Holds the information required
 /// to make a query.
@@ -20,19 +20,19 @@ impl QueryInfo {
             .trim()
             .to_string();
 
-        let track_name = if track_name == "" {
+        let track_name = if track_name.is_empty() {
             None
         } else {
             Some(track_name.to_string())
         };
 
-        let artist_name = if artist_name == "" {
+        let artist_name = if artist_name.is_empty() {
             None
         } else {
             Some(artist_name.to_string())
         };
 
-        let other_info = if other_info == "" {
+        let other_info = if other_info.is_empty() {
             None
         } else {
             Some(other_info.to_string())
@@ -58,7 +58,7 @@ impl QueryInfo {
 
     pub fn track_name(&self) -> &str {
         if let Some(track_name) = &self.track_name {
-            return &track_name;
+            return track_name;
         }
 
         ""
@@ -66,7 +66,7 @@ impl QueryInfo {
 
     pub fn artist_name(&self) -> &str {
         if let Some(artist_name) = &self.artist_name {
-            return &artist_name;
+            return artist_name;
         }
 
         ""
@@ -74,7 +74,7 @@ impl QueryInfo {
 
     pub fn other_info(&self) -> &str {
         if let Some(other_info) = &self.other_info {
-            return &other_info;
+            return other_info;
         }
 
         ""
@@ -91,7 +91,7 @@ impl QueryInfo {
 
 /// Holds the information pulled from the
 /// plugin service
-#[derive(PartialEq, Clone, PartialOrd, Debug)]
+#[derive(PartialEq, Clone, PartialOrd, Debug, Default)]
 pub struct QueryResult {
     data: Vec<QueryResultData>,
 }
@@ -106,12 +106,6 @@ impl QueryResult {
     }
 }
 
-impl Default for QueryResult {
-    fn default() -> Self {
-        Self { data: vec![] }
-    }
-}
-
 /// Each plugin has to implement this trait to define how a piece
 /// of information pulled from the plugin's service can be
 /// deserialized and transformed into the selected result type.
@@ -192,7 +186,7 @@ impl QueryResultData {
         let mut mins: u64 = secs / 60;
         if mins > 60 {
             let hours: u64 = mins / 60;
-            mins = mins - hours * 60;
+            mins -= hours * 60;
             return format!(
                 "{}:{:02}:{:02}",
                 hours,
@@ -200,6 +194,6 @@ impl QueryResultData {
                 secs - hours * 3600 - mins * 60
             );
         }
-        return format!("{:02}:{:02}", mins, secs - mins * 60);
+        format!("{:02}:{:02}", mins, secs - mins * 60)
     }
 }
@@ -20,6 +20,7 @@ impl Streamer {
 /// the creation of a `Streamer`
 pub struct StreamBuilder;
 impl StreamBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(stream_fn: StreamFn) -> Streamer {
         Streamer { stream_fn }
     }
@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::format::bytes::Truncated;
+
+/// Errors describing why the technical properties
+/// of an audio file couldn't be read
+#[derive(Debug)]
+pub enum ProbeError {
+    Io(std::io::Error),
+    UnsupportedFormat,
+    Malformed(String),
+}
+
+impl Display for ProbeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbeError::Io(err) => {
+                writeln!(f, "An I/O error occured while probing the file")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            ProbeError::UnsupportedFormat => {
+                writeln!(f, "The file isn't in a supported audio format")
+            }
+            ProbeError::Malformed(msg) => {
+                writeln!(f, "The audio stream is malformed: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for ProbeError {}
+
+impl From<std::io::Error> for ProbeError {
+    fn from(err: std::io::Error) -> Self {
+        ProbeError::Io(err)
+    }
+}
+
+impl From<Truncated> for ProbeError {
+    fn from(err: Truncated) -> Self {
+        ProbeError::Malformed(err.to_string())
+    }
+}
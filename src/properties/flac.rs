@@ -0,0 +1,161 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::format::{bytes::Bytes, skip_id3v2, AudioFormat};
+
+use super::{average_bitrate, samples_duration, AudioProperties, Codec, ProbeError};
+
+const BLOCK_STREAMINFO: u8 = 0;
+
+/// Reads the properties of a native FLAC stream from its STREAMINFO block
+pub(super) fn probe<R: Read + Seek>(file: &mut R) -> Result<AudioProperties, ProbeError> {
+    skip_id3v2(file)?;
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(ProbeError::Malformed("missing FLAC stream marker".into()));
+    }
+
+    let mut streaminfo = None;
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        if kind == BLOCK_STREAMINFO && streaminfo.is_none() {
+            let mut block = vec![0u8; len];
+            file.read_exact(&mut block)?;
+            streaminfo = Some(block);
+        } else {
+            file.seek(SeekFrom::Current(len as i64))?;
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    let streaminfo =
+        streaminfo.ok_or_else(|| ProbeError::Malformed("missing STREAMINFO block".into()))?;
+    let audio_start = file.stream_position()?;
+    let audio_end = file.seek(SeekFrom::End(0))?;
+
+    let mut properties = parse_streaminfo(&streaminfo)?;
+    properties.format = AudioFormat::Flac;
+    // The last block may claim more than the file holds
+    let audio_size = audio_end.saturating_sub(audio_start);
+    properties.bitrate = average_bitrate(audio_size, &properties.duration);
+    Ok(properties)
+}
+
+/// Parses the body of a STREAMINFO metadata block. The bitrate is left
+/// to the caller, which knows where the audio frames are.
+pub(super) fn parse_streaminfo(block: &[u8]) -> Result<AudioProperties, ProbeError> {
+    let mut bytes = Bytes::new(block);
+    // Block and frame sizes
+    bytes.skip(10)?;
+    let packed = bytes.u64_be()?;
+
+    let sample_rate = (packed >> 44) as u32;
+    let channels = ((packed >> 41) & 0x07) as u8 + 1;
+    let bits_per_sample = ((packed >> 36) & 0x1F) as u8 + 1;
+    let samples = packed & 0x0F_FFFF_FFFF;
+    if sample_rate == 0 {
+        return Err(ProbeError::Malformed("invalid FLAC sample rate".into()));
+    }
+
+    Ok(AudioProperties {
+        format: AudioFormat::Flac,
+        codec: Codec::Flac,
+        duration: samples_duration(samples, sample_rate),
+        bitrate: 0,
+        sample_rate,
+        channels,
+        bits_per_sample: Some(bits_per_sample),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::*;
+
+    fn block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+        let mut block = vec![kind | if last { 0x80 } else { 0 }];
+        block.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        block.extend_from_slice(body);
+        block
+    }
+
+    fn streaminfo(sample_rate: u64, channels: u64, bits: u64, samples: u64) -> Vec<u8> {
+        let mut body = vec![0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0];
+        let packed = sample_rate << 44 | (channels - 1) << 41 | (bits - 1) << 36 | samples;
+        body.extend_from_slice(&packed.to_be_bytes());
+        body.extend_from_slice(&[0; 16]);
+        body
+    }
+
+    fn flac(streaminfo: &[u8], audio: usize) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&block(0, false, streaminfo));
+        data.extend_from_slice(&block(4, false, &[0; 8]));
+        data.extend_from_slice(&block(1, true, &[0; 100]));
+        data.extend_from_slice(&vec![0xAA; audio]);
+        data
+    }
+
+    #[test]
+    fn reads_streaminfo() {
+        let data = flac(&streaminfo(44100, 2, 16, 441_000), 20_000);
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.format(), AudioFormat::Flac);
+        assert_eq!(properties.codec(), Codec::Flac);
+        assert_eq!(properties.duration(), &Duration::from_secs(10));
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bits_per_sample(), Some(16));
+        // Only what follows the metadata blocks counts
+        assert_eq!(properties.bitrate(), 16);
+
+        let properties = parse_streaminfo(&streaminfo(96000, 6, 24, 48000)).unwrap();
+        assert_eq!(properties.duration(), &Duration::from_millis(500));
+        assert_eq!(properties.channels(), 6);
+        assert_eq!(properties.bits_per_sample(), Some(24));
+    }
+
+    #[test]
+    fn skips_id3v2_tags() {
+        let mut data = b"ID3\x04\0\0\0\0\0\x02\0\0".to_vec();
+        data.extend_from_slice(&flac(&streaminfo(44100, 2, 16, 44100), 0));
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.duration(), &Duration::from_secs(1));
+    }
+
+    #[test]
+    fn refuses_invalid_streams() {
+        let data = flac(&streaminfo(0, 2, 16, 44100), 0);
+        assert!(matches!(
+            probe(&mut Cursor::new(&data)),
+            Err(ProbeError::Malformed(_))
+        ));
+
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&block(4, true, &[0; 8]));
+        assert!(matches!(
+            probe(&mut Cursor::new(&data)),
+            Err(ProbeError::Malformed(_))
+        ));
+        assert!(probe(&mut Cursor::new(b"OggS")).is_err());
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        let data = flac(&streaminfo(44100, 2, 16, 441_000), 10);
+        for len in 0..data.len() {
+            let _ = probe(&mut Cursor::new(&data[..len]));
+        }
+        assert!(parse_streaminfo(&[0; 17]).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{ffi::OsString, fs::File, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::AudioFormat;
+
+pub use self::error::ProbeError;
+
+mod error;
+mod flac;
+mod mp3;
+mod mp4;
+mod ogg;
+
+/// Reads the technical properties of the audio file at `path` from
+/// its stream headers. The format of the file is recognised by its
+/// content, not by its extension.
+pub fn probe(path: &OsString) -> Result<AudioProperties, ProbeError> {
+    let mut file = File::open(path)?;
+    let format = AudioFormat::detect(&mut file)?.ok_or(ProbeError::UnsupportedFormat)?;
+
+    match format {
+        AudioFormat::Mp3 => mp3::probe(&mut file),
+        AudioFormat::Flac => flac::probe(&mut file),
+        AudioFormat::Ogg => ogg::probe(&mut file),
+        AudioFormat::Mp4 => mp4::probe(&mut file),
+    }
+}
+
+/// Technical properties of an audio stream
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+pub struct AudioProperties {
+    format: AudioFormat,
+    codec: Codec,
+    duration: Duration,
+    /// Average bitrate, in kbit/s
+    bitrate: u32,
+    sample_rate: u32,
+    channels: u8,
+    bits_per_sample: Option<u8>,
+}
+
+impl AudioProperties {
+    pub fn format(&self) -> AudioFormat {
+        self.format
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn duration(&self) -> &Duration {
+        &self.duration
+    }
+
+    /// Returns the average bitrate, in kbit/s
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    /// Returns the bit depth of lossless streams
+    pub fn bits_per_sample(&self) -> Option<u8> {
+        self.bits_per_sample
+    }
+}
+
+/// How the audio stream is encoded
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Hash)]
+pub enum Codec {
+    /// MPEG audio layer I
+    Mp1,
+    /// MPEG audio layer II
+    Mp2,
+    /// MPEG audio layer III
+    Mp3,
+    Flac,
+    Vorbis,
+    Opus,
+    Aac,
+    Alac,
+    Unknown,
+}
+
+/// Computes the average bitrate in kbit/s of
+/// `bytes` of audio lasting `duration`
+fn average_bitrate(bytes: u64, duration: &Duration) -> u32 {
+    let secs = duration.as_secs_f64();
+    if secs <= 0.0 {
+        return 0;
+    }
+    (bytes as f64 * 8.0 / secs / 1000.0).round() as u32
+}
+
+/// Builds a duration from a number of samples at `rate`
+fn samples_duration(samples: u64, rate: u32) -> Duration {
+    if rate == 0 {
+        return Duration::ZERO;
+    }
+    let secs = samples / rate as u64;
+    let nanos = (samples % rate as u64) * 1_000_000_000 / rate as u64;
+    Duration::new(secs, nanos as u32)
+}
@@ -0,0 +1,332 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::format::{bytes::Bytes, mpeg::FrameHeader, read_up_to, skip_id3v2, AudioFormat};
+
+use super::{average_bitrate, samples_duration, AudioProperties, Codec, ProbeError};
+
+/// How far past the ID3v2 tag the first frame is searched for
+const SEARCH_SIZE: usize = 64 * 1024;
+
+/// Size of an ID3v1 tag, found at the very end of the file
+const ID3V1_SIZE: u64 = 128;
+
+/// Reads the properties of an MPEG audio stream. VBR streams are
+/// measured from their Xing or VBRI header, the others are assumed to
+/// have a constant bitrate.
+pub(super) fn probe<R: Read + Seek>(file: &mut R) -> Result<AudioProperties, ProbeError> {
+    let tag_size = skip_id3v2(file)?;
+    let mut buffer = vec![0u8; SEARCH_SIZE];
+    let read = read_up_to(file, &mut buffer)?;
+    buffer.truncate(read);
+
+    let (offset, header) = first_frame(&buffer)
+        .ok_or_else(|| ProbeError::Malformed("no MPEG audio frame found".into()))?;
+    let frame = &buffer[offset..];
+
+    let end = file.seek(SeekFrom::End(0))?;
+    let mut audio_end = end;
+    if end >= ID3V1_SIZE {
+        let mut marker = [0u8; 3];
+        file.seek(SeekFrom::End(-(ID3V1_SIZE as i64)))?;
+        file.read_exact(&mut marker)?;
+        if &marker == b"TAG" {
+            audio_end -= ID3V1_SIZE;
+        }
+    }
+    let audio_size = audio_end.saturating_sub(tag_size + offset as u64);
+
+    let channels = if header.mono { 1 } else { 2 };
+    let mut properties = AudioProperties {
+        format: AudioFormat::Mp3,
+        codec: codec(&header),
+        duration: Default::default(),
+        bitrate: header.bitrate,
+        sample_rate: header.sample_rate,
+        channels,
+        bits_per_sample: None,
+    };
+
+    let vbr = parse_xing(frame, &header)?.or(parse_vbri(frame)?);
+    match vbr {
+        Some(vbr) => {
+            let samples = (vbr.frames as u64 * header.samples_per_frame() as u64)
+                .saturating_sub(vbr.delay as u64 + vbr.padding as u64);
+            properties.duration = samples_duration(samples, header.sample_rate);
+            if vbr.constant {
+                return Ok(properties);
+            }
+            let bytes = match vbr.bytes {
+                Some(bytes) if bytes > 0 => bytes as u64,
+                _ => audio_size,
+            };
+            properties.bitrate = average_bitrate(bytes, &properties.duration);
+        }
+        None => {
+            if header.bitrate == 0 {
+                return Err(ProbeError::Malformed("free format stream".into()));
+            }
+            let bits = audio_size * 8;
+            properties.duration = samples_duration(bits, header.bitrate * 1000);
+        }
+    }
+
+    Ok(properties)
+}
+
+/// Returns the codec of the stream `header` starts
+fn codec(header: &FrameHeader) -> Codec {
+    match header.layer {
+        1 => Codec::Mp1,
+        2 => Codec::Mp2,
+        _ => Codec::Mp3,
+    }
+}
+
+/// Returns the offset and the header of the first frame in `data`. A
+/// frame only counts if it's followed by another, so that stray sync
+/// words are skipped.
+fn first_frame(data: &[u8]) -> Option<(usize, FrameHeader)> {
+    (0..data.len().saturating_sub(4)).find_map(|offset| {
+        let header = FrameHeader::parse(&data[offset..])?;
+        let next = match header.frame_size() {
+            Some(size) => offset + size as usize,
+            None => return None,
+        };
+        match data.get(next..) {
+            Some(rest) if rest.len() >= 4 => FrameHeader::parse(rest).map(|_| (offset, header)),
+            // The stream ends here, there's nothing more to check
+            _ => Some((offset, header)),
+        }
+    })
+}
+
+/// What a VBR header tells about the whole stream
+struct VbrInfo {
+    frames: u32,
+    bytes: Option<u32>,
+    /// Samples added by the encoder at the start of the stream
+    delay: u32,
+    /// Samples added by the encoder at the end of the stream
+    padding: u32,
+    /// Set by `Info` headers, which LAME writes in CBR streams
+    constant: bool,
+}
+
+/// Parses the Xing (or `Info`, for CBR) header of the first frame, along
+/// with the LAME extension that records the encoder delay and padding
+fn parse_xing(frame: &[u8], header: &FrameHeader) -> Result<Option<VbrInfo>, ProbeError> {
+    let data = match frame.get(header.xing_offset()..) {
+        Some(data) if data.starts_with(b"Xing") || data.starts_with(b"Info") => data,
+        _ => return Ok(None),
+    };
+
+    let mut bytes = Bytes::new(&data[4..]);
+    let flags = bytes.u32_be()?;
+    if flags & 0x01 == 0 {
+        return Ok(None);
+    }
+    let frames = bytes.u32_be()?;
+    let size = if flags & 0x02 != 0 {
+        Some(bytes.u32_be()?)
+    } else {
+        None
+    };
+    if flags & 0x04 != 0 {
+        // Table of contents
+        bytes.skip(100)?;
+    }
+    if flags & 0x08 != 0 {
+        // Quality indicator
+        bytes.skip(4)?;
+    }
+
+    let (mut delay, mut padding) = (0, 0);
+    if bytes.rest().starts_with(b"LAME") || bytes.rest().starts_with(b"Lavc") {
+        // Version string, revision, filter, peak and gains, flags and bitrate
+        if bytes.skip(21).is_ok() {
+            if let Ok(gaps) = bytes.u24_be() {
+                delay = gaps >> 12;
+                padding = gaps & 0x0FFF;
+            }
+        }
+    }
+
+    Ok(Some(VbrInfo {
+        frames,
+        bytes: size,
+        delay,
+        padding,
+        constant: data.starts_with(b"Info"),
+    }))
+}
+
+/// Parses the VBRI header written by the Fraunhofer encoder, which always
+/// sits 32 bytes after the frame header
+fn parse_vbri(frame: &[u8]) -> Result<Option<VbrInfo>, ProbeError> {
+    let data = match frame.get(4 + 32..) {
+        Some(data) if data.starts_with(b"VBRI") => data,
+        _ => return Ok(None),
+    };
+
+    let mut bytes = Bytes::new(&data[4..]);
+    // Version, delay and quality
+    bytes.skip(6)?;
+    let size = bytes.u32_be()?;
+    let frames = bytes.u32_be()?;
+
+    Ok(Some(VbrInfo {
+        frames,
+        bytes: Some(size),
+        delay: 0,
+        padding: 0,
+        constant: false,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::*;
+
+    /// Returns a silent frame with the given header, the size of
+    /// MPEG-1 layer III frames at 128 kbit/s and 44.1 kHz
+    fn frame(header: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&header);
+        frame
+    }
+
+    const STEREO: [u8; 4] = [0xFF, 0xFB, 0x90, 0x64];
+    const MONO: [u8; 4] = [0xFF, 0xFB, 0x90, 0xC4];
+
+    fn stream(first: Vec<u8>, count: usize) -> Vec<u8> {
+        let mut data = first;
+        for _ in 1..count {
+            data.extend_from_slice(&frame(STEREO));
+        }
+        data
+    }
+
+    /// A first frame holding a Xing header with every field and
+    /// a LAME extension, at the offset used by `header`
+    fn xing_frame(header: [u8; 4], offset: usize, tag: &[u8; 4]) -> Vec<u8> {
+        let mut frame = frame(header);
+        let mut xing = tag.to_vec();
+        xing.extend_from_slice(&[0, 0, 0, 0x0F]);
+        xing.extend_from_slice(&100u32.to_be_bytes());
+        xing.extend_from_slice(&50_000u32.to_be_bytes());
+        xing.extend_from_slice(&[0; 104]);
+        xing.extend_from_slice(b"LAME3.100");
+        xing.extend_from_slice(&[0; 12]);
+        // 576 samples of delay, 1000 of padding
+        xing.extend_from_slice(&[0x24, 0x03, 0xE8]);
+        frame[offset..offset + xing.len()].copy_from_slice(&xing);
+        frame
+    }
+
+    fn probe_bytes(data: &[u8]) -> Result<AudioProperties, ProbeError> {
+        probe(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn measures_constant_bitrate_streams() {
+        let properties = probe_bytes(&stream(frame(STEREO), 10)).unwrap();
+        assert_eq!(properties.codec(), Codec::Mp3);
+        assert_eq!(properties.bitrate(), 128);
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        // 4170 bytes at 128 kbit/s
+        assert_eq!(properties.duration(), &Duration::from_micros(260_625));
+
+        // Tags and junk before the first frame aren't audio
+        let mut data = b"ID3\x04\0\0\0\0\0\x05tag!!".to_vec();
+        data.extend_from_slice(b"junk");
+        data.extend_from_slice(&stream(frame(STEREO), 10));
+        let mut v1 = vec![0u8; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        data.extend_from_slice(&v1);
+        assert_eq!(
+            probe_bytes(&data).unwrap().duration(),
+            &Duration::from_micros(260_625)
+        );
+    }
+
+    #[test]
+    fn measures_xing_streams() {
+        let data = stream(xing_frame(STEREO, 36, b"Xing"), 2);
+        let properties = probe_bytes(&data).unwrap();
+        let duration = samples_duration(100 * 1152 - 576 - 1000, 44100);
+        assert_eq!(properties.duration(), &duration);
+        assert_eq!(properties.bitrate(), average_bitrate(50_000, &duration));
+
+        // Mono MPEG-1 streams have shorter side information
+        let data = stream(xing_frame(MONO, 21, b"Xing"), 2);
+        let properties = probe_bytes(&data).unwrap();
+        assert_eq!(properties.channels(), 1);
+        assert_eq!(properties.duration(), &duration);
+
+        // `Info` marks a constant bitrate
+        let data = stream(xing_frame(STEREO, 36, b"Info"), 2);
+        let properties = probe_bytes(&data).unwrap();
+        assert_eq!(properties.duration(), &duration);
+        assert_eq!(properties.bitrate(), 128);
+    }
+
+    #[test]
+    fn measures_mpeg2_streams() {
+        // MPEG-2 layer III at 80 kbit/s and 22.05 kHz, 261 bytes frames
+        let header = [0xFF, 0xF3, 0x90, 0x64];
+        let mut first = vec![0u8; 261];
+        first[..4].copy_from_slice(&header);
+        first[21..25].copy_from_slice(b"Xing");
+        first[25..29].copy_from_slice(&[0, 0, 0, 0x01]);
+        first[29..33].copy_from_slice(&10u32.to_be_bytes());
+        let data = [first.clone(), first].concat();
+
+        let properties = probe_bytes(&data).unwrap();
+        assert_eq!(properties.sample_rate(), 22050);
+        assert_eq!(properties.duration(), &samples_duration(10 * 576, 22050));
+    }
+
+    #[test]
+    fn measures_vbri_streams() {
+        let mut first = frame(STEREO);
+        first[36..40].copy_from_slice(b"VBRI");
+        first[46..50].copy_from_slice(&40_000u32.to_be_bytes());
+        first[50..54].copy_from_slice(&200u32.to_be_bytes());
+        let properties = probe_bytes(&stream(first, 2)).unwrap();
+        let duration = samples_duration(200 * 1152, 44100);
+        assert_eq!(properties.duration(), &duration);
+        assert_eq!(properties.bitrate(), average_bitrate(40_000, &duration));
+    }
+
+    #[test]
+    fn recognises_layers() {
+        // MPEG-1 layer II at 128 kbit/s and 44.1 kHz
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFD, 0x80, 0x64]);
+        let properties = probe_bytes(&[frame.clone(), frame].concat()).unwrap();
+        assert_eq!(properties.codec(), Codec::Mp2);
+    }
+
+    #[test]
+    fn refuses_streams_without_frames() {
+        assert!(probe_bytes(b"").is_err());
+        assert!(probe_bytes(&[0xFF; 1000]).is_err());
+        // Free format, whose frames can't be measured
+        assert!(probe_bytes(&stream(frame([0xFF, 0xFB, 0x00, 0x64]), 1)).is_err());
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        for data in [
+            stream(xing_frame(STEREO, 36, b"Xing"), 2),
+            stream(xing_frame(STEREO, 36, b"VBRI"), 2),
+        ] {
+            for len in 0..data.len() {
+                let _ = probe_bytes(&data[..len]);
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    io::{Read, Seek},
+    time::Duration,
+};
+
+use crate::format::{
+    bytes::Bytes,
+    mp4::{child, find, locate_top_level, read_top_level, Atoms},
+    AudioFormat,
+};
+
+use super::{average_bitrate, samples_duration, AudioProperties, Codec, ProbeError};
+
+/// Handler type of sound tracks
+const HANDLER_SOUND: &[u8; 4] = b"soun";
+
+/// Reads the properties of an MP4 file. The duration comes from the
+/// movie header, the stream properties from the first sound track.
+pub(super) fn probe<R: Read + Seek>(file: &mut R) -> Result<AudioProperties, ProbeError> {
+    let moov = read_top_level(file, b"moov")?
+        .ok_or_else(|| ProbeError::Malformed("missing moov atom".into()))?;
+    let mvhd =
+        child(&moov, b"mvhd").ok_or_else(|| ProbeError::Malformed("missing mvhd atom".into()))?;
+    let duration = parse_header_duration(mvhd)?;
+
+    let track = Atoms::new(&moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, body)| body)
+        .find(|trak| {
+            find(trak, &[b"mdia", b"hdlr"]).and_then(|hdlr| hdlr.get(8..12))
+                == Some(HANDLER_SOUND.as_slice())
+        })
+        .ok_or_else(|| ProbeError::Malformed("no sound track".into()))?;
+
+    let stsd = find(track, &[b"mdia", b"minf", b"stbl", b"stsd"])
+        .ok_or_else(|| ProbeError::Malformed("missing stsd atom".into()))?;
+    // Version, flags and entry count
+    let (kind, entry) = stsd
+        .get(8..)
+        .and_then(|entries| Atoms::new(entries).next())
+        .ok_or_else(|| ProbeError::Malformed("empty sample description".into()))?;
+
+    let mut bytes = Bytes::new(entry);
+    // Reserved, data reference index, version, revision and vendor
+    bytes.skip(16)?;
+    let channels = bytes.u16_be()? as u8;
+    let sample_size = bytes.u16_be()? as u8;
+    // Compression id and packet size
+    bytes.skip(4)?;
+    let mut sample_rate = bytes.u32_be()? >> 16;
+
+    let codec = match &kind {
+        b"mp4a" => Codec::Aac,
+        b"alac" => Codec::Alac,
+        _ => Codec::Unknown,
+    };
+    // Rates above 65535 Hz don't fit in the sample entry, but the
+    // media timescale of the track is the sample rate
+    if let Some(rate) = find(track, &[b"mdia", b"mdhd"]).and_then(|mdhd| media_timescale(mdhd).ok())
+    {
+        if sample_rate == 0 || rate > 0xFFFF {
+            sample_rate = rate;
+        }
+    }
+
+    let mdat_size = locate_top_level(file, b"mdat")?
+        .map(|location| location.size - location.header_size)
+        .unwrap_or_default();
+
+    Ok(AudioProperties {
+        format: AudioFormat::Mp4,
+        codec,
+        bitrate: average_bitrate(mdat_size, &duration),
+        duration,
+        sample_rate,
+        channels,
+        bits_per_sample: if codec == Codec::Alac {
+            Some(sample_size)
+        } else {
+            None
+        },
+    })
+}
+
+/// Reads the duration of a `mvhd` atom, in its own timescale
+fn parse_header_duration(mvhd: &[u8]) -> Result<Duration, ProbeError> {
+    let mut bytes = Bytes::new(mvhd);
+    let version = bytes.u8()?;
+    // Flags
+    bytes.skip(3)?;
+    let (timescale, duration) = if version == 1 {
+        // Creation and modification times
+        bytes.skip(16)?;
+        (bytes.u32_be()?, bytes.u64_be()?)
+    } else {
+        bytes.skip(8)?;
+        (bytes.u32_be()?, bytes.u32_be()? as u64)
+    };
+    if timescale == 0 {
+        return Err(ProbeError::Malformed("invalid movie timescale".into()));
+    }
+    Ok(samples_duration(duration, timescale))
+}
+
+/// Reads the timescale of a `mdhd` atom
+fn media_timescale(mdhd: &[u8]) -> Result<u32, ProbeError> {
+    let mut bytes = Bytes::new(mdhd);
+    let version = bytes.u8()?;
+    bytes.skip(if version == 1 { 3 + 16 } else { 3 + 8 })?;
+    Ok(bytes.u32_be()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::format::mp4::atom;
+
+    fn mvhd(version: u8, timescale: u32, duration: u64) -> Vec<u8> {
+        let mut body = vec![version, 0, 0, 0];
+        if version == 1 {
+            body.extend_from_slice(&[0; 16]);
+            body.extend_from_slice(&timescale.to_be_bytes());
+            body.extend_from_slice(&duration.to_be_bytes());
+        } else {
+            body.extend_from_slice(&[0; 8]);
+            body.extend_from_slice(&timescale.to_be_bytes());
+            body.extend_from_slice(&(duration as u32).to_be_bytes());
+        }
+        body.extend_from_slice(&[0; 80]);
+        atom(b"mvhd", &body)
+    }
+
+    fn track(handler: &[u8; 4], entry: &[u8; 4], sample_rate: u32, bits: u16) -> Vec<u8> {
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(handler);
+        hdlr.extend_from_slice(&[0; 12]);
+
+        let mut mdhd = vec![0; 12];
+        mdhd.extend_from_slice(&sample_rate.to_be_bytes());
+        mdhd.extend_from_slice(&[0; 8]);
+
+        let mut sample_entry = vec![0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+        sample_entry.extend_from_slice(&bits.to_be_bytes());
+        sample_entry.extend_from_slice(&[0; 4]);
+        sample_entry.extend_from_slice(&(sample_rate << 16).to_be_bytes());
+        let stsd = [vec![0, 0, 0, 0, 0, 0, 0, 1], atom(entry, &sample_entry)].concat();
+
+        let stbl = atom(b"stbl", &atom(b"stsd", &stsd));
+        let mdia = [
+            atom(b"hdlr", &hdlr),
+            atom(b"mdhd", &mdhd),
+            atom(b"minf", &stbl),
+        ]
+        .concat();
+        atom(b"trak", &atom(b"mdia", &mdia))
+    }
+
+    fn file(moov: &[Vec<u8>], mdat: usize) -> Vec<u8> {
+        [
+            atom(b"ftyp", b"M4A \0\0\0\0"),
+            atom(b"moov", &moov.concat()),
+            atom(b"mdat", &vec![0; mdat]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn reads_aac_tracks() {
+        let data = file(
+            &[
+                mvhd(0, 1000, 5000),
+                track(b"vide", b"avc1", 0, 0),
+                track(b"soun", b"mp4a", 44100, 16),
+            ],
+            20_000,
+        );
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.format(), AudioFormat::Mp4);
+        assert_eq!(properties.codec(), Codec::Aac);
+        assert_eq!(properties.duration(), &Duration::from_secs(5));
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(properties.bits_per_sample(), None);
+        assert_eq!(properties.bitrate(), 32);
+    }
+
+    #[test]
+    fn reads_alac_tracks() {
+        // A 64 bits duration, and a rate too high for the sample entry
+        let data = file(
+            &[
+                mvhd(1, 96000, 96000 * 3),
+                track(b"soun", b"alac", 96000, 24),
+            ],
+            0,
+        );
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.codec(), Codec::Alac);
+        assert_eq!(properties.duration(), &Duration::from_secs(3));
+        assert_eq!(properties.sample_rate(), 96000);
+        assert_eq!(properties.bits_per_sample(), Some(24));
+        assert_eq!(properties.bitrate(), 0);
+    }
+
+    #[test]
+    fn refuses_files_without_audio() {
+        let without_track = file(&[mvhd(0, 1000, 5000)], 0);
+        let video_only = file(&[mvhd(0, 1000, 5000), track(b"vide", b"avc1", 0, 0)], 0);
+        let no_timescale = file(&[mvhd(0, 0, 5000), track(b"soun", b"mp4a", 44100, 16)], 0);
+        let no_header = file(&[track(b"soun", b"mp4a", 44100, 16)], 0);
+        for data in [without_track, video_only, no_timescale, no_header] {
+            assert!(matches!(
+                probe(&mut Cursor::new(&data)),
+                Err(ProbeError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        let data = file(
+            &[mvhd(1, 1000, 5000), track(b"soun", b"mp4a", 44100, 16)],
+            10,
+        );
+        for len in 0..data.len() {
+            let _ = probe(&mut Cursor::new(&data[..len]));
+        }
+        assert!(parse_header_duration(&[0; 12]).is_err());
+        assert!(media_timescale(&[1; 20]).is_err());
+    }
+}
@@ -0,0 +1,251 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::format::{bytes::Bytes, ogg::read_packets, AudioFormat};
+
+use super::{
+    average_bitrate, flac::parse_streaminfo, samples_duration, AudioProperties, Codec, ProbeError,
+};
+
+/// Opus always decodes at 48 kHz, whatever the rate of the original input
+const OPUS_RATE: u32 = 48000;
+
+/// How much of the end of the file is searched for the last page
+/// before the window is widened
+const TAIL_SIZE: u64 = 64 * 1024;
+
+/// Reads the properties of the first logical bitstream of an Ogg file.
+/// The duration comes from the granule position of its last page.
+pub(super) fn probe<R: Read + Seek>(file: &mut R) -> Result<AudioProperties, ProbeError> {
+    let packets = read_packets(file, 1)?;
+    let header = packets
+        .first()
+        .ok_or_else(|| ProbeError::Malformed("missing identification header".into()))?;
+
+    file.seek(SeekFrom::Start(14))?;
+    let mut serial = [0u8; 4];
+    file.read_exact(&mut serial)?;
+    let serial = u32::from_le_bytes(serial);
+
+    let size = file.seek(SeekFrom::End(0))?;
+    let granule = last_granule(file, serial, size)?
+        .ok_or_else(|| ProbeError::Malformed("no page with a granule position".into()))?;
+
+    let mut properties = if header.starts_with(b"\x01vorbis") {
+        let mut bytes = Bytes::new(&header[7..]);
+        // Version
+        bytes.skip(4)?;
+        let channels = bytes.u8()?;
+        let sample_rate = bytes.u32_le()?;
+        AudioProperties {
+            format: AudioFormat::Ogg,
+            codec: Codec::Vorbis,
+            duration: samples_duration(granule, sample_rate),
+            bitrate: 0,
+            sample_rate,
+            channels,
+            bits_per_sample: None,
+        }
+    } else if header.starts_with(b"OpusHead") {
+        let mut bytes = Bytes::new(&header[8..]);
+        // Version
+        bytes.skip(1)?;
+        let channels = bytes.u8()?;
+        let pre_skip = u16::from_le_bytes(bytes.take(2)?.try_into().unwrap());
+        AudioProperties {
+            format: AudioFormat::Ogg,
+            codec: Codec::Opus,
+            duration: samples_duration(granule.saturating_sub(pre_skip as u64), OPUS_RATE),
+            bitrate: 0,
+            sample_rate: OPUS_RATE,
+            channels,
+            bits_per_sample: None,
+        }
+    } else if header.starts_with(b"\x7FFLAC") {
+        // Mapping version, header count, native marker and block header
+        let streaminfo = header
+            .get(17..)
+            .ok_or_else(|| ProbeError::Malformed("truncated Ogg FLAC header".into()))?;
+        let mut properties = parse_streaminfo(streaminfo)?;
+        properties.format = AudioFormat::Ogg;
+        properties.duration = samples_duration(granule, properties.sample_rate);
+        properties
+    } else {
+        return Err(ProbeError::UnsupportedFormat);
+    };
+
+    properties.bitrate = average_bitrate(size, &properties.duration);
+    Ok(properties)
+}
+
+/// Returns the granule position of the last page of the bitstream
+/// `serial`, searching backwards from the end of the file
+fn last_granule<R: Read + Seek>(
+    file: &mut R,
+    serial: u32,
+    size: u64,
+) -> Result<Option<u64>, ProbeError> {
+    let mut window = TAIL_SIZE;
+    loop {
+        let start = size.saturating_sub(window);
+        let mut tail = vec![0u8; (size - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut tail)?;
+
+        let mut end = tail.len();
+        while let Some(offset) = find_capture(&tail[..end]) {
+            let header = &tail[offset..];
+            if header.len() >= 27 && header[4] == 0 {
+                let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+                let page_serial = u32::from_le_bytes(header[14..18].try_into().unwrap());
+                // Pages where no packet ends have a granule position of -1
+                if page_serial == serial && granule != u64::MAX {
+                    return Ok(Some(granule));
+                }
+            }
+            end = offset;
+        }
+
+        if start == 0 {
+            return Ok(None);
+        }
+        window *= 4;
+    }
+}
+
+/// Returns the offset of the last Ogg capture pattern in `data`
+fn find_capture(data: &[u8]) -> Option<usize> {
+    data.windows(4).rposition(|window| window == b"OggS")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::*;
+    use crate::format::ogg::{paginate, Page};
+
+    fn audio_page(serial: u32, sequence: u32, granule_position: u64) -> Page {
+        Page {
+            header_type: 0,
+            granule_position,
+            serial,
+            sequence,
+            segments: vec![100],
+            data: vec![0; 100],
+        }
+    }
+
+    /// Returns a stream made of `headers` followed by audio pages
+    /// ending at `granules`
+    fn stream(headers: &[Vec<u8>], granules: &[u64]) -> Vec<u8> {
+        let mut pages = paginate(&headers[..1], 1, 0);
+        pages.extend(paginate(&headers[1..], 1, 1));
+        let sequence = pages.len() as u32;
+        for (index, granule) in granules.iter().enumerate() {
+            pages.push(audio_page(1, sequence + index as u32, *granule));
+        }
+
+        let mut data = vec![];
+        for page in pages {
+            page.write_to(&mut data).unwrap();
+        }
+        data
+    }
+
+    fn vorbis() -> Vec<Vec<u8>> {
+        let mut identification = b"\x01vorbis\0\0\0\0\x02".to_vec();
+        identification.extend_from_slice(&44100u32.to_le_bytes());
+        identification.extend_from_slice(&[0; 13]);
+        vec![
+            identification,
+            b"\x03vorbis".to_vec(),
+            b"\x05vorbis".to_vec(),
+        ]
+    }
+
+    #[test]
+    fn measures_vorbis_streams() {
+        let data = stream(&vorbis(), &[44100, 441_000]);
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.format(), AudioFormat::Ogg);
+        assert_eq!(properties.codec(), Codec::Vorbis);
+        assert_eq!(properties.duration(), &Duration::from_secs(10));
+        assert_eq!(properties.sample_rate(), 44100);
+        assert_eq!(properties.channels(), 2);
+        assert_eq!(
+            properties.bitrate(),
+            average_bitrate(data.len() as u64, &Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn takes_the_last_page_of_the_first_stream() {
+        let mut data = stream(&vorbis(), &[441_000]);
+        // Pages where no packet ends, then another stream
+        audio_page(1, 4, u64::MAX).write_to(&mut data).unwrap();
+        audio_page(2, 0, 999_999).write_to(&mut data).unwrap();
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.duration(), &Duration::from_secs(10));
+
+        // Found past the first window
+        let mut data = stream(&vorbis(), &[441_000]);
+        data.extend_from_slice(&vec![0; TAIL_SIZE as usize * 2]);
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.duration(), &Duration::from_secs(10));
+    }
+
+    #[test]
+    fn measures_opus_streams() {
+        let mut head = b"OpusHead\x01\x01".to_vec();
+        head.extend_from_slice(&312u16.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&[0; 3]);
+        let data = stream(&[head, b"OpusTags".to_vec()], &[5 * 48000 + 312]);
+
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.codec(), Codec::Opus);
+        assert_eq!(properties.duration(), &Duration::from_secs(5));
+        // Whatever the rate of the input
+        assert_eq!(properties.sample_rate(), 48000);
+        assert_eq!(properties.channels(), 1);
+    }
+
+    #[test]
+    fn measures_ogg_flac_streams() {
+        let mut head = b"\x7FFLAC\x01\0\0\x01fLaC\0\0\0\x22".to_vec();
+        head.extend_from_slice(&[0x10, 0, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        let packed: u64 = 48000 << 44 | 1 << 41 | 23 << 36 | 48000;
+        head.extend_from_slice(&packed.to_be_bytes());
+        head.extend_from_slice(&[0; 16]);
+        let data = stream(&[head, vec![0x84, 0, 0, 0]], &[96000]);
+
+        let properties = probe(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(properties.format(), AudioFormat::Ogg);
+        assert_eq!(properties.codec(), Codec::Flac);
+        // From the granule position, not from STREAMINFO
+        assert_eq!(properties.duration(), &Duration::from_secs(2));
+        assert_eq!(properties.bits_per_sample(), Some(24));
+    }
+
+    #[test]
+    fn refuses_unknown_codecs() {
+        let data = stream(&[b"\x80theora".to_vec()], &[10]);
+        assert!(matches!(
+            probe(&mut Cursor::new(&data)),
+            Err(ProbeError::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        let data = stream(&vorbis(), &[441_000]);
+        for len in 0..data.len() {
+            let _ = probe(&mut Cursor::new(&data[..len]));
+        }
+        // Identification headers cut short
+        for header in [&b"\x01vorbis\0"[..], b"OpusHead\x01", b"\x7FFLAC\x01\0"] {
+            let data = stream(&[header.to_vec()], &[10]);
+            assert!(probe(&mut Cursor::new(&data)).is_err());
+        }
+    }
+}
@@ -1,9 +1,13 @@
+use std::time::Duration;
+
 use crate::{
+    library::Library,
     playlist_manager::Playlist,
     song::{Song, SongDetails},
 };
 
 /// Handles a reproduction queue
+#[derive(Default)]
 pub struct QueueManager {
     songs: Vec<Song>,
     /// The index of the selected song in the queue, e.g. the one that is being
@@ -11,15 +15,6 @@ pub struct QueueManager {
     current: usize,
 }
 
-impl Default for QueueManager {
-    fn default() -> Self {
-        Self {
-            songs: vec![],
-            current: 0,
-        }
-    }
-}
-
 impl QueueManager {
     pub fn is_empty(&self) -> bool {
         self.songs.is_empty()
@@ -27,7 +22,7 @@ impl QueueManager {
 
     /// Returns all the songs in the queue
     pub fn songs(&self) -> Vec<&Song> {
-        self.songs.iter().map(|song| song).collect()
+        self.songs.iter().collect()
     }
 
     /// Returns a list holding the name of songs in the queue
@@ -53,12 +48,27 @@ impl QueueManager {
         slice.iter().map(|song| song.details()).collect()
     }
 
+    /// Returns the total duration of the songs in the queue
+    /// whose duration is known
+    pub fn duration(&self) -> Duration {
+        self.songs
+            .iter()
+            .filter_map(|song| song.details().duration())
+            .sum()
+    }
+
     /// Adds a song to the end of the queue
     pub fn push(&mut self, song: Song) {
         self.songs.push(song)
     }
 
+    /// Returns the selected song, e.g. the one that is being played
+    pub fn current(&self) -> Option<&Song> {
+        self.songs.get(self.current)
+    }
+
     /// Returns the next song in the queue, if there is one
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<&Song> {
         if self.current + 1 < self.songs.len() {
             self.current += 1;
@@ -110,22 +120,27 @@ impl QueueManager {
     /// the latter, so the queue should look like fully already played.
     ///
     /// #### NOTE
-    /// A playlist reference is received, and every song has
-    /// to be cloned to be pushed in the queue.
-    pub fn set_on_playlist(&mut self, playlist: &Playlist, index: usize) {
+    /// Playlist songs are looked up in `library`, and every song has
+    /// to be cloned to be pushed in the queue. Songs missing from the
+    /// library are skipped; if the song at `index` is one of them, the
+    /// next one becomes the 'current' one.
+    pub fn set_on_playlist(&mut self, playlist: &Playlist, library: &Library, index: usize) {
         self.clear();
-        let song_iter = playlist.songs().as_slice();
-        for song in &song_iter[index..playlist.songs().len()] {
-            self.push(song.clone());
+        let ids = playlist.songs();
+        // `index` is a position among every song of the playlist
+        let index = library.resolve(&ids[..index.min(ids.len())]).len();
+        let songs = library.resolve(ids);
+        for song in &songs[index..] {
+            self.push((*song).clone());
         }
-        for song in &song_iter[..index] {
-            self.push(song.clone());
+        for song in &songs[..index] {
+            self.push((*song).clone());
         }
 
-        if index < playlist.songs().len() {
+        if index < songs.len() {
             self.current = 0;
         } else {
-            self.current = playlist.songs().len();
+            self.current = songs.len();
         }
     }
 }
@@ -0,0 +1,217 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    song::LoadError,
+    storage::{Collection, Storage},
+};
+
+/// Extension of the copies made of documents before migrating them
+pub const BACKUP_EXTENSION: &str = "bak";
+
+/// Upgrades a document from the version its position in the list of
+/// migrations tells, starting from 1, to the next one
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// Migrations of the library file, in order
+const LIBRARY_MIGRATIONS: &[Migration] = &[];
+
+/// Migrations of song_meta files, in order
+const DETAILS_MIGRATIONS: &[Migration] = &[];
+
+/// Migrations of playlist files, in order
+const PLAYLIST_MIGRATIONS: &[Migration] = &[];
+
+/// The kinds of documents saved, each one with a format of its own.
+/// Documents without a version are from version 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentKind {
+    Library,
+    SongDetails,
+    Playlist,
+}
+
+impl DocumentKind {
+    /// Returns the version of the format documents are written in
+    pub fn version(&self) -> u64 {
+        1 + self.migrations().len() as u64
+    }
+
+    fn migrations(&self) -> &'static [Migration] {
+        match self {
+            DocumentKind::Library => LIBRARY_MIGRATIONS,
+            DocumentKind::SongDetails => DETAILS_MIGRATIONS,
+            DocumentKind::Playlist => PLAYLIST_MIGRATIONS,
+        }
+    }
+}
+
+/// Returns the name of the copy of the document `name`
+/// made before migrating it from `version`
+pub fn backup_name(name: &OsStr, version: u64) -> OsString {
+    let mut backup = name.to_os_string();
+    backup.push(format!(".v{}.{}", version, BACKUP_EXTENSION));
+    backup
+}
+
+/// Returns `true` if the document `name` is a copy made before a migration
+pub(crate) fn is_backup(name: &OsStr) -> bool {
+    Path::new(name).extension() == Some(OsStr::new(BACKUP_EXTENSION))
+}
+
+/// Reads the document `name`, of kind `kind`, as JSON. Documents from an
+/// older version of the format are upgraded one version at a time, once
+/// a copy of them is saved as `backup_name` tells; documents from a newer
+/// one are an error. Returns the document along with `true` if it was
+/// upgraded, for it to be written in the current format the next time
+/// it's saved. Errors refer to the location of the document.
+pub(crate) fn read(
+    storage: &dyn Storage,
+    collection: Collection,
+    name: &OsStr,
+    kind: DocumentKind,
+) -> Result<(Value, bool), LoadError> {
+    let location = || storage.location(collection, name);
+    let data = storage
+        .read(collection, name)
+        .map_err(|err| LoadError::from_io(&location(), err))?;
+    let mut value: Value =
+        serde_json::from_slice(&data).map_err(|err| LoadError::Malformed(location(), err))?;
+
+    let version = value
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(1)
+        .max(1);
+    if version > kind.version() {
+        return Err(LoadError::UnsupportedVersion(location(), version));
+    }
+    if version == kind.version() {
+        return Ok((value, false));
+    }
+
+    // The first copy is kept: it's the one written by the user's version
+    let backup = backup_name(name, version);
+    if !storage.exists(collection, &backup) {
+        storage
+            .write(collection, &backup, &data)
+            .map_err(|err| LoadError::from_io(&storage.location(collection, &backup), err))?;
+    }
+    for (from, migrate) in kind
+        .migrations()
+        .iter()
+        .enumerate()
+        .skip(version as usize - 1)
+    {
+        migrate(&mut value)
+            .map_err(|cause| LoadError::Migration(location(), from as u64 + 1, cause))?;
+    }
+    if let Value::Object(map) = &mut value {
+        map.insert("version".into(), kind.version().into());
+    }
+    Ok((value, true))
+}
+
+/// Returns `value` as a document of kind `kind`,
+/// along with the version of its format
+pub(crate) fn to_bytes<T: Serialize>(value: &T, kind: DocumentKind) -> serde_json::Result<Vec<u8>> {
+    let mut value = serde_json::to_value(value)?;
+    if let Value::Object(map) = &mut value {
+        map.insert("version".into(), kind.version().into());
+    }
+    serde_json::to_vec(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{playlist_manager::Playlist, storage::MemoryStorage};
+
+    fn store(storage: &MemoryStorage, name: &str, value: &Value) {
+        let data = serde_json::to_vec(value).unwrap();
+        storage
+            .write(Collection::Playlists, OsStr::new(name), &data)
+            .unwrap();
+    }
+
+    fn read_playlist(storage: &MemoryStorage, name: &str) -> Result<(Value, bool), LoadError> {
+        read(
+            storage,
+            Collection::Playlists,
+            OsStr::new(name),
+            DocumentKind::Playlist,
+        )
+    }
+
+    #[test]
+    fn versions_documents_without_one() {
+        let storage = MemoryStorage::new();
+        let document = json!({
+            "name": "Old",
+            "creation_date": "2020-01-01T00:00:00Z",
+            "songs": [],
+        });
+        store(&storage, "old.json", &document);
+
+        // Documents without a version are from version 1, the current one
+        let (value, upgraded) = read_playlist(&storage, "old.json").unwrap();
+        assert_eq!(value, document);
+        assert!(!upgraded);
+        assert!(!storage.exists(
+            Collection::Playlists,
+            &backup_name(OsStr::new("old.json"), 1)
+        ));
+
+        // They get one when saved again
+        let (playlist, legacy) = Playlist::load(&storage, OsStr::new("old.json")).unwrap();
+        assert!(legacy.is_empty());
+        let saved: Value = serde_json::from_slice(&playlist.to_bytes().unwrap()).unwrap();
+        assert_eq!(saved["version"], json!(DocumentKind::Playlist.version()));
+        assert_eq!(saved["name"], json!("Old"));
+    }
+
+    #[test]
+    fn reads_documents_by_version() {
+        let storage = MemoryStorage::new();
+        let current = DocumentKind::Playlist.version();
+        store(&storage, "zero.json", &json!({ "version": 0 }));
+        store(&storage, "current.json", &json!({ "version": current }));
+        store(&storage, "newer.json", &json!({ "version": current + 1 }));
+
+        assert!(!read_playlist(&storage, "zero.json").unwrap().1);
+        assert!(!read_playlist(&storage, "current.json").unwrap().1);
+        assert!(matches!(
+            read_playlist(&storage, "newer.json"),
+            Err(LoadError::UnsupportedVersion(_, version)) if version == current + 1
+        ));
+        assert!(matches!(
+            read_playlist(&storage, "missing.json"),
+            Err(LoadError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn names_backups() {
+        let backup = backup_name(OsStr::new("playlist.json"), 1);
+        assert_eq!(backup, "playlist.json.v1.bak");
+        assert!(is_backup(&backup));
+        assert!(!is_backup(OsStr::new("playlist.json")));
+    }
+
+    #[test]
+    fn writes_the_current_version() {
+        let bytes = to_bytes(&json!({ "name": "New" }), DocumentKind::Library).unwrap();
+        let value: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            value,
+            json!({ "name": "New", "version": DocumentKind::Library.version() })
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::fmt::Display;
+
+/// Errors describing why a search query couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// The query ends where something else was expected
+    UnexpectedEnd,
+    /// The token was found where it doesn't make sense
+    Unexpected(String),
+    UnknownField(String),
+    /// The value can't be compared with the field, e.g. a word with a year
+    InvalidValue(String, String),
+    UnclosedQuote,
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryError::UnexpectedEnd => writeln!(f, "The query ends unexpectedly"),
+            QueryError::Unexpected(token) => writeln!(f, "`{}` wasn't expected here", token),
+            QueryError::UnknownField(field) => writeln!(
+                f,
+                "`{}` isn't a known field, quote the text to look for it as is",
+                field
+            ),
+            QueryError::InvalidValue(field, value) => {
+                writeln!(f, "`{}` isn't a valid value for `{}`", value, field)
+            }
+            QueryError::UnclosedQuote => writeln!(f, "A quote isn't closed"),
+        }
+    }
+}
+
+impl Error for QueryError {}
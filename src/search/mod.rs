@@ -0,0 +1,521 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+};
+
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+use uuid::Uuid;
+
+use crate::song::SongDetails;
+
+pub use self::error::QueryError;
+pub use self::query::Query;
+
+mod error;
+mod query;
+
+/// The fields of song details searched
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SearchField {
+    Name,
+    Artist,
+    AlbumArtist,
+    Album,
+    Genre,
+    Composer,
+    Comment,
+}
+
+impl SearchField {
+    pub(crate) const ALL: [SearchField; 7] = [
+        SearchField::Name,
+        SearchField::Artist,
+        SearchField::AlbumArtist,
+        SearchField::Album,
+        SearchField::Genre,
+        SearchField::Composer,
+        SearchField::Comment,
+    ];
+
+    /// Returns the values of the field in `details`
+    pub(crate) fn values(self, details: &SongDetails) -> Vec<&str> {
+        let value = match self {
+            SearchField::Name => Some(details.name()),
+            SearchField::Artist => details.artist(),
+            SearchField::AlbumArtist => details.album_artist(),
+            SearchField::Album => details.album(),
+            SearchField::Genre => {
+                return details
+                    .genres()
+                    .iter()
+                    .map(|genre| genre.as_str())
+                    .collect()
+            }
+            SearchField::Composer => details.composer(),
+            SearchField::Comment => details.comment(),
+        };
+        value.into_iter().collect()
+    }
+
+    /// How much a match in the field counts
+    fn weight(&self) -> f32 {
+        match self {
+            SearchField::Name => 1.0,
+            SearchField::Artist => 0.8,
+            SearchField::AlbumArtist | SearchField::Album => 0.6,
+            SearchField::Genre | SearchField::Composer => 0.4,
+            SearchField::Comment => 0.2,
+        }
+    }
+}
+
+/// What matched a search in a field of a song
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Highlight {
+    field: SearchField,
+    text: String,
+    ranges: Vec<Range<usize>>,
+}
+
+impl Highlight {
+    pub fn field(&self) -> SearchField {
+        self.field
+    }
+
+    /// Returns the value of the field, e.g. the name of the song
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the byte ranges of `text` that matched, sorted
+    pub fn ranges(&self) -> &Vec<Range<usize>> {
+        &self.ranges
+    }
+}
+
+/// A song found by a search
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    id: Uuid,
+    score: f32,
+    highlights: Vec<Highlight>,
+}
+
+impl SearchResult {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns how well the song matched: the higher, the better
+    pub fn score(&self) -> f32 {
+        self.score
+    }
+
+    /// Returns the fields that matched, in the order of `SearchField`
+    pub fn highlights(&self) -> &Vec<Highlight> {
+        &self.highlights
+    }
+}
+
+/// How a word of the search matched a word of a field
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MatchKind {
+    Exact,
+    /// The word of the field starts with the one of the search
+    Prefix,
+    /// The word of the search is this many typos away
+    /// from the one of the field, or from its beginning
+    Fuzzy(usize),
+}
+
+impl MatchKind {
+    fn score(&self) -> f32 {
+        match self {
+            MatchKind::Exact => 1.0,
+            MatchKind::Prefix => 0.75,
+            MatchKind::Fuzzy(distance) => 0.5 / *distance as f32,
+        }
+    }
+}
+
+/// A word of a field, folded, with its position in the field
+#[derive(Clone, Debug)]
+struct Token {
+    word: String,
+    range: Range<usize>,
+}
+
+/// The words of a field of a song
+#[derive(Clone, Debug)]
+struct IndexedField {
+    field: SearchField,
+    text: String,
+    tokens: Vec<Token>,
+}
+
+/// Finds songs by the words of their details, ignoring case and
+/// diacritics. Words can be typed partially, as long as they're
+/// the beginning of a word, or with typos.
+#[derive(Default)]
+pub(crate) struct SearchIndex {
+    songs: HashMap<Uuid, Vec<IndexedField>>,
+    /// Songs having each word, sorted to look up prefixes
+    words: BTreeMap<String, BTreeSet<Uuid>>,
+}
+
+impl SearchIndex {
+    pub(crate) fn insert(&mut self, id: Uuid, details: &SongDetails) {
+        let mut fields = vec![];
+        for field in SearchField::ALL {
+            for text in field.values(details) {
+                if !text.is_empty() {
+                    fields.push(IndexedField {
+                        field,
+                        text: String::from(text),
+                        tokens: tokenize(text),
+                    });
+                }
+            }
+        }
+
+        for token in fields.iter().flat_map(|field| &field.tokens) {
+            self.words.entry(token.word.clone()).or_default().insert(id);
+        }
+        self.songs.insert(id, fields);
+    }
+
+    pub(crate) fn remove(&mut self, id: Uuid) {
+        let fields = match self.songs.remove(&id) {
+            Some(fields) => fields,
+            None => return,
+        };
+        for token in fields.iter().flat_map(|field| &field.tokens) {
+            if let Some(ids) = self.words.get_mut(&token.word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.words.remove(&token.word);
+                }
+            }
+        }
+    }
+
+    /// Returns the songs having every word of `query`, the best first
+    pub(crate) fn search(&self, query: &str) -> Vec<SearchResult> {
+        let terms: Vec<String> = tokenize(query)
+            .into_iter()
+            .map(|token| token.word)
+            .collect();
+        if terms.is_empty() {
+            return vec![];
+        }
+
+        // For each term, the words it matches and how
+        let matched: Vec<HashMap<&str, MatchKind>> =
+            terms.iter().map(|term| self.matching_words(term)).collect();
+
+        let mut candidates: Option<BTreeSet<Uuid>> = None;
+        for words in &matched {
+            let ids: BTreeSet<Uuid> = words
+                .keys()
+                .flat_map(|word| self.words[*word].iter().copied())
+                .collect();
+            candidates = Some(match candidates {
+                Some(candidates) => candidates.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+
+        let mut results: Vec<SearchResult> = candidates
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| self.rank(id, &matched))
+            .collect();
+        results.sort_by(|first, second| {
+            second
+                .score
+                .total_cmp(&first.score)
+                .then_with(|| first.id.cmp(&second.id))
+        });
+        results
+    }
+
+    /// Returns the words of the index matching `term`
+    fn matching_words(&self, term: &str) -> HashMap<&str, MatchKind> {
+        let mut words = HashMap::new();
+        for (word, _) in self.words.range(term.to_string()..) {
+            if !word.starts_with(term) {
+                break;
+            }
+            let kind = if word == term {
+                MatchKind::Exact
+            } else {
+                MatchKind::Prefix
+            };
+            words.insert(word.as_str(), kind);
+        }
+
+        let length = term.chars().count();
+        let allowed = match length {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        if allowed > 0 {
+            for word in self.words.keys() {
+                let count = word.chars().count();
+                if words.contains_key(word.as_str()) || count + allowed < length {
+                    continue;
+                }
+                // Longer words can be typed partially too
+                let prefix: String = word.chars().take(length).collect();
+                let found = if count > length + allowed {
+                    distance(term, &prefix, allowed)
+                } else {
+                    distance(term, word, allowed).or_else(|| distance(term, &prefix, allowed))
+                };
+                if let Some(distance) = found {
+                    words.insert(word.as_str(), MatchKind::Fuzzy(distance));
+                }
+            }
+        }
+        words
+    }
+
+    /// Scores the song with id `id`, and finds what matched in it
+    fn rank(&self, id: Uuid, matched: &[HashMap<&str, MatchKind>]) -> Option<SearchResult> {
+        let fields = self.songs.get(&id)?;
+        let mut score = 0.0;
+        let mut highlights: BTreeMap<(SearchField, usize), Vec<Range<usize>>> = BTreeMap::new();
+        for words in matched {
+            let mut best: f32 = 0.0;
+            for (position, field) in fields.iter().enumerate() {
+                for token in &field.tokens {
+                    if let Some(kind) = words.get(token.word.as_str()) {
+                        best = best.max(kind.score() * field.field.weight());
+                        highlights
+                            .entry((field.field, position))
+                            .or_default()
+                            .push(token.range.clone());
+                    }
+                }
+            }
+            score += best;
+        }
+
+        let highlights = highlights
+            .into_iter()
+            .map(|((field, position), mut ranges)| {
+                ranges.sort_by_key(|range| range.start);
+                ranges.dedup();
+                Highlight {
+                    field,
+                    text: fields[position].text.clone(),
+                    ranges,
+                }
+            })
+            .collect();
+        Some(SearchResult {
+            id,
+            score,
+            highlights,
+        })
+    }
+}
+
+/// Returns `text` in lower case and without diacritics
+pub fn fold(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Splits `text` into folded words, each one with its byte range in `text`
+fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (index, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(from)) => {
+                tokens.push(Token {
+                    word: fold(&text[from..index]),
+                    range: from..index,
+                });
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    tokens
+}
+
+/// Returns how many edits, counting the swap of two neighbouring
+/// characters as one, turn `first` into `second`, unless it's
+/// more than `limit`
+fn distance(first: &str, second: &str, limit: usize) -> Option<usize> {
+    let first: Vec<char> = first.chars().collect();
+    let second: Vec<char> = second.chars().collect();
+    let mut before: Vec<usize> = vec![0; second.len() + 1];
+    let mut previous: Vec<usize> = (0..=second.len()).collect();
+    for i in 1..=first.len() {
+        let mut current = vec![i; second.len() + 1];
+        for j in 1..=second.len() {
+            let cost = usize::from(first[i - 1] != second[j - 1]);
+            current[j] = (previous[j] + 1)
+                .min(current[j - 1] + 1)
+                .min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && first[i - 1] == second[j - 2] && first[i - 2] == second[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        if current.iter().min().is_some_and(|min| *min > limit) {
+            return None;
+        }
+        before = previous;
+        previous = current;
+    }
+    Some(previous[second.len()]).filter(|distance| *distance <= limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn details(name: &str, artist: Option<&str>) -> SongDetails {
+        SongDetails::new(name, artist, None, None)
+    }
+
+    /// Returns an index of songs with the given details, and their ids
+    fn index(songs: &[SongDetails]) -> (SearchIndex, Vec<Uuid>) {
+        let mut index = SearchIndex::default();
+        let ids: Vec<Uuid> = songs.iter().map(|_| Uuid::new_v4()).collect();
+        for (id, details) in ids.iter().zip(songs) {
+            index.insert(*id, details);
+        }
+        (index, ids)
+    }
+
+    fn found(index: &SearchIndex, query: &str) -> Vec<Uuid> {
+        index
+            .search(query)
+            .iter()
+            .map(|result| result.id())
+            .collect()
+    }
+
+    #[test]
+    fn counts_edits() {
+        assert_eq!(distance("love", "love", 2), Some(0));
+        assert_eq!(distance("love", "lave", 2), Some(1));
+        assert_eq!(distance("love", "lov", 2), Some(1));
+        assert_eq!(distance("love", "glove", 2), Some(1));
+        assert_eq!(distance("love", "olve", 2), Some(1));
+        assert_eq!(distance("café", "cafe", 2), Some(1));
+        assert_eq!(distance("love", "hate", 2), None);
+        assert_eq!(distance("love", "lave", 0), None);
+        assert_eq!(distance("", "abc", 3), Some(3));
+    }
+
+    #[test]
+    fn folds_case_and_diacritics() {
+        assert_eq!(fold("Héroes"), "heroes");
+        assert_eq!(fold("ÉCOLE Façade"), "ecole facade");
+        assert_eq!(fold("Ｆｕｌｌ"), "full");
+    }
+
+    #[test]
+    fn tokenizes_with_byte_ranges() {
+        let text = "Héroes — Bowie";
+        let tokens = tokenize(text);
+        let words: Vec<&str> = tokens.iter().map(|token| token.word.as_str()).collect();
+        assert_eq!(words, ["heroes", "bowie"]);
+        assert_eq!(tokens[0].range, 0..7);
+        assert_eq!(tokens[1].range, 12..17);
+        assert_eq!(&text[tokens[0].range.clone()], "Héroes");
+        assert_eq!(&text[tokens[1].range.clone()], "Bowie");
+
+        let text = "日本 語!";
+        let ranges: Vec<Range<usize>> = tokenize(text)
+            .into_iter()
+            .map(|token| token.range)
+            .collect();
+        assert_eq!(ranges, [0..6, 7..10]);
+        assert!(tokenize(" -- ").is_empty());
+    }
+
+    #[test]
+    fn ranks_exact_then_prefix_then_fuzzy() {
+        let (index, ids) = index(&[
+            details("Glove", None),
+            details("Lovely", None),
+            details("Love", None),
+            details("Hate", None),
+        ]);
+        assert_eq!(found(&index, "love"), [ids[2], ids[1], ids[0]]);
+        let scores: Vec<f32> = index
+            .search("love")
+            .iter()
+            .map(|result| result.score())
+            .collect();
+        assert_eq!(scores, [1.0, 0.75, 0.5]);
+    }
+
+    #[test]
+    fn weighs_fields() {
+        let (index, ids) = index(&[
+            details("Something", Some("Love")),
+            details("Love", Some("Someone")),
+        ]);
+        assert_eq!(found(&index, "love"), [ids[1], ids[0]]);
+    }
+
+    #[test]
+    fn breaks_ties_by_id() {
+        let (index, mut ids) = index(&[details("Love", None), details("Love", None)]);
+        ids.sort();
+        assert_eq!(found(&index, "love"), ids);
+    }
+
+    #[test]
+    fn needs_every_word() {
+        let (index, ids) = index(&[
+            details("Heroes", Some("David Bowie")),
+            details("Heroes", Some("Someone")),
+        ]);
+        assert_eq!(found(&index, "heroes bowie"), [ids[0]]);
+        assert!(found(&index, "heroes nobody").is_empty());
+        assert!(found(&index, "  ").is_empty());
+    }
+
+    #[test]
+    fn allows_typos_in_longer_words() {
+        let (index, ids) = index(&[details("Lve", None), details("Wonderwall", None)]);
+        // Words of three letters or less must be typed right
+        assert!(found(&index, "lev").is_empty());
+        assert_eq!(found(&index, "lvoe"), [ids[0]]);
+        assert_eq!(found(&index, "wondrewal"), [ids[1]]);
+        assert_eq!(found(&index, "wodner"), [ids[1]]);
+        assert!(found(&index, "wxyzerwall").is_empty());
+    }
+
+    #[test]
+    fn highlights_folded_matches() {
+        let (index, _) = index(&[details("Héroes de Héroes", Some("Bowie"))]);
+        let results = index.search("heroes");
+        let highlights = results[0].highlights();
+        assert_eq!(highlights.len(), 1);
+        assert_eq!(highlights[0].field(), SearchField::Name);
+        assert_eq!(highlights[0].text(), "Héroes de Héroes");
+        assert_eq!(highlights[0].ranges(), &vec![0..7, 11..18]);
+    }
+
+    #[test]
+    fn forgets_removed_songs() {
+        let (mut index, ids) = index(&[details("Love", None), details("Lovely", None)]);
+        index.remove(ids[0]);
+        assert_eq!(found(&index, "love"), [ids[1]]);
+        assert!(!index.words.contains_key("love"));
+        index.remove(ids[0]);
+    }
+}
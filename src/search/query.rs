@@ -0,0 +1,727 @@
+use std::{iter::Peekable, str::Chars};
+
+use serde::{Deserialize, Serialize};
+
+use super::{fold, QueryError, SearchField};
+use crate::song::SongDetails;
+
+/// A search query, in the syntax power users type. Words and quoted
+/// phrases are looked for in every text field, `field:value` only in
+/// `field`; numbers and durations can be compared, as in `year:>=2000`,
+/// `duration:<4m` or `year:1990..1999`. Filters must all match unless
+/// separated by `OR`; `-` or `NOT` negates them, and parentheses group
+/// them: `artist:"daft punk" year:>=2000 duration:<4m -live`.
+///
+/// Text fields are `name` (or `title`), `artist`, `albumartist`, `album`,
+/// `genre`, `composer` and `comment`: the value is looked for in them,
+/// ignoring case and diacritics, or compared with them as a whole with
+/// `=`. Numeric fields are `year`, `track`, `disc`, `plays`, `skips` and
+/// `stars`; `duration` takes `4m30s`, `4:30` or seconds, and `favourite`
+/// `yes` or `no`.
+///
+/// Words holding a colon, like `Re:Zero`, are taken for fields: they're
+/// looked for as text when quoted, as in `"Re:Zero"`. A dash on its own
+/// is a word. Queries are saved as they were typed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Query {
+    text: String,
+    expr: Expr,
+}
+
+impl Query {
+    /// Parses `text`. An empty query matches every song.
+    pub fn parse(text: &str) -> Result<Self, QueryError> {
+        let tokens = lex(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+        };
+        let expr = match tokens.is_empty() {
+            true => Expr::And(vec![]),
+            false => parser.or()?,
+        };
+        if let Some(token) = parser.peek() {
+            return Err(QueryError::Unexpected(token.to_string()));
+        }
+        Ok(Self {
+            text: String::from(text),
+            expr,
+        })
+    }
+
+    /// Returns the query as it was typed
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns `true` if a song with `details` matches the query
+    pub fn matches(&self, details: &SongDetails) -> bool {
+        self.expr.matches(details)
+    }
+}
+
+impl TryFrom<String> for Query {
+    type Error = QueryError;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        Self::parse(&text)
+    }
+}
+
+impl From<Query> for String {
+    fn from(query: Query) -> Self {
+        query.text
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Filter(Filter),
+}
+
+impl Expr {
+    fn matches(&self, details: &SongDetails) -> bool {
+        match self {
+            Expr::And(exprs) => exprs.iter().all(|expr| expr.matches(details)),
+            Expr::Or(exprs) => exprs.iter().any(|expr| expr.matches(details)),
+            Expr::Not(expr) => !expr.matches(details),
+            Expr::Filter(filter) => filter.matches(details),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    fn holds<T: PartialOrd>(&self, value: T, target: T) -> bool {
+        match self {
+            Comparison::Equal => value == target,
+            Comparison::Less => value < target,
+            Comparison::LessOrEqual => value <= target,
+            Comparison::Greater => value > target,
+            Comparison::GreaterOrEqual => value >= target,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum NumberField {
+    Year,
+    Track,
+    Disc,
+    Plays,
+    Skips,
+    Stars,
+}
+
+impl NumberField {
+    fn value(&self, details: &SongDetails) -> Option<u64> {
+        let stats = details.stats();
+        match self {
+            NumberField::Year => details.year().map(u64::from),
+            NumberField::Track => details.track_number().map(u64::from),
+            NumberField::Disc => details.disc_number().map(u64::from),
+            NumberField::Plays => Some(u64::from(stats.play_count())),
+            NumberField::Skips => Some(u64::from(stats.skip_count())),
+            // Songs that aren't rated have no stars
+            NumberField::Stars => Some(u64::from(stats.stars().unwrap_or(0))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Filter {
+    /// Looks for the folded `value` in `field`, or in every
+    /// text field, or compares it with them if `whole`
+    Text {
+        field: Option<SearchField>,
+        value: String,
+        whole: bool,
+    },
+    Number(NumberField, Comparison, u64),
+    /// Compares the duration of the song, in whole seconds
+    Duration(Comparison, u64),
+    Favourite(bool),
+}
+
+impl Filter {
+    fn matches(&self, details: &SongDetails) -> bool {
+        match self {
+            Filter::Text {
+                field,
+                value,
+                whole,
+            } => {
+                let fields = match field {
+                    Some(field) => vec![*field],
+                    None => SearchField::ALL.to_vec(),
+                };
+                fields
+                    .iter()
+                    .flat_map(|field| field.values(details))
+                    .map(fold)
+                    .any(|text| match whole {
+                        true => text == *value,
+                        false => text.contains(value.as_str()),
+                    })
+            }
+            Filter::Number(field, comparison, target) => field
+                .value(details)
+                .is_some_and(|value| comparison.holds(value, *target)),
+            Filter::Duration(comparison, target) => details
+                .duration()
+                .is_some_and(|duration| comparison.holds(duration.as_secs(), *target)),
+            Filter::Favourite(favourite) => details.stats().is_favourite() == *favourite,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Term {
+        field: Option<String>,
+        /// `>=`, `<`, ..., in front of the value of a field
+        operator: String,
+        value: String,
+    },
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Open => write!(f, "("),
+            Token::Close => write!(f, ")"),
+            Token::Not => write!(f, "NOT"),
+            Token::And => write!(f, "AND"),
+            Token::Or => write!(f, "OR"),
+            Token::Term {
+                field: Some(field),
+                operator,
+                value,
+            } => write!(f, "{}:{}{}", field, operator, value),
+            Token::Term { value, .. } => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Splits `text` into tokens
+fn lex(text: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '-' => {
+                chars.next();
+                // A dash on its own is a word, not a negation
+                let alone = chars.peek().is_none_or(|c| c.is_whitespace() || *c == ')');
+                tokens.push(match alone {
+                    true => Token::Term {
+                        field: None,
+                        operator: String::new(),
+                        value: String::from("-"),
+                    },
+                    false => Token::Not,
+                });
+            }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Term {
+                    field: None,
+                    operator: String::new(),
+                    value: quoted(&mut chars)?,
+                });
+            }
+            _ => {
+                let word = take_word(&mut chars);
+                tokens.push(match word.as_str() {
+                    "AND" | "&&" => Token::And,
+                    "OR" | "||" | "|" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => match word.split_once(':') {
+                        Some((field, rest)) if !field.is_empty() => {
+                            let split = rest
+                                .find(|c| !matches!(c, '<' | '>' | '='))
+                                .unwrap_or(rest.len());
+                            let (operator, mut value) = rest.split_at(split);
+                            let quoted_value;
+                            if value.is_empty() && chars.peek() == Some(&'"') {
+                                chars.next();
+                                quoted_value = quoted(&mut chars)?;
+                                value = &quoted_value;
+                            }
+                            Token::Term {
+                                field: Some(field.to_lowercase()),
+                                operator: String::from(operator),
+                                value: String::from(value),
+                            }
+                        }
+                        _ => Token::Term {
+                            field: None,
+                            operator: String::new(),
+                            value: word,
+                        },
+                    },
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Takes the characters up to the next space, parenthesis or quote
+fn take_word(chars: &mut Peekable<Chars>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || matches!(c, '(' | ')' | '"') {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+/// Takes the characters up to the closing quote, which is dropped
+fn quoted(chars: &mut Peekable<Chars>) -> Result<String, QueryError> {
+    let mut phrase = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(phrase);
+        }
+        phrase.push(c);
+    }
+    Err(QueryError::UnclosedQuote)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            exprs.push(self.and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    /// Filters next to each other must all match
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut exprs = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::Close) | Some(Token::Or) => break,
+                Some(Token::And) if !exprs.is_empty() => {
+                    self.next();
+                    exprs.push(self.unary()?);
+                }
+                _ => exprs.push(self.unary()?),
+            }
+        }
+        match exprs.len() {
+            0 => match self.peek() {
+                Some(token) => Err(QueryError::Unexpected(token.to_string())),
+                None => Err(QueryError::UnexpectedEnd),
+            },
+            1 => Ok(exprs.remove(0)),
+            _ => Ok(Expr::And(exprs)),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        match self.next() {
+            None => Err(QueryError::UnexpectedEnd),
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    Some(token) => Err(QueryError::Unexpected(token.to_string())),
+                    None => Err(QueryError::UnexpectedEnd),
+                }
+            }
+            Some(Token::Term {
+                field,
+                operator,
+                value,
+            }) => filter(field.as_deref(), operator, value),
+            Some(token) => Err(QueryError::Unexpected(token.to_string())),
+        }
+    }
+}
+
+/// What a field of a filter refers to
+#[derive(Clone, Copy)]
+enum FieldKind {
+    Text(SearchField),
+    Number(NumberField),
+    Duration,
+    Favourite,
+}
+
+impl FieldKind {
+    /// Returns the kind of the field named `name`, if there's one
+    fn from_name(name: &str) -> Option<Self> {
+        let kind = match name {
+            "name" | "title" => FieldKind::Text(SearchField::Name),
+            "artist" => FieldKind::Text(SearchField::Artist),
+            "albumartist" | "album_artist" => FieldKind::Text(SearchField::AlbumArtist),
+            "album" => FieldKind::Text(SearchField::Album),
+            "genre" => FieldKind::Text(SearchField::Genre),
+            "composer" => FieldKind::Text(SearchField::Composer),
+            "comment" => FieldKind::Text(SearchField::Comment),
+            "year" => FieldKind::Number(NumberField::Year),
+            "track" => FieldKind::Number(NumberField::Track),
+            "disc" => FieldKind::Number(NumberField::Disc),
+            "plays" => FieldKind::Number(NumberField::Plays),
+            "skips" => FieldKind::Number(NumberField::Skips),
+            "stars" | "rating" => FieldKind::Number(NumberField::Stars),
+            "duration" | "length" => FieldKind::Duration,
+            "favourite" | "favorite" => FieldKind::Favourite,
+            _ => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Builds the filter comparing `field` with `value`, as told by `operator`
+fn filter(field: Option<&str>, operator: &str, value: &str) -> Result<Expr, QueryError> {
+    let field = match field {
+        Some(field) => field,
+        None => {
+            return Ok(Expr::Filter(Filter::Text {
+                field: None,
+                value: fold(value),
+                whole: false,
+            }))
+        }
+    };
+    let kind =
+        FieldKind::from_name(field).ok_or_else(|| QueryError::UnknownField(String::from(field)))?;
+    let invalid =
+        || QueryError::InvalidValue(String::from(field), format!("{}{}", operator, value));
+    let comparison = match operator {
+        "" | "=" => Comparison::Equal,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        _ => return Err(invalid()),
+    };
+    if value.is_empty() {
+        return Err(invalid());
+    }
+
+    let parse: fn(&str) -> Option<u64> = match kind {
+        FieldKind::Text(text) => {
+            return match operator {
+                "" | "=" => Ok(Expr::Filter(Filter::Text {
+                    field: Some(text),
+                    value: fold(value),
+                    whole: operator == "=",
+                })),
+                _ => Err(invalid()),
+            };
+        }
+        FieldKind::Favourite => {
+            let favourite = match value.to_lowercase().as_str() {
+                "yes" | "true" | "1" => true,
+                "no" | "false" | "0" => false,
+                _ => return Err(invalid()),
+            };
+            return match comparison {
+                Comparison::Equal => Ok(Expr::Filter(Filter::Favourite(favourite))),
+                _ => Err(invalid()),
+            };
+        }
+        FieldKind::Duration => parse_duration,
+        FieldKind::Number(_) => |value| value.parse().ok(),
+    };
+    let make = |comparison: Comparison, value: u64| match kind {
+        FieldKind::Number(field) => Expr::Filter(Filter::Number(field, comparison, value)),
+        _ => Expr::Filter(Filter::Duration(comparison, value)),
+    };
+
+    // Ranges hold both of their ends
+    if let Some((from, to)) = value.split_once("..") {
+        if comparison != Comparison::Equal {
+            return Err(invalid());
+        }
+        let (from, to) = (
+            parse(from).ok_or_else(invalid)?,
+            parse(to).ok_or_else(invalid)?,
+        );
+        return Ok(Expr::And(vec![
+            make(Comparison::GreaterOrEqual, from),
+            make(Comparison::LessOrEqual, to),
+        ]));
+    }
+    let value = parse(value).ok_or_else(invalid)?;
+    Ok(make(comparison, value))
+}
+
+/// Parses a duration, in seconds, written as `1h2m3s`, `4m`, `1:02:03`,
+/// `4:30` or a number of seconds
+fn parse_duration(text: &str) -> Option<u64> {
+    if text.contains(':') {
+        return text.split(':').try_fold(0u64, |total, part| {
+            total.checked_mul(60)?.checked_add(part.parse().ok()?)
+        });
+    }
+    if let Ok(seconds) = text.parse() {
+        return Some(seconds);
+    }
+
+    let mut total = 0u64;
+    let mut number = String::new();
+    for c in text.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' => {
+                let value: u64 = number.parse().ok()?;
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total = total.checked_add(value.checked_mul(unit)?)?;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    match number.is_empty() {
+        true => Some(total),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn song(name: &str, artist: &str, year: u16, seconds: u64) -> SongDetails {
+        let mut details = SongDetails::new(name, Some(artist), Some(year), None);
+        details.set_duration(Duration::from_secs(seconds));
+        details
+    }
+
+    fn songs() -> Vec<SongDetails> {
+        let mut heroes = song("Héroes", "David Bowie", 1977, 370);
+        heroes.set_genres(&["Art Rock"]);
+        heroes.stats_mut().set_favourite(true);
+        heroes.stats_mut().set_stars(Some(5));
+        vec![
+            song("One More Time", "Daft Punk", 2000, 320),
+            song("Digital Love", "Daft Punk", 2001, 301),
+            song("Da Funk (Live)", "Daft Punk", 1997, 200),
+            heroes,
+        ]
+    }
+
+    /// Returns the names of the songs matching `query`
+    fn matching(query: &str) -> Vec<String> {
+        let query = Query::parse(query).unwrap();
+        songs()
+            .iter()
+            .filter(|details| query.matches(details))
+            .map(|details| details.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn matches_words_in_every_field() {
+        assert_eq!(matching("punk love"), ["Digital Love"]);
+        assert_eq!(matching("HEROES"), ["Héroes"]);
+        assert_eq!(matching("rock"), ["Héroes"]);
+        assert_eq!(matching("").len(), 4);
+    }
+
+    #[test]
+    fn matches_quoted_phrases() {
+        assert_eq!(matching("\"more time\""), ["One More Time"]);
+        assert!(matching("\"time more\"").is_empty());
+        assert_eq!(matching("artist:\"david bowie\""), ["Héroes"]);
+        assert_eq!(matching("name:=\"digital love\""), ["Digital Love"]);
+        assert!(matching("name:=digital").is_empty());
+    }
+
+    #[test]
+    fn negates_filters() {
+        assert_eq!(matching("punk -live -love"), ["One More Time"]);
+        assert_eq!(
+            matching("punk NOT year:<2000"),
+            ["One More Time", "Digital Love"]
+        );
+        assert_eq!(matching("--live"), ["Da Funk (Live)"]);
+        // Dashes inside words are kept
+        assert!(matching("daft-punk").is_empty());
+    }
+
+    #[test]
+    fn matches_colons_and_dashes_as_text() {
+        let song = song("Re:Zero - Opening", "Myth & Roid", 2016, 250);
+        let matches = |query: &str| Query::parse(query).unwrap().matches(&song);
+        assert!(matches("\"Re:Zero\""));
+        assert!(matches("\"re:zero\" -"));
+        assert!(matches("\"re:zero\" - opening"));
+        assert!(matches("(opening -)"));
+        assert!(!matches("\"Re:Zero\" -opening"));
+        assert!(!Query::parse("-").unwrap().matches(&songs()[0]));
+    }
+
+    #[test]
+    fn groups_alternatives() {
+        assert_eq!(matching("bowie OR funk"), ["Da Funk (Live)", "Héroes"]);
+        assert_eq!(
+            matching("(bowie OR punk) year:<2000"),
+            ["Da Funk (Live)", "Héroes"]
+        );
+        // AND binds tighter than OR
+        assert_eq!(
+            matching("bowie OR punk AND year:2001"),
+            ["Digital Love", "Héroes"]
+        );
+        assert_eq!(matching("-(punk OR bowie)"), Vec::<String>::new());
+        assert_eq!(matching("love || bowie"), ["Digital Love", "Héroes"]);
+    }
+
+    #[test]
+    fn compares_numbers_and_durations() {
+        assert_eq!(matching("year:>=2000"), ["One More Time", "Digital Love"]);
+        assert_eq!(
+            matching("year:1990..2000"),
+            ["One More Time", "Da Funk (Live)"]
+        );
+        assert_eq!(matching("duration:<5m"), ["Da Funk (Live)"]);
+        assert_eq!(matching("duration:5:01"), ["Digital Love"]);
+        assert_eq!(
+            matching("length:300..5m20s"),
+            ["One More Time", "Digital Love"]
+        );
+        assert_eq!(matching("duration:>=1h"), Vec::<String>::new());
+        assert_eq!(matching("stars:5 favourite:yes"), ["Héroes"]);
+        assert_eq!(matching("favourite:no").len(), 3);
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("4m30s"), Some(270));
+        assert_eq!(parse_duration("1h2m3s"), Some(3723));
+        assert_eq!(parse_duration("4:30"), Some(270));
+        assert_eq!(parse_duration("1:02:03"), Some(3723));
+        assert_eq!(parse_duration("4m30"), None);
+        assert_eq!(parse_duration("4x"), None);
+        assert_eq!(parse_duration("4::30"), None);
+        assert_eq!(parse_duration("99999999999999999999h"), None);
+        assert_eq!(parse_duration("5124095576030431h5124095576030431h"), None);
+        assert_eq!(parse_duration("307445734561825860:59:59"), None);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let error = |query: &str| Query::parse(query).unwrap_err();
+        assert_eq!(error("\"open"), QueryError::UnclosedQuote);
+        assert_eq!(error("artist:\"open"), QueryError::UnclosedQuote);
+        assert_eq!(error("(bowie"), QueryError::UnexpectedEnd);
+        assert_eq!(error("bowie OR"), QueryError::UnexpectedEnd);
+        assert_eq!(error("-("), QueryError::UnexpectedEnd);
+        assert_eq!(error("bowie)"), QueryError::Unexpected(String::from(")")));
+        assert_eq!(error("()"), QueryError::Unexpected(String::from(")")));
+        assert_eq!(
+            error("OR bowie"),
+            QueryError::Unexpected(String::from("OR"))
+        );
+        assert_eq!(
+            error("foo:1"),
+            QueryError::UnknownField(String::from("foo"))
+        );
+        assert_eq!(
+            error("foo:bar"),
+            QueryError::UnknownField(String::from("foo"))
+        );
+        assert_eq!(
+            error("foo:>"),
+            QueryError::UnknownField(String::from("foo"))
+        );
+        assert_eq!(
+            error("year:abc"),
+            QueryError::InvalidValue(String::from("year"), String::from("abc"))
+        );
+        assert_eq!(
+            error("artist:>x"),
+            QueryError::InvalidValue(String::from("artist"), String::from(">x"))
+        );
+        assert_eq!(
+            error("year:>1990..2000"),
+            QueryError::InvalidValue(String::from("year"), String::from(">1990..2000"))
+        );
+        assert_eq!(
+            error("favourite:maybe"),
+            QueryError::InvalidValue(String::from("favourite"), String::from("maybe"))
+        );
+        assert_eq!(
+            error("duration:99999999999999999999h"),
+            QueryError::InvalidValue(
+                String::from("duration"),
+                String::from("99999999999999999999h")
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_the_text_typed() {
+        let query = Query::parse("Artist:\"Daft Punk\"  -live").unwrap();
+        assert_eq!(query.text(), "Artist:\"Daft Punk\"  -live");
+        let saved = serde_json::to_string(&query).unwrap();
+        let loaded: Query = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.text(), query.text());
+        assert!(serde_json::from_str::<Query>("\"(open\"").is_err());
+    }
+}
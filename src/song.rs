@@ -1,43 +1,201 @@
-use std::{ffi::OsString, time::Duration};
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io::ErrorKind,
+    path::Path,
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    artwork::Artwork,
+    integrity::{FileStatus, Fingerprint},
+    loudness::{Loudness, LoudnessError, ReplayGain},
+    lyrics::Lyrics,
+    properties::{AudioProperties, ProbeError},
+    schema::{self, DocumentKind},
+    stats::ListeningStats,
+    storage::{Collection, Storage},
+    tags::TagError,
+};
 
 /// Represents a song. A Song is any file which can
 /// be reproduced.
 #[derive(Deserialize, Serialize, Clone, Debug, PartialOrd, Default)]
 pub struct Song {
+    /// Identifies the song across moves and renames. Songs saved before
+    /// ids existed get the nil id until `PlaylistManager::load` gives them
+    /// one.
+    #[serde(default)]
+    id: Uuid,
+
     /// Position of the file in the file system
     #[serde(skip)]
     path: OsString,
-    #[serde(skip)]
+    /// Position of the file as saved: relative to the library root when
+    /// the file is inside it, absolute otherwise
+    #[serde(rename = "path", with = "crate::os_string")]
+    stored_path: OsString,
+    /// Name of the song_meta file, inside the songs_meta directory
+    #[serde(with = "crate::os_string")]
     details_path: OsString,
 
-    #[serde(rename = "path")]
-    path_string: String,
-    #[serde(rename = "details_path")]
-    details_path_string: String,
-
     /// Detailed information about the song
     #[serde(skip)]
     details: SongDetails,
+    /// Set when the song_meta file couldn't be loaded and has to
+    /// be left untouched
+    #[serde(skip)]
+    read_only: bool,
 }
 
 impl Song {
-    pub fn new(path: &str, details_path: &str, details: SongDetails) -> Self {
-        Self {
-            path: OsString::from(path),
-            details_path: OsString::from(details_path),
-            path_string: path.to_string(),
-            details_path_string: details_path.to_string(),
+    /// Creates a song with a new id. Its song_meta file is named
+    /// after that id.
+    pub fn new<P: AsRef<OsStr>>(path: P, details: SongDetails) -> Self {
+        let path = path.as_ref().to_os_string();
+        let mut song = Self {
+            stored_path: path.clone(),
+            path,
             details,
+            ..Default::default()
+        };
+        song.set_id(Uuid::new_v4());
+        song
+    }
+
+    /// Creates a song with a new id for the file at `path`. Its details
+    /// are read from the tags and properties of the file, and it's named
+    /// after the file if it has no title.
+    pub fn from_file<P: AsRef<OsStr>>(path: P) -> Self {
+        let mut song = Self::new(path, SongDetails::default());
+        song.rebuild_details();
+        song
+    }
+
+    /// Resolves the saved path of the song against `library_root` and
+    /// loads its details from `storage`. The path is resolved even if
+    /// the details can't be loaded, in which case they are left as they
+    /// were. Returns `true` if the details were upgraded from an older
+    /// version of the format, and have to be saved again.
+    pub fn load(&mut self, library_root: &Path, storage: &dyn Storage) -> Result<bool, LoadError> {
+        let stored = Path::new(&self.stored_path);
+        self.path = if stored.is_relative() {
+            library_root.join(stored).into_os_string()
+        } else {
+            self.stored_path.clone()
+        };
+        let (details, upgraded) = SongDetails::read(storage, &self.details_path)?;
+        self.details = details;
+        Ok(upgraded)
+    }
+
+    /// Handles `err`, returned by `Song::load`, as told by `policy`.
+    /// Returns `false` if the song should be left out.
+    pub(crate) fn recover(
+        &mut self,
+        err: &LoadError,
+        policy: LoadPolicy,
+        storage: &dyn Storage,
+    ) -> bool {
+        match policy {
+            LoadPolicy::Fail | LoadPolicy::Skip => false,
+            // Without a song_meta file there's nothing to keep safe
+            LoadPolicy::Keep if matches!(err, LoadError::NotFound(_)) => {
+                self.rebuild_details();
+                true
+            }
+            LoadPolicy::Keep => {
+                self.read_only = true;
+                true
+            }
+            // Files written by a newer version are still valid, and
+            // the ones that couldn't be upgraded may become so: they
+            // mustn't be replaced
+            LoadPolicy::Repair
+                if matches!(
+                    err,
+                    LoadError::UnsupportedVersion(..) | LoadError::Migration(..)
+                ) =>
+            {
+                self.read_only = true;
+                true
+            }
+            LoadPolicy::Repair => {
+                if storage.exists(Collection::Songs, &self.details_path) {
+                    let mut broken = self.details_path.clone();
+                    broken.push(".broken");
+                    if storage
+                        .rename(Collection::Songs, &self.details_path, &broken)
+                        .is_err()
+                    {
+                        self.read_only = true;
+                        return true;
+                    }
+                }
+                self.rebuild_details();
+                true
+            }
+        }
+    }
+
+    /// Rebuilds the song details from the song file: its tags, its
+    /// properties and, if it has no title, its name
+    pub(crate) fn rebuild_details(&mut self) {
+        self.details = SongDetails::from_file(&self.path).unwrap_or_default();
+        if self.details.name().is_empty() {
+            if let Some(name) = Path::new(&self.path).file_stem() {
+                self.details.set_name(&name.to_string_lossy());
+            }
         }
+        let _ = self.probe();
     }
 
-    pub fn load(mut self) -> Self {
-        self.path = OsString::from(&self.path_string);
-        self.details_path = OsString::from(&self.details_path_string);
-        self.details = SongDetails::load(&self.details_path);
-        self
+    /// Returns `true` if the song_meta file of the song couldn't
+    /// be loaded and is left untouched when saving
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Gives the song the id `id`, renaming
+    /// its song_meta file accordingly
+    pub(crate) fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+        self.details_path = OsString::from(crate::file_name_from_id(&id));
+    }
+
+    /// Saves the path of the song relative to `library_root`
+    /// if the file is inside it
+    pub(crate) fn set_library_root(&mut self, library_root: &Path) {
+        self.stored_path = match Path::new(&self.path).strip_prefix(library_root) {
+            Ok(relative) => relative.as_os_str().to_os_string(),
+            Err(_) => self.path.clone(),
+        };
+    }
+
+    /// Moves the song from `from` to `to`, if its file is inside `from`.
+    /// Returns `true` if it was.
+    pub(crate) fn relocate(&mut self, from: &Path, to: &Path) -> bool {
+        let relative = match Path::new(&self.path).strip_prefix(from) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => return false,
+        };
+        self.path = if relative.as_os_str().is_empty() {
+            to.as_os_str().to_os_string()
+        } else {
+            to.join(relative).into_os_string()
+        };
+        true
     }
 
     /// Returns the position of the song in the file system.
@@ -45,7 +203,14 @@ impl Song {
         &self.path
     }
 
-    /// Returns the position of the song_meta file in the file system.
+    /// Returns the position of the song as saved, which is relative
+    /// to the library root if the song is inside it
+    pub fn stored_path(&self) -> &OsString {
+        &self.stored_path
+    }
+
+    /// Returns the name of the song_meta file, inside
+    /// the songs_meta directory.
     pub fn details_path(&self) -> &OsString {
         &self.details_path
     }
@@ -54,6 +219,55 @@ impl Song {
         &self.details
     }
 
+    /// Returns the name of the file holding the lyrics of the
+    /// song, next to its song_meta file
+    pub fn lyrics_path(&self) -> OsString {
+        Path::new(&self.details_path)
+            .with_extension("lrc")
+            .into_os_string()
+    }
+
+    /// Loads the lyrics of the song. They're looked for, in order, in
+    /// `storage`, in an LRC file next to the song file and in the tags
+    /// of the song file.
+    pub fn load_lyrics(&self, storage: &dyn Storage) -> Result<Option<Lyrics>, LoadError> {
+        let stored = self.lyrics_path();
+        let beside = Path::new(&self.path).with_extension("lrc").into_os_string();
+        let candidates = [
+            (
+                storage.read(Collection::Songs, &stored),
+                storage.location(Collection::Songs, &stored),
+            ),
+            (std::fs::read(&beside), beside),
+        ];
+        for (read, location) in candidates {
+            match read {
+                Ok(bytes) => return Ok(Some(Lyrics::parse(&String::from_utf8_lossy(&bytes)))),
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(LoadError::from_io(&location, err)),
+            }
+        }
+
+        // Files without tags, or not supported, just have no lyrics
+        Ok(crate::tags::read(&self.path)
+            .ok()
+            .and_then(|tags| tags.lyrics().cloned()))
+    }
+
+    /// Stores `lyrics` next to the song_meta file, in the LRC format.
+    /// `None` removes the stored lyrics.
+    pub fn save_lyrics(
+        &self,
+        storage: &dyn Storage,
+        lyrics: Option<&Lyrics>,
+    ) -> std::io::Result<()> {
+        let name = self.lyrics_path();
+        match lyrics {
+            Some(lyrics) => storage.write(Collection::Songs, &name, lyrics.to_lrc().as_bytes()),
+            None => storage.remove(Collection::Songs, &name),
+        }
+    }
+
     pub fn details_mut(&mut self) -> &mut SongDetails {
         &mut self.details
     }
@@ -61,35 +275,251 @@ impl Song {
     pub fn to_json_str(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
+
+    /// Reads the technical properties of the song file, storing
+    /// them in the song details along with its exact duration
+    pub fn probe(&mut self) -> Result<(), ProbeError> {
+        let properties = crate::properties::probe(&self.path)?;
+        self.details.set_properties(properties);
+        Ok(())
+    }
+
+    /// Decodes the song file to measure its loudness, which sets the
+    /// track ReplayGain values. Silent songs are left as they are.
+    pub fn analyze_loudness(&mut self) -> Result<(), LoudnessError> {
+        if let Some(loudness) = crate::loudness::analyze(&self.path)? {
+            self.details.set_loudness(loudness);
+        }
+        Ok(())
+    }
+
+    /// Records the fingerprint of the song file as it is now. Files
+    /// changed on purpose should be fingerprinted again, so that they
+    /// aren't reported as modified.
+    pub fn fingerprint(&mut self) -> std::io::Result<()> {
+        let fingerprint = Fingerprint::compute(&self.path)?;
+        self.details.set_fingerprint(fingerprint);
+        Ok(())
+    }
+
+    /// Checks the song file against its recorded fingerprint. Modified
+    /// files are probed again: they're corrupt if their audio stream can't
+    /// be read anymore or doesn't last as long as it used to.
+    pub fn verify(&self) -> FileStatus {
+        let status = match self.details.fingerprint() {
+            Some(fingerprint) => fingerprint.verify(&self.path),
+            None => return FileStatus::Unverified,
+        };
+        if status != FileStatus::Modified {
+            return status;
+        }
+
+        let probed = match crate::properties::probe(&self.path) {
+            Ok(probed) => probed,
+            Err(_) => return FileStatus::Corrupt,
+        };
+        match self.details.properties() {
+            Some(properties)
+                if probed.duration().abs_diff(*properties.duration()) > DURATION_TOLERANCE =>
+            {
+                FileStatus::Corrupt
+            }
+            _ => FileStatus::Modified,
+        }
+    }
+
+    /// Copies metadata between the song details and the tags embedded
+    /// in the song file, in the direction given by `sync`.
+    ///
+    /// When reading from the file, only the values actually found in its
+    /// tags replace the current ones, so an untagged file never erases
+    /// existing details. When writing, the file is left untouched if its
+    /// tags already match the details, and its fingerprint, if recorded,
+    /// is taken again after it's written.
+    pub fn sync_tags(&mut self, sync: TagSync) -> Result<(), TagError> {
+        match sync {
+            TagSync::FromFile => {
+                let tags = crate::tags::read(&self.path)?;
+                self.details.merge_tags(tags.details());
+            }
+            // Details of read only songs weren't loaded,
+            // they'd only erase the tags
+            TagSync::ToFile if self.read_only => {}
+            TagSync::ToFile => {
+                let tags = crate::tags::read(&self.path)?;
+                if !tags.details().same_tags(&self.details) {
+                    crate::tags::write(&self.path, &self.details)?;
+                    if self.details.fingerprint().is_some() {
+                        self.fingerprint()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How much the duration of a modified song file can change
+/// before the file is considered truncated
+const DURATION_TOLERANCE: Duration = Duration::from_millis(500);
+
+/// The direction in which metadata is copied between
+/// the song details and the tags of the song file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagSync {
+    /// Tags are overwritten with the song details
+    ToFile,
+    /// Song details are updated with the tags
+    FromFile,
 }
 
 impl PartialEq for Song {
     fn eq(&self, other: &Self) -> bool {
-        self.path == other.path
+        self.id == other.id
     }
 }
 
 impl Eq for Song {}
 
+/// What to do with a song whose details can't be loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LoadPolicy {
+    /// Loading fails with the error
+    Fail,
+    /// The song is left out. Its song_meta file is left untouched, but
+    /// playlists saved afterwards don't hold the song anymore.
+    Skip,
+    /// Details are rebuilt from the song file, and the original
+    /// song_meta file is set aside with a `.broken` extension
+    Repair,
+    /// The song is kept with empty details, and its song_meta file
+    /// is never overwritten. Songs without a song_meta file get their
+    /// details from the song file instead, as with `Repair`.
+    #[default]
+    Keep,
+}
+
+/// Errors describing why a song_meta or playlist
+/// file couldn't be loaded
+#[derive(Debug)]
+pub enum LoadError {
+    NotFound(OsString),
+    Unreadable(OsString, std::io::Error),
+    Malformed(OsString, serde_json::Error),
+    /// The file was written by a newer version of the format
+    UnsupportedVersion(OsString, u64),
+    /// The file couldn't be upgraded from the given
+    /// version of the format to the next one
+    Migration(OsString, u64, String),
+}
+
+impl LoadError {
+    /// Returns the path of the file that couldn't be loaded
+    pub fn path(&self) -> &OsString {
+        match self {
+            LoadError::NotFound(path)
+            | LoadError::Unreadable(path, _)
+            | LoadError::Malformed(path, _)
+            | LoadError::UnsupportedVersion(path, _)
+            | LoadError::Migration(path, _, _) => path,
+        }
+    }
+
+    pub(crate) fn from_io(path: &OsString, err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::NotFound => LoadError::NotFound(path.clone()),
+            _ => LoadError::Unreadable(path.clone(), err),
+        }
+    }
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::NotFound(path) => {
+                writeln!(f, "`{}` doesn't exist", path.to_string_lossy())
+            }
+            LoadError::Unreadable(path, err) => {
+                writeln!(f, "`{}` couldn't be read", path.to_string_lossy())?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            LoadError::Malformed(path, err) => {
+                writeln!(f, "`{}` isn't valid", path.to_string_lossy())?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            LoadError::UnsupportedVersion(path, version) => {
+                writeln!(
+                    f,
+                    "`{}` has version {}, which is newer than the supported one",
+                    path.to_string_lossy(),
+                    version
+                )
+            }
+            LoadError::Migration(path, version, cause) => {
+                writeln!(
+                    f,
+                    "`{}` couldn't be upgraded from version {}",
+                    path.to_string_lossy(),
+                    version
+                )?;
+                writeln!(f, "Here's the cause: {}", cause)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 /// Holds detailed information about
 /// a song.
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd)]
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, PartialOrd, Default)]
 pub struct SongDetails {
     name: String,
     artist: Option<String>,
     year: Option<u16>,
     duration: Option<Duration>,
-}
 
-impl Default for SongDetails {
-    fn default() -> Self {
-        Self {
-            name: String::new(),
-            artist: None,
-            year: None,
-            duration: None,
-        }
-    }
+    // Fields below were added after the first song_meta files were
+    // written, so they all need a default to keep those files loading.
+    #[serde(default)]
+    album: Option<String>,
+    #[serde(default)]
+    album_artist: Option<String>,
+    #[serde(default)]
+    track_number: Option<u16>,
+    #[serde(default)]
+    track_total: Option<u16>,
+    #[serde(default)]
+    disc_number: Option<u16>,
+    #[serde(default)]
+    disc_total: Option<u16>,
+    #[serde(default)]
+    genres: Vec<String>,
+    #[serde(default)]
+    composer: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+    /// Technical properties probed from the song file
+    #[serde(default)]
+    properties: Option<AudioProperties>,
+    /// Identifies the content of the song file, to detect changes
+    #[serde(default)]
+    fingerprint: Option<Fingerprint>,
+    #[serde(default)]
+    stats: ListeningStats,
+    /// Cover of the song, usually shared by its whole album
+    #[serde(default)]
+    artwork: Option<Artwork>,
+    /// Picture of the artist, as returned by plugins
+    #[serde(default)]
+    artist_artwork: Option<Artwork>,
+    /// Loudness measured on the song file
+    #[serde(default)]
+    loudness: Option<Loudness>,
+    /// Either read from tags or computed from the
+    /// loudness of the song and of its album
+    #[serde(default)]
+    replay_gain: ReplayGain,
 }
 
 impl SongDetails {
@@ -101,32 +531,44 @@ impl SongDetails {
     ) -> Self {
         Self {
             name: name.to_string(),
-            artist: match artist {
-                Some(artist) => Some(artist.to_string()),
-                None => None,
-            },
+            artist: artist.map(|artist| artist.to_string()),
             year,
             duration,
+            ..Default::default()
         }
     }
 
-    /// Loads song details from a config file
-    pub fn load(details: &OsString) -> Self {
-        let file = match std::fs::read_to_string(details) {
-            Ok(file) => file,
-            Err(_err) => {
-                return SongDetails::default();
-            }
-        };
+    /// Loads song details from the song_meta file `name` in `storage`,
+    /// upgrading it first if it's from an older version of the format
+    pub fn load(storage: &dyn Storage, name: &OsStr) -> Result<Self, LoadError> {
+        Self::read(storage, name).map(|(details, _)| details)
+    }
 
-        let details: SongDetails = match serde_json::from_str(&file) {
-            Ok(details) => details,
-            Err(_err) => {
-                return SongDetails::default();
-            }
-        };
+    /// Loads song details like `load`, along with `true` if they were upgraded
+    pub(crate) fn read(storage: &dyn Storage, name: &OsStr) -> Result<(Self, bool), LoadError> {
+        let location = || storage.location(Collection::Songs, name);
+        let (value, upgraded) =
+            schema::read(storage, Collection::Songs, name, DocumentKind::SongDetails)?;
+        let details =
+            serde_json::from_value(value).map_err(|err| LoadError::Malformed(location(), err))?;
+        Ok((details, upgraded))
+    }
 
-        details
+    /// Saves song details to the song_meta file `name` in
+    /// `storage`, along with the version of its format
+    pub fn save(&self, storage: &dyn Storage, name: &OsStr) -> std::io::Result<()> {
+        storage.write(Collection::Songs, name, &self.to_bytes()?)
+    }
+
+    /// Returns the content of the song_meta file of these details
+    pub(crate) fn to_bytes(&self) -> serde_json::Result<Vec<u8>> {
+        schema::to_bytes(self, DocumentKind::SongDetails)
+    }
+
+    /// Builds song details from the tags embedded in the
+    /// audio file at `path`
+    pub fn from_file(path: &OsString) -> Result<Self, TagError> {
+        Ok(crate::tags::read(path)?.into_details())
     }
 
     pub fn set_name(&mut self, name: &str) {
@@ -145,6 +587,89 @@ impl SongDetails {
         self.duration = Some(duration);
     }
 
+    pub fn set_fingerprint(&mut self, fingerprint: Fingerprint) {
+        self.fingerprint = Some(fingerprint);
+    }
+
+    /// Stores the technical properties of the song file,
+    /// whose duration replaces the current one
+    pub fn set_properties(&mut self, properties: AudioProperties) {
+        self.duration = Some(*properties.duration());
+        self.properties = Some(properties);
+    }
+
+    /// Sets the cover of the song, or removes it if `None`
+    pub fn set_artwork(&mut self, artwork: Option<Artwork>) {
+        self.artwork = artwork;
+    }
+
+    /// Sets the picture of the artist, or removes it if `None`
+    pub fn set_artist_artwork(&mut self, artwork: Option<Artwork>) {
+        self.artist_artwork = artwork;
+    }
+
+    /// Stores the loudness measured on the song file,
+    /// which replaces the track ReplayGain values
+    pub fn set_loudness(&mut self, loudness: Loudness) {
+        self.replay_gain.set_track(&loudness);
+        self.loudness = Some(loudness);
+    }
+
+    /// Sets the album ReplayGain values from
+    /// the loudness of the album of the song
+    pub fn set_album_loudness(&mut self, loudness: &Loudness) {
+        self.replay_gain.set_album(loudness);
+    }
+
+    pub fn set_replay_gain(&mut self, replay_gain: ReplayGain) {
+        self.replay_gain = replay_gain;
+    }
+
+    pub fn set_album(&mut self, album: &str) {
+        self.album = Some(String::from(album));
+    }
+
+    pub fn set_album_artist(&mut self, album_artist: &str) {
+        self.album_artist = Some(String::from(album_artist));
+    }
+
+    pub fn set_track_number(&mut self, track_number: u16) {
+        self.track_number = Some(track_number);
+    }
+
+    pub fn set_track_total(&mut self, track_total: u16) {
+        self.track_total = Some(track_total);
+    }
+
+    pub fn set_disc_number(&mut self, disc_number: u16) {
+        self.disc_number = Some(disc_number);
+    }
+
+    pub fn set_disc_total(&mut self, disc_total: u16) {
+        self.disc_total = Some(disc_total);
+    }
+
+    /// Replaces every genre of the song with `genres`
+    pub fn set_genres(&mut self, genres: &[&str]) {
+        self.genres = genres.iter().map(|genre| genre.to_string()).collect();
+    }
+
+    /// Adds `genre` to the song genres, but only if
+    /// it wasn't already there
+    pub fn add_genre(&mut self, genre: &str) {
+        if !self.genres.iter().any(|item| item == genre) {
+            self.genres.push(String::from(genre));
+        }
+    }
+
+    pub fn set_composer(&mut self, composer: &str) {
+        self.composer = Some(String::from(composer));
+    }
+
+    pub fn set_comment(&mut self, comment: &str) {
+        self.comment = Some(String::from(comment));
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -164,13 +689,172 @@ impl SongDetails {
         self.duration.as_ref()
     }
 
+    /// Returns how the song has been listened to and rated
+    pub fn stats(&self) -> &ListeningStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut ListeningStats {
+        &mut self.stats
+    }
+
+    /// Returns the fingerprint of the song file, if it has been taken
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    /// Returns the technical properties of the song file,
+    /// if it has been probed
+    pub fn properties(&self) -> Option<&AudioProperties> {
+        self.properties.as_ref()
+    }
+
+    pub fn artwork(&self) -> Option<&Artwork> {
+        self.artwork.as_ref()
+    }
+
+    pub fn artist_artwork(&self) -> Option<&Artwork> {
+        self.artist_artwork.as_ref()
+    }
+
+    pub fn loudness(&self) -> Option<&Loudness> {
+        self.loudness.as_ref()
+    }
+
+    pub fn replay_gain(&self) -> &ReplayGain {
+        &self.replay_gain
+    }
+
+    pub(crate) fn replay_gain_mut(&mut self) -> &mut ReplayGain {
+        &mut self.replay_gain
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.album.as_deref()
+    }
+
+    pub fn album_artist(&self) -> Option<&str> {
+        self.album_artist.as_deref()
+    }
+
+    pub fn track_number(&self) -> Option<u16> {
+        self.track_number
+    }
+
+    pub fn track_total(&self) -> Option<u16> {
+        self.track_total
+    }
+
+    pub fn disc_number(&self) -> Option<u16> {
+        self.disc_number
+    }
+
+    pub fn disc_total(&self) -> Option<u16> {
+        self.disc_total
+    }
+
+    /// Returns all the genres of the song
+    pub fn genres(&self) -> &[String] {
+        &self.genres
+    }
+
+    /// Returns the first genre of the song, if any
+    pub fn genre(&self) -> Option<&str> {
+        self.genres.first().map(|genre| &genre[..])
+    }
+
+    pub fn composer(&self) -> Option<&str> {
+        self.composer.as_deref()
+    }
+
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Replaces the fields stored in tags with the ones set in `tags`.
+    /// Fields missing from `tags` are left as they are.
+    pub(crate) fn merge_tags(&mut self, tags: &SongDetails) {
+        if !tags.name.is_empty() {
+            self.name = tags.name.clone();
+        }
+        if !tags.genres.is_empty() {
+            self.genres = tags.genres.clone();
+        }
+
+        fn merge<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if value.is_some() {
+                *target = value.clone();
+            }
+        }
+        merge(&mut self.artist, &tags.artist);
+        merge(&mut self.year, &tags.year);
+        merge(&mut self.album, &tags.album);
+        merge(&mut self.album_artist, &tags.album_artist);
+        merge(&mut self.track_number, &tags.track_number);
+        merge(&mut self.track_total, &tags.track_total);
+        merge(&mut self.disc_number, &tags.disc_number);
+        merge(&mut self.disc_total, &tags.disc_total);
+        merge(&mut self.composer, &tags.composer);
+        merge(&mut self.comment, &tags.comment);
+        self.replay_gain.merge(&tags.replay_gain);
+    }
+
+    /// Returns `true` if every field stored in tags
+    /// has the same value in `self` and `other`
+    pub(crate) fn same_tags(&self, other: &SongDetails) -> bool {
+        self.name == other.name
+            && self.artist == other.artist
+            && self.year == other.year
+            && self.album == other.album
+            && self.album_artist == other.album_artist
+            && self.track_number == other.track_number
+            && self.track_total == other.track_total
+            && self.disc_number == other.disc_number
+            && self.disc_total == other.disc_total
+            && self.genres == other.genres
+            && self.composer == other.composer
+            && self.comment == other.comment
+    }
+
+    /// Fills the fields missing from `self` with the ones of `other`,
+    /// which describes the same song, and adds up their listening stats.
+    /// What describes the song file, e.g. its properties, isn't merged.
+    pub fn merge(&mut self, other: &SongDetails) {
+        if self.name.is_empty() {
+            self.name = other.name.clone();
+        }
+        if self.genres.is_empty() {
+            self.genres = other.genres.clone();
+        }
+
+        fn fill<T: Clone>(target: &mut Option<T>, value: &Option<T>) {
+            if target.is_none() {
+                *target = value.clone();
+            }
+        }
+        fill(&mut self.artist, &other.artist);
+        fill(&mut self.year, &other.year);
+        fill(&mut self.duration, &other.duration);
+        fill(&mut self.album, &other.album);
+        fill(&mut self.album_artist, &other.album_artist);
+        fill(&mut self.track_number, &other.track_number);
+        fill(&mut self.track_total, &other.track_total);
+        fill(&mut self.disc_number, &other.disc_number);
+        fill(&mut self.disc_total, &other.disc_total);
+        fill(&mut self.composer, &other.composer);
+        fill(&mut self.comment, &other.comment);
+        fill(&mut self.artwork, &other.artwork);
+        fill(&mut self.artist_artwork, &other.artist_artwork);
+        self.stats.merge(&other.stats);
+    }
+
     pub fn duration_str(&self) -> Option<String> {
         if let Some(duration) = self.duration {
             let secs = duration.as_secs();
             let mut mins: u64 = secs / 60;
             if mins > 60 {
                 let hours: u64 = mins / 60;
-                mins = mins - hours * 60;
+                mins -= hours * 60;
                 return Some(format!(
                     "{}:{:02}:{:02}",
                     hours,
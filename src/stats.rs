@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Highest number of stars a song can be rated with
+pub const MAX_STARS: u8 = 5;
+
+/// How a song has been listened to
+/// and rated by the user
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, PartialOrd, Default)]
+#[serde(default)]
+pub struct ListeningStats {
+    play_count: u32,
+    skip_count: u32,
+    first_played: Option<DateTime<Utc>>,
+    last_played: Option<DateTime<Utc>>,
+    /// From 1 to `MAX_STARS`, `None` if the song isn't rated
+    stars: Option<u8>,
+    favourite: bool,
+}
+
+impl ListeningStats {
+    /// Records that the song was played, or skipped, at `at`
+    pub fn record(&mut self, event: PlaybackEvent, at: DateTime<Utc>) {
+        match event {
+            PlaybackEvent::Played => {
+                self.play_count += 1;
+                if self.first_played.is_none() {
+                    self.first_played = Some(at);
+                }
+                self.last_played = Some(at);
+            }
+            PlaybackEvent::Skipped => self.skip_count += 1,
+        }
+    }
+
+    /// Rates the song with `stars`, which are capped to `MAX_STARS`.
+    /// `None`, or zero stars, removes the rating.
+    pub fn set_stars(&mut self, stars: Option<u8>) {
+        self.stars = stars
+            .filter(|stars| *stars > 0)
+            .map(|stars| stars.min(MAX_STARS));
+    }
+
+    pub fn set_favourite(&mut self, favourite: bool) {
+        self.favourite = favourite;
+    }
+
+    /// Adds the plays and skips of `other`, the stats of the same song
+    /// kept apart, to these ones. The best rating is kept, and the song
+    /// is a favourite if it was in either.
+    pub fn merge(&mut self, other: &ListeningStats) {
+        self.play_count = self.play_count.saturating_add(other.play_count);
+        self.skip_count = self.skip_count.saturating_add(other.skip_count);
+        self.first_played = match (self.first_played, other.first_played) {
+            (Some(mine), Some(theirs)) => Some(mine.min(theirs)),
+            (mine, theirs) => mine.or(theirs),
+        };
+        self.last_played = self.last_played.max(other.last_played);
+        self.stars = self.stars.max(other.stars);
+        self.favourite |= other.favourite;
+    }
+
+    /// Returns how many times the song was played to the end
+    pub fn play_count(&self) -> u32 {
+        self.play_count
+    }
+
+    pub fn skip_count(&self) -> u32 {
+        self.skip_count
+    }
+
+    pub fn first_played(&self) -> Option<&DateTime<Utc>> {
+        self.first_played.as_ref()
+    }
+
+    pub fn last_played(&self) -> Option<&DateTime<Utc>> {
+        self.last_played.as_ref()
+    }
+
+    pub fn stars(&self) -> Option<u8> {
+        self.stars
+    }
+
+    pub fn is_favourite(&self) -> bool {
+        self.favourite
+    }
+}
+
+/// What happened to the song the player was on
+/// when the queue moved past it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackEvent {
+    /// The song was played to the end
+    Played,
+    /// The user moved to another song before the end
+    Skipped,
+}
@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::fmt::Display;
+
+/// Errors describing why a storage couldn't be opened or queried
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    #[cfg(feature = "sqlite")]
+    Database(rusqlite::Error),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::Io(err) => {
+                writeln!(f, "An I/O error occured while accessing the storage")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            #[cfg(feature = "sqlite")]
+            StorageError::Database(err) => {
+                writeln!(f, "The database couldn't be accessed")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+        }
+    }
+}
+
+impl Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        StorageError::Database(err)
+    }
+}
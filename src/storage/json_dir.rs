@@ -0,0 +1,135 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{Collection, Storage};
+
+/// Extension of the files documents are written to before taking
+/// the place of the old ones
+const TMP_EXTENSION: &str = "tmp";
+
+/// Saves each document in its own file: songs in the songs_meta
+/// directory, playlists in the playlists_meta one
+pub struct JsonDirStorage {
+    songs_meta: PathBuf,
+    playlists_meta: PathBuf,
+}
+
+impl JsonDirStorage {
+    pub fn new(songs_meta: OsString, playlists_meta: OsString) -> Self {
+        Self {
+            songs_meta: PathBuf::from(songs_meta),
+            playlists_meta: PathBuf::from(playlists_meta),
+        }
+    }
+
+    pub fn songs_meta(&self) -> &Path {
+        &self.songs_meta
+    }
+
+    pub fn playlists_meta(&self) -> &Path {
+        &self.playlists_meta
+    }
+
+    fn dir(&self, collection: Collection) -> &Path {
+        match collection {
+            Collection::Songs => &self.songs_meta,
+            Collection::Playlists => &self.playlists_meta,
+        }
+    }
+
+    fn path(&self, collection: Collection, name: &OsStr) -> PathBuf {
+        self.dir(collection).join(name)
+    }
+}
+
+impl Storage for JsonDirStorage {
+    fn read(&self, collection: Collection, name: &OsStr) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path(collection, name))
+    }
+
+    fn write(&self, collection: Collection, name: &OsStr, data: &[u8]) -> std::io::Result<()> {
+        // Written aside and moved in place once it's on disk, so a crash
+        // never leaves a document half written
+        let path = self.path(collection, name);
+        let mut tmp = OsString::from(".");
+        tmp.push(name);
+        tmp.push(".");
+        tmp.push(TMP_EXTENSION);
+        let tmp = self.path(collection, &tmp);
+
+        let written = File::create(&tmp).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        });
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(err);
+        }
+        std::fs::rename(&tmp, path)
+    }
+
+    fn remove(&self, collection: Collection, name: &OsStr) -> std::io::Result<()> {
+        match std::fs::remove_file(self.path(collection, name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, collection: Collection, from: &OsStr, to: &OsStr) -> std::io::Result<()> {
+        std::fs::rename(self.path(collection, from), self.path(collection, to))
+    }
+
+    fn exists(&self, collection: Collection, name: &OsStr) -> bool {
+        self.path(collection, name).is_file()
+    }
+
+    fn list(&self, collection: Collection) -> std::io::Result<Vec<OsString>> {
+        let mut names = vec![];
+        for entry in std::fs::read_dir(self.dir(collection))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let is_tmp = Path::new(&name).extension() == Some(OsStr::new(TMP_EXTENSION));
+            if entry.file_type()?.is_file() && !is_tmp {
+                names.push(name);
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn directory(&self, collection: Collection) -> Option<&Path> {
+        Some(self.dir(collection))
+    }
+
+    fn location(&self, collection: Collection, name: &OsStr) -> OsString {
+        self.path(collection, name).into_os_string()
+    }
+
+    /// Renames, removals and new files only survive a crash of the
+    /// system once the directories holding them are synced
+    fn flush(&self) -> std::io::Result<()> {
+        for dir in [&self.songs_meta, &self.playlists_meta] {
+            match sync_dir(dir) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened as files elsewhere,
+/// and their entries are synced with the files
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
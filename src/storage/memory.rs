@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+    sync::{Mutex, MutexGuard},
+};
+
+use super::{Collection, Storage};
+
+/// Keeps every document in memory, e.g. for tests or
+/// for applications that don't save their library
+#[derive(Default)]
+pub struct MemoryStorage {
+    documents: Mutex<BTreeMap<(Collection, OsString), Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn documents(&self) -> MutexGuard<'_, BTreeMap<(Collection, OsString), Vec<u8>>> {
+        // Documents are replaced as a whole, so they're
+        // never left half written by a panic
+        self.documents
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, collection: Collection, name: &OsStr) -> std::io::Result<Vec<u8>> {
+        self.documents()
+            .get(&(collection, name.to_os_string()))
+            .cloned()
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn write(&self, collection: Collection, name: &OsStr, data: &[u8]) -> std::io::Result<()> {
+        self.documents()
+            .insert((collection, name.to_os_string()), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, collection: Collection, name: &OsStr) -> std::io::Result<()> {
+        self.documents().remove(&(collection, name.to_os_string()));
+        Ok(())
+    }
+
+    fn rename(&self, collection: Collection, from: &OsStr, to: &OsStr) -> std::io::Result<()> {
+        let mut documents = self.documents();
+        let data = documents
+            .remove(&(collection, from.to_os_string()))
+            .ok_or(ErrorKind::NotFound)?;
+        documents.insert((collection, to.to_os_string()), data);
+        Ok(())
+    }
+
+    fn exists(&self, collection: Collection, name: &OsStr) -> bool {
+        self.documents()
+            .contains_key(&(collection, name.to_os_string()))
+    }
+
+    fn list(&self, collection: Collection) -> std::io::Result<Vec<OsString>> {
+        Ok(self
+            .documents()
+            .keys()
+            .filter(|(kind, _)| *kind == collection)
+            .map(|(_, name)| name.clone())
+            .collect())
+    }
+
+    fn location(&self, collection: Collection, name: &OsStr) -> OsString {
+        let mut location = OsString::from(collection.name());
+        location.push("/");
+        location.push(name);
+        location
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+pub use self::error::StorageError;
+pub use self::json_dir::JsonDirStorage;
+pub use self::memory::MemoryStorage;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStorage;
+
+mod error;
+mod json_dir;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+/// The groups documents are kept in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Collection {
+    /// Song details, lyrics and the library file
+    Songs,
+    Playlists,
+}
+
+impl Collection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collection::Songs => "songs",
+            Collection::Playlists => "playlists",
+        }
+    }
+}
+
+/// Where the library, song details and playlists are saved. Each one is
+/// a document, named as its file would be, inside a collection; what a
+/// document holds is up to its reader. Applications can supply their own
+/// backend by implementing this trait.
+pub trait Storage: Send {
+    /// Reads the document `name`. A missing document is an
+    /// error of kind `std::io::ErrorKind::NotFound`.
+    fn read(&self, collection: Collection, name: &OsStr) -> std::io::Result<Vec<u8>>;
+
+    /// Writes the document `name`, replacing it if it exists. The
+    /// document is either replaced as a whole or left as it was.
+    fn write(&self, collection: Collection, name: &OsStr, data: &[u8]) -> std::io::Result<()>;
+
+    /// Removes the document `name`. Removing a
+    /// missing document isn't an error.
+    fn remove(&self, collection: Collection, name: &OsStr) -> std::io::Result<()>;
+
+    /// Renames the document `from` to `to`, replacing `to` if it exists
+    fn rename(&self, collection: Collection, from: &OsStr, to: &OsStr) -> std::io::Result<()>;
+
+    fn exists(&self, collection: Collection, name: &OsStr) -> bool;
+
+    /// Returns the name of every document in `collection`, sorted
+    fn list(&self, collection: Collection) -> std::io::Result<Vec<OsString>>;
+
+    /// Returns where the document `name` is, e.g. the path
+    /// of its file, to tell users about it
+    fn location(&self, collection: Collection, name: &OsStr) -> OsString;
+
+    /// Returns the directory holding the documents of `collection`, if
+    /// each one is a file in it named as the document. Other programs
+    /// can then edit them, and their changes be watched.
+    fn directory(&self, _collection: Collection) -> Option<&Path> {
+        None
+    }
+
+    /// Makes sure what was written so far survives a crash of the
+    /// system, for backends that don't do it on each write
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Makes the writes done by `writes`. Backends that can apply
+    /// many writes at once, e.g. in a transaction, do so.
+    fn batch(&self, writes: &mut dyn FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+        writes()
+    }
+}
+
+/// Copies every document of `from` to `to`, replacing the ones with the
+/// same name. Returns how many documents were copied.
+pub fn copy(from: &dyn Storage, to: &dyn Storage) -> std::io::Result<usize> {
+    let mut copied = 0;
+    to.batch(&mut || {
+        for collection in [Collection::Songs, Collection::Playlists] {
+            for name in from.list(collection)? {
+                to.write(collection, &name, &from.read(collection, &name)?)?;
+                copied += 1;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(copied)
+}
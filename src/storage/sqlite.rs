@@ -0,0 +1,460 @@
+use std::{
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use rusqlite::{params, types::Value, Connection, OptionalExtension};
+use uuid::Uuid;
+
+use super::{Collection, JsonDirStorage, Storage, StorageError};
+use crate::{os_string, song::SongDetails};
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = NORMAL;
+    PRAGMA foreign_keys = ON;
+
+    CREATE TABLE IF NOT EXISTS documents (
+        collection TEXT NOT NULL,
+        name TEXT NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (collection, name)
+    );
+
+    CREATE TABLE IF NOT EXISTS songs (
+        key INTEGER PRIMARY KEY,
+        id TEXT NOT NULL UNIQUE,
+        title TEXT NOT NULL,
+        artist TEXT,
+        album TEXT,
+        album_artist TEXT,
+        year INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS songs_artist ON songs (artist);
+    CREATE INDEX IF NOT EXISTS songs_album ON songs (album, album_artist);
+    CREATE INDEX IF NOT EXISTS songs_year ON songs (year);
+    CREATE VIRTUAL TABLE IF NOT EXISTS songs_search USING fts5 (
+        title, artist, album, album_artist, genres
+    );
+
+    CREATE TABLE IF NOT EXISTS playlists (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        creation_date TEXT
+    );
+    CREATE TABLE IF NOT EXISTS playlist_entries (
+        playlist_id TEXT NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        song_id TEXT NOT NULL,
+        PRIMARY KEY (playlist_id, position)
+    );
+    CREATE INDEX IF NOT EXISTS playlist_entries_song ON playlist_entries (song_id);
+";
+
+/// Saves every document in an SQLite database. Documents are kept as
+/// they're written; song details and playlists are also broken down into
+/// the `songs`, `playlists` and `playlist_entries` tables, which back
+/// indexed queries such as `search`.
+///
+/// Clones share the same connection, so an application can keep one to
+/// query the database after handing the storage to a `PlaylistManager`.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if it doesn't exist
+    pub fn open(path: OsString) -> Result<Self, StorageError> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Opens a database living in memory only
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, StorageError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Copies the songs and playlists saved in the `songs_meta` and
+    /// `playlists_meta` directories into the database, in a single
+    /// transaction. Files in outdated formats are copied as they are,
+    /// and brought up to date when a `PlaylistManager` loads them.
+    /// Returns how many files were copied.
+    pub fn import(
+        &self,
+        songs_meta: OsString,
+        playlists_meta: OsString,
+    ) -> Result<usize, StorageError> {
+        let from = JsonDirStorage::new(songs_meta, playlists_meta);
+        Ok(super::copy(&from, self)?)
+    }
+
+    /// Returns the ids of the songs whose title, artist, album, album
+    /// artist or genres have words starting with every word of `text`,
+    /// best matches first
+    pub fn search(&self, text: &str) -> Result<Vec<Uuid>, StorageError> {
+        // Each word is quoted, so that it's never taken for an operator
+        let query: Vec<String> = text
+            .split_whitespace()
+            .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+            .collect();
+        if query.is_empty() {
+            return Ok(vec![]);
+        }
+        self.ids(
+            "SELECT songs.id FROM songs_search JOIN songs ON songs.key = songs_search.rowid
+             WHERE songs_search MATCH ?1 ORDER BY rank",
+            [query.join(" ")],
+        )
+    }
+
+    /// Returns the ids of the songs whose artist is `artist`
+    pub fn by_artist(&self, artist: &str) -> Result<Vec<Uuid>, StorageError> {
+        self.ids("SELECT id FROM songs WHERE artist = ?1", [artist])
+    }
+
+    /// Returns the ids of the songs of the album `album`
+    pub fn by_album(&self, album: &str) -> Result<Vec<Uuid>, StorageError> {
+        self.ids("SELECT id FROM songs WHERE album = ?1", [album])
+    }
+
+    /// Returns the ids of the songs released in `year`
+    pub fn by_year(&self, year: u16) -> Result<Vec<Uuid>, StorageError> {
+        self.ids("SELECT id FROM songs WHERE year = ?1", [year])
+    }
+
+    /// Returns the ids of the playlists holding the song with id `song`
+    pub fn playlists_with(&self, song: Uuid) -> Result<Vec<Uuid>, StorageError> {
+        self.ids(
+            "SELECT DISTINCT playlist_id FROM playlist_entries WHERE song_id = ?1",
+            [song.to_string()],
+        )
+    }
+
+    fn ids<P: rusqlite::Params>(&self, sql: &str, params: P) -> Result<Vec<Uuid>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare_cached(sql)?;
+        let rows = statement.query_map(params, |row| row.get::<_, String>(0))?;
+
+        let mut ids = vec![];
+        for id in rows {
+            // Rows are only written with valid ids
+            if let Ok(id) = Uuid::parse_str(&id?) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // Changes are made in transactions, so they're
+        // never left half made by a panic
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn read(&self, collection: Collection, name: &OsStr) -> std::io::Result<Vec<u8>> {
+        self.connection()
+            .query_row(
+                "SELECT data FROM documents WHERE collection = ?1 AND name = ?2",
+                params![collection.name(), stored_name(name)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(to_io)?
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+
+    fn write(&self, collection: Collection, name: &OsStr, data: &[u8]) -> std::io::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.savepoint().map_err(to_io)?;
+        insert(&transaction, collection, name, data).map_err(to_io)?;
+        transaction.commit().map_err(to_io)
+    }
+
+    fn remove(&self, collection: Collection, name: &OsStr) -> std::io::Result<()> {
+        let mut connection = self.connection();
+        let transaction = connection.savepoint().map_err(to_io)?;
+        delete(&transaction, collection, name).map_err(to_io)?;
+        transaction.commit().map_err(to_io)
+    }
+
+    fn rename(&self, collection: Collection, from: &OsStr, to: &OsStr) -> std::io::Result<()> {
+        let data = self.read(collection, from)?;
+        let mut connection = self.connection();
+        let transaction = connection.savepoint().map_err(to_io)?;
+        delete(&transaction, collection, from).map_err(to_io)?;
+        insert(&transaction, collection, to, &data).map_err(to_io)?;
+        transaction.commit().map_err(to_io)
+    }
+
+    fn exists(&self, collection: Collection, name: &OsStr) -> bool {
+        self.connection()
+            .query_row(
+                "SELECT 1 FROM documents WHERE collection = ?1 AND name = ?2",
+                params![collection.name(), stored_name(name)],
+                |_| Ok(()),
+            )
+            .optional()
+            .ok()
+            .flatten()
+            .is_some()
+    }
+
+    fn list(&self, collection: Collection) -> std::io::Result<Vec<OsString>> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare_cached("SELECT name FROM documents WHERE collection = ?1 ORDER BY name")
+            .map_err(to_io)?;
+        let names = statement
+            .query_map([collection.name()], |row| row.get::<_, Value>(0))
+            .map_err(to_io)?;
+        names
+            .map(|name| name.map(read_name).map_err(to_io))
+            .collect()
+    }
+
+    fn location(&self, collection: Collection, name: &OsStr) -> OsString {
+        let mut location = OsString::from(collection.name());
+        location.push("/");
+        location.push(name);
+        location
+    }
+
+    fn flush(&self) -> std::io::Result<()> {
+        // Commits are only synced to disk at checkpoints
+        self.connection()
+            .query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))
+            .map_err(to_io)
+    }
+
+    fn batch(&self, writes: &mut dyn FnMut() -> std::io::Result<()>) -> std::io::Result<()> {
+        // A savepoint, unlike a transaction, can be nested
+        // in the ones made by each write
+        self.connection()
+            .execute_batch("SAVEPOINT batch")
+            .map_err(to_io)?;
+        let result = writes();
+        let end = match result {
+            Ok(_) => "RELEASE batch",
+            Err(_) => "ROLLBACK TO batch; RELEASE batch",
+        };
+        self.connection().execute_batch(end).map_err(to_io)?;
+        result
+    }
+}
+
+fn to_io(err: rusqlite::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+/// How a document name is stored, as `crate::os_string` stores it: valid
+/// UTF-8 is kept as text, which is what databases written before non-UTF-8
+/// names were supported contain. Anything else is stored as a blob of the
+/// raw bytes on Unix, and of the UTF-16 code units on Windows. Blobs
+/// are kept as they are by the text column.
+fn stored_name(name: &OsStr) -> Value {
+    match name.to_str() {
+        Some(name) => Value::Text(name.to_string()),
+        None => non_utf8(name),
+    }
+}
+
+#[cfg(unix)]
+fn non_utf8(name: &OsStr) -> Value {
+    use std::os::unix::ffi::OsStrExt;
+    Value::Blob(name.as_bytes().to_vec())
+}
+
+#[cfg(windows)]
+fn non_utf8(name: &OsStr) -> Value {
+    use std::os::windows::ffi::OsStrExt;
+    Value::Blob(name.encode_wide().flat_map(u16::to_le_bytes).collect())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn non_utf8(name: &OsStr) -> Value {
+    Value::Text(name.to_string_lossy().into_owned())
+}
+
+/// Reads a name stored by `stored_name`
+fn read_name(name: Value) -> OsString {
+    match name {
+        Value::Blob(bytes) if cfg!(windows) => os_string::from_wide(
+            bytes
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .collect(),
+        ),
+        Value::Blob(bytes) => os_string::from_bytes(bytes),
+        Value::Text(name) => OsString::from(name),
+        // Names are only written as text or blobs
+        _ => OsString::new(),
+    }
+}
+
+/// Writes the document `name` and the rows it's broken down into
+fn insert(
+    connection: &Connection,
+    collection: Collection,
+    name: &OsStr,
+    data: &[u8],
+) -> rusqlite::Result<()> {
+    delete(connection, collection, name)?;
+    connection.execute(
+        "INSERT INTO documents (collection, name, data) VALUES (?1, ?2, ?3)",
+        params![collection.name(), stored_name(name), data],
+    )?;
+
+    // Only song_meta files and playlists named after their id are broken
+    // down: the library file, lyrics and outdated files are kept as they are
+    let id = match name
+        .to_str()
+        .and_then(|name| name.strip_suffix(".json"))
+        .map(Uuid::parse_str)
+    {
+        Some(Ok(id)) => id.to_string(),
+        _ => return Ok(()),
+    };
+    match collection {
+        Collection::Songs => {
+            let details: SongDetails = match serde_json::from_slice(data) {
+                Ok(details) => details,
+                Err(_) => return Ok(()),
+            };
+            let genres = details.genres().join("; ");
+            connection.execute(
+                "INSERT INTO songs (id, title, artist, album, album_artist, year)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    id,
+                    details.name(),
+                    details.artist(),
+                    details.album(),
+                    details.album_artist(),
+                    details.year(),
+                ],
+            )?;
+            // Search rows share the key of their song
+            connection.execute(
+                "INSERT INTO songs_search (rowid, title, artist, album, album_artist, genres)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    connection.last_insert_rowid(),
+                    details.name(),
+                    details.artist(),
+                    details.album(),
+                    details.album_artist(),
+                    genres,
+                ],
+            )?;
+        }
+        Collection::Playlists => {
+            let playlist: serde_json::Value = match serde_json::from_slice(data) {
+                Ok(playlist) => playlist,
+                Err(_) => return Ok(()),
+            };
+            let name = match playlist.get("name").and_then(|name| name.as_str()) {
+                Some(name) => name,
+                None => return Ok(()),
+            };
+            let creation_date = playlist.get("creation_date").and_then(|date| date.as_str());
+            connection.execute(
+                "INSERT INTO playlists (id, name, creation_date) VALUES (?1, ?2, ?3)",
+                params![id, name, creation_date],
+            )?;
+
+            // Songs held by playlists saved before the library
+            // existed have no entry until they're moved to it
+            let songs = playlist.get("songs").and_then(|songs| songs.as_array());
+            for (position, song) in songs.into_iter().flatten().enumerate() {
+                if let Some(song) = song.as_str() {
+                    connection.execute(
+                        "INSERT INTO playlist_entries (playlist_id, position, song_id)
+                         VALUES (?1, ?2, ?3)",
+                        params![id, position as i64, song],
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the document `name` and the rows it was broken down into
+fn delete(connection: &Connection, collection: Collection, name: &OsStr) -> rusqlite::Result<()> {
+    connection.execute(
+        "DELETE FROM documents WHERE collection = ?1 AND name = ?2",
+        params![collection.name(), stored_name(name)],
+    )?;
+
+    let id = match name
+        .to_str()
+        .and_then(|name| name.strip_suffix(".json"))
+        .map(Uuid::parse_str)
+    {
+        Some(Ok(id)) => id.to_string(),
+        _ => return Ok(()),
+    };
+    match collection {
+        Collection::Songs => {
+            connection.execute(
+                "DELETE FROM songs_search WHERE rowid = (SELECT key FROM songs WHERE id = ?1)",
+                [&id],
+            )?;
+            connection.execute("DELETE FROM songs WHERE id = ?1", [&id])?;
+        }
+        Collection::Playlists => {
+            connection.execute("DELETE FROM playlists WHERE id = ?1", [&id])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn keeps_names_that_arent_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let first = OsString::from_vec(b"caf\xe9.lrc".to_vec());
+        let second = OsString::from_vec(b"caf\xe8.lrc".to_vec());
+        storage.write(Collection::Songs, &first, b"first").unwrap();
+        storage
+            .write(Collection::Songs, &second, b"second")
+            .unwrap();
+        storage
+            .write(Collection::Songs, OsStr::new("café.lrc"), b"third")
+            .unwrap();
+
+        assert_eq!(storage.read(Collection::Songs, &first).unwrap(), b"first");
+        assert_eq!(storage.read(Collection::Songs, &second).unwrap(), b"second");
+        let mut names = storage.list(Collection::Songs).unwrap();
+        names.sort();
+        let mut expected = vec![first.clone(), second.clone(), OsString::from("café.lrc")];
+        expected.sort();
+        assert_eq!(names, expected);
+
+        let renamed = OsString::from_vec(b"\xff.lrc".to_vec());
+        storage.rename(Collection::Songs, &first, &renamed).unwrap();
+        assert!(!storage.exists(Collection::Songs, &first));
+        assert_eq!(storage.read(Collection::Songs, &renamed).unwrap(), b"first");
+        storage.remove(Collection::Songs, &second).unwrap();
+        assert!(!storage.exists(Collection::Songs, &second));
+        assert_eq!(storage.list(Collection::Songs).unwrap().len(), 2);
+    }
+}
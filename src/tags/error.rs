@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::fmt::Display;
+
+use crate::format::bytes::Truncated;
+
+/// Errors describing why the tags of
+/// an audio file couldn't be handled
+#[derive(Debug)]
+pub enum TagError {
+    Io(std::io::Error),
+    UnsupportedFormat,
+    Malformed(String),
+}
+
+impl Display for TagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TagError::Io(err) => {
+                writeln!(f, "An I/O error occured while accessing tags")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            TagError::UnsupportedFormat => {
+                writeln!(f, "The file isn't in a supported audio format")
+            }
+            TagError::Malformed(msg) => {
+                writeln!(f, "The tags are malformed: {}", msg)
+            }
+        }
+    }
+}
+
+impl Error for TagError {}
+
+impl From<std::io::Error> for TagError {
+    fn from(err: std::io::Error) -> Self {
+        TagError::Io(err)
+    }
+}
+
+impl From<Truncated> for TagError {
+    fn from(err: Truncated) -> Self {
+        TagError::Malformed(err.to_string())
+    }
+}
@@ -0,0 +1,1018 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use super::{Field, Picture, PictureKind, TagError, Tags};
+use crate::{
+    format::{
+        bytes::{syncsafe, Bytes},
+        read_up_to, skip_id3v2,
+    },
+    lyrics::{LyricLine, Lyrics},
+    song::SongDetails,
+};
+
+/// SYLT timestamps in milliseconds, rather than MPEG frames
+const SYLT_MILLISECONDS: u8 = 2;
+
+/// SYLT content types up to this one hold lyrics
+/// (0 is `other`, 1 is `lyrics`)
+const SYLT_LYRICS: u8 = 1;
+
+/// Genres that ID3v1, and ID3v2 by reference, identify by
+/// number. Includes the Winamp extensions.
+#[rustfmt::skip]
+pub(super) const GENRES: [&str; 192] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz",
+    "Metal", "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno",
+    "Industrial", "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno",
+    "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance", "Classical", "Instrumental",
+    "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise", "AlternRock", "Bass", "Soul", "Punk",
+    "Space", "Meditative", "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic", "Darkwave",
+    "Techno-Industrial", "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy",
+    "Cult", "Gangsta", "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American",
+    "Cabaret", "New Wave", "Psychadelic", "Rave", "Showtunes", "Trailer", "Lo-Fi", "Tribal",
+    "Acid Punk", "Acid Jazz", "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock", "Folk",
+    "Folk-Rock", "National Folk", "Swing", "Fast Fusion", "Bebob", "Latin", "Revival", "Celtic",
+    "Bluegrass", "Avantgarde", "Gothic Rock", "Progressive Rock", "Psychedelic Rock",
+    "Symphonic Rock", "Slow Rock", "Big Band", "Chorus", "Easy Listening", "Acoustic", "Humour",
+    "Speech", "Chanson", "Opera", "Chamber Music", "Sonata", "Symphony", "Booty Bass", "Primus",
+    "Porn Groove", "Satire", "Slow Jam", "Club", "Tango", "Samba", "Folklore", "Ballad",
+    "Power Ballad", "Rhythmic Soul", "Freestyle", "Duet", "Punk Rock", "Drum Solo", "A capella",
+    "Euro-House", "Dance Hall", "Goa", "Drum & Bass", "Club-House", "Hardcore", "Terror", "Indie",
+    "BritPop", "Negerpunk", "Polsk Punk", "Beat", "Christian Gangsta Rap", "Heavy Metal",
+    "Black Metal", "Crossover", "Contemporary Christian", "Christian Rock", "Merengue", "Salsa",
+    "Thrash Metal", "Anime", "JPop", "Synthpop", "Abstract", "Art Rock", "Baroque", "Bhangra",
+    "Big Beat", "Breakbeat", "Chillout", "Downtempo", "Dub", "EBM", "Eclectic", "Electro",
+    "Electroclash", "Emo", "Experimental", "Garage", "Global", "IDM", "Illbient", "Industro-Goth",
+    "Jam Band", "Krautrock", "Leftfield", "Lounge", "Math Rock", "New Romantic", "Nu-Breakz",
+    "Post-Punk", "Post-Rock", "Psytrance", "Shoegaze", "Space Rock", "Trop Rock", "World Music",
+    "Neoclassical", "Audiobook", "Audio Theatre", "Neue Deutsche Welle", "Podcast", "Indie Rock",
+    "G-Funk", "Dubstep", "Garage Rock", "Psybient",
+];
+
+/// ID3v2.2 frame identifiers and their ID3v2.3 counterpart,
+/// including the unofficial ones written by iTunes
+#[rustfmt::skip]
+const V22_IDS: [(&[u8; 3], &str); 67] = [
+    (b"BUF", "RBUF"), (b"CNT", "PCNT"), (b"COM", "COMM"), (b"CRA", "AENC"), (b"ETC", "ETCO"),
+    (b"EQU", "EQUA"), (b"GEO", "GEOB"), (b"IPL", "IPLS"), (b"MCI", "MCDI"), (b"MLL", "MLLT"),
+    (b"PIC", "PIC"), (b"POP", "POPM"), (b"REV", "RVRB"), (b"RVA", "RVAD"), (b"SLT", "SYLT"),
+    (b"STC", "SYTC"), (b"TAL", "TALB"), (b"TBP", "TBPM"), (b"TCM", "TCOM"), (b"TCO", "TCON"),
+    (b"TCR", "TCOP"), (b"TDA", "TDAT"), (b"TDY", "TDLY"), (b"TEN", "TENC"), (b"TFT", "TFLT"),
+    (b"TIM", "TIME"), (b"TKE", "TKEY"), (b"TLA", "TLAN"), (b"TLE", "TLEN"), (b"TMT", "TMED"),
+    (b"TOA", "TOPE"), (b"TOF", "TOFN"), (b"TOL", "TOLY"), (b"TOR", "TORY"), (b"TOT", "TOAL"),
+    (b"TP1", "TPE1"), (b"TP2", "TPE2"), (b"TP3", "TPE3"), (b"TP4", "TPE4"), (b"TPA", "TPOS"),
+    (b"TPB", "TPUB"), (b"TRC", "TSRC"), (b"TRD", "TRDA"), (b"TRK", "TRCK"), (b"TSI", "TSIZ"),
+    (b"TSS", "TSSE"), (b"TT1", "TIT1"), (b"TT2", "TIT2"), (b"TT3", "TIT3"), (b"TXT", "TEXT"),
+    (b"TXX", "TXXX"), (b"TYE", "TYER"), (b"UFI", "UFID"), (b"ULT", "USLT"), (b"WAF", "WOAF"),
+    (b"WAR", "WOAR"), (b"WAS", "WOAS"), (b"WCM", "WCOM"), (b"WCP", "WCOP"), (b"WPB", "WPUB"),
+    (b"WXX", "WXXX"), (b"TCP", "TCMP"), (b"TST", "TSOT"), (b"TSA", "TSOA"), (b"TSP", "TSOP"),
+    (b"TS2", "TSO2"), (b"TSC", "TSOC"),
+];
+
+/// Frames whose content comes from `SongDetails`. Any other frame found
+/// in an existing tag is preserved when writing.
+const MANAGED_FRAMES: [&str; 10] = [
+    "TIT2", "TPE1", "TALB", "TPE2", "TRCK", "TPOS", "TCON", "TCOM", "TYER", "TDRC",
+];
+
+/// Padding left after a tag that had to grow, so that following
+/// edits don't change its size
+const PADDING: usize = 1024;
+
+/// An ID3v2 tag as found at the start of a file
+struct TagV2 {
+    major: u8,
+    /// The frames, with tag-level unsynchronisation and
+    /// the extended header already removed
+    body: Vec<u8>,
+}
+
+/// A raw ID3v2 frame
+struct Frame<'a> {
+    id: String,
+    flags: u16,
+    data: &'a [u8],
+}
+
+/// Reads ID3v2 tags and, as fallback for missing
+/// values, the ID3v1 tag at the end of the file
+pub(super) fn read<R: Read + Seek>(file: &mut R) -> Result<Tags, TagError> {
+    let mut tags = Tags::default();
+
+    file.seek(SeekFrom::Start(0))?;
+    if let Some(tag) = read_v2(file)? {
+        for frame in frames(tag.major, &tag.body)? {
+            if let Some(data) = frame_content(tag.major, frame.flags, frame.data) {
+                parse_frame(&frame.id, &data, &mut tags);
+            }
+        }
+    }
+    read_v1(file, &mut tags)?;
+
+    Ok(tags)
+}
+
+/// Reads the ID3v2 tag at the current position of `file`, if there is one
+fn read_v2<R: Read + Seek>(file: &mut R) -> Result<Option<TagV2>, TagError> {
+    let mut header = [0u8; 10];
+    if read_up_to(file, &mut header)? < header.len() || &header[..3] != b"ID3" {
+        return Ok(None);
+    }
+
+    let major = header[3];
+    let flags = header[5];
+    if !(2..=4).contains(&major) {
+        return Ok(None);
+    }
+    // ID3v2.2 compression was never defined, so the tag can't be read
+    if major == 2 && flags & 0x40 != 0 {
+        return Ok(None);
+    }
+
+    let mut body = vec![0u8; syncsafe(&header[6..10]) as usize];
+    file.read_exact(&mut body)?;
+
+    // In ID3v2.4 unsynchronisation is signalled again on every frame
+    if flags & 0x80 != 0 && major < 4 {
+        body = remove_unsynchronisation(&body);
+    }
+
+    if flags & 0x40 != 0 {
+        let mut bytes = Bytes::new(&body);
+        let skip = match major {
+            3 => bytes.u32_be()? as usize + 4,
+            _ => syncsafe(bytes.take(4)?) as usize,
+        };
+        if skip > body.len() {
+            return Err(TagError::Malformed("invalid ID3v2 extended header".into()));
+        }
+        body.drain(..skip);
+    }
+
+    Ok(Some(TagV2 { major, body }))
+}
+
+/// Splits the body of a tag in its frames. ID3v2.2
+/// identifiers are converted to their ID3v2.3 form.
+fn frames(major: u8, body: &[u8]) -> Result<Vec<Frame<'_>>, TagError> {
+    let mut frames = vec![];
+    let mut bytes = Bytes::new(body);
+    let header_size = if major == 2 { 6 } else { 10 };
+
+    while bytes.remaining() >= header_size {
+        // Padding
+        if bytes.rest()[0] == 0 {
+            break;
+        }
+
+        let (id, size, flags) = if major == 2 {
+            let id = bytes.take(3)?;
+            (upgrade_v22_id(id), bytes.u24_be()? as usize, 0)
+        } else {
+            let id = String::from_utf8_lossy(bytes.take(4)?).to_string();
+            let size = if major == 4 {
+                syncsafe(bytes.take(4)?) as usize
+            } else {
+                bytes.u32_be()? as usize
+            };
+            (id, size, bytes.u16_be()?)
+        };
+
+        let data = match bytes.take(size) {
+            Ok(data) => data,
+            // A frame bigger than the tag, ignore what's left
+            Err(_) => break,
+        };
+        frames.push(Frame { id, flags, data });
+    }
+
+    Ok(frames)
+}
+
+/// Replaces the ID3v2 tag of the file at `path` with one holding
+/// `details`, and updates the ID3v1 tag if the file has one. ID3v2.2
+/// and ID3v2.3 tags become ID3v2.3, anything else becomes ID3v2.4.
+/// A tag that can't be read is replaced as a whole.
+pub(super) fn write(path: &OsString, details: &SongDetails) -> Result<(), TagError> {
+    let mut file = File::open(path)?;
+    let old = read_v2(&mut file)?;
+    // Taken from the header, so that unreadable tags are dropped too
+    file.seek(SeekFrom::Start(0))?;
+    let old_size = skip_id3v2(&mut file)?;
+    let v1 = new_v1(&mut file, details)?.filter(|(offset, _)| *offset >= old_size);
+    if old_size > file.seek(SeekFrom::End(0))? {
+        return Err(TagError::Malformed("ID3v2 tag larger than the file".into()));
+    }
+    drop(file);
+
+    let major = match &old {
+        Some(tag) if tag.major < 4 => 3,
+        _ => 4,
+    };
+
+    let mut body = vec![];
+    if let Some(tag) = &old {
+        for frame in frames(tag.major, &tag.body)? {
+            if is_managed(tag.major, &frame) {
+                continue;
+            }
+            if tag.major == 2 {
+                let (id, data) = upgrade_v22_frame(&frame)?;
+                write_frame(&mut body, major, &id, 0, &data);
+            } else {
+                write_frame(&mut body, major, &frame.id, frame.flags, frame.data);
+            }
+        }
+    }
+    for (id, content) in details_frames(major, details) {
+        write_frame(&mut body, major, id, 0, &content);
+    }
+
+    let padding = if body.len() as u64 + 10 <= old_size {
+        old_size as usize - 10 - body.len()
+    } else {
+        PADDING
+    };
+    body.resize(body.len() + padding, 0);
+
+    let mut tag = Vec::with_capacity(body.len() + 10);
+    tag.extend_from_slice(b"ID3");
+    tag.extend_from_slice(&[major, 0, 0]);
+    tag.extend_from_slice(&to_syncsafe(body.len() as u32));
+    tag.extend_from_slice(&body);
+
+    match &v1 {
+        Some((offset, v1)) => super::splice(path, &[(0, old_size, &tag), (*offset, 128, v1)]),
+        None => super::splice(path, &[(0, old_size, &tag)]),
+    }
+}
+
+/// Converts a frame of an ID3v2.2 tag, whose identifier has already
+/// been upgraded, to its ID3v2.3 form. Frames without an ID3v2.3
+/// counterpart can't be kept, so they are reported as errors.
+fn upgrade_v22_frame(frame: &Frame) -> Result<(String, Vec<u8>), TagError> {
+    if frame.id != "PIC" {
+        return match frame.id.len() {
+            4 => Ok((frame.id.clone(), frame.data.to_vec())),
+            _ => Err(TagError::Malformed(format!(
+                "the ID3v2.2 frame `{}` has no ID3v2.3 counterpart",
+                frame.id
+            ))),
+        };
+    }
+
+    // PIC holds a three characters image format where APIC has a MIME type
+    let invalid = || TagError::Malformed("invalid ID3v2.2 PIC frame".into());
+    let encoding = *frame.data.first().ok_or_else(invalid)?;
+    let format = frame.data.get(1..4).ok_or_else(invalid)?;
+    let mime_type = match format.to_ascii_uppercase().as_slice() {
+        b"PNG" => "image/png".to_string(),
+        b"JPG" => "image/jpeg".to_string(),
+        b"-->" => "-->".to_string(),
+        other => format!("image/{}", String::from_utf8_lossy(other).to_lowercase()),
+    };
+
+    let mut data = vec![encoding];
+    data.extend_from_slice(mime_type.as_bytes());
+    data.push(0);
+    data.extend_from_slice(&frame.data[4..]);
+    Ok(("APIC".to_string(), data))
+}
+
+/// Returns `true` if `frame` is generated from `SongDetails`
+fn is_managed(major: u8, frame: &Frame) -> bool {
+    if MANAGED_FRAMES.contains(&frame.id.as_str()) {
+        return true;
+    }
+    // Only user comments, the ones without description, are managed
+    if frame.id == "COMM" {
+        return frame_content(major, frame.flags, frame.data)
+            .and_then(|data| parse_comment(&data))
+            .map(|(description, _)| description.is_empty())
+            .unwrap_or(false);
+    }
+    false
+}
+
+/// Builds the content of the frames representing `details`
+fn details_frames(major: u8, details: &SongDetails) -> Vec<(&'static str, Vec<u8>)> {
+    let mut frames = vec![];
+    let mut text = |id: &'static str, values: &[String]| {
+        let values: Vec<&String> = values.iter().filter(|value| !value.is_empty()).collect();
+        if !values.is_empty() {
+            frames.push((id, encode_text(major, &values)));
+        }
+    };
+
+    text("TIT2", &[details.name().to_string()]);
+    text("TPE1", &optional(details.artist()));
+    text("TALB", &optional(details.album()));
+    text("TPE2", &optional(details.album_artist()));
+    text("TCOM", &optional(details.composer()));
+    text("TRCK", &pair(details.track_number(), details.track_total()));
+    text("TPOS", &pair(details.disc_number(), details.disc_total()));
+    text("TCON", details.genres());
+    let year = optional(details.year().map(|year| year.to_string()).as_deref());
+    text(if major == 4 { "TDRC" } else { "TYER" }, &year);
+
+    if let Some(comment) = details.comment() {
+        let text = encode_text(major, &[&comment.to_string()]);
+        // Encoding, language, empty description and text
+        let mut content = vec![text[0]];
+        content.extend_from_slice(b"eng");
+        if major == 4 {
+            content.push(0);
+        } else {
+            content.extend_from_slice(&[0xFF, 0xFE, 0, 0]);
+        }
+        content.extend_from_slice(&text[1..]);
+        frames.push(("COMM", content));
+    }
+
+    frames
+}
+
+fn optional(value: Option<&str>) -> Vec<String> {
+    value.map(|value| value.to_string()).into_iter().collect()
+}
+
+/// Formats number and total as `n/t`
+fn pair(number: Option<u16>, total: Option<u16>) -> Vec<String> {
+    match (number, total) {
+        (Some(number), Some(total)) => vec![format!("{}/{}", number, total)],
+        (Some(number), None) => vec![number.to_string()],
+        _ => vec![],
+    }
+}
+
+/// Encodes a text frame content: UTF-8 for ID3v2.4, UTF-16
+/// for ID3v2.3. Values are separated by a terminator.
+fn encode_text(major: u8, values: &[&String]) -> Vec<u8> {
+    let mut content = vec![];
+    if major == 4 {
+        content.push(3);
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                content.push(0);
+            }
+            content.extend_from_slice(value.as_bytes());
+        }
+    } else {
+        content.push(1);
+        for (index, value) in values.iter().enumerate() {
+            if index > 0 {
+                content.extend_from_slice(&[0, 0]);
+            }
+            content.extend_from_slice(&[0xFF, 0xFE]);
+            for unit in value.encode_utf16() {
+                content.extend_from_slice(&unit.to_le_bytes());
+            }
+        }
+    }
+    content
+}
+
+fn write_frame(body: &mut Vec<u8>, major: u8, id: &str, flags: u16, data: &[u8]) {
+    body.extend_from_slice(id.as_bytes());
+    if major == 4 {
+        body.extend_from_slice(&to_syncsafe(data.len() as u32));
+    } else {
+        body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    }
+    body.extend_from_slice(&flags.to_be_bytes());
+    body.extend_from_slice(data);
+}
+
+fn to_syncsafe(value: u32) -> [u8; 4] {
+    [
+        (value >> 21 & 0x7F) as u8,
+        (value >> 14 & 0x7F) as u8,
+        (value >> 7 & 0x7F) as u8,
+        (value & 0x7F) as u8,
+    ]
+}
+
+/// Returns the content of a frame after processing its flags, or `None`
+/// if the frame is compressed or encrypted.
+fn frame_content(major: u8, flags: u16, data: &[u8]) -> Option<Vec<u8>> {
+    let mut data = data;
+    match major {
+        3 => {
+            if flags & 0x00C0 != 0 {
+                return None;
+            }
+            if flags & 0x0020 != 0 {
+                data = data.get(1..)?;
+            }
+            Some(data.to_vec())
+        }
+        4 => {
+            if flags & 0x000C != 0 {
+                return None;
+            }
+            if flags & 0x0040 != 0 {
+                data = data.get(1..)?;
+            }
+            if flags & 0x0001 != 0 {
+                data = data.get(4..)?;
+            }
+            if flags & 0x0002 != 0 {
+                return Some(remove_unsynchronisation(data));
+            }
+            Some(data.to_vec())
+        }
+        _ => Some(data.to_vec()),
+    }
+}
+
+fn parse_frame(id: &str, data: &[u8], tags: &mut Tags) {
+    if data.is_empty() {
+        return;
+    }
+
+    match id {
+        "COMM" => {
+            if let Some((description, text)) = parse_comment(data) {
+                // Players store their own data in comments with a
+                // description (e.g. `iTunNORM`). Only user ones matter.
+                if description.is_empty() {
+                    tags.set(Field::Comment, &text);
+                }
+            }
+        }
+        "APIC" | "PIC" => {
+            if let Some(picture) = parse_picture(id == "PIC", data) {
+                tags.pictures.push(picture);
+            }
+        }
+        "USLT" => {
+            // Same layout as comments
+            if let Some((_, text)) = parse_comment(data) {
+                if text.trim().is_empty() {
+                    return;
+                }
+                let parsed = Lyrics::parse(&text);
+                let lyrics = tags.lyrics.get_or_insert_with(Default::default);
+                if let Some(plain) = parsed.plain() {
+                    if lyrics.plain().is_none() {
+                        lyrics.set_plain(plain);
+                    }
+                } else if !lyrics.is_synced() {
+                    // Some writers put LRC in USLT frames
+                    lyrics.set_synced(parsed.synced().to_vec());
+                }
+            }
+        }
+        "SYLT" => {
+            if let Some(lines) = parse_synced_lyrics(data) {
+                let lyrics = tags.lyrics.get_or_insert_with(Default::default);
+                if !lyrics.is_synced() {
+                    lyrics.set_synced(lines);
+                }
+            }
+        }
+        "TXXX" => {
+            // Description, then value
+            let encoding = data[0];
+            let (description, value) = split_terminated(encoding, &data[1..]);
+            let value = decode_text_list(encoding, value);
+            if let Some(value) = value.first() {
+                tags.set_replay_gain(&decode_text(encoding, description), value);
+            }
+        }
+        "TCON" => {
+            for value in decode_text_list(data[0], &data[1..]) {
+                for genre in parse_genre(&value) {
+                    tags.set(Field::Genre, &genre);
+                }
+            }
+        }
+        _ => {
+            let field = match id {
+                "TIT2" => Field::Name,
+                "TPE1" => Field::Artist,
+                "TALB" => Field::Album,
+                "TPE2" => Field::AlbumArtist,
+                "TRCK" => Field::Track,
+                "TPOS" => Field::Disc,
+                "TCOM" => Field::Composer,
+                "TYER" | "TDRC" => Field::Year,
+                _ => return,
+            };
+            let values = decode_text_list(data[0], &data[1..]);
+            if field == Field::Artist || field == Field::Composer {
+                tags.set(field, &values.join("; "));
+            } else if let Some(value) = values.first() {
+                tags.set(field, value);
+            }
+        }
+    }
+}
+
+/// Returns description and text of a COMM frame
+fn parse_comment(data: &[u8]) -> Option<(String, String)> {
+    let encoding = *data.first()?;
+    let data = data.get(4..)?;
+    let (description, text) = split_terminated(encoding, data);
+    Some((
+        decode_text(encoding, description),
+        decode_text(encoding, text),
+    ))
+}
+
+/// Parses the lines of a SYLT frame holding lyrics. Frames
+/// timed with MPEG frames rather than milliseconds are skipped.
+fn parse_synced_lyrics(data: &[u8]) -> Option<Vec<LyricLine>> {
+    let encoding = *data.first()?;
+    // Language, then timestamp format and content type
+    let format = *data.get(4)?;
+    let content = *data.get(5)?;
+    if format != SYLT_MILLISECONDS || content > SYLT_LYRICS {
+        return None;
+    }
+
+    let (_, mut rest) = split_terminated(encoding, data.get(6..)?);
+    let mut lines = vec![];
+    while !rest.is_empty() {
+        let (text, next) = split_terminated(encoding, rest);
+        let time = next.get(..4)?;
+        let time = u32::from_be_bytes(time.try_into().unwrap());
+        lines.push(LyricLine::new(
+            Duration::from_millis(time as u64),
+            &decode_text(encoding, text),
+        ));
+        rest = &next[4..];
+    }
+    Some(lines)
+}
+
+/// Parses an APIC frame, or a PIC frame if `v22` is set
+fn parse_picture(v22: bool, data: &[u8]) -> Option<Picture> {
+    let encoding = *data.first()?;
+    let mut data = data.get(1..)?;
+
+    let mime_type = if v22 {
+        let format = data.get(..3)?;
+        data = &data[3..];
+        match format {
+            b"PNG" | b"png" => "image/png".to_string(),
+            _ => "image/jpeg".to_string(),
+        }
+    } else {
+        let (mime, rest) = split_terminated(0, data);
+        data = rest;
+        let mime = decode_text(0, mime);
+        // Some writers omit the `image/` part
+        if mime.contains('/') {
+            mime
+        } else {
+            format!("image/{}", mime.to_lowercase())
+        }
+    };
+
+    let kind = PictureKind::from_code(*data.first()?);
+    let (description, image) = split_terminated(encoding, &data[1..]);
+
+    Some(Picture::new(
+        kind,
+        &mime_type,
+        &decode_text(encoding, description),
+        image.to_vec(),
+    ))
+}
+
+/// Parses a TCON value, which may contain references to
+/// the ID3v1 genre list like `(17)` or `17`
+fn parse_genre(value: &str) -> Vec<String> {
+    let value = value.trim();
+    if let Ok(index) = value.parse::<usize>() {
+        return GENRES
+            .get(index)
+            .map(|genre| genre.to_string())
+            .into_iter()
+            .collect();
+    }
+    match value {
+        "RX" => return vec!["Remix".to_string()],
+        "CR" => return vec!["Cover".to_string()],
+        _ => (),
+    }
+
+    let mut genres = vec![];
+    let mut rest = value;
+    while rest.starts_with('(') && !rest.starts_with("((") {
+        let end = match rest.find(')') {
+            Some(end) => end,
+            None => break,
+        };
+        genres.extend(parse_genre(&rest[1..end]));
+        rest = &rest[end + 1..];
+    }
+
+    // A text after references refines them
+    let rest = rest.trim().replacen("((", "(", 1);
+    if !rest.is_empty() {
+        return vec![rest];
+    }
+    genres
+}
+
+/// Fills the fields still missing from the ID3v1 tag at the end of `file`
+fn read_v1<R: Read + Seek>(file: &mut R, tags: &mut Tags) -> Result<(), TagError> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 128 {
+        return Ok(());
+    }
+
+    let mut tag = [0u8; 128];
+    file.seek(SeekFrom::End(-128))?;
+    file.read_exact(&mut tag)?;
+    if &tag[..3] != b"TAG" {
+        return Ok(());
+    }
+
+    tags.set_missing(Field::Name, &decode_latin1(&tag[3..33]));
+    tags.set_missing(Field::Artist, &decode_latin1(&tag[33..63]));
+    tags.set_missing(Field::Album, &decode_latin1(&tag[63..93]));
+    tags.set_missing(Field::Year, &decode_latin1(&tag[93..97]));
+
+    // ID3v1.1 steals the last two bytes of the comment for the track
+    if tag[125] == 0 && tag[126] != 0 {
+        tags.set_missing(Field::Comment, &decode_latin1(&tag[97..125]));
+        tags.set_missing(Field::Track, &tag[126].to_string());
+    } else {
+        tags.set_missing(Field::Comment, &decode_latin1(&tag[97..127]));
+    }
+
+    if let Some(genre) = GENRES.get(tag[127] as usize) {
+        tags.set_missing(Field::Genre, genre);
+    }
+
+    Ok(())
+}
+
+/// Builds the ID3v1 tag holding `details` that replaces the one at the
+/// end of `file`, and returns it with the offset of the latter. Returns
+/// `None` if the file has no such tag.
+fn new_v1<R: Read + Seek>(
+    file: &mut R,
+    details: &SongDetails,
+) -> Result<Option<(u64, [u8; 128])>, TagError> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < 128 {
+        return Ok(None);
+    }
+
+    let mut tag = [0u8; 128];
+    file.seek(SeekFrom::End(-128))?;
+    file.read_exact(&mut tag)?;
+    if &tag[..3] != b"TAG" {
+        return Ok(None);
+    }
+
+    let mut new = [0u8; 128];
+    new[..3].copy_from_slice(b"TAG");
+    encode_latin1(&mut new[3..33], details.name());
+    encode_latin1(&mut new[33..63], details.artist().unwrap_or(""));
+    encode_latin1(&mut new[63..93], details.album().unwrap_or(""));
+    if let Some(year) = details.year() {
+        encode_latin1(&mut new[93..97], &format!("{:04}", year));
+    }
+    encode_latin1(&mut new[97..125], details.comment().unwrap_or(""));
+    new[126] = details.track_number().unwrap_or(0).min(255) as u8;
+    new[127] = details
+        .genre()
+        .and_then(|genre| {
+            GENRES
+                .iter()
+                .position(|item| item.eq_ignore_ascii_case(genre))
+        })
+        .map(|index| index as u8)
+        .unwrap_or(255);
+
+    Ok(Some((len - 128, new)))
+}
+
+/// Writes `value` into `field` as ISO-8859-1, truncating
+/// it and replacing characters that can't be represented
+fn encode_latin1(field: &mut [u8], value: &str) {
+    for (slot, c) in field.iter_mut().zip(value.chars()) {
+        *slot = if (c as u32) < 256 {
+            c as u32 as u8
+        } else {
+            b'?'
+        };
+    }
+}
+
+/// Maps the three characters identifiers of ID3v2.2 to their
+/// ID3v2.3 counterpart. `PIC` is kept, as its content differs
+/// from the one of `APIC`, and so are unknown identifiers.
+fn upgrade_v22_id(id: &[u8]) -> String {
+    V22_IDS
+        .iter()
+        .find(|(old, _)| old.as_slice() == id)
+        .map(|(_, new)| new.to_string())
+        .unwrap_or_else(|| String::from_utf8_lossy(id).to_string())
+}
+
+fn remove_unsynchronisation(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut previous = 0u8;
+    for byte in data {
+        if !(previous == 0xFF && *byte == 0x00) {
+            result.push(*byte);
+        }
+        previous = *byte;
+    }
+    result
+}
+
+/// Splits `data` at the first string terminator of the
+/// given encoding, dropping the terminator
+fn split_terminated(encoding: u8, data: &[u8]) -> (&[u8], &[u8]) {
+    if encoding == 1 || encoding == 2 {
+        let mut index = 0;
+        while index + 1 < data.len() {
+            if data[index] == 0 && data[index + 1] == 0 {
+                return (&data[..index], &data[index + 2..]);
+            }
+            index += 2;
+        }
+        (data, &[])
+    } else {
+        match data.iter().position(|byte| *byte == 0) {
+            Some(index) => (&data[..index], &data[index + 1..]),
+            None => (data, &[]),
+        }
+    }
+}
+
+/// Decodes a list of terminator separated strings
+fn decode_text_list(encoding: u8, data: &[u8]) -> Vec<String> {
+    let mut values = vec![];
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (value, next) = split_terminated(encoding, rest);
+        values.push(decode_text(encoding, value));
+        rest = next;
+    }
+    values
+}
+
+fn decode_text(encoding: u8, data: &[u8]) -> String {
+    match encoding {
+        1 => decode_utf16(data, false),
+        2 => decode_utf16(data, true),
+        3 => String::from_utf8_lossy(data).to_string(),
+        _ => decode_latin1(data),
+    }
+}
+
+/// Decodes UTF-16 honouring a leading byte order mark.
+/// `big_endian` is used when there isn't one.
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let (data, big_endian) = match data {
+        [0xFE, 0xFF, rest @ ..] => (rest, true),
+        [0xFF, 0xFE, rest @ ..] => (rest, false),
+        _ => (data, big_endian),
+    };
+
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|pair| {
+            if big_endian {
+                u16::from_be_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_le_bytes([pair[0], pair[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn decode_latin1(data: &[u8]) -> String {
+    data.iter()
+        .take_while(|byte| **byte != 0)
+        .map(|byte| *byte as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn tag(major: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        let mut tag = vec![b'I', b'D', b'3', major, 0, flags];
+        tag.extend_from_slice(&to_syncsafe(body.len() as u32));
+        tag.extend_from_slice(body);
+        tag
+    }
+
+    fn frame(major: u8, id: &str, flags: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![];
+        write_frame(&mut frame, major, id, flags, data);
+        frame
+    }
+
+    fn text(value: &str) -> Vec<u8> {
+        let mut data = vec![0];
+        data.extend_from_slice(value.as_bytes());
+        data
+    }
+
+    fn v1(name: &str, track: u8, genre: u8) -> Vec<u8> {
+        let mut tag = vec![0u8; 128];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[3..3 + name.len()].copy_from_slice(name.as_bytes());
+        tag[33..39].copy_from_slice(b"Artist");
+        tag[93..97].copy_from_slice(b"1987");
+        tag[97..104].copy_from_slice(b"Comment");
+        tag[126] = track;
+        tag[127] = genre;
+        tag
+    }
+
+    fn parse(data: &[u8]) -> Result<Tags, TagError> {
+        read(&mut Cursor::new(data))
+    }
+
+    #[test]
+    fn reads_id3v1() {
+        let data = [vec![0xAA; 300], v1("Name", 5, 17)].concat();
+        let tags = parse(&data).unwrap();
+        let details = tags.details();
+        assert_eq!(details.name(), "Name");
+        assert_eq!(details.artist(), Some("Artist"));
+        assert_eq!(details.year(), Some(1987));
+        assert_eq!(details.comment(), Some("Comment"));
+        assert_eq!(details.track_number(), Some(5));
+        assert_eq!(details.genres(), ["Rock"]);
+
+        // ID3v1.0 has no track, and 255 is no genre
+        let details = parse(&v1("Name", 0, 255)).unwrap().into_details();
+        assert_eq!(details.track_number(), None);
+        assert!(details.genres().is_empty());
+    }
+
+    #[test]
+    fn reads_id3v2_before_id3v1() {
+        let body = frame(3, "TIT2", 0, &text("From v2"));
+        let data = [tag(3, 0, &body), v1("From v1", 2, 0)].concat();
+        let details = parse(&data).unwrap().into_details();
+        assert_eq!(details.name(), "From v2");
+        assert_eq!(details.artist(), Some("Artist"));
+        assert_eq!(details.genres(), ["Blues"]);
+    }
+
+    #[test]
+    fn reads_frame_sizes_of_each_version() {
+        // 200 bytes, whose syncsafe and plain encodings differ
+        let name = "x".repeat(199);
+        for major in [3, 4] {
+            let mut body = frame(major, "TIT2", 0, &text(&name));
+            body.extend_from_slice(&frame(major, "TPE1", 0, &text("Artist")));
+            let details = parse(&tag(major, 0, &body)).unwrap().into_details();
+            assert_eq!(details.name(), name);
+            assert_eq!(details.artist(), Some("Artist"));
+        }
+
+        let mut body = b"TT2\0\0\x04\0Old".to_vec();
+        body.extend_from_slice(b"COM\0\0\x09\0eng\0Note");
+        body.extend_from_slice(b"TCO\0\0\x05\0(17)");
+        let details = parse(&tag(2, 0, &body)).unwrap().into_details();
+        assert_eq!(details.name(), "Old");
+        assert_eq!(details.comment(), Some("Note"));
+        assert_eq!(details.genres(), ["Rock"]);
+    }
+
+    #[test]
+    fn decodes_text_encodings() {
+        let mut utf16 = vec![1, 0xFF, 0xFE];
+        utf16.extend("Ré".encode_utf16().flat_map(u16::to_le_bytes));
+        let mut body = frame(4, "TIT2", 0, &utf16);
+        body.extend_from_slice(&frame(4, "TALB", 0, b"\x03Caf\xC3\xA9"));
+        body.extend_from_slice(&frame(4, "TPE1", 0, b"\0One\0Two"));
+        let details = parse(&tag(4, 0, &body)).unwrap().into_details();
+        assert_eq!(details.name(), "Ré");
+        assert_eq!(details.album(), Some("Café"));
+        assert_eq!(details.artist(), Some("One; Two"));
+    }
+
+    #[test]
+    fn removes_unsynchronisation() {
+        // `aÿà` in ISO-8859-1, with a zero stuffed after 0xFF
+        let frame = frame(3, "TIT2", 0, b"\0a\xFF\xE0");
+        let mut body = frame[..13].to_vec();
+        body.extend_from_slice(&[0x00, 0xE0]);
+        let details = parse(&tag(3, 0x80, &body)).unwrap().into_details();
+        assert_eq!(details.name(), "aÿà");
+
+        // ID3v2.4 flags it on the frame, here with a data length indicator
+        let mut body = vec![];
+        write_frame(&mut body, 4, "TIT2", 0x0003, b"\0\0\0\x04\0a\xFF\x00\xE0");
+        let details = parse(&tag(4, 0, &body)).unwrap().into_details();
+        assert_eq!(details.name(), "aÿà");
+    }
+
+    #[test]
+    fn skips_extended_headers() {
+        // ID3v2.3 doesn't count the size field, ID3v2.4 does
+        let mut body = b"\0\0\0\x06\0\0\0\0\0\0".to_vec();
+        body.extend_from_slice(&frame(3, "TIT2", 0, &text("Name")));
+        assert_eq!(
+            parse(&tag(3, 0x40, &body)).unwrap().details().name(),
+            "Name"
+        );
+
+        let mut body = b"\0\0\0\x06\x01\0".to_vec();
+        body.extend_from_slice(&frame(4, "TIT2", 0, &text("Name")));
+        assert_eq!(
+            parse(&tag(4, 0x40, &body)).unwrap().details().name(),
+            "Name"
+        );
+
+        let body = b"\0\0\0\x7F\x01\0".to_vec();
+        assert!(matches!(
+            parse(&tag(4, 0x40, &body)),
+            Err(TagError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn reads_pictures_and_lyrics() {
+        let mut body = frame(3, "APIC", 0, b"\0image/png\0\x03cover\0\x89PNG");
+        body.extend_from_slice(&frame(3, "USLT", 0, b"\0eng\0Some words"));
+        let mut sylt = b"\0eng\x02\x01\0".to_vec();
+        sylt.extend_from_slice(b"First\0\0\0\x03\xE8");
+        sylt.extend_from_slice(b"Second\0\0\0\x07\xD0");
+        body.extend_from_slice(&frame(3, "SYLT", 0, &sylt));
+
+        let tags = parse(&tag(3, 0, &body)).unwrap();
+        assert_eq!(
+            tags.pictures(),
+            &vec![Picture::new(
+                PictureKind::FrontCover,
+                "image/png",
+                "cover",
+                b"\x89PNG".to_vec()
+            )]
+        );
+        let lyrics = tags.lyrics().unwrap();
+        assert_eq!(lyrics.plain(), Some("Some words"));
+        assert_eq!(
+            lyrics.synced(),
+            [
+                LyricLine::new(Duration::from_secs(1), "First"),
+                LyricLine::new(Duration::from_secs(2), "Second")
+            ]
+        );
+    }
+
+    #[test]
+    fn upgrades_id3v22_pictures() {
+        let frame = Frame {
+            id: "PIC".to_string(),
+            flags: 0,
+            data: b"\0JPG\x03\0\xFF\xD8",
+        };
+        let (id, data) = upgrade_v22_frame(&frame).unwrap();
+        assert_eq!(id, "APIC");
+        assert_eq!(data, b"\0image/jpeg\0\x03\0\xFF\xD8");
+        let picture = parse_picture(false, &data).unwrap();
+        assert_eq!(Some(picture), parse_picture(true, frame.data));
+
+        let truncated = Frame {
+            data: b"\0JP",
+            ..frame
+        };
+        assert!(upgrade_v22_frame(&truncated).is_err());
+    }
+
+    #[test]
+    fn survives_truncated_tags() {
+        let mut body = b"\0\0\0\x06\x01\0".to_vec();
+        body.extend_from_slice(&frame(4, "TIT2", 0, &text("Name")));
+        body.extend_from_slice(&frame(4, "APIC", 0, b"\0image/png\0\x03d\0\x89PNG"));
+        body.extend_from_slice(&frame(4, "SYLT", 0, b"\0eng\x02\x01\0First\0\0\0"));
+        body.extend_from_slice(&frame(4, "COMM", 0, b"\0en"));
+        body.extend_from_slice(&frame(4, "TXXX", 0, b"\x01\xFF"));
+        let data = [tag(4, 0x40, &body), v1("Name", 1, 1)].concat();
+
+        assert_eq!(parse(&data).unwrap().details().name(), "Name");
+        for len in 0..data.len() {
+            let _ = parse(&data[..len]);
+        }
+        // The tag claims more than there is
+        let mut data = tag(3, 0, &frame(3, "TIT2", 0, &text("Name")));
+        data[9] += 100;
+        assert!(parse(&data).is_err());
+    }
+}
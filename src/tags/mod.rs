@@ -0,0 +1,605 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+};
+
+use crate::{format::AudioFormat, lyrics::Lyrics, song::SongDetails};
+
+pub use self::error::TagError;
+
+mod error;
+mod id3;
+mod mp4;
+mod vorbis;
+
+/// Reads the tags embedded in the audio file at `path`. The format
+/// of the file is recognised by its content, not by its extension.
+///
+/// ID3v1 and ID3v2 are read from MP3 files, Vorbis comments from FLAC
+/// and Ogg files and iTunes metadata atoms from MP4 files.
+pub fn read(path: &OsString) -> Result<Tags, TagError> {
+    let mut file = File::open(path)?;
+    let format = AudioFormat::detect(&mut file)?.ok_or(TagError::UnsupportedFormat)?;
+
+    match format {
+        AudioFormat::Mp3 => id3::read(&mut file),
+        AudioFormat::Flac => vorbis::read_flac(&mut file),
+        AudioFormat::Ogg => vorbis::read_ogg(&mut file),
+        AudioFormat::Mp4 => mp4::read(&mut file),
+    }
+}
+
+/// Writes `details` into the tags embedded in the audio file at `path`.
+///
+/// Fields left empty in `details` are removed from the tags, while
+/// pictures and any tag the core doesn't handle are preserved. The
+/// file is left untouched if anything goes wrong.
+pub fn write(path: &OsString, details: &SongDetails) -> Result<(), TagError> {
+    let format = AudioFormat::from_path(path)?.ok_or(TagError::UnsupportedFormat)?;
+
+    match format {
+        AudioFormat::Mp3 => id3::write(path, details),
+        AudioFormat::Flac => vorbis::write_flac(path, details),
+        AudioFormat::Ogg => vorbis::write_ogg(path, details),
+        AudioFormat::Mp4 => mp4::write(path, details),
+    }
+}
+
+/// What has been read from the tags
+/// of an audio file
+#[derive(Clone, Debug, Default)]
+pub struct Tags {
+    details: SongDetails,
+    pictures: Vec<Picture>,
+    lyrics: Option<Lyrics>,
+}
+
+impl Tags {
+    pub fn details(&self) -> &SongDetails {
+        &self.details
+    }
+
+    pub fn into_details(self) -> SongDetails {
+        self.details
+    }
+
+    /// Returns every picture embedded in the file
+    pub fn pictures(&self) -> &Vec<Picture> {
+        &self.pictures
+    }
+
+    /// Returns the lyrics embedded in the file, if there are
+    pub fn lyrics(&self) -> Option<&Lyrics> {
+        self.lyrics.as_ref()
+    }
+
+    /// Stores lyrics found in a text field, whose
+    /// format isn't known. The first ones win.
+    fn set_lyrics(&mut self, text: &str) {
+        if self.lyrics.is_none() && !text.trim().is_empty() {
+            self.lyrics = Some(Lyrics::parse(text));
+        }
+    }
+
+    /// Stores the value of the tag `key` if it's a ReplayGain one.
+    /// Returns `false` if it isn't.
+    fn set_replay_gain(&mut self, key: &str, value: &str) -> bool {
+        self.details.replay_gain_mut().set_tag(key, value)
+    }
+
+    /// Returns the front cover, or the first picture
+    /// if none is marked as such
+    pub fn cover(&self) -> Option<&Picture> {
+        self.pictures
+            .iter()
+            .find(|picture| picture.kind() == PictureKind::FrontCover)
+            .or_else(|| self.pictures.first())
+    }
+
+    /// Stores `value` in the details field identified by `field`,
+    /// converting it as needed. Invalid or empty values are ignored.
+    fn set(&mut self, field: Field, value: &str) {
+        let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+        if value.is_empty() {
+            return;
+        }
+
+        let details = &mut self.details;
+        match field {
+            Field::Name => details.set_name(value),
+            Field::Artist => details.set_artist(value),
+            Field::Album => details.set_album(value),
+            Field::AlbumArtist => details.set_album_artist(value),
+            Field::Composer => details.set_composer(value),
+            Field::Comment => details.set_comment(value),
+            Field::Genre => details.add_genre(value),
+            Field::Year => {
+                if let Some(year) = parse_year(value) {
+                    details.set_year(year);
+                }
+            }
+            Field::Track => {
+                let (number, total) = parse_pair(value);
+                if let Some(number) = number {
+                    details.set_track_number(number);
+                }
+                if let Some(total) = total {
+                    details.set_track_total(total);
+                }
+            }
+            Field::TrackTotal => {
+                if let Ok(total) = value.parse() {
+                    details.set_track_total(total);
+                }
+            }
+            Field::Disc => {
+                let (number, total) = parse_pair(value);
+                if let Some(number) = number {
+                    details.set_disc_number(number);
+                }
+                if let Some(total) = total {
+                    details.set_disc_total(total);
+                }
+            }
+            Field::DiscTotal => {
+                if let Ok(total) = value.parse() {
+                    details.set_disc_total(total);
+                }
+            }
+        }
+    }
+
+    /// Stores `value` as `set` does, unless the field already has one
+    fn set_missing(&mut self, field: Field, value: &str) {
+        let details = &self.details;
+        let missing = match field {
+            Field::Name => details.name().is_empty(),
+            Field::Artist => details.artist().is_none(),
+            Field::Album => details.album().is_none(),
+            Field::AlbumArtist => details.album_artist().is_none(),
+            Field::Composer => details.composer().is_none(),
+            Field::Comment => details.comment().is_none(),
+            Field::Genre => details.genres().is_empty(),
+            Field::Year => details.year().is_none(),
+            Field::Track => details.track_number().is_none(),
+            Field::TrackTotal => details.track_total().is_none(),
+            Field::Disc => details.disc_number().is_none(),
+            Field::DiscTotal => details.disc_total().is_none(),
+        };
+        if missing {
+            self.set(field, value);
+        }
+    }
+}
+
+/// A picture embedded in an audio file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Picture {
+    kind: PictureKind,
+    mime_type: String,
+    description: String,
+    data: Vec<u8>,
+}
+
+impl Picture {
+    pub fn new(kind: PictureKind, mime_type: &str, description: &str, data: Vec<u8>) -> Self {
+        Self {
+            kind,
+            mime_type: mime_type.to_string(),
+            description: description.to_string(),
+            data,
+        }
+    }
+
+    pub fn kind(&self) -> PictureKind {
+        self.kind
+    }
+
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    /// Returns the encoded image
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// What a picture represents. Values follow the picture
+/// types shared by ID3v2 and FLAC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PictureKind {
+    FrontCover,
+    BackCover,
+    Artist,
+    Other(u8),
+}
+
+impl PictureKind {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            3 => PictureKind::FrontCover,
+            4 => PictureKind::BackCover,
+            8 => PictureKind::Artist,
+            code => PictureKind::Other(code),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match self {
+            PictureKind::FrontCover => 3,
+            PictureKind::BackCover => 4,
+            PictureKind::Artist => 8,
+            PictureKind::Other(code) => *code,
+        }
+    }
+}
+
+/// The details fields that tag formats
+/// have in common
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Name,
+    Artist,
+    Album,
+    AlbumArtist,
+    Track,
+    TrackTotal,
+    Disc,
+    DiscTotal,
+    Genre,
+    Composer,
+    Year,
+    Comment,
+}
+
+/// Rebuilds the file at `path` in a temporary copy which then
+/// replaces the original, swapping the `len` bytes at `offset` for
+/// `replacement` for every `(offset, len, replacement)` in `edits`.
+/// The edits must be sorted by offset and must not overlap.
+fn splice(path: &OsString, edits: &[(u64, u64, &[u8])]) -> Result<(), TagError> {
+    let mut source = File::open(path)?;
+    let temp = temp_path(path);
+    let result = (|| -> std::io::Result<()> {
+        let mut target = File::create(&temp)?;
+        let mut position = 0;
+        for (offset, len, replacement) in edits {
+            std::io::copy(&mut (&mut source).take(offset - position), &mut target)?;
+            target.write_all(replacement)?;
+            position = offset + len;
+            source.seek(SeekFrom::Start(position))?;
+        }
+        std::io::copy(&mut source, &mut target)?;
+        target.sync_all()
+    })();
+
+    persist(&temp, path, result)
+}
+
+/// Returns the path of the temporary file used while rewriting `path`
+fn temp_path(path: &OsString) -> OsString {
+    let mut temp = path.clone();
+    temp.push(".tmp");
+    temp
+}
+
+/// Moves `temp` over `path`, with the permissions of the
+/// latter, if `result` is ok, removes it otherwise
+fn persist(temp: &OsString, path: &OsString, result: std::io::Result<()>) -> Result<(), TagError> {
+    let result = result
+        .and_then(|_| std::fs::set_permissions(temp, std::fs::metadata(path)?.permissions()))
+        .and_then(|_| std::fs::rename(temp, path));
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = std::fs::remove_file(temp);
+            Err(TagError::Io(err))
+        }
+    }
+}
+
+/// Parses values like `3/12` or `3` into
+/// number and total
+fn parse_pair(value: &str) -> (Option<u16>, Option<u16>) {
+    let mut parts = value.splitn(2, '/');
+    let number = parts.next().and_then(|part| part.trim().parse().ok());
+    let total = parts.next().and_then(|part| part.trim().parse().ok());
+    (number, total)
+}
+
+/// Extracts the year from dates like `1999`,
+/// `1999-04-01` or `1999-04-01T10:00:00`
+fn parse_year(value: &str) -> Option<u16> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() != 4 {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::format::{bytes::syncsafe, mp4 as atoms, ogg};
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(data: &[u8]) -> Self {
+            let name = format!("phosphorus-{}", Uuid::new_v4());
+            let file = Self(std::env::temp_dir().join(name));
+            std::fs::write(&file.0, data).unwrap();
+            file
+        }
+
+        fn path(&self) -> OsString {
+            self.0.clone().into_os_string()
+        }
+
+        fn read(&self) -> Vec<u8> {
+            std::fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn details() -> SongDetails {
+        let mut details = SongDetails::new("New", Some("Artist"), Some(1999), None);
+        details.set_track_number(3);
+        details
+    }
+
+    fn assert_details(tags: &Tags) {
+        let details = tags.details();
+        assert_eq!(details.name(), "New");
+        assert_eq!(details.artist(), Some("Artist"));
+        assert_eq!(details.year(), Some(1999));
+        assert_eq!(details.track_number(), Some(3));
+    }
+
+    /// Two silent MPEG-1 layer III frames, at 128 kbit/s and 44.1 kHz
+    fn mpeg_audio() -> Vec<u8> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x64]);
+        [frame.clone(), frame].concat()
+    }
+
+    fn id3v22_tag(frames: &[(&[u8; 3], &[u8])]) -> Vec<u8> {
+        let mut body = vec![];
+        for (id, data) in frames {
+            body.extend_from_slice(*id);
+            body.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+            body.extend_from_slice(data);
+        }
+        let mut tag = b"ID3\x02\0\0".to_vec();
+        tag.extend_from_slice(&[0, 0, 0, body.len() as u8]);
+        tag.extend_from_slice(&body);
+        tag
+    }
+
+    fn id3v1_tag(name: &str) -> Vec<u8> {
+        let mut tag = vec![0u8; 128];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[3..3 + name.len()].copy_from_slice(name.as_bytes());
+        tag[127] = 255;
+        tag
+    }
+
+    #[test]
+    fn writes_id3_tags() {
+        let pic = b"\0PNG\x03cover\0\x01\x02\x03";
+        let tag = id3v22_tag(&[(b"TT2", b"\0Old"), (b"PIC", pic), (b"TCP", b"\x001")]);
+        let file = TempFile::new(&[tag, mpeg_audio(), id3v1_tag("Old")].concat());
+
+        write(&file.path(), &details()).unwrap();
+        let tags = read(&file.path()).unwrap();
+        assert_details(&tags);
+        assert_eq!(
+            tags.pictures(),
+            &vec![Picture::new(
+                PictureKind::FrontCover,
+                "image/png",
+                "cover",
+                vec![1, 2, 3]
+            )]
+        );
+
+        let data = file.read();
+        // ID3v2.2 tags are upgraded to ID3v2.3
+        assert_eq!(&data[..4], b"ID3\x03");
+        assert!(data.windows(4).any(|window| window == b"TCMP"));
+        let size = 10 + syncsafe(&data[6..10]) as usize;
+        assert_eq!(&data[size..data.len() - 128], mpeg_audio().as_slice());
+        assert_eq!(&data[data.len() - 128..data.len() - 125], b"TAG");
+        assert_eq!(&data[data.len() - 125..data.len() - 122], b"New");
+
+        // Once written, the tag keeps its size
+        write(&file.path(), &details()).unwrap();
+        assert_eq!(file.read(), data);
+    }
+
+    #[test]
+    fn replaces_unreadable_id3_tags() {
+        let mut data = b"ID3\x05\0\0\0\0\0\x04junk".to_vec();
+        data.extend_from_slice(&mpeg_audio());
+        let file = TempFile::new(&data);
+
+        write(&file.path(), &details()).unwrap();
+        assert_details(&read(&file.path()).unwrap());
+        let data = file.read();
+        assert_eq!(&data[..4], b"ID3\x04");
+        assert!(data.ends_with(&mpeg_audio()));
+        assert!(!data.windows(4).any(|window| window == b"junk"));
+    }
+
+    #[test]
+    fn refuses_id3v22_frames_without_counterpart() {
+        let tag = id3v22_tag(&[(b"TT2", b"\0Old"), (b"CRM", b"owner\0x\0")]);
+        let data = [tag, mpeg_audio()].concat();
+        let file = TempFile::new(&data);
+
+        assert!(matches!(
+            write(&file.path(), &details()),
+            Err(TagError::Malformed(_))
+        ));
+        assert_eq!(file.read(), data);
+    }
+
+    fn flac_block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+        let mut block = vec![kind | if last { 0x80 } else { 0 }];
+        block.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        block.extend_from_slice(body);
+        block
+    }
+
+    fn comment_list(comments: &[&str]) -> Vec<u8> {
+        let mut block = 4u32.to_le_bytes().to_vec();
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        block
+    }
+
+    #[test]
+    fn writes_flac_comments() {
+        let mut picture = 3u32.to_be_bytes().to_vec();
+        picture.extend_from_slice(&[0, 0, 0, 9]);
+        picture.extend_from_slice(b"image/png");
+        picture.extend_from_slice(&[0; 20]);
+        picture.extend_from_slice(&[0, 0, 0, 3, 1, 2, 3]);
+
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, false, &[0; 34]));
+        data.extend_from_slice(&flac_block(
+            4,
+            false,
+            &comment_list(&["TITLE=Old", "CUSTOM=kept"]),
+        ));
+        data.extend_from_slice(&flac_block(6, true, &picture));
+        data.extend_from_slice(b"audio");
+        let file = TempFile::new(&data);
+
+        write(&file.path(), &details()).unwrap();
+        let tags = read(&file.path()).unwrap();
+        assert_details(&tags);
+        assert_eq!(tags.pictures().len(), 1);
+        assert_eq!(tags.pictures()[0].data(), &[1, 2, 3]);
+
+        let data = file.read();
+        // STREAMINFO stays first
+        assert_eq!(data[4], 0);
+        assert!(data.windows(11).any(|window| window == b"CUSTOM=kept"));
+        assert!(data.ends_with(b"audio"));
+    }
+
+    #[test]
+    fn writes_ogg_comments() {
+        let serial = 7;
+        let identification = b"\x01vorbis".to_vec();
+        // Big enough to span two pages, so that the pages
+        // following the new, smaller, one are renumbered
+        let title = format!("TITLE={}", "x".repeat(70_000));
+        let mut comments = b"\x03vorbis".to_vec();
+        comments.extend_from_slice(&comment_list(&[&title, "CUSTOM=kept"]));
+        comments.push(1);
+        let setup = b"\x05vorbis".to_vec();
+
+        let mut data = vec![];
+        let mut pages = ogg::paginate(&[identification], serial, 0);
+        pages.extend(ogg::paginate(&[comments, setup], serial, 1));
+        assert_eq!(pages.len(), 3);
+        pages.push(ogg::Page {
+            header_type: 0,
+            granule_position: 1000,
+            serial,
+            sequence: 3,
+            segments: vec![5],
+            data: b"audio".to_vec(),
+        });
+        for page in &pages {
+            page.write_to(&mut data).unwrap();
+        }
+        let file = TempFile::new(&data);
+
+        write(&file.path(), &details()).unwrap();
+        assert_details(&read(&file.path()).unwrap());
+
+        let data = file.read();
+        assert!(data.windows(11).any(|window| window == b"CUSTOM=kept"));
+        let mut reader = data.as_slice();
+        let mut pages = vec![];
+        while let Some(page) = ogg::read_page(&mut reader).unwrap() {
+            pages.push(page);
+        }
+        assert_eq!(pages.len(), 3);
+        let audio = &pages[2];
+        assert_eq!(audio.sequence, 2);
+        assert_eq!(audio.granule_position, 1000);
+        assert_eq!(audio.data, b"audio");
+    }
+
+    #[test]
+    fn writes_mp4_items() {
+        let ilst = [
+            mp4::item(b"\xA9nam", mp4::DATA_UTF8, b"Old"),
+            mp4::item(b"\xA9too", mp4::DATA_UTF8, b"encoder"),
+        ]
+        .concat();
+        let mut meta = vec![0u8; 4];
+        meta.extend_from_slice(&atoms::atom(b"ilst", &ilst));
+        let udta = atoms::atom(b"udta", &atoms::atom(b"meta", &meta));
+
+        let ftyp = atoms::atom(b"ftyp", b"M4A \0\0\0\0M4A isom");
+        let stco = |offset: u32| {
+            let mut body = vec![0, 0, 0, 0, 0, 0, 0, 1];
+            body.extend_from_slice(&offset.to_be_bytes());
+            let mut atom = atoms::atom(b"stco", &body);
+            for kind in [b"stbl", b"minf", b"mdia", b"trak"] {
+                atom = atoms::atom(kind, &atom);
+            }
+            atom
+        };
+        let moov_size = 8 + stco(0).len() + udta.len();
+        // The chunk starts right after the header of `mdat`
+        let chunk = (ftyp.len() + moov_size + 8) as u32;
+        let moov = atoms::atom(b"moov", &[stco(chunk), udta].concat());
+        let data = [ftyp, moov, atoms::atom(b"mdat", b"audio")].concat();
+        let file = TempFile::new(&data);
+
+        write(&file.path(), &details()).unwrap();
+        assert_details(&read(&file.path()).unwrap());
+
+        let data = file.read();
+        let moov = atoms::read_top_level(&mut std::io::Cursor::new(&data), b"moov")
+            .unwrap()
+            .unwrap();
+        let ilst = atoms::find(&moov, &[b"udta", b"meta", b"ilst"]).unwrap();
+        let encoder = atoms::child(ilst, b"\xA9too").unwrap();
+        assert_eq!(
+            mp4::data_atoms(encoder),
+            vec![(mp4::DATA_UTF8, &b"encoder"[..])]
+        );
+
+        // The chunk offset follows the media data
+        let stco = atoms::find(&moov, &[b"trak", b"mdia", b"minf", b"stbl", b"stco"]).unwrap();
+        let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap());
+        assert_ne!(offset, chunk);
+        assert_eq!(&data[offset as usize..offset as usize + 5], b"audio");
+    }
+}
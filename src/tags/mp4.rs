@@ -0,0 +1,347 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use super::{id3::GENRES, Field, Picture, PictureKind, TagError, Tags};
+use crate::{
+    format::mp4::{self, Atoms},
+    song::SongDetails,
+};
+
+/// Well known types of the `data` atom
+pub(super) const DATA_UTF8: u32 = 1;
+pub(super) const DATA_PNG: u32 = 14;
+pub(super) const DATA_IMPLICIT: u32 = 0;
+
+/// Items whose content comes from `SongDetails`. Any other item
+/// found in an existing `ilst` is preserved when writing.
+const MANAGED_ITEMS: [&[u8; 4]; 11] = [
+    b"\xA9nam", b"\xA9ART", b"\xA9alb", b"aART", b"\xA9gen", b"gnre", b"\xA9wrt", b"\xA9day",
+    b"\xA9cmt", b"trkn", b"disk",
+];
+
+/// Reads the iTunes style metadata stored
+/// in `moov/udta/meta/ilst`
+pub(super) fn read<R: Read + Seek>(file: &mut R) -> Result<Tags, TagError> {
+    let mut tags = Tags::default();
+
+    let moov = mp4::read_top_level(file, b"moov")?
+        .ok_or_else(|| TagError::Malformed("missing moov atom".into()))?;
+    let ilst = match mp4::find(&moov, &[b"udta", b"meta", b"ilst"]) {
+        Some(ilst) => ilst,
+        None => return Ok(tags),
+    };
+
+    for (kind, item) in Atoms::new(ilst) {
+        if &kind == b"----" {
+            parse_freeform(item, &mut tags);
+            continue;
+        }
+        for (data_type, value) in data_atoms(item) {
+            parse_item(&kind, data_type, value, &mut tags);
+        }
+    }
+
+    Ok(tags)
+}
+
+fn parse_item(kind: &[u8; 4], data_type: u32, value: &[u8], tags: &mut Tags) {
+    let field = match kind {
+        b"\xA9nam" => Field::Name,
+        b"\xA9ART" => Field::Artist,
+        b"\xA9alb" => Field::Album,
+        b"aART" => Field::AlbumArtist,
+        b"\xA9gen" => Field::Genre,
+        b"\xA9wrt" => Field::Composer,
+        b"\xA9day" => Field::Year,
+        b"\xA9cmt" => Field::Comment,
+        b"trkn" | b"disk" => {
+            // Padding, number, total
+            if value.len() >= 6 {
+                let number = u16::from_be_bytes([value[2], value[3]]);
+                let total = u16::from_be_bytes([value[4], value[5]]);
+                let pair = match total {
+                    0 => number.to_string(),
+                    total => format!("{}/{}", number, total),
+                };
+                let field = if kind == b"trkn" {
+                    Field::Track
+                } else {
+                    Field::Disc
+                };
+                tags.set(field, &pair);
+            }
+            return;
+        }
+        b"gnre" => {
+            // ID3v1 genre index, shifted by one
+            if value.len() >= 2 {
+                let index = u16::from_be_bytes([value[0], value[1]]) as usize;
+                if let Some(genre) = index.checked_sub(1).and_then(|index| GENRES.get(index)) {
+                    tags.set(Field::Genre, genre);
+                }
+            }
+            return;
+        }
+        b"\xA9lyr" => {
+            if data_type == DATA_UTF8 {
+                tags.set_lyrics(&String::from_utf8_lossy(value));
+            }
+            return;
+        }
+        b"covr" => {
+            let mime_type = match data_type {
+                DATA_PNG => "image/png",
+                _ => "image/jpeg",
+            };
+            tags.pictures.push(Picture::new(
+                PictureKind::FrontCover,
+                mime_type,
+                "",
+                value.to_vec(),
+            ));
+            return;
+        }
+        _ => return,
+    };
+
+    if data_type == DATA_UTF8 {
+        tags.set(field, &String::from_utf8_lossy(value));
+    }
+}
+
+/// Parses a freeform item, made of a `mean` atom naming who defined
+/// it, a `name` atom and `data` atoms. Only ReplayGain ones are known.
+fn parse_freeform(item: &[u8], tags: &mut Tags) {
+    let name = Atoms::new(item)
+        .find(|(kind, body)| kind == b"name" && body.len() >= 4)
+        // After version and flags
+        .map(|(_, body)| String::from_utf8_lossy(&body[4..]).to_string());
+    let name = match name {
+        Some(name) => name,
+        None => return,
+    };
+
+    for (data_type, value) in data_atoms(item) {
+        if data_type == DATA_UTF8 {
+            tags.set_replay_gain(&name, &String::from_utf8_lossy(value));
+        }
+    }
+}
+
+/// Returns type and payload of every `data`
+/// atom held by a metadata item
+pub(super) fn data_atoms(item: &[u8]) -> Vec<(u32, &[u8])> {
+    Atoms::new(item)
+        .filter(|(kind, body)| kind == b"data" && body.len() >= 8)
+        .map(|(_, body)| {
+            // The first byte is the version, the locale follows the type
+            let data_type = u32::from_be_bytes([0, body[1], body[2], body[3]]);
+            (data_type, &body[8..])
+        })
+        .collect()
+}
+
+/// Replaces the metadata items of the MP4 file at `path`. When the
+/// media data follows the `moov` atom, chunk offsets are shifted by
+/// the change in size of the latter.
+pub(super) fn write(path: &OsString, details: &SongDetails) -> Result<(), TagError> {
+    let mut file = File::open(path)?;
+    let location = mp4::locate_top_level(&mut file, b"moov")?
+        .ok_or_else(|| TagError::Malformed("missing moov atom".into()))?;
+    let mut moov = vec![0u8; (location.size - location.header_size) as usize];
+    file.seek(SeekFrom::Start(location.offset + location.header_size))?;
+    file.read_exact(&mut moov)?;
+    drop(file);
+
+    let mut ilst = vec![];
+    if let Some(existing) = mp4::find(&moov, &[b"udta", b"meta", b"ilst"]) {
+        for (kind, item) in Atoms::new(existing) {
+            if !MANAGED_ITEMS.contains(&&kind) {
+                ilst.extend_from_slice(&mp4::atom(&kind, item));
+            }
+        }
+    }
+    ilst.extend_from_slice(&details_items(details));
+
+    let mut body = mp4::replace(&moov, &[b"udta", b"meta", b"ilst"], &ilst);
+    let new_moov_size = body.len() as u64 + 8;
+    let delta = new_moov_size as i64 - location.size as i64;
+    if delta != 0 {
+        mp4::adjust_chunk_offsets(&mut body, location.offset, delta);
+    }
+
+    let moov = mp4::atom(b"moov", &body);
+    super::splice(path, &[(location.offset, location.size, &moov)])
+}
+
+/// Builds the metadata items representing `details`
+fn details_items(details: &SongDetails) -> Vec<u8> {
+    let mut items = vec![];
+    let mut text = |kind: &[u8; 4], value: Option<&str>| {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            items.extend_from_slice(&item(kind, DATA_UTF8, value.as_bytes()));
+        }
+    };
+
+    text(b"\xA9nam", Some(details.name()));
+    text(b"\xA9ART", details.artist());
+    text(b"\xA9alb", details.album());
+    text(b"aART", details.album_artist());
+    for genre in details.genres() {
+        text(b"\xA9gen", Some(genre));
+    }
+    text(b"\xA9wrt", details.composer());
+    text(
+        b"\xA9day",
+        details.year().map(|year| year.to_string()).as_deref(),
+    );
+    text(b"\xA9cmt", details.comment());
+
+    if let Some(number) = details.track_number() {
+        let total = details.track_total().unwrap_or(0);
+        let mut value = vec![0, 0];
+        value.extend_from_slice(&number.to_be_bytes());
+        value.extend_from_slice(&total.to_be_bytes());
+        value.extend_from_slice(&[0, 0]);
+        items.extend_from_slice(&item(b"trkn", DATA_IMPLICIT, &value));
+    }
+    if let Some(number) = details.disc_number() {
+        let total = details.disc_total().unwrap_or(0);
+        let mut value = vec![0, 0];
+        value.extend_from_slice(&number.to_be_bytes());
+        value.extend_from_slice(&total.to_be_bytes());
+        items.extend_from_slice(&item(b"disk", DATA_IMPLICIT, &value));
+    }
+
+    items
+}
+
+/// Serializes a metadata item holding a single `data` atom
+pub(super) fn item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(value.len() + 8);
+    data.extend_from_slice(&data_type.to_be_bytes());
+    // Locale
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(value);
+    mp4::atom(kind, &mp4::atom(b"data", &data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn file(meta: &[u8]) -> Vec<u8> {
+        let udta = mp4::atom(b"udta", &mp4::atom(b"meta", meta));
+        let moov = mp4::atom(b"moov", &[mp4::atom(b"mvhd", &[0; 100]), udta].concat());
+        [mp4::atom(b"ftyp", b"M4A \0\0\0\0"), moov].concat()
+    }
+
+    fn items() -> Vec<u8> {
+        let mut freeform = mp4::atom(b"mean", b"\0\0\0\0com.apple.iTunes");
+        freeform.extend_from_slice(&mp4::atom(b"name", b"\0\0\0\0replaygain_track_gain"));
+        freeform.extend_from_slice(&mp4::atom(b"data", b"\0\0\0\x01\0\0\0\0-3.00 dB"));
+
+        [
+            item(b"\xA9nam", DATA_UTF8, b"Name"),
+            item(b"\xA9ART", DATA_UTF8, b"Artist"),
+            item(b"\xA9day", DATA_UTF8, b"2004-01-01T00:00:00Z"),
+            item(b"trkn", DATA_IMPLICIT, &[0, 0, 0, 3, 0, 12, 0, 0]),
+            item(b"disk", DATA_IMPLICIT, &[0, 0, 0, 1, 0, 0]),
+            item(b"gnre", DATA_IMPLICIT, &[0, 18]),
+            item(b"covr", DATA_PNG, b"\x89PNG"),
+            item(b"\xA9lyr", DATA_UTF8, b"Some words"),
+            mp4::atom(b"----", &freeform),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn reads_items() {
+        let meta = [vec![0; 4], mp4::atom(b"ilst", &items())].concat();
+        let tags = read(&mut Cursor::new(file(&meta))).unwrap();
+        let details = tags.details();
+        assert_eq!(details.name(), "Name");
+        assert_eq!(details.artist(), Some("Artist"));
+        assert_eq!(details.year(), Some(2004));
+        assert_eq!(details.track_number(), Some(3));
+        assert_eq!(details.track_total(), Some(12));
+        assert_eq!(details.disc_number(), Some(1));
+        assert_eq!(details.disc_total(), None);
+        assert_eq!(details.genres(), ["Rock"]);
+        assert_eq!(details.replay_gain().track_gain(), Some(-3.0));
+        assert_eq!(tags.lyrics().unwrap().plain(), Some("Some words"));
+        assert_eq!(
+            tags.pictures(),
+            &vec![Picture::new(
+                PictureKind::FrontCover,
+                "image/png",
+                "",
+                b"\x89PNG".to_vec()
+            )]
+        );
+    }
+
+    #[test]
+    fn reads_quicktime_meta() {
+        // Without version and flags, starting with the handler
+        let mut meta = mp4::atom(b"hdlr", &[0; 20]);
+        meta.extend_from_slice(&mp4::atom(b"ilst", &items()));
+        let tags = read(&mut Cursor::new(file(&meta))).unwrap();
+        assert_eq!(tags.details().name(), "Name");
+    }
+
+    #[test]
+    fn reads_files_without_items() {
+        let tags = read(&mut Cursor::new(file(&[0; 4]))).unwrap();
+        assert_eq!(tags.details().name(), "");
+        assert!(read(&mut Cursor::new(b"\0\0\0\x08free")).is_err());
+    }
+
+    #[test]
+    fn builds_items_from_details() {
+        let mut details = SongDetails::new("Name", Some("Artist"), Some(2004), None);
+        details.set_track_number(3);
+        details.set_disc_number(1);
+        details.set_disc_total(2);
+        details.set_genres(&["Rock", "Jazz"]);
+
+        let meta = [vec![0; 4], mp4::atom(b"ilst", &details_items(&details))].concat();
+        let tags = read(&mut Cursor::new(file(&meta))).unwrap();
+        let parsed = tags.details();
+        assert_eq!(parsed.name(), "Name");
+        assert_eq!(parsed.artist(), Some("Artist"));
+        assert_eq!(parsed.year(), Some(2004));
+        assert_eq!(parsed.track_number(), Some(3));
+        assert_eq!(parsed.track_total(), None);
+        assert_eq!(parsed.disc_number(), Some(1));
+        assert_eq!(parsed.disc_total(), Some(2));
+        assert_eq!(parsed.genres(), ["Rock", "Jazz"]);
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        let meta = [vec![0; 4], mp4::atom(b"ilst", &items())].concat();
+        let data = file(&meta);
+        for len in 0..data.len() {
+            let _ = read(&mut Cursor::new(&data[..len]));
+        }
+
+        // Items too short for what they should hold
+        let ilst = [
+            item(b"trkn", DATA_IMPLICIT, &[0, 0, 0]),
+            item(b"gnre", DATA_IMPLICIT, &[0]),
+            mp4::atom(b"\xA9nam", &mp4::atom(b"data", b"\0\0\0")),
+            mp4::atom(b"----", &mp4::atom(b"name", b"\0\0")),
+        ]
+        .concat();
+        let meta = [vec![0; 4], mp4::atom(b"ilst", &ilst)].concat();
+        let details = read(&mut Cursor::new(file(&meta))).unwrap().into_details();
+        assert_eq!(details.name(), "");
+        assert_eq!(details.track_number(), None);
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    ffi::OsString,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom},
+};
+
+use super::{Field, Picture, PictureKind, TagError, Tags};
+use crate::{
+    format::{bytes::Bytes, ogg, skip_id3v2},
+    song::SongDetails,
+};
+
+/// FLAC metadata block types
+const BLOCK_PADDING: u8 = 1;
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+const BLOCK_PICTURE: u8 = 6;
+
+/// Padding left after metadata that had to grow, so that
+/// following edits don't change its size
+const PADDING: usize = 1024;
+
+/// Vendor string used for comment blocks created from scratch
+const VENDOR: &str = "phosphorus";
+
+/// Reads Vorbis comments and pictures from
+/// the metadata blocks of a FLAC file
+pub(super) fn read_flac<R: Read + Seek>(file: &mut R) -> Result<Tags, TagError> {
+    let mut tags = Tags::default();
+
+    skip_id3v2(file)?;
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(TagError::Malformed("missing FLAC stream marker".into()));
+    }
+
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7F;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        match kind {
+            BLOCK_VORBIS_COMMENT | BLOCK_PICTURE => {
+                let mut block = vec![0u8; len];
+                file.read_exact(&mut block)?;
+                if kind == BLOCK_VORBIS_COMMENT {
+                    parse_comments(&block, &mut tags)?;
+                } else {
+                    tags.pictures.push(parse_picture(&block)?);
+                }
+            }
+            _ => {
+                file.seek(SeekFrom::Current(len as i64))?;
+            }
+        }
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Reads Vorbis comments from an Ogg Vorbis,
+/// Opus or FLAC stream
+pub(super) fn read_ogg<R: Read + Seek>(file: &mut R) -> Result<Tags, TagError> {
+    let mut tags = Tags::default();
+
+    let packets = ogg::read_packets(file, 2)?;
+    if packets.len() < 2 {
+        return Err(TagError::Malformed("missing Ogg comment header".into()));
+    }
+
+    let comments = comment_packet_body(&packets[0], &packets[1])
+        .ok_or_else(|| TagError::Malformed("unknown Ogg codec".into()))?;
+    parse_comments(comments, &mut tags)?;
+
+    Ok(tags)
+}
+
+/// Returns the comment block held by the second packet of an Ogg
+/// stream, skipping the codec specific prefix. The first packet
+/// identifies the codec.
+pub(super) fn comment_packet_body<'a>(
+    identification: &[u8],
+    comments: &'a [u8],
+) -> Option<&'a [u8]> {
+    if identification.starts_with(b"\x01vorbis") {
+        comments.strip_prefix(b"\x03vorbis")
+    } else if identification.starts_with(b"OpusHead") {
+        comments.strip_prefix(b"OpusTags")
+    } else if identification.starts_with(b"\x7FFLAC") {
+        comments.get(4..)
+    } else {
+        None
+    }
+}
+
+/// Parses a Vorbis comment block into `tags`
+fn parse_comments(data: &[u8], tags: &mut Tags) -> Result<(), TagError> {
+    let (_, comments) = parse_comment_list(data)?;
+
+    for comment in comments {
+        let (key, value) = match comment.split_once('=') {
+            Some(pair) => pair,
+            None => continue,
+        };
+
+        if key.eq_ignore_ascii_case("METADATA_BLOCK_PICTURE") {
+            if let Some(block) = decode_base64(value) {
+                tags.pictures.push(parse_picture(&block)?);
+            }
+            continue;
+        }
+
+        if key.eq_ignore_ascii_case("LYRICS") || key.eq_ignore_ascii_case("UNSYNCEDLYRICS") {
+            tags.set_lyrics(value);
+            continue;
+        }
+
+        if tags.set_replay_gain(key, value) {
+            continue;
+        }
+
+        let field = match field_for_key(key) {
+            Some(field) => field,
+            None => continue,
+        };
+        // Keys may be repeated: every genre is kept, while for
+        // other fields the first value wins.
+        if field == Field::Genre || !has_field(tags, field) {
+            tags.set(field, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a Vorbis comment block in vendor string and comments
+fn parse_comment_list(data: &[u8]) -> Result<(String, Vec<String>), TagError> {
+    let mut bytes = Bytes::new(data);
+    let vendor_len = bytes.u32_le()? as usize;
+    let vendor = String::from_utf8_lossy(bytes.take(vendor_len)?).to_string();
+
+    let count = bytes.u32_le()?;
+    let mut comments = vec![];
+    for _ in 0..count {
+        let len = bytes.u32_le()? as usize;
+        comments.push(String::from_utf8_lossy(bytes.take(len)?).to_string());
+    }
+
+    Ok((vendor, comments))
+}
+
+/// Maps a comment key to the details field it holds
+fn field_for_key(key: &str) -> Option<Field> {
+    let field = match key.to_uppercase().as_str() {
+        "TITLE" => Field::Name,
+        "ARTIST" => Field::Artist,
+        "ALBUM" => Field::Album,
+        "ALBUMARTIST" | "ALBUM ARTIST" | "ALBUM_ARTIST" => Field::AlbumArtist,
+        "TRACKNUMBER" => Field::Track,
+        "TRACKTOTAL" | "TOTALTRACKS" => Field::TrackTotal,
+        "DISCNUMBER" => Field::Disc,
+        "DISCTOTAL" | "TOTALDISCS" => Field::DiscTotal,
+        "GENRE" => Field::Genre,
+        "COMPOSER" => Field::Composer,
+        "DATE" | "YEAR" => Field::Year,
+        "COMMENT" | "DESCRIPTION" => Field::Comment,
+        _ => return None,
+    };
+    Some(field)
+}
+
+/// Builds a Vorbis comment block holding `details`. The vendor string
+/// and the comments not mapped to details fields are taken from
+/// `existing`, if provided.
+fn build_comments(existing: Option<&[u8]>, details: &SongDetails) -> Result<Vec<u8>, TagError> {
+    let (vendor, mut comments) = match existing {
+        Some(data) => parse_comment_list(data)?,
+        None => (VENDOR.to_string(), vec![]),
+    };
+    comments.retain(|comment| {
+        let key = comment.split('=').next().unwrap_or("");
+        field_for_key(key).is_none()
+    });
+
+    let mut add = |key: &str, value: Option<String>| {
+        if let Some(value) = value.filter(|value| !value.is_empty()) {
+            comments.push(format!("{}={}", key, value));
+        }
+    };
+    add("TITLE", Some(details.name().to_string()));
+    add("ARTIST", details.artist().map(String::from));
+    add("ALBUM", details.album().map(String::from));
+    add("ALBUMARTIST", details.album_artist().map(String::from));
+    add("TRACKNUMBER", details.track_number().map(|n| n.to_string()));
+    add("TRACKTOTAL", details.track_total().map(|n| n.to_string()));
+    add("DISCNUMBER", details.disc_number().map(|n| n.to_string()));
+    add("DISCTOTAL", details.disc_total().map(|n| n.to_string()));
+    for genre in details.genres() {
+        add("GENRE", Some(genre.clone()));
+    }
+    add("COMPOSER", details.composer().map(String::from));
+    add("DATE", details.year().map(|year| year.to_string()));
+    add("COMMENT", details.comment().map(String::from));
+
+    let mut block = vec![];
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor.as_bytes());
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        block.extend_from_slice(comment.as_bytes());
+    }
+    Ok(block)
+}
+
+/// Replaces the Vorbis comments of the FLAC file at `path`. Padding is
+/// used, when available, so that the metadata keeps its size.
+pub(super) fn write_flac(path: &OsString, details: &SongDetails) -> Result<(), TagError> {
+    let mut file = File::open(path)?;
+    let start = skip_id3v2(&mut file)? + 4;
+    let mut marker = [0u8; 4];
+    file.read_exact(&mut marker)?;
+    if &marker != b"fLaC" {
+        return Err(TagError::Malformed("missing FLAC stream marker".into()));
+    }
+
+    let mut blocks = vec![];
+    let mut old_len = 0;
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let mut block = vec![0u8; len];
+        file.read_exact(&mut block)?;
+        old_len += 4 + len as u64;
+        blocks.push((header[0] & 0x7F, block));
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+    drop(file);
+
+    let existing = blocks
+        .iter()
+        .find(|(kind, _)| *kind == BLOCK_VORBIS_COMMENT)
+        .map(|(_, block)| block.as_slice());
+    let comments = build_comments(existing, details)?;
+
+    blocks.retain(|(kind, _)| *kind != BLOCK_VORBIS_COMMENT && *kind != BLOCK_PADDING);
+    // STREAMINFO must stay first
+    blocks.insert(1.min(blocks.len()), (BLOCK_VORBIS_COMMENT, comments));
+
+    let len: u64 = blocks.iter().map(|(_, block)| 4 + block.len() as u64).sum();
+    if len + 4 <= old_len {
+        blocks.push((BLOCK_PADDING, vec![0; (old_len - len - 4) as usize]));
+    } else if len != old_len {
+        blocks.push((BLOCK_PADDING, vec![0; PADDING]));
+    }
+
+    let mut metadata = vec![];
+    let count = blocks.len();
+    for (index, (kind, block)) in blocks.into_iter().enumerate() {
+        let last = if index + 1 == count { 0x80 } else { 0 };
+        metadata.push(kind | last);
+        metadata.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        metadata.extend_from_slice(&block);
+    }
+
+    super::splice(path, &[(start, old_len, &metadata)])
+}
+
+/// Replaces the comment header of the Ogg Vorbis or Opus file at
+/// `path`. The pages following the headers are renumbered, so the
+/// whole file is rewritten.
+pub(super) fn write_ogg(path: &OsString, details: &SongDetails) -> Result<(), TagError> {
+    let mut source = BufReader::new(File::open(path)?);
+
+    let first = ogg::read_page(&mut source)?
+        .ok_or_else(|| TagError::Malformed("empty Ogg stream".into()))?;
+    let (header_count, prefix): (usize, &[u8]) = if first.data.starts_with(b"\x01vorbis") {
+        (3, b"\x03vorbis")
+    } else if first.data.starts_with(b"OpusHead") {
+        (2, b"OpusTags")
+    } else {
+        return Err(TagError::UnsupportedFormat);
+    };
+
+    // Collects the remaining header packets, which must end
+    // exactly where a page ends
+    let mut packets = vec![];
+    let mut packet = vec![];
+    let mut old_pages = 1;
+    while packets.len() < header_count - 1 {
+        let page = ogg::read_page(&mut source)?
+            .ok_or_else(|| TagError::Malformed("missing Ogg header packets".into()))?;
+        if page.serial != first.serial {
+            return Err(TagError::UnsupportedFormat);
+        }
+        old_pages += 1;
+
+        let mut offset = 0;
+        for lacing in &page.segments {
+            if packets.len() == header_count - 1 {
+                return Err(TagError::Malformed("audio data in header page".into()));
+            }
+            let lacing = *lacing as usize;
+            packet.extend_from_slice(&page.data[offset..offset + lacing]);
+            offset += lacing;
+            if lacing < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+    }
+
+    let existing = packets[0]
+        .strip_prefix(prefix)
+        .ok_or_else(|| TagError::Malformed("invalid Ogg comment header".into()))?;
+    let mut comments = prefix.to_vec();
+    comments.extend_from_slice(&build_comments(Some(existing), details)?);
+    if header_count == 3 {
+        // Vorbis framing bit
+        comments.push(1);
+    }
+    packets[0] = comments;
+
+    let pages = ogg::paginate(&packets, first.serial, first.sequence + 1);
+    let shift = pages.len() as i64 - (old_pages - 1) as i64;
+
+    let temp = super::temp_path(path);
+    let result = (|| -> std::io::Result<()> {
+        let mut target = BufWriter::new(File::create(&temp)?);
+        first.write_to(&mut target)?;
+        for page in pages {
+            page.write_to(&mut target)?;
+        }
+        while let Some(mut page) = ogg::read_page(&mut source)? {
+            if page.serial == first.serial {
+                page.sequence = (page.sequence as i64 + shift) as u32;
+            }
+            page.write_to(&mut target)?;
+        }
+        target.into_inner()?.sync_all()
+    })();
+
+    super::persist(&temp, path, result)
+}
+
+fn has_field(tags: &Tags, field: Field) -> bool {
+    let details = &tags.details;
+    match field {
+        Field::Name => !details.name().is_empty(),
+        Field::Artist => details.artist().is_some(),
+        Field::Album => details.album().is_some(),
+        Field::AlbumArtist => details.album_artist().is_some(),
+        Field::Track => details.track_number().is_some(),
+        Field::TrackTotal => details.track_total().is_some(),
+        Field::Disc => details.disc_number().is_some(),
+        Field::DiscTotal => details.disc_total().is_some(),
+        Field::Genre => !details.genres().is_empty(),
+        Field::Composer => details.composer().is_some(),
+        Field::Year => details.year().is_some(),
+        Field::Comment => details.comment().is_some(),
+    }
+}
+
+/// Parses the content of a FLAC PICTURE block
+fn parse_picture(data: &[u8]) -> Result<Picture, TagError> {
+    let mut bytes = Bytes::new(data);
+    let kind = bytes.u32_be()?;
+    let mime_len = bytes.u32_be()? as usize;
+    let mime_type = String::from_utf8_lossy(bytes.take(mime_len)?).to_string();
+    let description_len = bytes.u32_be()? as usize;
+    let description = String::from_utf8_lossy(bytes.take(description_len)?).to_string();
+    // Width, height, color depth and number of colors
+    bytes.skip(16)?;
+    let data_len = bytes.u32_be()? as usize;
+    let image = bytes.take(data_len)?.to_vec();
+
+    Ok(Picture::new(
+        PictureKind::from_code(kind.min(u8::MAX as u32) as u8),
+        &mime_type,
+        &description,
+        image,
+    ))
+}
+
+/// Decodes standard base64, ignoring whitespace. Returns `None` for
+/// invalid input.
+fn decode_base64(input: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::with_capacity(input.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn comment_list(comments: &[&str]) -> Vec<u8> {
+        let mut block = 4u32.to_le_bytes().to_vec();
+        block.extend_from_slice(b"test");
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        block
+    }
+
+    fn flac_block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+        let mut block = vec![kind | if last { 0x80 } else { 0 }];
+        block.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        block.extend_from_slice(body);
+        block
+    }
+
+    /// A FLAC picture block holding the PNG image `[1, 2]`
+    fn picture_block() -> Vec<u8> {
+        let mut block = vec![0, 0, 0, 3, 0, 0, 0, 9];
+        block.extend_from_slice(b"image/png\0\0\0\x01d");
+        block.extend_from_slice(&[0; 16]);
+        block.extend_from_slice(&[0, 0, 0, 2, 1, 2]);
+        block
+    }
+
+    fn flac(comments: &[&str]) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, false, &[0; 34]));
+        data.extend_from_slice(&flac_block(1, false, &[0; 8]));
+        data.extend_from_slice(&flac_block(4, false, &comment_list(comments)));
+        data.extend_from_slice(&flac_block(6, true, &picture_block()));
+        data
+    }
+
+    fn ogg(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = vec![];
+        for page in ogg::paginate(&packets[..1], 1, 0)
+            .into_iter()
+            .chain(ogg::paginate(&packets[1..], 1, 1))
+        {
+            page.write_to(&mut data).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn reads_flac_comments() {
+        let data = flac(&[
+            "title=Name",
+            "ARTIST=Artist",
+            "ARTIST=Ignored",
+            "GENRE=Rock",
+            "GENRE=Jazz",
+            "TRACKNUMBER=3/12",
+            "DISCNUMBER=1",
+            "TOTALDISCS=2",
+            "DATE=1999-04-01",
+            "REPLAYGAIN_TRACK_GAIN=-6.5 dB",
+            "LYRICS=[00:01.00]First",
+            "UNKNOWN=value",
+            "not a comment",
+        ]);
+        let tags = read_flac(&mut Cursor::new(&data)).unwrap();
+        let details = tags.details();
+        assert_eq!(details.name(), "Name");
+        assert_eq!(details.artist(), Some("Artist"));
+        assert_eq!(details.genres(), ["Rock", "Jazz"]);
+        assert_eq!(details.track_number(), Some(3));
+        assert_eq!(details.track_total(), Some(12));
+        assert_eq!(details.disc_number(), Some(1));
+        assert_eq!(details.disc_total(), Some(2));
+        assert_eq!(details.year(), Some(1999));
+        assert_eq!(details.replay_gain().track_gain(), Some(-6.5));
+        assert!(tags.lyrics().unwrap().is_synced());
+        assert_eq!(
+            tags.pictures(),
+            &vec![Picture::new(
+                PictureKind::FrontCover,
+                "image/png",
+                "d",
+                vec![1, 2]
+            )]
+        );
+    }
+
+    #[test]
+    fn reads_ogg_comments() {
+        let picture = "METADATA_BLOCK_PICTURE=\
+            AAAAAwAAAAlpbWFnZS9wbmcAAAABZAAAAAAAAAAAAAAAAAAAAAAAAAACAQI=";
+        let comments = comment_list(&["TITLE=Name", picture]);
+
+        let vorbis = [b"\x03vorbis".to_vec(), comments.clone(), vec![1]].concat();
+        let data = ogg(&[b"\x01vorbis".to_vec(), vorbis, b"\x05vorbis".to_vec()]);
+        let tags = read_ogg(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(tags.details().name(), "Name");
+        assert_eq!(tags.pictures()[0].data(), &[1, 2]);
+
+        let opus = [b"OpusTags".to_vec(), comments.clone()].concat();
+        let data = ogg(&[b"OpusHead".to_vec(), opus]);
+        let tags = read_ogg(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(tags.details().name(), "Name");
+
+        // Ogg FLAC prefixes the block with its metadata header
+        let flac = [vec![0x84, 0, 0, 0], comments].concat();
+        let data = ogg(&[b"\x7FFLAC".to_vec(), flac]);
+        let tags = read_ogg(&mut Cursor::new(&data)).unwrap();
+        assert_eq!(tags.details().name(), "Name");
+
+        let data = ogg(&[b"\x01video".to_vec(), b"\x03video".to_vec()]);
+        assert!(read_ogg(&mut Cursor::new(&data)).is_err());
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("aGVs\nbG8").unwrap(), b"hello");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert!(decode_base64("a*b").is_none());
+    }
+
+    #[test]
+    fn keeps_unmapped_comments_when_building() {
+        let existing = comment_list(&["TITLE=Old", "CUSTOM=kept", "GENRE=Old"]);
+        let mut details = SongDetails::new("New", None, None, None);
+        details.add_genre("Rock");
+        let block = build_comments(Some(&existing), &details).unwrap();
+        let (vendor, comments) = parse_comment_list(&block).unwrap();
+        assert_eq!(vendor, "test");
+        assert_eq!(comments, ["CUSTOM=kept", "TITLE=New", "GENRE=Rock"]);
+
+        let block = build_comments(None, &details).unwrap();
+        assert_eq!(parse_comment_list(&block).unwrap().0, VENDOR);
+    }
+
+    #[test]
+    fn survives_truncated_input() {
+        let data = flac(&["TITLE=Name", "ARTIST=Artist"]);
+        for len in 0..data.len() {
+            assert!(read_flac(&mut Cursor::new(&data[..len])).is_err());
+        }
+
+        let comments = [b"OpusTags".to_vec(), comment_list(&["TITLE=Name"])].concat();
+        let data = ogg(&[b"OpusHead".to_vec(), comments]);
+        for len in 0..data.len() {
+            assert!(read_ogg(&mut Cursor::new(&data[..len])).is_err());
+        }
+
+        // Counts and lengths bigger than the block
+        let mut block = comment_list(&["TITLE=Name"]);
+        block[8] = 0xFF;
+        assert!(parse_comment_list(&block).is_err());
+        let mut block = comment_list(&["TITLE=Name"]);
+        block[12] = 0xFF;
+        assert!(parse_comment_list(&block).is_err());
+        assert!(parse_picture(&picture_block()[..30]).is_err());
+    }
+}
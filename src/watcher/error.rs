@@ -0,0 +1,32 @@
+use std::error::Error;
+use std::fmt::Display;
+
+/// Errors describing why directories couldn't be watched
+#[derive(Debug)]
+pub enum WatchError {
+    Notify(notify::Error),
+    /// The storage keeps its documents somewhere that can't be watched
+    Unsupported,
+}
+
+impl Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::Notify(err) => {
+                writeln!(f, "The directories couldn't be watched")?;
+                writeln!(f, "Here's the cause: {}", err)
+            }
+            WatchError::Unsupported => {
+                writeln!(f, "The storage doesn't keep its documents in directories")
+            }
+        }
+    }
+}
+
+impl Error for WatchError {}
+
+impl From<notify::Error> for WatchError {
+    fn from(err: notify::Error) -> Self {
+        WatchError::Notify(err)
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+};
+
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _,
+};
+
+pub use self::error::WatchError;
+
+mod error;
+
+/// A change made to watched directories
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The file or directory was created, edited or removed
+    Changed(PathBuf),
+    /// The file or directory was moved from the first path to the second
+    Moved(PathBuf, PathBuf),
+    /// Changes were lost, e.g. because too many happened at once:
+    /// anything may have changed
+    Rescan,
+}
+
+/// Watches directories, and everything inside them, for changes
+/// made by other programs, using the native mechanism of the system,
+/// e.g. inotify on Linux
+pub struct Watcher {
+    watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    /// Each watched directory, canonicalized, and as it was given
+    dirs: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Watcher {
+    pub fn new() -> Result<Self, WatchError> {
+        let (sender, events) = channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+        Ok(Self {
+            watcher,
+            events,
+            dirs: vec![],
+        })
+    }
+
+    /// Starts watching `dir` and everything inside it
+    pub fn watch(&mut self, dir: &Path) -> Result<(), WatchError> {
+        self.watcher.watch(dir, RecursiveMode::Recursive)?;
+        let canonical = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        self.dirs.push((canonical, dir.to_path_buf()));
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, dir: &Path) -> Result<(), WatchError> {
+        self.watcher.unwatch(dir)?;
+        self.dirs.retain(|(_, watched)| watched != dir);
+        Ok(())
+    }
+
+    /// Returns `path`, as reported by the system, inside the watched
+    /// directory as it was given to `watch`, e.g. relative or through
+    /// a symbolic link, for it to be compared with other paths
+    fn as_watched(&self, path: PathBuf) -> PathBuf {
+        if self
+            .dirs
+            .iter()
+            .any(|(_, watched)| path.starts_with(watched))
+        {
+            return path;
+        }
+        self.dirs
+            .iter()
+            .filter_map(|(canonical, watched)| {
+                let rest = path.strip_prefix(canonical).ok()?;
+                Some((canonical.components().count(), watched.join(rest)))
+            })
+            .max_by_key(|(depth, _)| *depth)
+            .map(|(_, path)| path)
+            .unwrap_or(path)
+    }
+
+    /// Returns the changes that happened since the last call, without
+    /// waiting for new ones. Paths are inside the watched directories as
+    /// they were given to `watch`.
+    pub fn changes(&self) -> Vec<Change> {
+        let mut changes = vec![];
+        for event in self.events.try_iter() {
+            let event = match event {
+                Ok(event) => event,
+                Err(_) => {
+                    changes.push(Change::Rescan);
+                    continue;
+                }
+            };
+            if event.need_rescan() {
+                changes.push(Change::Rescan);
+                continue;
+            }
+
+            match event.kind {
+                EventKind::Access(_) => {}
+                EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                    let mut paths = event.paths.into_iter();
+                    if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                        changes.push(Change::Moved(self.as_watched(from), self.as_watched(to)));
+                    }
+                }
+                _ => changes.extend(
+                    event
+                        .paths
+                        .into_iter()
+                        .map(|path| Change::Changed(self.as_watched(path))),
+                ),
+            }
+        }
+        changes
+    }
+}